@@ -0,0 +1,153 @@
+//! Synthetic load against `/check` or `/proxy` of an instance, at a fixed
+//! rate over a number of keys, for sizing deployments.
+
+use anyhow::{bail, Result};
+use clap::{Args, ValueEnum};
+use reqwest::Url;
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use tokio::sync::Semaphore;
+
+#[derive(Debug, Args)]
+pub struct BenchArgs {
+    #[arg(long, value_enum, default_value_t = Endpoint::Check)]
+    endpoint: Endpoint,
+    /// Destination of the requests, selecting their policy.
+    #[arg(long, default_value = "http://127.0.0.1/")]
+    url: String,
+    /// Distinct rate limit keys, used in turn.
+    #[arg(long, default_value_t = 100)]
+    keys: u64,
+    /// Requests started per second.
+    #[arg(long, default_value_t = 100.0)]
+    rate: f64,
+    #[arg(long, default_value_t = 10)]
+    duration_secs: u64,
+    /// Size of the JSON body of proxied requests.
+    #[arg(long, default_value_t = 0)]
+    payload_bytes: usize,
+    /// Most requests in flight; the rate drops once they are all waiting.
+    #[arg(long, default_value_t = 64)]
+    concurrency: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Endpoint {
+    Check,
+    Proxy,
+}
+
+enum Outcome {
+    Allowed,
+    Rejected(String),
+    Failed,
+}
+
+#[derive(Default)]
+struct Tally {
+    /// Latency of every request, failed ones included, in microseconds.
+    latencies_us: Vec<u64>,
+    allowed: u64,
+    rejected: BTreeMap<String, u64>,
+    errors: u64,
+}
+
+/// Sends the load and reports latency percentiles and rejection ratios.
+pub async fn run(server: &Url, args: BenchArgs) -> Result<Value> {
+    if !args.rate.is_finite() || args.rate <= 0.0 || args.keys == 0 || args.concurrency == 0 {
+        bail!("rate, keys and concurrency must be positive");
+    }
+    let endpoint = match args.endpoint {
+        Endpoint::Check => crate::url(server, &["check"]),
+        Endpoint::Proxy => crate::url(server, &["proxy"]),
+    };
+    let payload = "x".repeat(args.payload_bytes);
+    let http = reqwest::Client::new();
+    let slots = Arc::new(Semaphore::new(args.concurrency));
+    let tally = Arc::new(Mutex::new(Tally::default()));
+
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(args.duration_secs);
+    let mut ticker = tokio::time::interval(Duration::from_secs_f64(1.0 / args.rate));
+    let mut sent = 0u64;
+    while ticker.tick().await < tokio::time::Instant::from_std(deadline) {
+        let slot = slots.clone().acquire_owned().await?;
+        let key = format!("bench-{}", sent % args.keys);
+        let body = match args.endpoint {
+            Endpoint::Check => json!({ "key": key, "url": args.url }),
+            Endpoint::Proxy => json!({
+                "key": key,
+                "url": args.url,
+                "method": if payload.is_empty() { "GET" } else { "POST" },
+                "headers": {},
+                "query": {},
+                "body": if payload.is_empty() { Value::Null } else { json!({ "payload": payload }) },
+            }),
+        };
+        let request = http.post(endpoint.clone()).json(&body);
+        let (tally, check) = (tally.clone(), args.endpoint == Endpoint::Check);
+        tokio::spawn(async move {
+            let sent_at = Instant::now();
+            let outcome = match request.send().await {
+                Ok(response) => classify(response, check).await,
+                Err(_) => Outcome::Failed,
+            };
+            let mut tally = tally.lock().expect("tally lock poisoned");
+            match outcome {
+                Outcome::Allowed => tally.allowed += 1,
+                Outcome::Rejected(reason) => *tally.rejected.entry(reason).or_default() += 1,
+                Outcome::Failed => tally.errors += 1,
+            }
+            tally.latencies_us.push(sent_at.elapsed().as_micros() as u64);
+            drop(slot);
+        });
+        sent += 1;
+    }
+    // Waits for the requests in flight
+    let _ = slots.acquire_many(args.concurrency as u32).await?;
+    let elapsed = started.elapsed().as_secs_f64();
+
+    let mut tally = tally.lock().expect("tally lock poisoned");
+    tally.latencies_us.sort_unstable();
+    let percentile = |p: f64| {
+        let latencies = &tally.latencies_us;
+        let i = ((latencies.len() as f64 * p).ceil() as usize).clamp(1, latencies.len().max(1)) - 1;
+        latencies.get(i).map_or(0.0, |us| *us as f64 / 1000.0)
+    };
+    let rejected: u64 = tally.rejected.values().sum();
+    Ok(json!({
+        "endpoint": endpoint.as_str(),
+        "requests": sent,
+        "rate_per_sec": sent as f64 / elapsed,
+        "allowed": tally.allowed,
+        "rejected": rejected,
+        "rejected_ratio": rejected as f64 / sent.max(1) as f64,
+        "errors": tally.errors,
+        "latency_p50_ms": percentile(0.5),
+        "latency_p90_ms": percentile(0.9),
+        "latency_p99_ms": percentile(0.99),
+        "latency_max_ms": percentile(1.0),
+        "rejections": tally.rejected.iter().map(|(reason, count)| json!({ "reason": reason, "count": count })).collect::<Vec<_>>(),
+    }))
+}
+
+/// Rejections are told by the `X-Rejection-Reason` of proxied requests and
+/// the `allowed` of checks.
+async fn classify(response: reqwest::Response, check: bool) -> Outcome {
+    let reason = response.headers().get("x-rejection-reason").and_then(|v| v.to_str().ok()).map(str::to_string);
+    let status = response.status();
+    if let Some(reason) = reason {
+        return Outcome::Rejected(reason);
+    }
+    if !check {
+        return if status.is_server_error() { Outcome::Failed } else { Outcome::Allowed };
+    }
+    match response.json::<Value>().await {
+        Ok(answer) if status.is_success() => match answer["allowed"].as_bool() {
+            Some(true) => Outcome::Allowed,
+            Some(false) => Outcome::Rejected(answer["reason"].as_str().unwrap_or("rate_limited").to_string()),
+            None => Outcome::Failed,
+        },
+        _ => Outcome::Failed,
+    }
+}
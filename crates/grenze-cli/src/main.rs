@@ -0,0 +1,298 @@
+//! Command line client of the grenze admin API: inspects and resets buckets,
+//! manages key rules, tails usage, runs simulations and reloads scripts. It
+//! also load tests instances.
+
+mod bench;
+mod output;
+
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use reqwest::{Method, Url};
+use serde_json::{json, Value};
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+use crate::{bench::BenchArgs, output::Format};
+
+#[derive(Debug, Parser)]
+#[command(name = "grenze-cli", version, about = "Operates a grenze instance through its admin API")]
+struct Cli {
+    /// Base URL of the grenze instance.
+    #[arg(long, env = "GRENZE_URL", default_value = "http://localhost:8080", global = true)]
+    server: String,
+    /// Admin token of the instance.
+    #[arg(long, env = "GRENZE_ADMIN_TOKEN", hide_env_values = true, global = true)]
+    token: Option<String>,
+    #[arg(long, short, value_enum, default_value_t = Format::Table, global = true)]
+    output: Format,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Shows the buckets a key draws from and whether its next request fits.
+    Explain {
+        key: String,
+        /// Destination of the request, selecting policy and bucket.
+        #[arg(long)]
+        url: Option<String>,
+        /// Policy to explain; the one resolved for the destination if unset.
+        #[arg(long)]
+        policy: Option<String>,
+    },
+    /// Empties a bucket, named as by `explain`.
+    Reset { bucket: String },
+    /// Reads, writes or deletes the rule of a key.
+    Rule {
+        #[command(subcommand)]
+        action: RuleAction,
+    },
+    /// Has every instance drop its cached key rules.
+    FlushRules,
+    /// Shows the usage of a key per period and policy.
+    Usage {
+        key: String,
+        #[arg(long, value_enum, default_value_t = Granularity::Hour)]
+        granularity: Granularity,
+        #[arg(long)]
+        from_ms: Option<i64>,
+        #[arg(long)]
+        to_ms: Option<i64>,
+        /// Keeps polling, printing periods as their counts change.
+        #[arg(long, short)]
+        follow: bool,
+        #[arg(long, default_value_t = 10)]
+        interval_secs: u64,
+    },
+    /// Shows the billable usage of every key in a period, e.g. `2026-10`.
+    Billing {
+        period: String,
+        /// Prints the report as CSV.
+        #[arg(long)]
+        csv: bool,
+    },
+    /// Replays the request trace of a JSON file against fresh buckets.
+    Simulate { file: PathBuf },
+    /// Recompiles the routing script.
+    ReloadScript,
+    /// Sends synthetic traffic and reports latency percentiles and
+    /// rejection ratios.
+    Bench(BenchArgs),
+}
+
+#[derive(Debug, Subcommand)]
+enum RuleAction {
+    Get {
+        key: String,
+    },
+    /// Replaces the rule of a key.
+    Set {
+        key: String,
+        /// Rejects every request of the key.
+        #[arg(long)]
+        banned: bool,
+        /// Told to banned callers.
+        #[arg(long)]
+        reason: Option<String>,
+        #[arg(long)]
+        capacity: Option<u32>,
+        #[arg(long)]
+        leak_per_sec: Option<f64>,
+        /// Lifetime of the rule; forever if unset.
+        #[arg(long)]
+        ttl_secs: Option<u64>,
+    },
+    Delete {
+        key: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Granularity {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl Granularity {
+    fn as_str(self) -> &'static str {
+        match self {
+            Granularity::Minute => "minute",
+            Granularity::Hour => "hour",
+            Granularity::Day => "day",
+        }
+    }
+}
+
+struct Admin {
+    http: reqwest::Client,
+    base: Url,
+    token: String,
+}
+
+/// `base` with `segments` appended to its path.
+fn url(base: &Url, segments: &[&str]) -> Url {
+    let mut url = base.clone();
+    url.path_segments_mut().expect("base URL checked to be http(s)").pop_if_empty().extend(segments);
+    url
+}
+
+impl Admin {
+    fn url(&self, segments: &[&str]) -> Url {
+        url(&self.base, segments)
+    }
+
+    /// Sends a request, failing with the server's error on non-2xx answers.
+    async fn send(&self, method: Method, url: Url, body: Option<&Value>) -> Result<reqwest::Response> {
+        let mut request = self.http.request(method, url.clone()).bearer_auth(&self.token);
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        let response = request.send().await.with_context(|| format!("failed to reach {}", url))?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            match serde_json::from_str::<Value>(&text) {
+                Ok(error) => bail!("{} {}: {}", status.as_u16(), error["error"].as_str().unwrap_or("error"), error["message"].as_str().unwrap_or(&text)),
+                Err(_) => bail!("{}: {}", status, text),
+            }
+        }
+        Ok(response)
+    }
+
+    async fn json(&self, method: Method, url: Url, body: Option<&Value>) -> Result<Value> {
+        Ok(self.send(method, url, body).await?.json().await?)
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    if let Err(e) = run(Cli::parse()).await {
+        eprintln!("error: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    let base = Url::parse(&cli.server).with_context(|| format!("invalid server URL '{}'", cli.server))?;
+    if !matches!(base.scheme(), "http" | "https") {
+        bail!("server URL must be http(s)");
+    }
+    let command = match cli.command {
+        Command::Bench(args) => {
+            output::print(&bench::run(&base, args).await?, cli.output);
+            return Ok(());
+        },
+        command => command,
+    };
+    let Some(token) = cli.token else {
+        bail!("an admin token is required, as --token or GRENZE_ADMIN_TOKEN");
+    };
+    let admin = Admin {
+        http: reqwest::Client::new(),
+        base,
+        token,
+    };
+    let format = cli.output;
+
+    let value = match command {
+        Command::Explain { key, url, policy } => {
+            let mut query = admin.url(&["admin", "explain"]);
+            query.query_pairs_mut().append_pair("key", &key);
+            if let Some(url) = &url {
+                query.query_pairs_mut().append_pair("url", url);
+            }
+            if let Some(policy) = &policy {
+                query.query_pairs_mut().append_pair("policy", policy);
+            }
+            admin.json(Method::GET, query, None).await?
+        },
+        Command::Reset { bucket } => admin.json(Method::DELETE, admin.url(&["admin", "buckets", &bucket]), None).await?,
+        Command::Rule { action } => match action {
+            RuleAction::Get { key } => admin.json(Method::GET, admin.url(&["admin", "keys", &key, "rule"]), None).await?,
+            RuleAction::Set {
+                key,
+                banned,
+                reason,
+                capacity,
+                leak_per_sec,
+                ttl_secs,
+            } => {
+                let rule = json!({
+                    "banned": banned,
+                    "reason": reason,
+                    "capacity": capacity,
+                    "leak_per_sec": leak_per_sec,
+                    "ttl_secs": ttl_secs,
+                });
+                admin.json(Method::PUT, admin.url(&["admin", "keys", &key, "rule"]), Some(&rule)).await?
+            },
+            RuleAction::Delete { key } => admin.json(Method::DELETE, admin.url(&["admin", "keys", &key, "rule"]), None).await?,
+        },
+        Command::FlushRules => admin.json(Method::DELETE, admin.url(&["admin", "key-rules", "cache"]), None).await?,
+        Command::Usage {
+            key,
+            granularity,
+            from_ms,
+            to_ms,
+            follow,
+            interval_secs,
+        } => {
+            let mut url = admin.url(&["admin", "usage", &key]);
+            url.query_pairs_mut().append_pair("granularity", granularity.as_str());
+            if let Some(from_ms) = from_ms {
+                url.query_pairs_mut().append_pair("from_ms", &from_ms.to_string());
+            }
+            if let Some(to_ms) = to_ms {
+                url.query_pairs_mut().append_pair("to_ms", &to_ms.to_string());
+            }
+            if follow {
+                return tail(&admin, url, Duration::from_secs(interval_secs.max(1)), format).await;
+            }
+            admin.json(Method::GET, url, None).await?
+        },
+        Command::Billing { period, csv } => {
+            let mut url = admin.url(&["admin", "billing", &period]);
+            if csv {
+                url.query_pairs_mut().append_pair("format", "csv");
+                print!("{}", admin.send(Method::GET, url, None).await?.text().await?);
+                return Ok(());
+            }
+            admin.json(Method::GET, url, None).await?
+        },
+        Command::Simulate { file } => {
+            let text = std::fs::read_to_string(&file).with_context(|| format!("failed to read {}", file.display()))?;
+            let simulation: Value = serde_json::from_str(&text).with_context(|| format!("invalid simulation in {}", file.display()))?;
+            admin.json(Method::POST, admin.url(&["admin", "simulate"]), Some(&simulation)).await?
+        },
+        Command::ReloadScript => admin.json(Method::POST, admin.url(&["admin", "script", "reload"]), None).await?,
+        Command::Bench(_) => unreachable!("run without the admin API above"),
+    };
+    output::print(&value, format);
+    Ok(())
+}
+
+/// Polls the usage at `url` every `interval`, printing the periods whose
+/// counts changed since the previous poll, all of them at first.
+async fn tail(admin: &Admin, url: Url, interval: Duration, format: Format) -> Result<()> {
+    let mut seen: HashMap<(i64, String), Value> = HashMap::new();
+    loop {
+        let counts = admin.json(Method::GET, url.clone(), None).await?;
+        let changed: Vec<Value> = counts
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|count| {
+                let id = (count["period_ms"].as_i64().unwrap_or(0), count["policy"].as_str().unwrap_or_default().to_string());
+                seen.insert(id, (*count).clone()).as_ref() != Some(*count)
+            })
+            .cloned()
+            .collect();
+        match format {
+            Format::Json => changed.iter().for_each(|count| println!("{}", count)),
+            Format::Table if !changed.is_empty() => output::print(&Value::Array(changed), format),
+            Format::Table => {},
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
@@ -0,0 +1,80 @@
+//! Rendering of admin API answers, either as the JSON the server sent or as
+//! plain text tables for people.
+
+use clap::ValueEnum;
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Table,
+    Json,
+}
+
+/// Prints `value` in `format`. Tables show an object's scalar fields as
+/// name and value, followed by a table per array field; arrays of objects
+/// get a column per field of their first element.
+pub fn print(value: &Value, format: Format) {
+    match format {
+        Format::Json => println!("{}", serde_json::to_string_pretty(value).unwrap_or_default()),
+        Format::Table => print!("{}", render(value)),
+    }
+}
+
+fn render(value: &Value) -> String {
+    match value {
+        Value::Array(items) => table(items),
+        Value::Object(fields) => {
+            let scalars: Vec<_> = fields.iter().filter(|(_, v)| !v.is_array()).map(|(name, v)| vec![name.clone(), cell(v)]).collect();
+            let mut out = columns(&[], &scalars);
+            for (name, v) in fields {
+                if let Value::Array(items) = v {
+                    out.push_str(&format!("\n{}:\n", name));
+                    out.push_str(&table(items));
+                }
+            }
+            out
+        },
+        scalar => format!("{}\n", cell(scalar)),
+    }
+}
+
+fn table(items: &[Value]) -> String {
+    let Some(Value::Object(first)) = items.first() else {
+        let rows: Vec<_> = items.iter().map(|item| vec![cell(item)]).collect();
+        return columns(&[], &rows);
+    };
+    let header: Vec<String> = first.keys().cloned().collect();
+    let rows: Vec<Vec<String>> = items
+        .iter()
+        .map(|item| header.iter().map(|name| item.get(name).map(cell).unwrap_or_default()).collect())
+        .collect();
+    columns(&header, &rows)
+}
+
+/// Rows aligned into columns, under an upper-cased header unless empty.
+fn columns(header: &[String], rows: &[Vec<String>]) -> String {
+    let header: Vec<String> = header.iter().map(|name| name.to_uppercase().replace('_', " ")).collect();
+    let all = || std::iter::once(&header).filter(|h| !h.is_empty()).chain(rows);
+    let widths: Vec<usize> = (0..all().map(Vec::len).max().unwrap_or(0))
+        .map(|i| all().filter_map(|row| row.get(i)).map(|c| c.chars().count()).max().unwrap_or(0))
+        .collect();
+    let mut out = String::new();
+    for row in all() {
+        let line: Vec<String> = row.iter().zip(&widths).map(|(c, w)| format!("{:<w$}", c, w = w)).collect();
+        out.push_str(line.join("  ").trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::String(s) => s.clone(),
+        Value::Number(n) => match n.as_f64() {
+            Some(f) if n.is_f64() => format!("{:.2}", f),
+            _ => n.to_string(),
+        },
+        other => other.to_string(),
+    }
+}
@@ -0,0 +1,98 @@
+//! Errors of client calls.
+
+use reqwest::{header::RETRY_AFTER, StatusCode};
+use std::{fmt, time::Duration};
+
+/// Header naming the cause of a rejection.
+const X_REJECTION_REASON: &str = "x-rejection-reason";
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The base URL, or a URL built from it, is invalid.
+    InvalidUrl(String),
+    /// grenze rejected the request, after any retries.
+    Rejected(Rejection),
+    /// A download got an unsuccessful response, e.g. `404` from the
+    /// downstream.
+    Status(StatusCode),
+    /// grenze could not be reached, or the response not be read.
+    Transport(reqwest::Error),
+    /// A file to upload or download to could not be read or written.
+    Io(std::io::Error),
+}
+
+/// Rejection of a request by grenze, rather than a response of the
+/// downstream.
+#[derive(Debug, Clone)]
+pub struct Rejection {
+    pub status: StatusCode,
+    /// Cause of the rejection, e.g. `rate_limited` or `circuit_open`.
+    pub reason: String,
+    /// `message` of the default JSON error body, if the response has one.
+    pub message: Option<String>,
+    pub retry_after: Option<Duration>,
+}
+
+impl Rejection {
+    /// Whether waiting may get the request admitted, unlike e.g. a ban.
+    pub fn is_transient(&self) -> bool {
+        matches!(self.status, StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE)
+    }
+
+    /// Rejection `response` is, if grenze rejected the request; the
+    /// response is returned as it is otherwise.
+    pub(crate) async fn check(response: reqwest::Response) -> Result<reqwest::Response> {
+        let Some(reason) = response.headers().get(X_REJECTION_REASON).and_then(|v| v.to_str().ok()).map(str::to_string) else {
+            return Ok(response);
+        };
+        let status = response.status();
+        let retry_after = response.headers().get(RETRY_AFTER).and_then(|v| v.to_str().ok()).and_then(|v| v.trim().parse().ok()).map(Duration::from_secs);
+        let body = response.json::<serde_json::Value>().await.ok();
+        let message = body.as_ref().and_then(|b| b["message"].as_str()).map(str::to_string);
+        Err(Error::Rejected(Rejection {
+            status,
+            reason,
+            message,
+            retry_after,
+        }))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidUrl(url) => write!(f, "invalid URL '{}'", url),
+            Error::Rejected(rejection) => match &rejection.message {
+                Some(message) => write!(f, "rejected by grenze with {} {}: {}", rejection.status, rejection.reason, message),
+                None => write!(f, "rejected by grenze with {} {}", rejection.status, rejection.reason),
+            },
+            Error::Status(status) => write!(f, "request failed with {}", status),
+            Error::Transport(e) => write!(f, "failed to reach grenze: {}", e),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Transport(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Transport(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
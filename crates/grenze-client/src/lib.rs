@@ -0,0 +1,227 @@
+//! Client of the grenze `/proxy` and passthrough APIs.
+//!
+//! With [`Pacing`], the client mirrors the bucket of every rate limit key it
+//! sends requests for, using the leaky bucket math of [`grenze_core`], and
+//! holds requests back until they fit instead of sending them only to be
+//! rejected. The mirrors follow the `RateLimit-*` and `Retry-After` headers
+//! grenze answers with, so requests of other clients sharing a key are taken
+//! into account too:
+//!
+//! ```no_run
+//! # async fn example() -> grenze_client::Result<()> {
+//! use grenze_client::{Client, Pacing, ProxyRequest};
+//!
+//! let client = Client::new("http://localhost:8080")?.with_pacing(Pacing::new(10, 2.0));
+//! for page in 1..=20 {
+//!     let request = ProxyRequest::new("user-123", "GET", "https://api.example.com/items").with_query("page", page.to_string());
+//!     // Past the first 10, requests leave at 2 per second
+//!     let response = client.proxy(&request).await?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Rejections by grenze are returned as [`Error::Rejected`], once a
+//! [`RetryPolicy`] gives up on them, while responses of the downstream are
+//! returned whatever their status. [`PassthroughRequest`]s stream their
+//! bodies, e.g. to upload or download files:
+//!
+//! ```no_run
+//! # async fn example(client: grenze_client::Client) -> grenze_client::Result<()> {
+//! use grenze_client::{PassthroughRequest, RetryPolicy};
+//! use reqwest::Method;
+//! use std::time::Duration;
+//!
+//! let upload = PassthroughRequest::new("user-123", "storage", Method::PUT, "/buckets/backups/db.tar").with_file("db.tar").await?;
+//! client.passthrough(upload).await?;
+//! let download = PassthroughRequest::new("user-123", "storage", Method::GET, "/buckets/backups/db.tar")
+//!     .with_retry(RetryPolicy::exponential(3, Duration::from_millis(200)));
+//! let bytes = client.download(download, "restored.tar").await?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod error;
+mod pacing;
+mod passthrough;
+mod retry;
+
+use futures::StreamExt;
+use reqwest::Method;
+use serde::Serialize;
+use std::{collections::HashMap, path::Path, sync::Arc};
+use tokio::io::AsyncWriteExt;
+
+pub use error::{Error, Rejection, Result};
+pub use pacing::Pacing;
+pub use passthrough::PassthroughRequest;
+pub use retry::RetryPolicy;
+
+use crate::pacing::Pacer;
+
+/// Body of a `/proxy` request.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ProxyRequest {
+    /// Rate limit key, which paced requests are mirrored by.
+    pub key: String,
+    pub url: String,
+    pub method: String,
+    pub headers: HashMap<String, String>,
+    /// Query parameters, replacing those of the same name in `url`.
+    pub query: HashMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    /// Retries of this request instead of the client's.
+    #[serde(skip)]
+    pub retry: Option<RetryPolicy>,
+}
+
+impl ProxyRequest {
+    pub fn new(key: impl Into<String>, method: impl Into<String>, url: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            method: method.into(),
+            url: url.into(),
+            ..Self::default()
+        }
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.insert(name.into(), value.into());
+        self
+    }
+
+    pub fn with_query(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.query.insert(name.into(), value.into());
+        self
+    }
+
+    pub fn with_body(mut self, body: serde_json::Value) -> Self {
+        self.body = Some(body);
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+/// Client of one grenze server. Clones share their connections and pacing.
+#[derive(Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: reqwest::Url,
+    pacer: Option<Arc<Pacer>>,
+    retry: RetryPolicy,
+}
+
+impl Client {
+    /// Client of the server at `base_url`, e.g. `http://localhost:8080`,
+    /// sending requests as soon as they are made and without retrying them.
+    pub fn new(base_url: &str) -> Result<Self> {
+        let mut base_url = reqwest::Url::parse(base_url).map_err(|_| Error::InvalidUrl(base_url.to_string()))?;
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+        Ok(Self {
+            http: reqwest::Client::new(),
+            base_url,
+            pacer: None,
+            retry: RetryPolicy::none(),
+        })
+    }
+
+    /// Sends requests through `http`, e.g. one with timeouts or TLS settings
+    /// of its own.
+    pub fn with_http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// Holds requests back until their key's mirrored bucket has room.
+    pub fn with_pacing(mut self, pacing: Pacing) -> Self {
+        self.pacer = Some(Arc::new(Pacer::new(pacing)));
+        self
+    }
+
+    /// Retries requests that do not set their own policy as `retry` says.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sends `request` through grenze once its key's bucket has room, and
+    /// returns the downstream's response, whatever its status.
+    pub async fn proxy(&self, request: &ProxyRequest) -> Result<reqwest::Response> {
+        let url = self.base_url.join("proxy").map_err(|_| Error::InvalidUrl(self.base_url.to_string()))?;
+        let builder = self.http.post(url).json(request);
+        let method = Method::from_bytes(request.method.to_uppercase().as_bytes()).unwrap_or(Method::POST);
+        self.send(&request.key, &method, builder, request.retry.as_ref().unwrap_or(&self.retry)).await
+    }
+
+    /// Sends `request` through the passthrough endpoint, streaming its body,
+    /// and returns the downstream's response, whose body can be streamed with
+    /// `bytes_stream`.
+    pub async fn passthrough(&self, request: PassthroughRequest) -> Result<reqwest::Response> {
+        let mut builder = self.http.request(request.method.clone(), request.url(&self.base_url)?).headers(request.headers);
+        if let Some(body) = request.body {
+            builder = builder.body(body);
+        }
+        self.send(&request.key, &request.method, builder, request.retry.as_ref().unwrap_or(&self.retry)).await
+    }
+
+    /// Streams the body of the downstream's response to `request` into the
+    /// file at `path`, returning its length. Unsuccessful responses fail with
+    /// [`Error::Status`], leaving the file untouched.
+    pub async fn download(&self, request: PassthroughRequest, path: impl AsRef<Path>) -> Result<u64> {
+        let response = self.passthrough(request).await?;
+        if !response.status().is_success() {
+            return Err(Error::Status(response.status()));
+        }
+        let mut file = tokio::fs::File::create(path).await?;
+        let mut body = response.bytes_stream();
+        let mut written = 0;
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk?;
+            file.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+        }
+        file.flush().await?;
+        Ok(written)
+    }
+
+    /// Sends a request for `key` to be made downstream with `method`, paced
+    /// and retried. Requests whose body is streamed cannot be cloned, and are
+    /// sent only once.
+    async fn send(&self, key: &str, method: &Method, mut builder: reqwest::RequestBuilder, retry: &RetryPolicy) -> Result<reqwest::Response> {
+        let mut attempt = 0;
+        loop {
+            let next = builder.try_clone();
+            if let Some(pacer) = &self.pacer {
+                pacer.wait(key).await;
+            }
+            let result = match builder.send().await {
+                Ok(response) => {
+                    if let Some(pacer) = &self.pacer {
+                        pacer.observe(key, response.status(), response.headers());
+                    }
+                    Rejection::check(response).await
+                },
+                Err(e) => Err(Error::Transport(e)),
+            };
+            let error = match result {
+                Ok(response) => return Ok(response),
+                Err(error) => error,
+            };
+            let (Some(next), Some(delay)) = (next, retry.delay(attempt, method, &error)) else {
+                return Err(error);
+            };
+            tokio::time::sleep(delay).await;
+            builder = next;
+            attempt += 1;
+        }
+    }
+}
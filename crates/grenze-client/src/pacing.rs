@@ -0,0 +1,163 @@
+//! Client-side mirrors of the buckets of rate limit keys.
+
+use grenze_core::Bucket;
+use reqwest::{header::{HeaderMap, HeaderName, RETRY_AFTER}, StatusCode};
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+use tokio::time::Instant;
+
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+
+/// Size of the buckets requests are paced to, that of the keys' policy.
+/// grenze reports capacities with every admitted request, overriding
+/// `capacity`; the leak is not reported and has to match the policy's.
+#[derive(Debug, Clone, Copy)]
+pub struct Pacing {
+    pub capacity: u32,
+    pub leak_per_sec: f64,
+}
+
+impl Pacing {
+    pub fn new(capacity: u32, leak_per_sec: f64) -> Self {
+        Self { capacity, leak_per_sec }
+    }
+}
+
+/// Bucket of a key as the client sees it.
+struct Mirror {
+    capacity: f64,
+    bucket: Option<Bucket>,
+}
+
+pub(crate) struct Pacer {
+    pacing: Pacing,
+    started: Instant,
+    keys: Mutex<HashMap<String, Mirror>>,
+}
+
+impl Pacer {
+    pub fn new(pacing: Pacing) -> Self {
+        Self {
+            pacing,
+            started: Instant::now(),
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn now_ms(&self) -> i64 {
+        self.started.elapsed().as_millis() as i64
+    }
+
+    /// Waits until a request for `key` fits into its mirrored bucket, taking
+    /// its token right away so that concurrent requests queue up behind it.
+    pub async fn wait(&self, key: &str) {
+        let wait_ms = {
+            let mut keys = self.keys.lock().expect("pacing lock poisoned");
+            let now_ms = self.now_ms();
+            // Drained mirrors are dropped as new keys come in, as they would
+            // be created again just the same
+            if !keys.contains_key(key) {
+                keys.retain(|_, mirror| mirror.bucket.is_some_and(|b| b.level(now_ms) > 0.0));
+            }
+            let mirror = keys.entry(key.to_string()).or_insert_with(|| Mirror {
+                capacity: self.pacing.capacity as f64,
+                bucket: None,
+            });
+            // Buckets too small for a request are left to grenze to reject
+            let wait_ms = mirror.bucket.and_then(|b| b.wait_ms(1.0, mirror.capacity, now_ms)).unwrap_or(0);
+            mirror.bucket = Some(Bucket::charge(mirror.bucket.as_ref(), 1.0, self.pacing.leak_per_sec, now_ms));
+            wait_ms
+        };
+        if wait_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(wait_ms as u64)).await;
+        }
+    }
+
+    /// Catches the mirror of `key` up with the bucket grenze reports. The
+    /// fuller of both wins, as the mirror also holds requests still waiting
+    /// to be sent.
+    pub fn observe(&self, key: &str, status: StatusCode, headers: &HeaderMap) {
+        let number = |name: &HeaderName| headers.get(name).and_then(|v| v.to_str().ok()).and_then(|v| v.trim().parse::<u64>().ok());
+        let mut keys = self.keys.lock().expect("pacing lock poisoned");
+        let Some(mirror) = keys.get_mut(key) else {
+            return;
+        };
+        let fill = match (status, number(&RETRY_AFTER)) {
+            // Rejections report the bucket the request waits longest for,
+            // which may be shared with other keys; the next request is held
+            // back until it would be admitted
+            (StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE, Some(retry_after)) => mirror.capacity - 1.0 + retry_after as f64 * self.pacing.leak_per_sec,
+            _ => match (number(&RATELIMIT_LIMIT), number(&RATELIMIT_REMAINING)) {
+                (Some(limit), Some(remaining)) => {
+                    mirror.capacity = limit as f64;
+                    limit.saturating_sub(remaining) as f64
+                },
+                _ => return,
+            },
+        };
+        let now_ms = self.now_ms();
+        let level = mirror.bucket.map_or(0.0, |b| b.level(now_ms));
+        mirror.bucket = Some(Bucket {
+            fill: fill.max(level),
+            last_ms: now_ms,
+            leak_per_sec: self.pacing.leak_per_sec,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    async fn waited_ms(pacer: &Pacer, key: &str) -> u128 {
+        let started = Instant::now();
+        pacer.wait(key).await;
+        started.elapsed().as_millis()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn spaces_out_requests_past_the_capacity() {
+        let pacer = Pacer::new(Pacing::new(2, 4.0));
+        assert_eq!(waited_ms(&pacer, "a").await, 0);
+        assert_eq!(waited_ms(&pacer, "a").await, 0);
+        assert_eq!(waited_ms(&pacer, "a").await, 250);
+        assert_eq!(waited_ms(&pacer, "a").await, 250);
+        // Keys are paced on their own
+        assert_eq!(waited_ms(&pacer, "b").await, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_drained_mirrors_for_new_keys() {
+        let pacer = Pacer::new(Pacing::new(2, 4.0));
+        pacer.wait("a").await;
+        pacer.wait("b").await;
+        tokio::time::advance(Duration::from_millis(250)).await;
+        pacer.wait("c").await;
+        let keys = pacer.keys.lock().expect("pacing lock poisoned");
+        assert_eq!(keys.len(), 1);
+        assert!(keys.contains_key("c"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn follows_the_reported_remaining_tokens() {
+        let pacer = Pacer::new(Pacing::new(100, 2.0));
+        pacer.wait("a").await;
+        let mut headers = HeaderMap::new();
+        headers.insert(RATELIMIT_LIMIT, HeaderValue::from(5));
+        headers.insert(RATELIMIT_REMAINING, HeaderValue::from(0));
+        pacer.observe("a", StatusCode::OK, &headers);
+        assert_eq!(waited_ms(&pacer, "a").await, 500);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn holds_requests_back_for_retry_after() {
+        let pacer = Pacer::new(Pacing::new(10, 1.0));
+        pacer.wait("a").await;
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from(3));
+        pacer.observe("a", StatusCode::TOO_MANY_REQUESTS, &headers);
+        assert_eq!(waited_ms(&pacer, "a").await, 3000);
+    }
+}
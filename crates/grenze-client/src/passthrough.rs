@@ -0,0 +1,109 @@
+//! Requests through the passthrough endpoint, `/p/{key}/{*path}`, whose
+//! bodies are streamed in both directions instead of wrapped in JSON.
+
+use futures::TryStream;
+use reqwest::{header::{HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH}, Method};
+use std::path::Path;
+
+use crate::{error::{Error, Result}, retry::RetryPolicy};
+
+/// Request forwarded to one of grenze's passthrough upstreams as it is.
+#[derive(Debug)]
+pub struct PassthroughRequest {
+    pub key: String,
+    /// Name of the upstream, or its base URL if grenze allows any.
+    pub upstream: String,
+    pub method: Method,
+    /// Path below the upstream's base URL, sent without encoding it further.
+    pub path: String,
+    pub headers: HeaderMap,
+    pub query: Vec<(String, String)>,
+    pub body: Option<reqwest::Body>,
+    /// Retries of this request instead of the client's.
+    pub retry: Option<RetryPolicy>,
+}
+
+impl PassthroughRequest {
+    pub fn new(key: impl Into<String>, upstream: impl Into<String>, method: Method, path: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            upstream: upstream.into(),
+            method,
+            path: path.into(),
+            headers: HeaderMap::new(),
+            query: Vec::new(),
+            body: None,
+            retry: None,
+        }
+    }
+
+    /// Adds a header, ignoring names and values that cannot be sent.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+            self.headers.append(name, value);
+        }
+        self
+    }
+
+    pub fn with_query(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.query.push((name.into(), value.into()));
+        self
+    }
+
+    /// Sends `body`, which can be retried if it is held in memory.
+    pub fn with_body(mut self, body: impl Into<reqwest::Body>) -> Self {
+        self.body = Some(body.into());
+        self
+    }
+
+    /// Streams the body from `stream` as it yields chunks. Streamed bodies
+    /// are sent once, so the request is not retried.
+    pub fn with_stream<S>(self, stream: S) -> Self
+    where
+        S: TryStream + Send + 'static,
+        S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+        bytes::Bytes: From<S::Ok>,
+    {
+        self.with_body(reqwest::Body::wrap_stream(stream))
+    }
+
+    /// Streams the body from the file at `path`, announcing its length.
+    pub async fn with_file(mut self, path: impl AsRef<Path>) -> Result<Self> {
+        let file = tokio::fs::File::open(path).await?;
+        let len = file.metadata().await?.len();
+        self.headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+        Ok(self.with_body(file))
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// URL of the request below grenze's `base_url`.
+    pub(crate) fn url(&self, base_url: &reqwest::Url) -> Result<reqwest::Url> {
+        let mut url = base_url.join("p/").map_err(|_| Error::InvalidUrl(base_url.to_string()))?;
+        url.path_segments_mut().map_err(|_| Error::InvalidUrl(base_url.to_string()))?.pop_if_empty().push(&self.key);
+        url.set_path(&format!("{}/{}", url.path(), self.path.trim_start_matches('/')));
+        let mut pairs = url.query_pairs_mut();
+        pairs.append_pair("upstream", &self.upstream);
+        for (name, value) in &self.query {
+            pairs.append_pair(name, value);
+        }
+        drop(pairs);
+        Ok(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_the_passthrough_url() {
+        let base_url = reqwest::Url::parse("http://grenze:8080/edge/").unwrap();
+        let request = PassthroughRequest::new("org/alice", "github", Method::GET, "/repos/cchexcode/grenze/issues").with_query("state", "open");
+        let url = request.url(&base_url).unwrap();
+        assert_eq!(url.as_str(), "http://grenze:8080/edge/p/org%2Falice/repos/cchexcode/grenze/issues?upstream=github&state=open");
+    }
+}
@@ -0,0 +1,119 @@
+//! Retrying requests grenze rejected for now or could not be reached for.
+
+use reqwest::Method;
+use std::time::Duration;
+
+use crate::error::Error;
+
+/// When to retry a call, set for a client and overridable per request.
+/// Transient rejections, i.e. `429` and `503`, are retried once their
+/// `Retry-After` has passed, and connection failures after the backoff.
+/// Timeouts are retried after the backoff only for idempotent methods, as
+/// the downstream may have received the request already.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt; 0 disables retrying.
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled for every further one.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// Rejections asking to wait longer, e.g. for a used up quota, are
+    /// returned instead of retried.
+    pub max_retry_after: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            max_retry_after: Duration::from_secs(60),
+        }
+    }
+
+    /// Up to `max_retries` retries, backing off exponentially from `backoff`.
+    pub fn exponential(max_retries: u32, backoff: Duration) -> Self {
+        Self {
+            max_retries,
+            backoff,
+            ..Self::none()
+        }
+    }
+
+    pub fn with_max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    pub fn with_max_retry_after(mut self, max_retry_after: Duration) -> Self {
+        self.max_retry_after = max_retry_after;
+        self
+    }
+
+    /// Wait before retrying a call with the downstream method `method` that
+    /// failed with `error` on attempt `attempt`, counted from 0; `None` if it
+    /// is not retried.
+    pub(crate) fn delay(&self, attempt: u32, method: &Method, error: &Error) -> Option<Duration> {
+        if attempt >= self.max_retries {
+            return None;
+        }
+        let backoff = self.backoff.saturating_mul(2u32.saturating_pow(attempt)).min(self.max_backoff);
+        match error {
+            Error::Rejected(rejection) if rejection.is_transient() => match rejection.retry_after {
+                Some(retry_after) if retry_after > self.max_retry_after => None,
+                Some(retry_after) => Some(retry_after.max(backoff)),
+                None => Some(backoff),
+            },
+            Error::Transport(e) if e.is_connect() => Some(backoff),
+            Error::Transport(e) if e.is_timeout() && is_idempotent(method) => Some(backoff),
+            _ => None,
+        }
+    }
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE)
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+
+    use super::*;
+    use crate::error::Rejection;
+
+    fn rejection(status: StatusCode, retry_after_secs: Option<u64>) -> Error {
+        Error::Rejected(Rejection {
+            status,
+            reason: "rate_limited".to_string(),
+            message: None,
+            retry_after: retry_after_secs.map(Duration::from_secs),
+        })
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_maximum() {
+        let policy = RetryPolicy::exponential(5, Duration::from_secs(1)).with_max_backoff(Duration::from_secs(5));
+        let error = rejection(StatusCode::SERVICE_UNAVAILABLE, None);
+        let delays: Vec<_> = (0..6).map(|attempt| policy.delay(attempt, &Method::POST, &error)).collect();
+        let secs = |s| Some(Duration::from_secs(s));
+        assert_eq!(delays, [secs(1), secs(2), secs(4), secs(5), secs(5), None]);
+    }
+
+    #[test]
+    fn waits_for_retry_after() {
+        let policy = RetryPolicy::exponential(3, Duration::from_millis(100));
+        assert_eq!(policy.delay(0, &Method::POST, &rejection(StatusCode::TOO_MANY_REQUESTS, Some(3))), Some(Duration::from_secs(3)));
+        // Too long a wait, and rejections waiting does not help with, are returned
+        assert_eq!(policy.delay(0, &Method::POST, &rejection(StatusCode::TOO_MANY_REQUESTS, Some(3600))), None);
+        assert_eq!(policy.delay(0, &Method::POST, &rejection(StatusCode::FORBIDDEN, None)), None);
+        assert_eq!(RetryPolicy::none().delay(0, &Method::POST, &rejection(StatusCode::TOO_MANY_REQUESTS, Some(1))), None);
+    }
+}
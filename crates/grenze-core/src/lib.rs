@@ -0,0 +1,103 @@
+//! Leaky bucket math of grenze, shared by all limiter stores.
+//!
+//! The crate does no I/O and depends on nothing but `std`, so it builds for
+//! `wasm32` too: edge workers, browser tooling and clients can mirror a
+//! bucket to pace their own requests, or replay traces with [`simulate`],
+//! with the same results as the server:
+//!
+//! ```
+//! use grenze_core::Bucket;
+//!
+//! let (allowed, bucket) = Bucket::admit(None, 10.0, 10.0, 2.0, 0);
+//! assert!(allowed);
+//! // One more token fits once half a second has leaked out
+//! assert_eq!(bucket.wait_ms(1.0, 10.0, 0), Some(500));
+//! ```
+
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Bucket {
+    pub fill: f64,
+    pub last_ms: i64,
+    pub leak_per_sec: f64,
+}
+
+impl Bucket {
+    pub fn level(&self, now_ms: i64) -> f64 {
+        let elapsed_ms = (now_ms - self.last_ms).max(0);
+        (self.fill - (elapsed_ms as f64 / 1000.0) * self.leak_per_sec).max(0.0)
+    }
+
+    /// Adds `cost` at `now_ms` if it fits, leaking first.
+    pub fn admit(bucket: Option<&Bucket>, cost: f64, capacity: f64, leak_per_sec: f64, now_ms: i64) -> (bool, Bucket) {
+        let fill = bucket.map(|b| b.level(now_ms)).unwrap_or(0.0);
+        let allowed = fill + cost <= capacity;
+        let next = Bucket {
+            fill: if allowed { fill + cost } else { fill },
+            last_ms: now_ms,
+            leak_per_sec,
+        };
+        (allowed, next)
+    }
+
+    /// Adds `amount` at `now_ms` regardless of capacity, leaking first.
+    pub fn charge(bucket: Option<&Bucket>, amount: f64, leak_per_sec: f64, now_ms: i64) -> Bucket {
+        let fill = bucket.map(|b| b.level(now_ms)).unwrap_or(0.0);
+        Bucket {
+            fill: (fill + amount).max(0.0),
+            last_ms: now_ms,
+            leak_per_sec,
+        }
+    }
+
+    /// Milliseconds from `now_ms` until `cost` fits into the bucket; `None`
+    /// if it never does.
+    pub fn wait_ms(&self, cost: f64, capacity: f64, now_ms: i64) -> Option<i64> {
+        let excess = self.level(now_ms) + cost - capacity;
+        if excess <= 0.0 {
+            return Some(0);
+        }
+        if cost > capacity || self.leak_per_sec <= 0.0 {
+            return None;
+        }
+        Some((excess / self.leak_per_sec * 1000.0).ceil() as i64)
+    }
+}
+
+/// One request of a synthetic trace.
+#[derive(Debug, Clone)]
+pub struct TraceEntry {
+    pub at_ms: i64,
+    pub bucket: String,
+    pub cost: f64,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TraceOutcome {
+    pub at_ms: i64,
+    pub bucket: String,
+    pub allowed: bool,
+    /// Fill level after the request.
+    pub fill: f64,
+}
+
+/// Replays a trace against empty buckets with the same leak math as the
+/// stores, without touching them.
+pub fn simulate(capacity: f64, leak_per_sec: f64, trace: &[TraceEntry]) -> Vec<TraceOutcome> {
+    let mut buckets: HashMap<&str, Bucket> = HashMap::new();
+    trace
+        .iter()
+        .map(|entry| {
+            let (allowed, bucket) = Bucket::admit(buckets.get(entry.bucket.as_str()), entry.cost, capacity, leak_per_sec, entry.at_ms);
+            buckets.insert(&entry.bucket, bucket);
+            TraceOutcome {
+                at_ms: entry.at_ms,
+                bucket: entry.bucket.clone(),
+                allowed,
+                fill: bucket.fill,
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,11 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        // SAFETY: build scripts are single threaded
+        unsafe { std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().expect("vendored protoc")) };
+        tonic_prost_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/grenze.proto"], &["proto"])
+            .expect("failed to compile protos");
+    }
+}
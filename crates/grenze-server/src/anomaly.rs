@@ -0,0 +1,325 @@
+//! Detection of keys behaving unlike themselves: request rate, share of
+//! rejected requests or number of distinct destinations far above the key's
+//! baseline. Anomalies are counted on `/metrics`, logged, optionally POSTed
+//! to a webhook, and can put the key under a stricter policy for a while.
+//! Every instance judges the requests it sees.
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{collections::{HashMap, HashSet}, fmt::Write, sync::{Arc, Mutex}, time::Duration};
+
+use crate::{limiter::Clock, logging, policy::PolicySet};
+
+/// Upper bound of tracked keys; requests of further ones are not tracked.
+const MAX_KEYS: usize = 100_000;
+/// Distinct destinations counted per key and window.
+const MAX_DESTINATIONS: usize = 1000;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnomalyConfig {
+    /// Length of the windows whose behavior is compared to the baseline.
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    /// Weight of the latest window in the baseline, a moving average over
+    /// the previous windows.
+    #[serde(default = "default_smoothing")]
+    pub smoothing: f64,
+    /// Windows a key must have been seen for before it can be flagged.
+    #[serde(default = "default_warmup_windows")]
+    pub warmup_windows: u32,
+    /// Requests needed in a window before a key can be flagged.
+    #[serde(default = "default_min_requests")]
+    pub min_requests: u64,
+    #[serde(default)]
+    pub thresholds: Thresholds,
+    /// URL anomalies are POSTed to; they are only logged without one.
+    #[serde(default)]
+    pub webhook: Option<String>,
+    /// Stricter policy flagged keys are put under for a while.
+    #[serde(default)]
+    pub penalty: Option<AnomalyPenalty>,
+}
+
+fn default_window_secs() -> u64 {
+    60
+}
+
+fn default_smoothing() -> f64 {
+    0.1
+}
+
+fn default_warmup_windows() -> u32 {
+    10
+}
+
+fn default_min_requests() -> u64 {
+    20
+}
+
+/// How far a window may deviate from the baseline; a signal is not checked
+/// if its threshold is `null`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Thresholds {
+    /// Multiple of the baseline request rate.
+    pub request_rate: Option<f64>,
+    /// Rise of the share of rejected requests over the baseline's, e.g.
+    /// `0.5` for 10% to 60%.
+    pub rejection_rate: Option<f64>,
+    /// Multiple of the baseline number of distinct destination hosts.
+    pub destinations: Option<f64>,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            request_rate: Some(5.0),
+            rejection_rate: Some(0.5),
+            destinations: Some(5.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnomalyPenalty {
+    /// Policy flagged keys are limited under, whatever their destination.
+    pub policy: String,
+    pub duration_secs: u64,
+}
+
+impl AnomalyConfig {
+    /// Checks the settings, and that the penalty policy is in `policies`
+    /// unless those may still change at runtime.
+    pub fn validate(&self, policies: Option<&PolicySet>) -> Result<()> {
+        if self.window_secs == 0 || self.warmup_windows == 0 {
+            bail!("anomalies window_secs and warmup_windows must be positive");
+        }
+        if !(self.smoothing > 0.0 && self.smoothing <= 1.0) {
+            bail!("anomalies smoothing must be in (0, 1]");
+        }
+        let thresholds = &self.thresholds;
+        if [thresholds.request_rate, thresholds.rejection_rate, thresholds.destinations].iter().flatten().any(|t| !t.is_finite() || *t <= 0.0) {
+            bail!("anomalies thresholds must be positive");
+        }
+        if let Some(webhook) = &self.webhook
+            && !matches!(reqwest::Url::parse(webhook).map(|u| u.scheme().to_string()).as_deref(), Ok("http" | "https"))
+        {
+            bail!("anomalies webhook must be an http(s) URL");
+        }
+        if let Some(penalty) = &self.penalty {
+            if penalty.duration_secs == 0 {
+                bail!("anomalies penalty duration_secs must be positive");
+            }
+            if let Some(policies) = policies
+                && policies.get(&penalty.policy).is_none()
+            {
+                bail!("anomalies penalty: unknown policy '{}'", penalty.policy);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Behavior of a key in a window.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Window {
+    pub requests: u64,
+    pub rejections: u64,
+    #[serde(serialize_with = "count")]
+    pub destinations: HashSet<String>,
+}
+
+fn count<S: serde::Serializer>(destinations: &HashSet<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(destinations.len() as u64)
+}
+
+impl Window {
+    fn rejection_rate(&self) -> f64 {
+        if self.requests == 0 { 0.0 } else { self.rejections as f64 / self.requests as f64 }
+    }
+}
+
+/// Usual behavior of a key per window.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Baseline {
+    pub requests: f64,
+    pub rejection_rate: f64,
+    pub destinations: f64,
+    /// Windows the baseline was computed from.
+    pub windows: u32,
+}
+
+#[derive(Default)]
+struct KeyState {
+    window: Window,
+    baseline: Baseline,
+    /// End of the key's penalty, if it is serving one.
+    penalized_until_ms: Option<i64>,
+}
+
+/// A key whose window deviated from its baseline.
+#[derive(Debug, Clone, Serialize)]
+pub struct Anomaly {
+    pub key: String,
+    /// Signals past their threshold: `request_rate`, `rejection_rate` or
+    /// `destinations`.
+    pub signals: Vec<&'static str>,
+    pub window: Window,
+    pub baseline: Baseline,
+    pub penalized_until_ms: Option<i64>,
+}
+
+pub struct AnomalyDetector {
+    config: AnomalyConfig,
+    keys: Mutex<HashMap<String, KeyState>>,
+    /// Anomalies detected so far by signal.
+    detected: Mutex<HashMap<&'static str, u64>>,
+}
+
+impl AnomalyDetector {
+    pub fn new(config: &AnomalyConfig) -> Self {
+        Self {
+            config: config.clone(),
+            keys: Mutex::default(),
+            detected: Mutex::default(),
+        }
+    }
+
+    /// Records a request of `key` to `destination`.
+    pub fn record(&self, key: &str, destination: Option<&str>, allowed: bool) {
+        let mut keys = self.keys.lock().expect("anomaly lock poisoned");
+        if keys.len() >= MAX_KEYS && !keys.contains_key(key) {
+            return;
+        }
+        let window = &mut keys.entry(key.to_string()).or_default().window;
+        window.requests += 1;
+        window.rejections += !allowed as u64;
+        if let Some(destination) = destination
+            && window.destinations.len() < MAX_DESTINATIONS
+            && !window.destinations.contains(destination)
+        {
+            window.destinations.insert(destination.to_string());
+        }
+    }
+
+    /// Policy `key` is limited under while serving a penalty.
+    pub fn penalty(&self, key: &str, now_ms: i64) -> Option<&str> {
+        let penalty = self.config.penalty.as_ref()?;
+        let keys = self.keys.lock().expect("anomaly lock poisoned");
+        keys.get(key)?.penalized_until_ms.filter(|until| *until > now_ms).map(|_| penalty.policy.as_str())
+    }
+
+    /// Closes the current window of every key, returning the keys that
+    /// deviated from their baseline. Anomalous windows are left out of the
+    /// baseline, so a key cannot make a new normal of its misbehavior; keys
+    /// whose baseline has faded away are forgotten.
+    fn evaluate(&self, now_ms: i64) -> Vec<Anomaly> {
+        let mut anomalies = Vec::new();
+        let mut keys = self.keys.lock().expect("anomaly lock poisoned");
+        keys.retain(|key, state| {
+            let window = std::mem::take(&mut state.window);
+            let signals = self.signals(&window, &state.baseline);
+            if signals.is_empty() {
+                let baseline = &mut state.baseline;
+                let weight = if baseline.windows == 0 { 1.0 } else { self.config.smoothing };
+                baseline.requests += weight * (window.requests as f64 - baseline.requests);
+                baseline.destinations += weight * (window.destinations.len() as f64 - baseline.destinations);
+                if window.requests > 0 {
+                    baseline.rejection_rate += weight * (window.rejection_rate() - baseline.rejection_rate);
+                }
+                baseline.windows = baseline.windows.saturating_add(1);
+            } else {
+                if let Some(penalty) = &self.config.penalty {
+                    state.penalized_until_ms = Some(now_ms + (penalty.duration_secs * 1000) as i64);
+                }
+                anomalies.push(Anomaly {
+                    key: key.clone(),
+                    signals,
+                    window,
+                    baseline: state.baseline.clone(),
+                    penalized_until_ms: state.penalized_until_ms,
+                });
+            }
+            state.baseline.requests >= 0.5 || state.penalized_until_ms.is_some_and(|until| until > now_ms)
+        });
+        let mut detected = self.detected.lock().expect("anomaly lock poisoned");
+        for signal in anomalies.iter().flat_map(|a| &a.signals) {
+            *detected.entry(signal).or_default() += 1;
+        }
+        anomalies
+    }
+
+    fn signals(&self, window: &Window, baseline: &Baseline) -> Vec<&'static str> {
+        let mut signals = Vec::new();
+        if baseline.windows < self.config.warmup_windows || window.requests < self.config.min_requests {
+            return signals;
+        }
+        let thresholds = &self.config.thresholds;
+        if let Some(factor) = thresholds.request_rate
+            && window.requests as f64 > factor * baseline.requests.max(1.0)
+        {
+            signals.push("request_rate");
+        }
+        if let Some(rise) = thresholds.rejection_rate
+            && window.rejection_rate() > baseline.rejection_rate + rise
+        {
+            signals.push("rejection_rate");
+        }
+        if let Some(factor) = thresholds.destinations
+            && window.destinations.len() as f64 > factor * baseline.destinations.max(1.0)
+        {
+            signals.push("destinations");
+        }
+        signals
+    }
+
+    /// Renders the detected anomalies and penalized keys in the Prometheus
+    /// text exposition format.
+    pub fn render(&self, now_ms: i64) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP grenze_anomalies_total Keys found deviating from their baseline, by signal.\n");
+        out.push_str("# TYPE grenze_anomalies_total counter\n");
+        let detected = self.detected.lock().expect("anomaly lock poisoned").clone();
+        for signal in ["request_rate", "rejection_rate", "destinations"] {
+            let _ = writeln!(out, "grenze_anomalies_total{{signal=\"{}\"}} {}", signal, detected.get(signal).copied().unwrap_or_default());
+        }
+        let keys = self.keys.lock().expect("anomaly lock poisoned");
+        let penalized = keys.values().filter(|s| s.penalized_until_ms.is_some_and(|until| until > now_ms)).count();
+        out.push_str("# HELP grenze_anomalies_penalized_keys Keys currently limited under the anomaly penalty policy.\n");
+        out.push_str("# TYPE grenze_anomalies_penalized_keys gauge\n");
+        let _ = writeln!(out, "grenze_anomalies_penalized_keys {}", penalized);
+        out
+    }
+
+    /// Closes a window every `window_secs` in the background, reporting the
+    /// keys that deviated from their baseline.
+    pub fn spawn_evaluation(self: &Arc<Self>, http_client: reqwest::Client, clock: Arc<dyn Clock>) {
+        let detector = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(detector.config.window_secs));
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                for anomaly in detector.evaluate(clock.now_ms()) {
+                    println!(
+                        "Anomaly of key {}: {} ({} requests, {} rejected, {} destinations)",
+                        anomaly.key,
+                        anomaly.signals.join(", "),
+                        anomaly.window.requests,
+                        anomaly.window.rejections,
+                        anomaly.window.destinations.len()
+                    );
+                    if let Some(webhook) = &detector.config.webhook {
+                        let result = http_client.post(webhook).json(&json!({ "anomaly": anomaly })).send().await.and_then(|r| r.error_for_status());
+                        if let Err(e) = result {
+                            logging::warn("anomaly_alert", format_args!("Failed to send anomaly of key {}: {}", anomaly.key, e));
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
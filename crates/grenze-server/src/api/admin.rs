@@ -0,0 +1,922 @@
+use axum::{extract::{Path, Query, Request, State}, http::{header::{AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_TYPE}, StatusCode}, middleware::{self, Next}, response::{IntoResponse, Response}, Json, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::{IntoParams, ToSchema};
+
+use super::{proxy::{self, AppState}, ApiError};
+use crate::{billing::BillingReport, erasure::{Erasure, Tenant}, har, limiter::{self, BucketState, TraceEntry}, policy::{self, Policy}, recording::Selection, rules::KeyRule, slo::SloStatus, usage::{self, Granularity, UsageCount}};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Bearer token required on every admin request.
+    pub token: String,
+}
+
+/// Routes below `/admin`, guarded by the admin token. Only mounted when an
+/// admin token is configured.
+pub fn router(state: AppState) -> Router<AppState> {
+    let router = Router::new();
+    #[cfg(feature = "wasm")]
+    let router = router
+        .route("/admin/plugins", axum::routing::get(plugins::list))
+        .route("/admin/plugins/{name}/{action}", axum::routing::post(plugins::set_enabled));
+    router
+        .route("/admin/script/reload", axum::routing::post(reload_script))
+        .route("/admin/slo", axum::routing::get(slo))
+        .route("/admin/simulate", axum::routing::post(simulate))
+        .route("/admin/explain", axum::routing::get(explain))
+        .route("/admin/replay", axum::routing::post(replay))
+        .route("/admin/har", axum::routing::get(export_har))
+        .route("/admin/snapshot", axum::routing::get(export_snapshot).post(import_snapshot))
+        .route("/admin/buckets/{bucket}", axum::routing::delete(reset_bucket))
+        .route("/admin/keys/{key}/rule", axum::routing::get(get_rule).put(put_rule).delete(delete_rule))
+        .route("/admin/key-rules/cache", axum::routing::delete(flush_rules))
+        .route("/admin/usage/{key}", axum::routing::get(usage))
+        .route("/admin/billing/{period}", axum::routing::get(billing))
+        .route("/admin/tenants/{id}/data", axum::routing::delete(erase_tenant))
+        .layer(middleware::from_fn_with_state(state, require_token))
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/script/reload",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "Script reloaded"),
+        (status = 404, description = "No script configured", body = ApiError),
+        (status = 422, description = "Script does not compile", body = ApiError),
+    )
+)]
+async fn reload_script(State(state): State<AppState>) -> Response {
+    let Some(scripts) = &state.scripts else {
+        let payload = Json(json!({
+            "error": "not_found",
+            "message": "No script configured"
+        }));
+        return (StatusCode::NOT_FOUND, payload).into_response();
+    };
+    match scripts.reload() {
+        Ok(()) => Json(json!({ "reloaded": true })).into_response(),
+        Err(e) => {
+            let payload = Json(json!({
+                "error": "invalid_script",
+                "message": format!("{:#}", e)
+            }));
+            (StatusCode::UNPROCESSABLE_ENTITY, payload).into_response()
+        },
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct SimulationRequest {
+    /// Policy deciding the buckets; the configured policies if unset.
+    #[serde(default)]
+    #[schema(value_type = Option<Object>)]
+    policy: Option<Policy>,
+    /// Bucket size; the server's if unset.
+    #[serde(default)]
+    capacity: Option<f64>,
+    #[serde(default)]
+    leak_per_sec: Option<f64>,
+    requests: Vec<SimulatedRequest>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct SimulatedRequest {
+    /// Milliseconds since the start of the trace.
+    at_ms: i64,
+    key: String,
+    #[serde(default)]
+    url: Option<String>,
+    #[serde(default = "default_cost")]
+    cost: f64,
+}
+
+fn default_cost() -> f64 {
+    1.0
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/slo",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "Success rate and latency of every destination in the SLO window", body = [SloStatus]),
+        (status = 404, description = "SLO tracking not configured", body = ApiError),
+    )
+)]
+async fn slo(State(state): State<AppState>) -> Response {
+    let Some(slo) = &state.slo else {
+        let payload = Json(json!({
+            "error": "not_found",
+            "message": "SLO tracking not configured"
+        }));
+        return (StatusCode::NOT_FOUND, payload).into_response();
+    };
+    Json(slo.statuses(state.clock.now_ms())).into_response()
+}
+
+/// Replays a synthetic request trace against fresh buckets and reports which
+/// requests would have been admitted, without touching the live limiter.
+#[utoipa::path(
+    post,
+    path = "/admin/simulate",
+    tag = "admin",
+    security(("admin_token" = [])),
+    request_body = SimulationRequest,
+    responses(
+        (status = 200, description = "Outcome of every request with the allowed and denied totals"),
+        (status = 400, description = "Invalid bucket size", body = ApiError),
+    )
+)]
+async fn simulate(State(state): State<AppState>, Json(sim): Json<SimulationRequest>) -> Response {
+    let capacity = sim.capacity.unwrap_or(state.capacity as f64);
+    let leak_per_sec = sim.leak_per_sec.unwrap_or(state.leak_per_sec);
+    if capacity <= 0.0 || leak_per_sec <= 0.0 {
+        let payload = Json(json!({
+            "error": "invalid_simulation",
+            "message": "capacity and leak_per_sec must be positive"
+        }));
+        return (StatusCode::BAD_REQUEST, payload).into_response();
+    }
+    let policies = state.policies.load();
+    let trace: Vec<TraceEntry> = sim
+        .requests
+        .iter()
+        .map(|r| {
+            let url = r.url.as_deref().and_then(|u| reqwest::Url::parse(u).ok());
+            let host = url.as_ref().and_then(|u| u.host_str());
+            let authority = url.as_ref().and_then(policy::authority);
+            let policy = sim.policy.as_ref().unwrap_or_else(|| policies.resolve(host));
+            TraceEntry {
+                at_ms: r.at_ms,
+                bucket: policy.bucket_key(&r.key, authority.as_deref()),
+                cost: r.cost,
+            }
+        })
+        .collect();
+    let outcomes = limiter::simulate(capacity, leak_per_sec, &trace);
+    let allowed = outcomes.iter().filter(|o| o.allowed).count();
+    Json(json!({
+        "capacity": capacity,
+        "leak_per_sec": leak_per_sec,
+        "allowed": allowed,
+        "denied": outcomes.len() - allowed,
+        "requests": outcomes,
+    }))
+    .into_response()
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[serde(deny_unknown_fields)]
+struct ExplainQuery {
+    /// Rate limit key, as derived by grenze.
+    key: String,
+    /// Policy to explain; the one resolved for `url` if unset.
+    policy: Option<String>,
+    /// Destination of the request, selecting policy and bucket as it would
+    /// for a proxied request.
+    url: Option<String>,
+}
+
+/// Limits applying to a key and whether its next request would fit.
+#[derive(Debug, Serialize, ToSchema)]
+struct Explanation {
+    key: String,
+    policy: String,
+    /// Whether the key serves an anomaly penalty, overriding the policy.
+    penalized: bool,
+    /// Rule written for the key through the admin API.
+    rule: Option<KeyRule>,
+    /// The key's own bucket first, then those of the policy's `limits`.
+    limits: Vec<LimitState>,
+    /// First bucket the next request would not fit into.
+    rejected_by: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct LimitState {
+    bucket: String,
+    capacity: u32,
+    leak_per_sec: f64,
+    fill: f64,
+    /// Requests that fit right now.
+    remaining: u64,
+    /// Seconds until the bucket has drained.
+    reset_secs: u64,
+    /// Seconds until the next request fits; 0 if it does now.
+    retry_after_secs: u64,
+}
+
+/// Reports the buckets a request of a key draws from, their fill levels and
+/// which one would reject its next request, without taking tokens.
+#[utoipa::path(
+    get,
+    path = "/admin/explain",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(ExplainQuery),
+    responses(
+        (status = 200, description = "Limits of the key and their state", body = Explanation),
+        (status = 404, description = "Unknown policy", body = ApiError),
+        (status = 500, description = "Store failed", body = ApiError),
+    )
+)]
+async fn explain(State(state): State<AppState>, Query(query): Query<ExplainQuery>) -> Response {
+    let policies = state.policies.load();
+    let now_ms = state.clock.now_ms();
+    let url = query.url.as_deref().and_then(|u| reqwest::Url::parse(u).ok());
+    let authority = url.as_ref().and_then(policy::authority);
+
+    // Resolved as for a proxied request, an anomaly penalty taking precedence
+    let penalty = state.anomalies.as_ref().and_then(|a| a.penalty(&query.key, now_ms));
+    let policy = match penalty.or(query.policy.as_deref()) {
+        Some(name) => match policies.get(name) {
+            Some(policy) => policy,
+            None => {
+                let payload = Json(json!({
+                    "error": "not_found",
+                    "message": format!("Unknown policy '{}'", name)
+                }));
+                return (StatusCode::NOT_FOUND, payload).into_response();
+            },
+        },
+        None => policies.resolve(url.as_ref().and_then(|u| u.host_str())),
+    };
+
+    let bucket = policy.bucket_key(&query.key, authority.as_deref());
+    let rule = state.key_rule(&query.key).await;
+    let mut buckets = state.limits(policy, bucket, &query.key, authority.as_deref());
+    if let Some(rule) = &rule {
+        rule.apply(&mut buckets[0]);
+    }
+    let mut limits = Vec::new();
+    for (i, limit) in buckets.into_iter().enumerate() {
+        // The key's own limit may be a sliding log, read by admitting nothing
+        let log = policy.sliding_log.as_ref().filter(|_| i == 0).map(|log| limiter::LogLimit {
+            log: limit.bucket.clone(),
+            limit: limit.capacity,
+            window_ms: (log.window_secs * 1000) as i64,
+        });
+        let read = match &log {
+            Some(log) => state.limiter.allow_log(log, 0, now_ms).await.map(|logged| {
+                let reset_secs = ((logged.until_ms - now_ms).max(0) as u64).div_ceil(1000);
+                let retry_after_secs = if logged.taken >= limit.capacity { reset_secs } else { 0 };
+                (logged.taken as f64, reset_secs, retry_after_secs)
+            }),
+            None => state.limiter.level(&limit.bucket, limit.leak_per_sec, now_ms).await.map(|fill| {
+                let drain = |fill: f64| (fill.max(0.0) / limit.leak_per_sec).ceil() as u64;
+                (fill, drain(fill), drain(fill + 1.0 - limit.capacity as f64))
+            }),
+        };
+        let (fill, reset_secs, retry_after_secs) = match read {
+            Ok(read) => read,
+            Err(e) => {
+                let payload = Json(json!({
+                    "error": "explain_failed",
+                    "message": format!("{:#}", e)
+                }));
+                return (StatusCode::INTERNAL_SERVER_ERROR, payload).into_response();
+            },
+        };
+        limits.push(LimitState {
+            remaining: (limit.capacity as f64 - fill).floor().max(0.0) as u64,
+            reset_secs,
+            retry_after_secs,
+            bucket: limit.bucket,
+            capacity: limit.capacity,
+            leak_per_sec: limit.leak_per_sec,
+            fill,
+        });
+    }
+    let rejected_by = limits.iter().find(|l| l.fill + 1.0 > l.capacity as f64).map(|l| l.bucket.clone());
+    Json(Explanation {
+        key: query.key,
+        policy: policy.name.clone(),
+        penalized: penalty.is_some(),
+        rule: rule.map(|r| KeyRule::clone(&r)),
+        limits,
+        rejected_by,
+    })
+    .into_response()
+}
+
+/// Most recordings replayed by one call.
+const MAX_REPLAYED: usize = 1000;
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct ReplayRequest {
+    /// Recording to replay; those in the time window if unset.
+    #[serde(default)]
+    id: Option<String>,
+    /// Replays the recordings from this time on.
+    #[serde(default)]
+    from_ms: Option<i64>,
+    /// Replays the recordings before this time.
+    #[serde(default)]
+    to_ms: Option<i64>,
+    /// Only replays the recordings of this rate limit key.
+    #[serde(default)]
+    key: Option<String>,
+    /// Most recordings replayed, oldest first.
+    #[serde(default = "default_replay_limit")]
+    limit: usize,
+    /// Requests sent per second.
+    #[serde(default = "default_replay_rate")]
+    rate_per_sec: f64,
+    /// Base URL whose scheme, host and port replace the recorded ones.
+    #[serde(default)]
+    upstream: Option<String>,
+}
+
+fn default_replay_limit() -> usize {
+    100
+}
+
+fn default_replay_rate() -> f64 {
+    10.0
+}
+
+/// Outcome of a replayed request.
+#[derive(Debug, Serialize, ToSchema)]
+struct ReplayResult {
+    id: String,
+    method: String,
+    url: String,
+    recorded_status: u16,
+    /// Status answered to the replay, unless it failed.
+    status: Option<u16>,
+    latency_ms: u64,
+    error: Option<String>,
+}
+
+/// Re-issues a recorded request, or those of a time window at a controlled
+/// rate, against the recorded or another upstream. Replays bypass the
+/// limiter and are answered once all have been sent.
+#[utoipa::path(
+    post,
+    path = "/admin/replay",
+    tag = "admin",
+    security(("admin_token" = [])),
+    request_body = ReplayRequest,
+    responses(
+        (status = 200, description = "Outcome of every replayed request", body = [ReplayResult]),
+        (status = 400, description = "Invalid selection, rate or upstream", body = ApiError),
+        (status = 404, description = "Recording not configured, or no such recording", body = ApiError),
+        (status = 500, description = "Recordings could not be read", body = ApiError),
+    )
+)]
+async fn replay(State(state): State<AppState>, Json(req): Json<ReplayRequest>) -> Response {
+    let Some(recorder) = &state.recorder else {
+        return ApiError::new(StatusCode::NOT_FOUND, "not_found", "Recording not configured").into_response();
+    };
+    let invalid = |message: &str| ApiError::new(StatusCode::BAD_REQUEST, "invalid_replay", message).into_response();
+    let (from_ms, to_ms) = match (&req.id, req.from_ms, req.to_ms) {
+        (Some(_), None, None) => (0, 0),
+        (None, Some(from_ms), Some(to_ms)) if from_ms < to_ms => (from_ms, to_ms),
+        _ => return invalid("Either id, or from_ms before to_ms, must be given"),
+    };
+    if req.limit == 0 || req.limit > MAX_REPLAYED {
+        return invalid(&format!("limit must be between 1 and {}", MAX_REPLAYED));
+    }
+    if !req.rate_per_sec.is_finite() || req.rate_per_sec <= 0.0 {
+        return invalid("rate_per_sec must be positive");
+    }
+    let upstream = match req.upstream.as_deref().map(reqwest::Url::parse) {
+        None => None,
+        Some(Ok(url)) if matches!(url.scheme(), "http" | "https") && url.host_str().is_some() => Some(url),
+        Some(_) => return invalid("upstream must be an http(s) URL"),
+    };
+
+    let selection = Selection {
+        id: req.id.as_deref(),
+        from_ms,
+        to_ms,
+        key: req.key.as_deref(),
+        limit: req.limit,
+    };
+    let recordings = match recorder.load(&selection).await {
+        Ok(recordings) => recordings,
+        Err(e) => return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "replay_failed", format!("{:#}", e)).into_response(),
+    };
+    if let Some(id) = &req.id
+        && recordings.is_empty()
+    {
+        return ApiError::new(StatusCode::NOT_FOUND, "not_found", format!("No recording '{}'", id)).into_response();
+    }
+
+    let mut ticker = tokio::time::interval(std::time::Duration::from_secs_f64(1.0 / req.rate_per_sec));
+    let mut results = Vec::with_capacity(recordings.len());
+    for recording in &recordings {
+        ticker.tick().await;
+        let started = std::time::Instant::now();
+        let outcome = proxy::replay(&state, recording, upstream.as_ref()).await;
+        results.push(ReplayResult {
+            id: recording.id.clone(),
+            method: recording.method.clone(),
+            url: recording.url.clone(),
+            recorded_status: recording.status,
+            status: outcome.as_ref().ok().map(|s| s.as_u16()),
+            latency_ms: started.elapsed().as_millis() as u64,
+            error: outcome.err(),
+        });
+    }
+    println!("Replayed {} recorded requests", results.len());
+    Json(results).into_response()
+}
+
+/// Most recordings exported into one archive.
+const MAX_EXPORTED: usize = 10_000;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[serde(deny_unknown_fields)]
+struct HarQuery {
+    /// Only exports the recordings of this rate limit key.
+    key: Option<String>,
+    /// Exports the recordings from this time on.
+    from_ms: i64,
+    /// Exports the recordings before this time.
+    to_ms: i64,
+    /// Most recordings exported, oldest first; 1000 if unset.
+    limit: Option<usize>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/har",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(HarQuery),
+    responses(
+        (status = 200, description = "HTTP Archive (HAR 1.2) of the recorded requests and responses", content_type = "application/json"),
+        (status = 400, description = "Invalid time range or limit", body = ApiError),
+        (status = 404, description = "Recording not configured", body = ApiError),
+        (status = 500, description = "Recordings could not be read", body = ApiError),
+    )
+)]
+async fn export_har(State(state): State<AppState>, Query(query): Query<HarQuery>) -> Response {
+    let Some(recorder) = &state.recorder else {
+        return ApiError::new(StatusCode::NOT_FOUND, "not_found", "Recording not configured").into_response();
+    };
+    let limit = query.limit.unwrap_or(1000);
+    if query.from_ms >= query.to_ms || limit == 0 || limit > MAX_EXPORTED {
+        let message = format!("from_ms must be before to_ms, and limit between 1 and {}", MAX_EXPORTED);
+        return ApiError::new(StatusCode::BAD_REQUEST, "invalid_range", message).into_response();
+    }
+    let selection = Selection {
+        id: None,
+        from_ms: query.from_ms,
+        to_ms: query.to_ms,
+        key: query.key.as_deref(),
+        limit,
+    };
+    match recorder.load(&selection).await {
+        Ok(recordings) => {
+            let filename = format!("attachment; filename=\"grenze-{}-{}.har\"", query.from_ms, query.to_ms);
+            ([(CONTENT_DISPOSITION, filename)], Json(har::archive(&recordings))).into_response()
+        },
+        Err(e) => ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "export_failed", format!("{:#}", e)).into_response(),
+    }
+}
+
+/// Limiter state as moved between clusters.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct Snapshot {
+    taken_at_ms: i64,
+    buckets: Vec<BucketState>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/snapshot",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses((status = 200, description = "State of every bucket holding requests", body = Snapshot), (status = 500, description = "Export failed", body = ApiError))
+)]
+async fn export_snapshot(State(state): State<AppState>) -> Response {
+    let taken_at_ms = state.clock.now_ms();
+    match state.limiter.export().await {
+        Ok(buckets) => Json(Snapshot { taken_at_ms, buckets }).into_response(),
+        Err(e) => {
+            let payload = Json(json!({
+                "error": "snapshot_failed",
+                "message": format!("{:#}", e)
+            }));
+            (StatusCode::INTERNAL_SERVER_ERROR, payload).into_response()
+        },
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/snapshot",
+    tag = "admin",
+    security(("admin_token" = [])),
+    request_body = Snapshot,
+    responses((status = 200, description = "Buckets imported"), (status = 500, description = "Import failed", body = ApiError))
+)]
+async fn import_snapshot(State(state): State<AppState>, Json(snapshot): Json<Snapshot>) -> Response {
+    match state.limiter.import(&snapshot.buckets, state.capacity, state.leak_per_sec).await {
+        Ok(()) => Json(json!({ "imported": snapshot.buckets.len() })).into_response(),
+        Err(e) => {
+            let payload = Json(json!({
+                "error": "snapshot_failed",
+                "message": format!("{:#}", e)
+            }));
+            (StatusCode::INTERNAL_SERVER_ERROR, payload).into_response()
+        },
+    }
+}
+
+/// Empties a bucket, as if it had drained, e.g. to lift a limit hit during
+/// an incident. Bucket names are those reported by `/admin/explain`.
+#[utoipa::path(
+    delete,
+    path = "/admin/buckets/{bucket}",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(("bucket" = String, Path, description = "Bucket name")),
+    responses((status = 200, description = "Bucket emptied"))
+)]
+async fn reset_bucket(State(state): State<AppState>, Path(bucket): Path<String>) -> Response {
+    state.limiter.evict(&bucket).await;
+    println!("Reset bucket {}", bucket);
+    Json(json!({ "reset": bucket })).into_response()
+}
+
+fn rules_not_configured() -> Response {
+    let payload = Json(json!({
+        "error": "not_found",
+        "message": "Key rules not configured"
+    }));
+    (StatusCode::NOT_FOUND, payload).into_response()
+}
+
+fn rule_failed(e: anyhow::Error) -> Response {
+    let payload = Json(json!({
+        "error": "rule_failed",
+        "message": format!("{:#}", e)
+    }));
+    (StatusCode::INTERNAL_SERVER_ERROR, payload).into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/keys/{key}/rule",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(("key" = String, Path, description = "Rate limit key")),
+    responses(
+        (status = 200, description = "Rule of the key", body = KeyRule),
+        (status = 404, description = "No rule for the key, or key rules not configured", body = ApiError),
+        (status = 500, description = "Store failed", body = ApiError),
+    )
+)]
+async fn get_rule(State(state): State<AppState>, Path(key): Path<String>) -> Response {
+    let Some(rules) = &state.rules else {
+        return rules_not_configured();
+    };
+    match rules.get(&key, state.clock.now_ms()).await {
+        Ok(Some(rule)) => Json(KeyRule::clone(&rule)).into_response(),
+        Ok(None) => {
+            let payload = Json(json!({
+                "error": "not_found",
+                "message": format!("No rule for key {}", key)
+            }));
+            (StatusCode::NOT_FOUND, payload).into_response()
+        },
+        Err(e) => rule_failed(e),
+    }
+}
+
+/// Bans the key or sizes its own bucket, replacing any previous rule. Every
+/// instance applies the rule from the next request on.
+#[utoipa::path(
+    put,
+    path = "/admin/keys/{key}/rule",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(("key" = String, Path, description = "Rate limit key")),
+    request_body = KeyRule,
+    responses(
+        (status = 200, description = "Rule stored", body = KeyRule),
+        (status = 400, description = "Invalid rule", body = ApiError),
+        (status = 404, description = "Key rules not configured", body = ApiError),
+        (status = 500, description = "Store failed", body = ApiError),
+    )
+)]
+async fn put_rule(State(state): State<AppState>, Path(key): Path<String>, Json(rule): Json<KeyRule>) -> Response {
+    let Some(rules) = &state.rules else {
+        return rules_not_configured();
+    };
+    if let Err(e) = rule.validate() {
+        let payload = Json(json!({
+            "error": "invalid_rule",
+            "message": e.to_string()
+        }));
+        return (StatusCode::BAD_REQUEST, payload).into_response();
+    }
+    match rules.set(&key, rule, state.clock.now_ms()).await {
+        Ok(rule) => Json(rule).into_response(),
+        Err(e) => rule_failed(e),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/keys/{key}/rule",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(("key" = String, Path, description = "Rate limit key")),
+    responses(
+        (status = 200, description = "Rule deleted, if there was one"),
+        (status = 404, description = "Key rules not configured", body = ApiError),
+        (status = 500, description = "Store failed", body = ApiError),
+    )
+)]
+async fn delete_rule(State(state): State<AppState>, Path(key): Path<String>) -> Response {
+    let Some(rules) = &state.rules else {
+        return rules_not_configured();
+    };
+    match rules.delete(&key).await {
+        Ok(deleted) => Json(json!({ "deleted": deleted })).into_response(),
+        Err(e) => rule_failed(e),
+    }
+}
+
+/// Has every instance drop its cached key rules and read them from Redis
+/// again, e.g. after rules were restored or edited there directly.
+#[utoipa::path(
+    delete,
+    path = "/admin/key-rules/cache",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "Caches flushed"),
+        (status = 404, description = "Key rules not configured", body = ApiError),
+        (status = 500, description = "Store failed", body = ApiError),
+    )
+)]
+async fn flush_rules(State(state): State<AppState>) -> Response {
+    let Some(rules) = &state.rules else {
+        return rules_not_configured();
+    };
+    match rules.flush().await {
+        Ok(()) => Json(json!({ "flushed": true })).into_response(),
+        Err(e) => rule_failed(e),
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[serde(deny_unknown_fields)]
+struct UsageQuery {
+    /// Length of the periods; `hour` if unset.
+    granularity: Option<Granularity>,
+    /// Periods starting from this time on; the 24 periods before `to_ms` if
+    /// unset.
+    from_ms: Option<i64>,
+    /// Periods starting before this time; now if unset.
+    to_ms: Option<i64>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/usage/{key}",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(("key" = String, Path, description = "Rate limit key"), UsageQuery),
+    responses(
+        (status = 200, description = "Allowed and limited requests of the key per period and policy", body = [UsageCount]),
+        (status = 400, description = "Invalid time range", body = ApiError),
+        (status = 404, description = "Usage history not configured", body = ApiError),
+        (status = 500, description = "Store failed", body = ApiError),
+    )
+)]
+async fn usage(State(state): State<AppState>, Path(key): Path<String>, Query(query): Query<UsageQuery>) -> Response {
+    if state.usage.is_none() {
+        let payload = Json(json!({
+            "error": "not_found",
+            "message": "Usage history not configured"
+        }));
+        return (StatusCode::NOT_FOUND, payload).into_response();
+    }
+    let granularity = query.granularity.unwrap_or(Granularity::Hour);
+    let to_ms = query.to_ms.unwrap_or_else(|| state.clock.now_ms());
+    let from_ms = query.from_ms.unwrap_or(to_ms - 24 * granularity.period_ms());
+    if from_ms >= to_ms || (to_ms - from_ms) / granularity.period_ms() > usage::MAX_PERIODS {
+        let payload = Json(json!({
+            "error": "invalid_range",
+            "message": format!("from_ms must be before to_ms, at most {} periods apart", usage::MAX_PERIODS)
+        }));
+        return (StatusCode::BAD_REQUEST, payload).into_response();
+    }
+    match state.limiter.usage(&key, granularity, from_ms, to_ms).await {
+        Ok(counts) => Json(counts).into_response(),
+        Err(e) => {
+            let payload = Json(json!({
+                "error": "usage_failed",
+                "message": format!("{:#}", e)
+            }));
+            (StatusCode::INTERNAL_SERVER_ERROR, payload).into_response()
+        },
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[serde(deny_unknown_fields)]
+struct BillingQuery {
+    /// `json` (default) or `csv`.
+    format: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/billing/{period}",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(("period" = String, Path, description = "Billing period, e.g. `2026-10` or `2026-10-16`"), BillingQuery),
+    responses(
+        (status = 200, description = "Billable usage of every key in the period, as CSV with `format=csv`", body = BillingReport),
+        (status = 400, description = "Invalid period or format", body = ApiError),
+        (status = 404, description = "Billing not configured", body = ApiError),
+        (status = 500, description = "Store failed", body = ApiError),
+    )
+)]
+async fn billing(State(state): State<AppState>, Path(period): Path<String>, Query(query): Query<BillingQuery>) -> Response {
+    let Some(billing) = &state.billing else {
+        let payload = Json(json!({
+            "error": "not_found",
+            "message": "Billing not configured"
+        }));
+        return (StatusCode::NOT_FOUND, payload).into_response();
+    };
+    let csv = match query.format.as_deref() {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(_) => {
+            let payload = Json(json!({
+                "error": "invalid_format",
+                "message": "Format must be 'json' or 'csv'"
+            }));
+            return (StatusCode::BAD_REQUEST, payload).into_response();
+        },
+    };
+    let Some(period) = billing.period(&period) else {
+        let payload = Json(json!({
+            "error": "invalid_period",
+            "message": format!("'{}' is not a billing period", period)
+        }));
+        return (StatusCode::BAD_REQUEST, payload).into_response();
+    };
+    match billing.report(state.limiter.as_ref(), &period).await {
+        Ok(report) if csv => ([(CONTENT_TYPE, "text/csv")], report.to_csv()).into_response(),
+        Ok(report) => Json(report).into_response(),
+        Err(e) => {
+            let payload = Json(json!({
+                "error": "billing_failed",
+                "message": format!("{:#}", e)
+            }));
+            (StatusCode::INTERNAL_SERVER_ERROR, payload).into_response()
+        },
+    }
+}
+
+/// What was deleted of a tenant.
+#[derive(Debug, Serialize, ToSchema)]
+struct ErasureReport {
+    tenant: String,
+    erased_at_ms: i64,
+    erased: Erasure,
+    /// What a second pass found and deleted, written while the first ran.
+    left: Erasure,
+    /// Whether the second pass found nothing left of the tenant.
+    verified: bool,
+}
+
+/// Deletes everything stored about a tenant: its buckets, first seen times,
+/// cookie jars, quota counters and usage history, and what this instance
+/// holds of them in memory.
+#[utoipa::path(
+    delete,
+    path = "/admin/tenants/{id}/data",
+    tag = "admin",
+    security(("admin_token" = [])),
+    params(("id" = String, Path, description = "Tenant, owning the rate limit key equal to it and those starting with it and the tenant delimiter")),
+    responses(
+        (status = 200, description = "Data deleted", body = ErasureReport),
+        (status = 500, description = "Store failed; some data may be left", body = ApiError),
+    )
+)]
+async fn erase_tenant(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    let delimiter = state.keys.as_ref().map_or(":", |keys| keys.delimiter());
+    let tenant = Tenant::new(&id, delimiter);
+    // Pending counts would otherwise be flushed after the erasure
+    if let Some(usage) = &state.usage {
+        usage.forget(&tenant);
+    }
+    if let Some(keys) = &state.keys {
+        keys.forget(&tenant);
+    }
+    state.metrics.forget(&tenant);
+    let erased_at_ms = state.clock.now_ms();
+    let result = match state.limiter.erase(&tenant).await {
+        Ok(erased) => state.limiter.erase(&tenant).await.map(|left| (erased, left)),
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok((erased, left)) => {
+            println!("Erased {} records of tenant {}", erased.total() + left.total(), id);
+            Json(ErasureReport {
+                tenant: id,
+                erased_at_ms,
+                verified: left.total() == 0,
+                erased,
+                left,
+            })
+            .into_response()
+        },
+        Err(e) => ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "erasure_failed", format!("{:#}", e)).into_response(),
+    }
+}
+
+async fn require_token(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let provided = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let authorized = match (&state.admin, provided) {
+        (Some(admin), Some(token)) => constant_time_eq(admin.token.as_bytes(), token.as_bytes()),
+        _ => false,
+    };
+    if !authorized {
+        let payload = Json(json!({
+            "error": "unauthorized",
+            "message": "Admin token required"
+        }));
+        return (StatusCode::UNAUTHORIZED, payload).into_response();
+    }
+    next.run(request).await
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(feature = "wasm")]
+mod plugins {
+    use axum::{extract::{Path, State}, http::StatusCode, response::IntoResponse, Json};
+    use serde_json::json;
+    use std::{sync::atomic::Ordering, time::UNIX_EPOCH};
+
+    use crate::api::proxy::AppState;
+
+    pub async fn list(State(state): State<AppState>) -> impl IntoResponse {
+        let plugins: Vec<_> = state
+            .plugins
+            .list()
+            .await
+            .iter()
+            .map(|p| {
+                json!({
+                    "name": p.config.name,
+                    "path": p.config.path,
+                    "policies": p.config.policies,
+                    "enabled": p.enabled.load(Ordering::Relaxed),
+                    "loaded_at": p.loaded_at().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+                    "stats": p.stats,
+                })
+            })
+            .collect();
+        Json(json!({ "plugins": plugins }))
+    }
+
+    pub async fn set_enabled(State(state): State<AppState>, Path((name, action)): Path<(String, String)>) -> impl IntoResponse {
+        let enabled = match action.as_str() {
+            "enable" => true,
+            "disable" => false,
+            _ => {
+                let payload = Json(json!({
+                    "error": "invalid_action",
+                    "message": "Action must be 'enable' or 'disable'"
+                }));
+                return (StatusCode::BAD_REQUEST, payload).into_response();
+            },
+        };
+        if !state.plugins.set_enabled(&name, enabled).await {
+            let payload = Json(json!({
+                "error": "not_found",
+                "message": format!("No plugin named '{}'", name)
+            }));
+            return (StatusCode::NOT_FOUND, payload).into_response();
+        }
+        Json(json!({ "name": name, "enabled": enabled })).into_response()
+    }
+}
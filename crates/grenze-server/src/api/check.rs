@@ -0,0 +1,281 @@
+//! Rate limit decisions without proxying, for callers that send their
+//! requests themselves: `/check` takes tokens from the buckets a request
+//! would draw from and reports whether it may be sent.
+
+use axum::{extract::{ConnectInfo, State}, http::{HeaderMap, StatusCode}, response::{IntoResponse, Response}, Extension, Json};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use utoipa::ToSchema;
+
+use super::{proxy::{authorize, AppState, Verdict}, ApiError};
+use crate::{limiter::BucketLimit, middleware::Context, policy::{self, PolicySet}, rejection::Reason, tls::ClientIdentity};
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CheckRequest {
+    /// Rate limit key, derived like the proxy's.
+    #[serde(default)]
+    pub key: String,
+    /// Destination of the request, selecting policy and bucket as it would
+    /// for a proxied request.
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default = "default_tokens")]
+    #[schema(default = 1)]
+    pub tokens: u32,
+}
+
+fn default_tokens() -> u32 {
+    1
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CheckResponse {
+    pub allowed: bool,
+    /// Whether the request is past the allowance of the key's plan, to be
+    /// billed as overage.
+    pub overage: bool,
+    pub policy: String,
+    pub bucket: String,
+    /// Cause of the rejection, if rejected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'static str>,
+}
+
+/// Tokens taken, or not, for a request.
+pub(crate) struct Taken {
+    pub allowed: bool,
+    pub overage: bool,
+    pub policy: String,
+    pub reason: Option<Reason>,
+    /// Buckets the tokens were taken from, the request's own first.
+    pub limits: Vec<BucketLimit>,
+}
+
+/// A request resolved to its key, policy and buckets, ready to take tokens.
+struct Prepared<'a> {
+    ctx: Context<'a>,
+    limits: Vec<BucketLimit>,
+    host: Option<String>,
+}
+
+impl Prepared<'_> {
+    fn taken(self, verdict: Verdict) -> Taken {
+        Taken {
+            allowed: verdict.allowed(),
+            overage: matches!(verdict, Verdict::Overage { .. }),
+            policy: self.ctx.policy.name.clone(),
+            reason: verdict.reason(self.ctx.policy, &self.limits),
+            limits: self.limits,
+        }
+    }
+}
+
+/// Takes `tokens` from every bucket a request by `client_key` to `url` draws
+/// from, if they fit into all of them.
+pub(crate) async fn take(
+    state: &AppState,
+    peer: SocketAddr,
+    identity: Option<&ClientIdentity>,
+    headers: &HeaderMap,
+    client_key: &str,
+    url: Option<&str>,
+    tokens: u32,
+) -> Result<Taken, ApiError> {
+    let policies = state.policies.load();
+    let prepared = prepare(state, &policies, peer, identity, headers, client_key, url, tokens).await?;
+    let verdict = state.allow(&prepared.ctx, &prepared.limits, prepared.host.as_deref(), tokens as f64).await;
+    Ok(prepared.taken(verdict))
+}
+
+/// Like [`take`], for reservations. Their unused tokens are given back by
+/// charging the buckets, which a sliding log cannot be, so policies with one
+/// are refused.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn take_reservable(
+    state: &AppState,
+    peer: SocketAddr,
+    identity: Option<&ClientIdentity>,
+    headers: &HeaderMap,
+    client_key: &str,
+    url: Option<&str>,
+    tokens: u32,
+) -> Result<Taken, ApiError> {
+    let policies = state.policies.load();
+    let prepared = prepare(state, &policies, peer, identity, headers, client_key, url, tokens).await?;
+    if prepared.ctx.policy.sliding_log.is_some() {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "invalid_reservation", "Policies with a sliding log do not support reservations"));
+    }
+    let verdict = state.allow(&prepared.ctx, &prepared.limits, prepared.host.as_deref(), tokens as f64).await;
+    Ok(prepared.taken(verdict))
+}
+
+/// Resolves the key, policy and buckets of a request taking `tokens`.
+#[allow(clippy::too_many_arguments)]
+async fn prepare<'a>(
+    state: &AppState,
+    policies: &'a PolicySet,
+    peer: SocketAddr,
+    identity: Option<&ClientIdentity>,
+    headers: &HeaderMap,
+    client_key: &str,
+    url: Option<&str>,
+    tokens: u32,
+) -> Result<Prepared<'a>, ApiError> {
+    let key = authorize(state, peer, identity, headers, client_key)?;
+    let url = url.and_then(|u| reqwest::Url::parse(u).ok());
+    let host = url.as_ref().and_then(|u| u.host_str());
+    let authority = url.as_ref().and_then(policy::authority);
+    let class = state.classification.as_ref().and_then(|c| c.classify(None, url.as_ref().map(|u| u.as_str()), headers));
+    if class.is_some_and(|rule| rule.block) {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "blocked", "Requests like this are not allowed"));
+    }
+    let penalty = state.anomalies.as_ref().and_then(|a| a.penalty(&key, state.clock.now_ms()));
+    let chosen = penalty.or_else(|| class.and_then(|rule| rule.policy.as_deref()));
+    let ctx = Context {
+        peer,
+        policy: chosen.and_then(|name| policies.get(name)).unwrap_or_else(|| policies.resolve(host)),
+        key,
+        trace: state.trace(headers),
+    };
+    let bucket = ctx.policy.bucket_key(&ctx.key, authority.as_deref());
+    if !state.admit_key(&ctx, &bucket).await {
+        return Err(ApiError::new(StatusCode::TOO_MANY_REQUESTS, "too_many_keys", "Too many distinct rate limit keys for this tenant"));
+    }
+    let mut limits = state.limits(ctx.policy, bucket, &ctx.key, authority.as_deref());
+    state.apply_rule(&ctx.key, &mut limits[0]).await?;
+    state.locate(&ctx, headers, &mut limits[0])?;
+    state.warm_up(ctx.policy, &mut limits[0]).await;
+    if tokens == 0 || limits.iter().any(|l| tokens > l.capacity) {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "invalid_tokens", "tokens must be between 1 and the bucket capacity"));
+    }
+    state.share(ctx.policy, &ctx.key, authority.as_deref(), &mut limits).await;
+    Ok(Prepared {
+        ctx,
+        limits,
+        host: host.map(str::to_string),
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/check",
+    tag = "decisions",
+    request_body = CheckRequest,
+    responses(
+        (status = 200, description = "Whether the tokens were taken", body = CheckResponse),
+        (status = 400, description = "Rate limit key missing or tokens exceed the bucket capacity", body = ApiError),
+        (status = 403, description = "Client, its region or the request blocked, or the key banned", body = ApiError),
+        (status = 429, description = "Too many distinct keys for the tenant", body = ApiError),
+    )
+)]
+pub async fn check(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    identity: Option<Extension<ClientIdentity>>,
+    headers: HeaderMap,
+    Json(req): Json<CheckRequest>,
+) -> Response {
+    let identity = identity.map(|Extension(id)| id);
+    match take(&state, peer, identity.as_ref(), &headers, &req.key, req.url.as_deref(), req.tokens).await {
+        Ok(taken) => Json(CheckResponse {
+            allowed: taken.allowed,
+            overage: taken.overage,
+            policy: taken.policy,
+            bucket: taken.limits[0].bucket.clone(),
+            reason: taken.reason.map(Reason::code),
+        })
+        .into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Most checks in one batch.
+const MAX_BATCH: usize = 100;
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct BatchCheckRequest {
+    pub checks: Vec<CheckRequest>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BatchCheckResponse {
+    /// Outcomes in the order of the checks.
+    pub results: Vec<CheckResult>,
+}
+
+/// Outcome of a check in a batch: its decision, or why it was refused.
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum CheckResult {
+    Checked(CheckResponse),
+    Refused(CheckRefusal),
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CheckRefusal {
+    /// Status `/check` would have answered with.
+    pub status: u16,
+    pub error: String,
+    pub message: String,
+}
+
+/// Checks up to 100 requests at once. Their tokens are taken in one round trip
+/// to the store, each check being admitted or not on its own as by `/check`.
+#[utoipa::path(
+    post,
+    path = "/check/batch",
+    tag = "decisions",
+    request_body = BatchCheckRequest,
+    responses(
+        (status = 200, description = "Outcome of every check", body = BatchCheckResponse),
+        (status = 400, description = "No checks, or more than 100", body = ApiError),
+    )
+)]
+pub async fn check_batch(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    identity: Option<Extension<ClientIdentity>>,
+    headers: HeaderMap,
+    Json(req): Json<BatchCheckRequest>,
+) -> Response {
+    if req.checks.is_empty() || req.checks.len() > MAX_BATCH {
+        let message = format!("A batch holds between 1 and {} checks", MAX_BATCH);
+        return ApiError::new(StatusCode::BAD_REQUEST, "invalid_batch", message).into_response();
+    }
+    let identity = identity.map(|Extension(id)| id);
+    let policies = state.policies.load();
+    let mut prepared = Vec::with_capacity(req.checks.len());
+    for check in &req.checks {
+        prepared.push(prepare(&state, &policies, peer, identity.as_ref(), &headers, &check.key, check.url.as_deref(), check.tokens).await);
+    }
+    let requests: Vec<_> = prepared
+        .iter()
+        .zip(&req.checks)
+        .filter_map(|(p, check)| p.as_ref().ok().map(|p| (&p.ctx, p.limits.as_slice(), p.host.as_deref(), check.tokens as f64)))
+        .collect();
+    let mut verdicts = state.allow_batch(&requests).await.into_iter();
+    drop(requests);
+    let results = prepared
+        .into_iter()
+        .map(|p| match p {
+            Ok(p) => {
+                let taken = p.taken(verdicts.next().expect("one verdict per prepared check"));
+                CheckResult::Checked(CheckResponse {
+                    allowed: taken.allowed,
+                    overage: taken.overage,
+                    policy: taken.policy,
+                    bucket: taken.limits[0].bucket.clone(),
+                    reason: taken.reason.map(Reason::code),
+                })
+            },
+            Err(e) => CheckResult::Refused(CheckRefusal {
+                status: e.status.as_u16(),
+                error: e.error.to_string(),
+                message: e.message,
+            }),
+        })
+        .collect();
+    Json(BatchCheckResponse { results }).into_response()
+}
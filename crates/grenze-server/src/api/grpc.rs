@@ -0,0 +1,193 @@
+//! The decision and admin operations over gRPC, as specified in
+//! `proto/grenze.proto`. Served next to the JSON API on the same port; the
+//! admin service is only mounted when an admin token is configured.
+
+use axum::{extract::ConnectInfo, http::{HeaderMap, StatusCode}};
+use futures::Stream;
+use std::{net::SocketAddr, pin::Pin};
+use tokio::sync::broadcast::error::RecvError;
+use tonic::{metadata::MetadataValue, service::Routes, Code, Request, Response, Status};
+
+use super::{admin::constant_time_eq, check, proxy::AppState, reservations, ApiError};
+use crate::tls::ClientIdentity;
+
+pub mod pb {
+    #![allow(clippy::all)]
+    tonic::include_proto!("grenze.v1");
+}
+
+use pb::{admin_server::{Admin, AdminServer}, decisions_server::{Decisions, DecisionsServer}};
+
+/// gRPC services, to be merged into the HTTP router.
+pub fn router(state: AppState) -> axum::Router {
+    let mut routes = Routes::new(DecisionsServer::new(DecisionService { state: state.clone() }));
+    if state.admin.is_some() {
+        routes = routes.add_service(AdminServer::new(AdminService { state }));
+    }
+    routes.into_axum_router()
+}
+
+impl From<ApiError> for Status {
+    fn from(e: ApiError) -> Self {
+        let code = match e.status {
+            StatusCode::BAD_REQUEST => Code::InvalidArgument,
+            StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+            StatusCode::FORBIDDEN => Code::PermissionDenied,
+            StatusCode::NOT_FOUND => Code::NotFound,
+            StatusCode::CONFLICT => Code::AlreadyExists,
+            StatusCode::GONE => Code::FailedPrecondition,
+            StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+            StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+            _ => Code::Internal,
+        };
+        // Keeps the machine readable error of the JSON API
+        let mut status = Status::new(code, e.message);
+        status.metadata_mut().insert("grenze-error", MetadataValue::from_static(e.error));
+        status
+    }
+}
+
+/// Peer address, client certificate identity and metadata of a call, as the
+/// HTTP handlers get them.
+fn caller<T>(request: &Request<T>) -> Result<(SocketAddr, Option<ClientIdentity>, HeaderMap), Status> {
+    let Some(ConnectInfo(peer)) = request.extensions().get::<ConnectInfo<SocketAddr>>() else {
+        return Err(Status::internal("Peer address unknown"));
+    };
+    let identity = request.extensions().get::<ClientIdentity>().cloned();
+    Ok((*peer, identity, request.metadata().clone().into_headers()))
+}
+
+struct DecisionService {
+    state: AppState,
+}
+
+#[tonic::async_trait]
+impl Decisions for DecisionService {
+    async fn check(&self, request: Request<pb::CheckRequest>) -> Result<Response<pb::CheckResponse>, Status> {
+        let (peer, identity, headers) = caller(&request)?;
+        let req = request.into_inner();
+        let taken = check::take(&self.state, peer, identity.as_ref(), &headers, &req.key, req.url.as_deref(), req.tokens.unwrap_or(1)).await?;
+        Ok(Response::new(pb::CheckResponse {
+            allowed: taken.allowed,
+            policy: taken.policy,
+            bucket: taken.limits[0].bucket.clone(),
+            reason: taken.reason.map(|r| r.code().to_string()),
+        }))
+    }
+
+    async fn reserve(&self, request: Request<pb::ReserveRequest>) -> Result<Response<pb::ReserveResponse>, Status> {
+        let (peer, identity, headers) = caller(&request)?;
+        let req = request.into_inner();
+        let req = reservations::ReserveRequest {
+            key: req.key,
+            url: req.url,
+            tokens: req.tokens,
+            ttl_secs: req.ttl_secs,
+        };
+        let reserved = reservations::reserve_tokens(&self.state, peer, identity.as_ref(), &headers, &req).await?;
+        Ok(Response::new(pb::ReserveResponse {
+            reservation_id: reserved.reservation_id,
+            tokens: reserved.tokens,
+            expires_at_ms: reserved.expires_at_ms,
+        }))
+    }
+
+    async fn commit(&self, request: Request<pb::CommitRequest>) -> Result<Response<pb::SettleResponse>, Status> {
+        let req = request.into_inner();
+        let settled = reservations::settle(&self.state, &req.reservation_id, req.tokens_used).await?;
+        Ok(Response::new(pb::SettleResponse {
+            committed: settled.committed,
+            released: settled.released,
+        }))
+    }
+
+    async fn release(&self, request: Request<pb::ReleaseRequest>) -> Result<Response<pb::SettleResponse>, Status> {
+        let req = request.into_inner();
+        let settled = reservations::settle(&self.state, &req.reservation_id, Some(0)).await?;
+        Ok(Response::new(pb::SettleResponse {
+            committed: settled.committed,
+            released: settled.released,
+        }))
+    }
+}
+
+struct AdminService {
+    state: AppState,
+}
+
+impl AdminService {
+    /// Checks the admin token in the call's `authorization` metadata.
+    fn require_token<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let provided = request
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        match (&self.state.admin, provided) {
+            (Some(admin), Some(token)) if constant_time_eq(admin.token.as_bytes(), token.as_bytes()) => Ok(()),
+            _ => Err(Status::unauthenticated("Admin token required")),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Admin for AdminService {
+    async fn reload_script(&self, request: Request<pb::ReloadScriptRequest>) -> Result<Response<pb::ReloadScriptResponse>, Status> {
+        self.require_token(&request)?;
+        let Some(scripts) = &self.state.scripts else {
+            return Err(Status::not_found("No script configured"));
+        };
+        match scripts.reload() {
+            Ok(()) => Ok(Response::new(pb::ReloadScriptResponse {})),
+            Err(e) => Err(Status::invalid_argument(format!("{:#}", e))),
+        }
+    }
+
+    async fn export_snapshot(&self, request: Request<pb::ExportSnapshotRequest>) -> Result<Response<pb::Snapshot>, Status> {
+        self.require_token(&request)?;
+        let taken_at_ms = self.state.clock.now_ms();
+        let buckets = self.state.limiter.export().await.map_err(|e| Status::internal(format!("{:#}", e)))?;
+        Ok(Response::new(pb::Snapshot {
+            taken_at_ms,
+            buckets: buckets
+                .into_iter()
+                .map(|b| pb::BucketState {
+                    bucket: b.bucket,
+                    fill: b.fill,
+                    last_ms: b.last_ms,
+                })
+                .collect(),
+        }))
+    }
+
+    type WatchDecisionsStream = Pin<Box<dyn Stream<Item = Result<pb::Decision, Status>> + Send>>;
+
+    async fn watch_decisions(&self, request: Request<pb::WatchDecisionsRequest>) -> Result<Response<Self::WatchDecisionsStream>, Status> {
+        self.require_token(&request)?;
+        let policy = request.into_inner().policy;
+        let decisions = self.state.decisions.subscribe();
+        let stream = futures::stream::unfold((decisions, policy), |(mut decisions, policy)| async move {
+            loop {
+                match decisions.recv().await {
+                    Ok(d) if policy.as_ref().is_none_or(|p| *p == d.policy) => {
+                        let decision = pb::Decision {
+                            at_ms: d.at_ms,
+                            key: d.key,
+                            policy: d.policy,
+                            bucket: d.bucket,
+                            allowed: d.allowed,
+                            fill: d.fill,
+                            capacity: d.capacity,
+                        };
+                        return Some((Ok(decision), (decisions, policy)));
+                    },
+                    Ok(_) => {},
+                    // Watchers that fall behind miss decisions rather than slow down requests
+                    Err(RecvError::Lagged(_)) => {},
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
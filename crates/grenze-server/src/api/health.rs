@@ -1,9 +1,35 @@
-use axum::Json;
+use axum::{extract::State, http::StatusCode, response::{IntoResponse, Response}, Json};
 use serde_json::json;
 
+use super::proxy::AppState;
+
+#[utoipa::path(get, path = "/health", tag = "health", responses((status = 200, description = "Server is up")))]
 pub async fn health() -> Json<serde_json::Value> {
     Json(json!({
         "status": "ok",
         "version": env!("CARGO_PKG_VERSION"),
     }))
-}
\ No newline at end of file
+}
+
+/// Reports whether the bucket store is usable, for load balancers to route
+/// around instances whose limiter circuit breaker is open.
+#[utoipa::path(
+    get,
+    path = "/ready",
+    tag = "health",
+    responses((status = 200, description = "Bucket store is usable"), (status = 503, description = "Limiter circuit breaker is open"))
+)]
+pub async fn ready(State(state): State<AppState>) -> Response {
+    if state.breaker.is_open() {
+        let payload = Json(json!({
+            "status": "unavailable",
+            "limiter": "open",
+        }));
+        return (StatusCode::SERVICE_UNAVAILABLE, payload).into_response();
+    }
+    Json(json!({
+        "status": "ready",
+        "limiter": "closed",
+    }))
+    .into_response()
+}
@@ -0,0 +1,33 @@
+use axum::{extract::State, http::{header::{ACCEPT, CONTENT_TYPE}, HeaderMap}, response::IntoResponse};
+
+use super::proxy::AppState;
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "health",
+    responses((status = 200, description = "Prometheus text format, or OpenMetrics with exemplars if accepted", content_type = "text/plain"))
+)]
+pub async fn metrics(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    // Exemplars are only part of OpenMetrics
+    let openmetrics = headers.get_all(ACCEPT).iter().filter_map(|v| v.to_str().ok()).any(|v| v.contains("application/openmetrics-text"));
+    let mut body = state.metrics.render(state.clock.now_ms(), &state.breaker, openmetrics);
+    if let Some(leader) = state.scheduler.is_leader() {
+        body.push_str("# HELP grenze_leader Whether this instance runs the background tasks.\n");
+        body.push_str("# TYPE grenze_leader gauge\n");
+        body.push_str(&format!("grenze_leader {}\n", leader as u8));
+    }
+    if let Some(slo) = &state.slo {
+        body.push_str(&slo.render(state.clock.now_ms()));
+    }
+    if let Some(anomalies) = &state.anomalies {
+        body.push_str(&anomalies.render(state.clock.now_ms()));
+    }
+    if let Some(rules) = &state.rules {
+        body.push_str(&rules.render());
+    }
+    match openmetrics {
+        true => ([(CONTENT_TYPE, "application/openmetrics-text; version=1.0.0; charset=utf-8")], crate::metrics::openmetrics(&body)),
+        false => ([(CONTENT_TYPE, "text/plain; version=0.0.4")], body),
+    }
+}
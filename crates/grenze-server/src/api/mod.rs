@@ -1,2 +1,163 @@
+use anyhow::Result;
+use axum::{http::StatusCode, middleware, response::{IntoResponse, Response}, routing::{get, post}, Json, Router};
+use serde_json::json;
+use std::{borrow::Cow, sync::Arc, time::Duration};
+use serde::Serialize;
+use utoipa::{openapi::{schema::{ArrayBuilder, ObjectBuilder, Type}, RefOr, Schema}, PartialSchema, ToSchema};
+
+use crate::{concurrency::{self, ConcurrencyLimit}, config::Config, panics, rejection::{Reason, X_REJECTION_REASON}, server};
+
+pub mod admin;
+pub mod check;
+#[cfg(feature = "grpc")]
+pub mod grpc;
 pub mod health;
-pub mod proxy;
\ No newline at end of file
+pub mod metrics;
+pub mod openapi;
+pub mod passthrough;
+pub mod proxy;
+pub mod reservations;
+
+/// All routes served by grenze, as configured.
+pub fn router(config: &Config, state: proxy::AppState) -> Result<Router> {
+    // One cap shared by every route proxying requests
+    let concurrency = config.concurrency.as_ref().map(|c| Arc::new(ConcurrencyLimit::new(c, state.metrics.clone())));
+    let limited = |limit: &Arc<ConcurrencyLimit>| middleware::from_fn_with_state(limit.clone(), concurrency::limit);
+    let mut proxy = post(proxy::proxy);
+    if let Some(limit) = &concurrency {
+        proxy = proxy.layer(limited(limit));
+    }
+    let mut app = Router::new()
+        .route("/health", get(health::health))
+        .route("/ready", get(health::ready))
+        .route("/metrics", get(metrics::metrics))
+        .route("/proxy", proxy)
+        .route("/proxy/dry-run", post(proxy::dry_run))
+        .route("/check", post(check::check))
+        .route("/check/batch", post(check::check_batch))
+        .route("/openapi.json", get(openapi::openapi));
+    if config.admin.is_some() {
+        app = app.merge(admin::router(state.clone()));
+    }
+    if config.reservations.is_some() {
+        app = app.merge(reservations::router());
+    }
+    if config.passthrough.is_some() {
+        let mut passthrough = passthrough::router();
+        if let Some(limit) = &concurrency {
+            passthrough = passthrough.route_layer(limited(limit));
+        }
+        app = app.merge(passthrough);
+    }
+    #[cfg(feature = "grpc")]
+    let grpc = grpc::router(state.clone());
+    let mut sidecar = Router::new().fallback(passthrough::sidecar).with_state(state.clone());
+    if let Some(limit) = &concurrency {
+        sidecar = sidecar.layer(limited(limit));
+    }
+    let mut app = app.with_state(state);
+    #[cfg(feature = "grpc")]
+    {
+        app = app.merge(grpc);
+    }
+    // Replaces the gRPC fallback, so unknown gRPC calls go upstream too
+    if config.sidecar.is_some() {
+        app = app.fallback_service(sidecar);
+    }
+    if let Some(timeout) = config.server.body_read_timeout_ms {
+        app = app.layer(middleware::from_fn_with_state(Duration::from_millis(timeout), server::body_read_timeout));
+    }
+    if let Some(compression) = &config.compression {
+        app = app.layer(compression.layer());
+    }
+    if let Some(cors) = &config.cors {
+        app = app.layer(cors.layer()?);
+    }
+    Ok(app.layer(middleware::from_fn(panics::catch_panic)))
+}
+
+/// A rejection shared by the HTTP and gRPC APIs, answered as
+/// `{"error", "message"}` JSON over HTTP, with the invalid fields of the
+/// request if there are any.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub error: &'static str,
+    pub message: String,
+    pub fields: Vec<FieldError>,
+}
+
+/// What is wrong with one field of a request.
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    /// Path of the field, e.g. `method` or `headers.X-Trace`.
+    pub field: String,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, error: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            error,
+            message: message.into(),
+            fields: Vec::new(),
+        }
+    }
+
+    /// `400 invalid_request` listing the invalid `fields`, which the message
+    /// sums up for clients only reading it.
+    pub fn invalid_fields(fields: Vec<FieldError>) -> Self {
+        let summary: Vec<String> = fields.iter().map(|f| format!("{} {}", f.field, f.message)).collect();
+        Self {
+            fields,
+            ..Self::new(StatusCode::BAD_REQUEST, "invalid_request", format!("Invalid request: {}", summary.join("; ")))
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut payload = json!({
+            "error": self.error,
+            "message": self.message
+        });
+        if !self.fields.is_empty() {
+            payload["fields"] = json!(self.fields);
+        }
+        let payload = Json(payload);
+        match Reason::from_code(self.error) {
+            Some(reason) => (self.status, [(X_REJECTION_REASON, reason.code())], payload).into_response(),
+            None => (self.status, payload).into_response(),
+        }
+    }
+}
+
+impl PartialSchema for ApiError {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .property("error", ObjectBuilder::new().schema_type(Type::String).description(Some("Machine readable error code")))
+            .property("message", ObjectBuilder::new().schema_type(Type::String))
+            .property(
+                "fields",
+                ArrayBuilder::new()
+                    .items(
+                        ObjectBuilder::new()
+                            .property("field", ObjectBuilder::new().schema_type(Type::String))
+                            .property("message", ObjectBuilder::new().schema_type(Type::String))
+                            .required("field")
+                            .required("message"),
+                    )
+                    .description(Some("Invalid fields of the request")),
+            )
+            .required("error")
+            .required("message")
+            .into()
+    }
+}
+
+impl ToSchema for ApiError {
+    fn name() -> Cow<'static, str> {
+        Cow::Borrowed("Error")
+    }
+}
@@ -0,0 +1,63 @@
+//! OpenAPI document of the JSON API, served on `/openapi.json` for client
+//! teams to generate SDKs from.
+
+use axum::{extract::State, Json};
+use utoipa::{openapi::{security::{HttpAuthScheme, HttpBuilder, SecurityScheme}, OpenApi as Document}, Modify, OpenApi};
+
+use super::{admin, check, health, metrics, proxy::{self, AppState}, reservations};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "grenze", description = "Rate limiting HTTP proxy"),
+    paths(
+        health::health,
+        health::ready,
+        metrics::metrics,
+        proxy::proxy,
+        proxy::dry_run,
+        check::check,
+        check::check_batch,
+        reservations::reserve,
+        reservations::commit,
+        reservations::release,
+        admin::reload_script,
+        admin::slo,
+        admin::simulate,
+        admin::explain,
+        admin::replay,
+        admin::export_har,
+        admin::export_snapshot,
+        admin::import_snapshot,
+        admin::reset_bucket,
+        admin::get_rule,
+        admin::put_rule,
+        admin::delete_rule,
+        admin::flush_rules,
+        admin::usage,
+        admin::billing,
+        admin::erase_tenant,
+    ),
+    modifiers(&AdminToken)
+)]
+struct ApiDoc;
+
+/// Declares the bearer token guarding the admin API.
+struct AdminToken;
+
+impl Modify for AdminToken {
+    fn modify(&self, openapi: &mut Document) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme("admin_token", SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()));
+    }
+}
+
+/// The document, limited to the routes mounted with this configuration.
+pub async fn openapi(State(state): State<AppState>) -> Json<Document> {
+    let mut doc = ApiDoc::openapi();
+    doc.paths.paths.retain(|path, _| match path.as_str() {
+        "/reserve" | "/commit" | "/release" => state.reservations.is_some(),
+        path if path.starts_with("/admin/") => state.admin.is_some(),
+        _ => true,
+    });
+    Json(doc)
+}
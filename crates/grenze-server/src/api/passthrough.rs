@@ -0,0 +1,180 @@
+//! Proxying without the JSON envelope: `ANY /p/{key}/{*path}?upstream=...`
+//! forwards the method, path, query string, headers and body of the incoming
+//! request as they are, so curl and existing HTTP clients can go through
+//! grenze by changing their base URL. In sidecar mode, requests to any other
+//! path are forwarded the same way.
+
+use anyhow::{bail, Context as _, Result};
+use axum::{body::Bytes, extract::{ConnectInfo, Path, Request, State}, http::{HeaderMap, Method, StatusCode, Uri}, response::{IntoResponse, Response}, Extension, Router};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use std::{collections::HashMap, net::SocketAddr};
+
+use super::{proxy::{forward, resolve, AppState, ProxyRequest, CALLER_HEADERS}, ApiError};
+use crate::tls::ClientIdentity;
+
+/// Body size buffered to verify signatures, as for `/proxy` bodies.
+const SIGNED_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PassthroughConfig {
+    /// Upstream base URLs by name, selected with `?upstream=<name>`.
+    pub upstreams: HashMap<String, String>,
+    /// Whether `?upstream=` may also be a base URL of any upstream.
+    pub allow_urls: bool,
+}
+
+impl PassthroughConfig {
+    pub fn validate(&self) -> Result<()> {
+        for (name, base) in &self.upstreams {
+            let url = reqwest::Url::parse(base).with_context(|| format!("passthrough upstream '{}'", name))?;
+            if !matches!(url.scheme(), "http" | "https") || url.query().is_some() {
+                bail!("passthrough upstream '{}' must be an http(s) URL without query", name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Base URL `upstream` stands for.
+    fn base_url(&self, upstream: &str) -> Option<String> {
+        if let Some(base) = self.upstreams.get(upstream) {
+            return Some(base.clone());
+        }
+        let url = reqwest::Url::parse(upstream).ok().filter(|_| self.allow_urls)?;
+        matches!(url.scheme(), "http" | "https").then(|| upstream.to_string())
+    }
+}
+
+/// Routes of the passthrough proxy. Only mounted when passthrough is
+/// configured.
+pub fn router() -> Router<AppState> {
+    Router::new().route("/p/{key}/{*path}", axum::routing::any(passthrough))
+}
+
+/// Headers describing the connection to grenze rather than the request.
+fn is_hop_by_hop(name: &str) -> bool {
+    matches!(
+        name,
+        "connection" | "keep-alive" | "proxy-authenticate" | "proxy-authorization" | "te" | "trailer" | "transfer-encoding" | "upgrade" | "host"
+    )
+}
+
+async fn passthrough(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    identity: Option<Extension<ClientIdentity>>,
+    Path((key, _)): Path<(String, String)>,
+    request: Request,
+) -> Response {
+    let url = upstream_url(&state, request.uri());
+    relay(state, peer, identity, key, url, request).await
+}
+
+/// Forwards requests not matching any other route to the sidecar's upstream,
+/// under the pod's key.
+pub async fn sidecar(State(state): State<AppState>, ConnectInfo(peer): ConnectInfo<SocketAddr>, identity: Option<Extension<ClientIdentity>>, request: Request) -> Response {
+    let Some(sidecar) = state.sidecar.clone() else {
+        return ApiError::new(StatusCode::NOT_FOUND, "not_found", "Sidecar mode is not enabled").into_response();
+    };
+    let mut url = format!("{}{}", sidecar.upstream, request.uri().path());
+    if let Some(query) = request.uri().query() {
+        url.push('?');
+        url.push_str(query);
+    }
+    relay(state, peer, identity, sidecar.key.clone(), Ok(url), request).await
+}
+
+/// Sends the request to `url` as it is, under `key`.
+async fn relay(state: AppState, peer: SocketAddr, identity: Option<Extension<ClientIdentity>>, key: String, url: Result<String, ApiError>, request: Request) -> Response {
+    let (parts, body) = request.into_parts();
+    let headers = parts.headers;
+    let mut req = match url {
+        Ok(url) => envelope(key, url, &parts.method, &headers),
+        Err(e) => return state.middleware.on_reject(None, e.into_response()).await,
+    };
+    // Signed bodies are verified before anything is sent, so they are
+    // buffered; all others are streamed
+    let signed = match &state.signing {
+        Some(_) => match axum::body::to_bytes(body, SIGNED_BODY_LIMIT).await {
+            Ok(bytes) => {
+                req.raw_body = Some(bytes.clone().into());
+                bytes
+            },
+            Err(e) => {
+                let e = ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "body_too_large", e.to_string());
+                return state.middleware.on_reject(None, e.into_response()).await;
+            },
+        },
+        None => {
+            req.raw_body = Some(reqwest::Body::wrap_stream(body.into_data_stream()));
+            Bytes::new()
+        },
+    };
+
+    let policies = state.policies.load();
+    let (ctx, req) = match resolve(&state, &policies, peer, identity.map(|Extension(id)| id), &headers, req) {
+        Ok(resolved) => resolved,
+        Err(response) => return state.middleware.on_reject(None, response).await,
+    };
+    match forward(&state, &ctx, req, &headers, &signed).await {
+        Ok(response) => response,
+        Err(response) => state.middleware.on_reject(Some(&ctx), response).await,
+    }
+}
+
+/// URL a passthrough request is forwarded to: its path below `/p/{key}/`
+/// appended to the selected upstream, with the remaining query string.
+fn upstream_url(state: &AppState, uri: &Uri) -> Result<String, ApiError> {
+    let Some(config) = &state.passthrough else {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "not_found", "Passthrough is not enabled"));
+    };
+    // The path is forwarded as sent, without decoding it
+    let path = uri.path().trim_start_matches("/p/").split_once('/').map_or("", |(_, path)| path);
+
+    let mut upstream = None;
+    let mut query = Vec::new();
+    for pair in uri.query().unwrap_or_default().split('&').filter(|p| !p.is_empty()) {
+        match pair.split_once('=') {
+            Some(("upstream", value)) => upstream = Some(percent_decode_str(value).decode_utf8_lossy().into_owned()),
+            _ => query.push(pair),
+        }
+    }
+    let Some(upstream) = upstream else {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "missing_upstream", "Request must select an upstream with ?upstream="));
+    };
+    let Some(base) = config.base_url(&upstream) else {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "unknown_upstream", format!("Upstream '{}' is not configured", upstream)));
+    };
+    let mut url = format!("{}/{}", base.trim_end_matches('/'), path);
+    if !query.is_empty() {
+        url.push('?');
+        url.push_str(&query.join("&"));
+    }
+    Ok(url)
+}
+
+/// Translates the incoming request into the envelope `/proxy` takes.
+fn envelope(key: String, url: String, method: &Method, headers: &HeaderMap) -> ProxyRequest {
+    let mut headers = crate::headers::to_map(headers);
+    // Accept, byte ranges and preconditions are passed through separately
+    headers.retain(|name, value| {
+        // Willingness to receive trailers is passed on, unlike other hop-by-hop headers
+        let trailers = name == "te" && value.values().iter().all(|v| v.eq_ignore_ascii_case("trailers"));
+        (trailers || !is_hop_by_hop(name)) && !CALLER_HEADERS.iter().any(|h| h.as_str() == name)
+    });
+
+    ProxyRequest {
+        key,
+        url,
+        method: method.to_string(),
+        headers,
+        query: HashMap::new(),
+        body: None,
+        timeout_ms: None,
+        capacity: None,
+        leak_per_sec: None,
+        decompress: None,
+        raw_body: None,
+    }
+}
@@ -1,76 +1,918 @@
-use axum::{extract::State, http::{header::{CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE}, HeaderMap, Method, StatusCode}, response::IntoResponse, Json};
-use anyhow::Result;
-use redis::Script;
+use axum::{body::Bytes, extract::{ConnectInfo, State}, Extension, http::{header::{ACCEPT, ACCEPT_RANGES, AUTHORIZATION, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, COOKIE, ETAG, IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, IF_UNMODIFIED_SINCE, LAST_MODIFIED, PROXY_AUTHORIZATION, RANGE, RETRY_AFTER, TRAILER}, HeaderMap, HeaderName, HeaderValue, Method, StatusCode}, response::{IntoResponse, Response}, Json};
+use anyhow::{Context as _, Result};
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::Frame;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{sync::Arc, time::{SystemTime, UNIX_EPOCH, Duration}};
-use tokio::sync::Mutex;
+use std::{collections::HashMap, convert::Infallible, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::sync::broadcast;
+use utoipa::ToSchema;
+
+#[cfg(feature = "wasm")]
+use crate::plugin::PluginManager;
+use crate::{anomaly::AnomalyDetector, api::{admin::AdminConfig, passthrough::PassthroughConfig, reservations::ReservationsConfig, ApiError, FieldError}, billing::Billing, breaker::{BreakerStore, CircuitBreaker, FailureMode}, cardinality::{KeyAdmission, KeyTracker}, classify::ClassificationConfig, client_profiles::ClientProfiles, config::Config, credentials::SecretStore, destinations::DestinationsConfig, dynamodb::DynamoDbStore, encoding::EncodingConfig, etcd, expiry, fairness::ActiveKeys, geoip::GeoIp, headers::TemplateContext, hedge::Latencies, key::{KeyContext, KeyTemplate}, leader::Scheduler, limiter::{Admission, BucketLimit, BucketSize, Clock, ClockSource, LimiterStore, LogLimit, QuotaAdmission, RedisStore, StoreConfig, SystemClock}, logging::{self, Outcome, RequestSampling}, memcached::MemcachedStore, metrics::{Decision, Metrics}, middleware::{Context, DownstreamResponse, MiddlewareRegistry, ProxyMiddleware}, overrides::OverridesConfig, policy::{self, ErrorBodies, ErrorBody, Policies, Policy, PolicySet}, postgres::PostgresStore, recording::{Recorder, Recording, RecordingSink, REDACTED}, rejection::{Reason, RejectionFields, RejectionsConfig, X_REJECTION_REASON}, replication::ReplicatedStore, rules::{KeyRule, KeyRules}, schema::{BodyValidation, Refusal}, script::{ScriptRequest, Scripts}, shards::ShardedStore, sidecar::Sidecar, signing::{SigningConfig, Verification}, slo::SloTracker, statsd::StatsdExporter, tls::{ClientIdentity, TlsConfig}, trace::{Span, TraceContext, Tracer}, upstream_tls::{self, UpstreamClients}, usage::UsageTracker};
+
+/// Decisions buffered for admin watchers that fall behind.
+const DECISION_BUFFER: usize = 1024;
+
+/// Marks responses to requests past the allowance of the key's plan.
+const X_OVERAGE: HeaderName = HeaderName::from_static("x-overage");
+
+/// Capacity, tokens left and seconds until drained of the bucket a response
+/// reports on.
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
 
 #[derive(Clone)]
 pub struct AppState {
     pub http_client: reqwest::Client,
-    pub redis: Arc<Mutex<redis::aio::MultiplexedConnection>>,
+    pub limiter: Arc<dyn LimiterStore>,
+    pub breaker: Arc<CircuitBreaker>,
+    pub on_error: FailureMode,
+    pub clock: Arc<dyn Clock>,
+    pub metrics: Arc<Metrics>,
+    /// Feed of rate limit decisions for admin watchers.
+    pub decisions: broadcast::Sender<Decision>,
+    pub statsd: Option<Arc<StatsdExporter>>,
+    pub slo: Option<Arc<SloTracker>>,
+    pub anomalies: Option<Arc<AnomalyDetector>>,
+    pub usage: Option<Arc<UsageTracker>>,
+    pub billing: Option<Arc<Billing>>,
+    /// Bucket size unless a policy or key overrides it.
     pub capacity: u32,
     pub leak_per_sec: f64,
+    /// Bucket sizes of specific rate limit keys.
+    pub key_sizes: Arc<HashMap<String, BucketSize>>,
+    pub key_template: Arc<KeyTemplate>,
+    pub policies: Arc<Policies>,
+    pub tls: Option<Arc<TlsConfig>>,
+    pub signing: Option<Arc<SigningConfig>>,
+    pub secrets: Arc<SecretStore>,
+    pub middleware: Arc<MiddlewareRegistry>,
+    #[cfg(feature = "wasm")]
+    pub plugins: Arc<PluginManager>,
+    pub admin: Option<Arc<AdminConfig>>,
+    pub reservations: Option<Arc<ReservationsConfig>>,
+    pub passthrough: Option<Arc<PassthroughConfig>>,
+    pub sidecar: Option<Arc<Sidecar>>,
+    pub overrides: Option<Arc<OverridesConfig>>,
+    pub geoip: Option<Arc<GeoIp>>,
+    pub classification: Option<Arc<ClassificationConfig>>,
+    pub destinations: Arc<DestinationsConfig>,
+    pub rejections: Option<Arc<RejectionsConfig>>,
+    pub recorder: Option<Arc<Recorder>>,
+    pub rules: Option<Arc<KeyRules>>,
+    pub tracer: Option<Arc<Tracer>>,
+    /// Share of proxied requests logged by outcome.
+    pub request_log: Option<RequestSampling>,
+    pub scripts: Option<Arc<Scripts>>,
+    pub keys: Option<Arc<KeyTracker>>,
+    /// Runs background tasks on the instance elected leader.
+    pub scheduler: Arc<Scheduler>,
+    /// Recent downstream latencies of policies hedging requests.
+    pub latencies: Arc<Latencies>,
+    /// Downstream clients of the client profiles.
+    pub client_profiles: Arc<ClientProfiles>,
+    /// Downstream clients of policies with TLS settings of their own.
+    pub upstream_clients: Arc<UpstreamClients>,
+    /// Keys recently drawing from shared buckets with fair queuing.
+    pub active_keys: Arc<ActiveKeys>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
 pub struct ProxyRequest {
-    // Mandatory rate limit key supplied by the client
+    /// Rate limit key supplied by the client, required unless the key template
+    /// derives the key from other request data.
+    #[serde(default)]
     pub key: String,
     pub url: String,
     pub method: String,
-    pub headers: std::collections::HashMap<String, String>,
-    pub query: std::collections::HashMap<String, String>,
+    /// Headers, repeated for every value given as an array.
+    pub headers: std::collections::HashMap<String, MultiValue>,
+    /// Query parameters, replacing those of the same name in `url`.
+    pub query: std::collections::HashMap<String, MultiValue>,
+    /// JSON body of the downstream request.
     #[serde(default)]
+    #[schema(value_type = Option<Object>)]
     pub body: Option<serde_json::Value>,
     #[serde(default)]
     pub timeout_ms: Option<u64>,
+    /// Bucket size for this request, honored for callers holding the
+    /// override scope and clamped to the configured maximums.
+    #[serde(default)]
+    pub capacity: Option<u32>,
+    #[serde(default)]
+    pub leak_per_sec: Option<f64>,
+    /// Whether a compressed response is decoded before it is returned,
+    /// overriding the policy's `encoding`.
+    #[serde(default)]
+    pub decompress: Option<bool>,
+    /// Body sent downstream as is instead of `body`, set for passthrough
+    /// requests.
+    #[serde(skip)]
+    pub raw_body: Option<reqwest::Body>,
+}
+
+/// Whether a request was admitted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    /// Admitted, leaving the key's own bucket filled to `fill`; `None` if
+    /// the store failed open or the policy keeps a sliding log instead.
+    Allowed { fill: Option<f64> },
+    /// Admitted past the allowance of the key's plan.
+    Overage { fill: Option<f64> },
+    /// Rejected with the bucket the request waits longest for, `limit` in
+    /// its limits, filled to `fill`.
+    RateLimited { limit: usize, fill: f64 },
+    /// Rejected past the allowance of the key's plan, which renews at
+    /// `until_ms`.
+    QuotaExceeded { until_ms: i64 },
+    /// Rejected by the key's sliding log, which has room for the request at
+    /// `until_ms`.
+    LogFull { until_ms: i64 },
+    /// Rejected as the store failed and requests fail closed.
+    Unavailable { circuit_open: bool },
+}
+
+impl Verdict {
+    pub fn allowed(self) -> bool {
+        matches!(self, Verdict::Allowed { .. } | Verdict::Overage { .. })
+    }
+
+    /// Cause of the rejection of a request drawing from `limits` under
+    /// `policy`, if it was rejected.
+    pub fn reason(self, policy: &Policy, limits: &[BucketLimit]) -> Option<Reason> {
+        match self {
+            Verdict::Allowed { .. } | Verdict::Overage { .. } => None,
+            Verdict::RateLimited { limit, .. } if policy.shares(&limits[limit].bucket) => Some(Reason::LimitExceeded),
+            Verdict::RateLimited { .. } | Verdict::LogFull { .. } => Some(Reason::RateLimited),
+            Verdict::QuotaExceeded { .. } => Some(Reason::QuotaExceeded),
+            Verdict::Unavailable { circuit_open: true } => Some(Reason::CircuitOpen),
+            Verdict::Unavailable { circuit_open: false } => Some(Reason::StoreUnavailable),
+        }
+    }
+}
+
+/// Buckets of `limits` a request draws tokens from, all but its own if the
+/// policy keeps a sliding log instead.
+fn buckets<'a>(ctx: &Context<'_>, limits: &'a [BucketLimit]) -> &'a [BucketLimit] {
+    match ctx.policy.sliding_log {
+        Some(_) => &limits[1..],
+        None => limits,
+    }
+}
+
+/// Rejection of a request by the bucket it waits longest for to have room,
+/// of `limits` admitting it as `admissions` say. Its index in the request's
+/// limits is offset by `offset`, the buckets preceding `limits`.
+fn constrained(limits: &[BucketLimit], admissions: &[Admission], cost: f64, offset: usize) -> Verdict {
+    let wait = |(limit, admission): &(&BucketLimit, &Admission)| (admission.fill + cost - limit.capacity as f64).max(0.0) / limit.leak_per_sec;
+    let rejecting = limits.iter().zip(admissions).enumerate().filter(|(_, (_, admission))| !admission.allowed);
+    match rejecting.max_by(|(_, a), (_, b)| wait(a).total_cmp(&wait(b))) {
+        Some((i, (_, admission))) => Verdict::RateLimited { limit: i + offset, fill: admission.fill },
+        None => Verdict::RateLimited { limit: offset, fill: admissions.first().map_or(0.0, |a| a.fill) },
+    }
+}
+
+/// Admission of a request into all of its buckets, with the fill of its own.
+fn combine(admissions: &[Admission]) -> Admission {
+    Admission {
+        allowed: admissions.iter().all(|a| a.allowed),
+        fill: admissions.first().map_or(0.0, |a| a.fill),
+    }
+}
+
+/// Value of a query parameter or header, repeated if it holds several.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum MultiValue {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl MultiValue {
+    pub fn values(&self) -> &[String] {
+        match self {
+            MultiValue::One(value) => std::slice::from_ref(value),
+            MultiValue::Many(values) => values,
+        }
+    }
+
+    pub fn push(&mut self, value: String) {
+        match self {
+            MultiValue::One(first) => *self = MultiValue::Many(vec![std::mem::take(first), value]),
+            MultiValue::Many(values) => values.push(value),
+        }
+    }
+}
+
+impl ProxyRequest {
+    /// Checks the method and headers, which could not be sent otherwise,
+    /// listing every invalid one.
+    pub fn check(&self) -> Result<(), ApiError> {
+        let mut fields = Vec::new();
+        let mut invalid = |field: String, message: &str| fields.push(FieldError { field, message: message.to_string() });
+        if Method::from_bytes(self.method.to_uppercase().as_bytes()).is_err() {
+            invalid("method".to_string(), "is not an HTTP method");
+        }
+        let mut headers: Vec<_> = self.headers.iter().collect();
+        headers.sort_by_key(|(name, _)| *name);
+        for (name, value) in headers {
+            if HeaderName::from_bytes(name.as_bytes()).is_err() {
+                invalid(format!("headers.{}", name), "is not a valid header name");
+                continue;
+            }
+            for (i, v) in value.values().iter().enumerate() {
+                if HeaderValue::from_str(v).is_err() {
+                    let field = match value {
+                        MultiValue::One(_) => format!("headers.{}", name),
+                        MultiValue::Many(_) => format!("headers.{}[{}]", name, i),
+                    };
+                    invalid(field, "contains characters not allowed in header values");
+                }
+            }
+        }
+        if fields.is_empty() {
+            return Ok(());
+        }
+        Err(ApiError::invalid_fields(fields))
+    }
 }
 
+/// Merges `query` into the query string of `url`. Its parameters replace all
+/// of the same name in `url`; the others keep their order and come first.
+pub(crate) fn merge_query(url: &mut reqwest::Url, query: &HashMap<String, MultiValue>) {
+    if query.is_empty() {
+        return;
+    }
+    let kept: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(name, _)| !query.contains_key(name.as_ref()))
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    // Sorted for stable URLs
+    let mut added: Vec<_> = query.iter().collect();
+    added.sort_by_key(|(name, _)| *name);
+    let mut pairs = url.query_pairs_mut();
+    pairs.clear().extend_pairs(kept);
+    for (name, value) in added {
+        for value in value.values() {
+            pairs.append_pair(name, value);
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/proxy",
+    tag = "proxy",
+    request_body = ProxyRequest,
+    responses(
+        (status = 200, description = "Response of the downstream, with its status"),
+        (status = 400, description = "Rate limit key missing, URL, method or headers invalid", body = ApiError),
+        (status = 401, description = "Request signature invalid", body = ApiError),
+        (status = 403, description = "Client, its region, the request or its destination blocked, or the key banned", body = ApiError),
+        (status = 415, description = "Content type not accepted by the policy", body = ApiError),
+        (status = 422, description = "Body does not match the policy's schema", body = ApiError),
+        (status = 429, description = "Rate limited or past the plan's quota", body = ApiError),
+        (status = 502, description = "Downstream request failed", body = ApiError),
+        (status = 503, description = "Too many requests in flight, or the limiter unavailable while failing closed", body = ApiError),
+    )
+)]
 pub async fn proxy(
     State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    identity: Option<Extension<ClientIdentity>>,
     headers: HeaderMap,
-    axum::extract::Json(req): axum::extract::Json<ProxyRequest>,
-) -> impl IntoResponse {
-    // Require and enforce caller-provided rate limit key
-    let key = req.key.trim().to_string();
-    if key.is_empty() {
-        let payload = Json(json!({
-            "error": "missing_key",
-            "message": "Request must include non-empty 'key'"
-        }));
-        return (StatusCode::BAD_REQUEST, payload).into_response();
+    body: Bytes,
+) -> Response {
+    // The raw body is kept around for signature verification
+    let req = match Json::<ProxyRequest>::from_bytes(&body) {
+        Ok(Json(r)) => r,
+        Err(rejection) => return state.middleware.on_reject(None, rejection.into_response()).await,
+    };
+    let policies = state.policies.load();
+    let (ctx, req) = match resolve(&state, &policies, peer, identity.map(|Extension(id)| id), &headers, req) {
+        Ok(resolved) => resolved,
+        Err(response) => return state.middleware.on_reject(None, response).await,
+    };
+    match forward(&state, &ctx, req, &headers, &body).await {
+        Ok(response) => response,
+        Err(response) => state.middleware.on_reject(Some(&ctx), response).await,
+    }
+}
+
+/// Downstream request grenze would send for a proxy request.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DryRun {
+    pub key: String,
+    pub policy: String,
+    pub bucket: String,
+    pub method: String,
+    /// Destination with the query parameters merged in.
+    pub url: String,
+    /// Headers as sent, with credentials and cookies redacted. Cookies from
+    /// the key's jar are not included.
+    pub headers: HashMap<String, MultiValue>,
+    #[schema(value_type = Option<Object>)]
+    pub body: Option<serde_json::Value>,
+    /// Whether one of the policy's mocks would answer instead of the
+    /// downstream.
+    pub mocked: bool,
+}
+
+/// Headers whose values a dry run does not reveal.
+const REDACTED_HEADERS: [HeaderName; 3] = [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE];
+
+#[utoipa::path(
+    post,
+    path = "/proxy/dry-run",
+    tag = "proxy",
+    request_body = ProxyRequest,
+    responses(
+        (status = 200, description = "Request that would be sent downstream", body = DryRun),
+        (status = 400, description = "Rate limit key missing, URL, method or headers invalid", body = ApiError),
+        (status = 401, description = "Request signature invalid", body = ApiError),
+        (status = 403, description = "Client, its region, the request or its destination blocked", body = ApiError),
+        (status = 415, description = "Content type not accepted by the policy", body = ApiError),
+        (status = 422, description = "Body does not match the policy's schema", body = ApiError),
+    )
+)]
+pub async fn dry_run(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    identity: Option<Extension<ClientIdentity>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let req = match Json::<ProxyRequest>::from_bytes(&body) {
+        Ok(Json(r)) => r,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let policies = state.policies.load();
+    let (ctx, mut req) = match resolve(&state, &policies, peer, identity.map(|Extension(id)| id), &headers, req) {
+        Ok(resolved) => resolved,
+        Err(response) => return response,
+    };
+    // The signature is not remembered, so the request can still be sent
+    if let Some(signing) = &state.signing
+        && let Verification::Invalid(message) = signing.verify(&ctx.key, &headers, &body, state.clock.now_ms())
+    {
+        return ApiError::new(StatusCode::UNAUTHORIZED, "invalid_signature", message).into_response();
+    }
+    if let Err(response) = state.middleware.on_request(&ctx, &mut req).await {
+        return response;
+    }
+    if let Err(e) = req.check() {
+        return e.into_response();
+    }
+    if let Some(validation) = &ctx.policy.validation
+        && let Err(e) = check_request(validation, &req)
+    {
+        return e.into_response();
+    }
+    let mut url = match state.destinations.parse(&req.url) {
+        Ok(url) => url,
+        Err(e) => return e.into_response(),
+    };
+
+    let policy = ctx.policy;
+    let bucket = policy.bucket_key(&ctx.key, policy::authority(&url).as_deref());
+    let method = Method::from_bytes(req.method.to_uppercase().as_bytes()).expect("method checked above");
+    let mocked = policy.mocks.iter().any(|m| m.matches(&req.method, &req.url));
+    let mut req_headers = rewrite_headers(&ctx, method.as_str(), Some(&url), req.headers);
+    merge_query(&mut url, &req.query);
+    for name in &CALLER_HEADERS {
+        if let Some(value) = headers.get(name).and_then(|v| v.to_str().ok()) {
+            match req_headers.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(name.as_str())) {
+                Some((_, values)) => values.push(value.to_string()),
+                None => {
+                    req_headers.insert(name.to_string(), MultiValue::One(value.to_string()));
+                },
+            }
+        }
+    }
+    if req.body.is_some() && !req_headers.keys().any(|k| k.eq_ignore_ascii_case(CONTENT_TYPE.as_str())) {
+        req_headers.insert(CONTENT_TYPE.to_string(), MultiValue::One("application/json".to_string()));
+    }
+
+    // Credentials are only fetched when sending
+    for (name, value) in req_headers.iter_mut() {
+        if REDACTED_HEADERS.iter().any(|h| h.as_str().eq_ignore_ascii_case(name)) {
+            *value = MultiValue::One(REDACTED.to_string());
+        }
+    }
+    if policy.auth.is_some() {
+        req_headers.retain(|k, _| !k.eq_ignore_ascii_case(AUTHORIZATION.as_str()));
+        req_headers.insert(AUTHORIZATION.to_string(), MultiValue::One(REDACTED.to_string()));
+    }
+
+    Json(DryRun {
+        key: ctx.key.clone(),
+        policy: policy.name.clone(),
+        bucket,
+        method: method.to_string(),
+        url: url.to_string(),
+        headers: req_headers,
+        body: req.body,
+        mocked,
+    })
+    .into_response()
+}
+
+/// Determines the rate limit key and policy of a request.
+#[allow(clippy::result_large_err)]
+pub(crate) fn resolve<'a>(
+    state: &AppState,
+    policies: &'a PolicySet,
+    peer: SocketAddr,
+    identity: Option<ClientIdentity>,
+    headers: &HeaderMap,
+    mut req: ProxyRequest,
+) -> Result<(Context<'a>, ProxyRequest), Response> {
+    let key = authorize(state, peer, identity.as_ref(), headers, &req.key).map_err(IntoResponse::into_response)?;
+    if req.capacity.is_some() || req.leak_per_sec.is_some() {
+        if !state.overrides.as_ref().is_some_and(|o| o.permits(identity.as_ref(), headers)) {
+            let payload = Json(json!({
+                "error": "forbidden",
+                "message": "Overriding the bucket size requires the override scope"
+            }));
+            return Err((StatusCode::FORBIDDEN, payload).into_response());
+        }
+        if req.leak_per_sec.is_some_and(|l| !l.is_finite() || l <= 0.0) {
+            let payload = Json(json!({
+                "error": "invalid_override",
+                "message": "leak_per_sec must be positive"
+            }));
+            return Err((StatusCode::BAD_REQUEST, payload).into_response());
+        }
     }
-    if !state.allow(&key).await {
+    let key_ctx = KeyContext {
+        key: &req.key,
+        peer: Some(peer.ip()),
+        identity: identity.as_ref().map(|id| id.0.as_str()),
+        headers,
+    };
+
+    // Let the routing script override the key, destination and policy
+    let mut routed_policy = None;
+    let key = match &state.scripts {
+        Some(scripts) => {
+            let request = ScriptRequest {
+                key: &key,
+                client_key: &req.key,
+                ip: state.key_template.client_ip(&key_ctx).map(|ip| ip.to_string()).unwrap_or_default(),
+                method: &req.method,
+                url: &req.url,
+                host: &reqwest::Url::parse(&req.url).ok().and_then(|u| u.host_str().map(str::to_string)).unwrap_or_default(),
+                headers: headers
+                    .iter()
+                    .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+                    .collect(),
+                query: &req.query,
+                body: &req.body,
+            };
+            match scripts.route(&request) {
+                Ok(routing) => {
+                    if let Some(destination) = routing.destination {
+                        req.url = destination;
+                    }
+                    routed_policy = routing.policy;
+                    routing.key.unwrap_or(key)
+                }
+                Err(e) => {
+                    logging::warn("routing_script", format_args!("Routing script failed: {:#}", e));
+                    let payload = Json(json!({
+                        "error": "script_error",
+                        "message": "Routing script failed"
+                    }));
+                    return Err((StatusCode::INTERNAL_SERVER_ERROR, payload).into_response());
+                }
+            }
+        }
+        None => key,
+    };
+
+    // Block or assign a policy by the request's classification
+    let class = state.classification.as_ref().and_then(|c| c.classify(Some(&req.method), Some(&req.url), headers));
+    if class.is_some_and(|rule| rule.block) {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "blocked", "Requests like this are not allowed").into_response());
+    }
+
+    // Resolve the policy for the destination, unless the key serves an
+    // anomaly penalty or the routing script or the classification chose one
+    let host = reqwest::Url::parse(&req.url).ok().and_then(|u| u.host_str().map(str::to_string));
+    let penalty = state.anomalies.as_ref().and_then(|a| a.penalty(&key, state.clock.now_ms())).map(str::to_string);
+    let chosen = penalty.or(routed_policy).or_else(|| class.and_then(|rule| rule.policy.clone()));
+    let policy = match chosen.map(|name| (policies.get(&name), name)) {
+        Some((Some(p), _)) => p,
+        Some((None, name)) => {
+            logging::warn("policy", format_args!("Request assigned unknown policy '{}'", name));
+            policies.resolve(host.as_deref())
+        },
+        None => policies.resolve(host.as_deref()),
+    };
+
+    Ok((Context { peer, key, policy, trace: state.trace(headers) }, req))
+}
+
+/// Checks the client certificate and derives the rate limit key from the one
+/// the client supplied.
+pub(crate) fn authorize(state: &AppState, peer: SocketAddr, identity: Option<&ClientIdentity>, headers: &HeaderMap, client_key: &str) -> Result<String, ApiError> {
+    if let Some(tls) = &state.tls
+        && !tls.authorize(identity)
+    {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "forbidden", "Client certificate identity is not allowed"));
+    }
+
+    // Derive and enforce the rate limit key
+    let key_ctx = KeyContext {
+        key: client_key,
+        peer: Some(peer.ip()),
+        identity: identity.map(|id| id.0.as_str()),
+        headers,
+    };
+    state.key_template.derive(&key_ctx).map_err(|message| ApiError::new(StatusCode::BAD_REQUEST, "missing_key", message))
+}
+
+/// Verifies, rate limits and forwards a resolved request, recording its span
+/// if it is traced and logging it if sampled. `body` is the raw request body
+/// signatures are verified against.
+pub(crate) async fn forward(state: &AppState, ctx: &Context<'_>, req: ProxyRequest, headers: &HeaderMap, body: &Bytes) -> Result<Response, Response> {
+    let (started_at, started) = (std::time::SystemTime::now(), std::time::Instant::now());
+    let method = req.method.to_uppercase();
+    let target = state.request_log.is_some().then(|| req.url.split(['?', '#']).next().unwrap_or_default().to_string());
+    let result = limit_and_forward(state, ctx, req, headers, body).await;
+    let status = match &result {
+        Ok(response) | Err(response) => response.status(),
+    };
+    if let (Some(request_log), Some(target)) = (&state.request_log, &target) {
+        let outcome = match &result {
+            _ if status.is_server_error() => Outcome::Error,
+            Ok(_) => Outcome::Allowed,
+            Err(_) => Outcome::Rejected,
+        };
+        let reason = match &result {
+            Err(response) => response.headers().get(X_REJECTION_REASON).and_then(|v| v.to_str().ok()),
+            Ok(_) => None,
+        };
+        request_log.log(outcome, reason, ctx, &method, target, status.as_u16(), started.elapsed());
+    }
+    if let (Some(tracer), Some(trace)) = (&state.tracer, &ctx.trace) {
+        let mut tags = vec![("grenze.key", ctx.key.clone()), ("grenze.policy", ctx.policy.name.clone()), ("http.status_code", status.as_u16().to_string())];
+        if status.is_server_error() {
+            tags.push(("error", status.to_string()));
+        }
+        tracer.record(Span {
+            trace,
+            id: &trace.span_id,
+            parent_id: trace.parent_id.as_deref(),
+            kind: "SERVER",
+            name: &method,
+            started: started_at,
+            duration: started.elapsed(),
+            tags,
+        });
+    }
+    result
+}
+
+/// Refuses requests whose content type or body the policy does not accept.
+/// Requests without either are not checked for a content type, and raw
+/// passthrough bodies are not checked against the schema.
+fn check_request(validation: &BodyValidation, req: &ProxyRequest) -> Result<(), ApiError> {
+    let content_type = req
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(CONTENT_TYPE.as_str()))
+        .and_then(|(_, value)| value.values().first())
+        .map(String::as_str);
+    let mut checked = Ok(());
+    if content_type.is_some() || req.body.is_some() || req.raw_body.is_some() {
+        checked = validation.check_content_type(content_type);
+    }
+    if req.raw_body.is_none() {
+        checked = checked.and_then(|()| validation.check_body(req.body.as_ref()));
+    }
+    checked.map_err(|refusal| match refusal {
+        Refusal::ContentType(declared) => ApiError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_content_type", format!("Content type {} is not accepted", declared)),
+        Refusal::Body { pointer, message } => ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "invalid_body", format!("Body at {}: {}", pointer, message)),
+    })
+}
+
+async fn limit_and_forward(state: &AppState, ctx: &Context<'_>, mut req: ProxyRequest, headers: &HeaderMap, body: &Bytes) -> Result<Response, Response> {
+    let (key, policy) = (&ctx.key, ctx.policy);
+
+    // Verify the request signature and reject replays
+    if let Some(signing) = &state.signing {
+        match signing.verify(key, headers, body, state.clock.now_ms()) {
+            Verification::Skipped => {}
+            Verification::Valid { signature, ttl_secs } => {
+                if !state.remember_signature(&signature, ttl_secs).await {
+                    let payload = Json(json!({
+                        "error": "invalid_signature",
+                        "message": "Signature has already been used"
+                    }));
+                    return Err((StatusCode::UNAUTHORIZED, payload).into_response());
+                }
+            }
+            Verification::Invalid(message) => {
+                let payload = Json(json!({
+                    "error": "invalid_signature",
+                    "message": message
+                }));
+                return Err((StatusCode::UNAUTHORIZED, payload).into_response());
+            }
+        }
+    }
+
+    state.middleware.on_request(ctx, &mut req).await?;
+    req.check().map_err(IntoResponse::into_response)?;
+    if let Some(validation) = &policy.validation {
+        check_request(validation, &req).map_err(IntoResponse::into_response)?;
+    }
+
+    // The bucket follows the destination as modified by middleware
+    let dest_url = state.destinations.parse(&req.url).map_err(IntoResponse::into_response)?;
+    let authority = policy::authority(&dest_url);
+    let bucket = policy.bucket_key(key, authority.as_deref());
+    if !state.admit_key(ctx, &bucket).await {
         let payload = Json(json!({
-            "error": "rate_limited",
-            "message": "Too many requests"
+            "error": "too_many_keys",
+            "message": "Too many distinct rate limit keys for this tenant"
         }));
-        return (StatusCode::TOO_MANY_REQUESTS, payload).into_response();
+        return Err((StatusCode::TOO_MANY_REQUESTS, payload).into_response());
+    }
+    let mut limits = state.limits(policy, bucket, key, authority.as_deref());
+    state.apply_rule(key, &mut limits[0]).await.map_err(IntoResponse::into_response)?;
+    if let Some(overrides) = &state.overrides {
+        overrides.apply(&mut limits[0], req.capacity, req.leak_per_sec);
     }
+    state.locate(ctx, headers, &mut limits[0]).map_err(IntoResponse::into_response)?;
+    state.warm_up(policy, &mut limits[0]).await;
+    state.share(policy, key, authority.as_deref(), &mut limits).await;
+    let verdict = state.allow(ctx, &limits, dest_url.host_str(), 1.0).await;
+    // Rejections report the bucket the request waits longest for
+    let rejected = match verdict {
+        Verdict::RateLimited { limit, fill } => {
+            let limit = &limits[limit];
+            let drain = |fill: f64| (fill.max(0.0) / limit.leak_per_sec).ceil() as u64;
+            let remaining = (limit.capacity as f64 - fill).floor().max(0.0) as u64;
+            Some((limit.capacity, remaining, drain(fill), drain(fill + 1.0 - limit.capacity as f64)))
+        },
+        Verdict::QuotaExceeded { until_ms } | Verdict::LogFull { until_ms } => {
+            let reset = ((until_ms - state.clock.now_ms()).max(0) as u64).div_ceil(1000);
+            Some((limits[0].capacity, 0, reset, reset))
+        },
+        // The store may be back once the breaker probes it again
+        Verdict::Unavailable { .. } => {
+            let retry_after = state.breaker.probe_interval_secs();
+            Some((limits[0].capacity, 0, retry_after, retry_after))
+        },
+        Verdict::Allowed { .. } | Verdict::Overage { .. } => None,
+    };
+    if let (Some((limit, remaining, reset, retry_after)), Some(reason)) = (rejected, verdict.reason(policy, &limits)) {
+        let fields = RejectionFields {
+            error: reason.code(),
+            message: reason.message(),
+            key,
+            policy: &policy.name,
+            limit,
+            remaining,
+            reset,
+            retry_after,
+        };
+        return Err(state.reject(ctx, &fields));
+    }
+
+    // Answer from the policy's mocks, if one matches, instead of the downstream
+    let mut response = match policy.mocks.iter().find(|m| m.matches(&req.method, &req.url)) {
+        Some(mock) => mock.respond().await,
+        None => {
+            let encoding = policy.encoding.clone().unwrap_or_default();
+            let decompress = req.decompress.unwrap_or(encoding.decompress);
+            let recording = state.recorder.as_ref().and_then(|r| r.capture(key, &policy.name, &req, state.clock.now_ms()));
+            let started = std::time::Instant::now();
+            let result = call(state, ctx, req, headers).await;
+            if let (Some(recorder), Some(recording)) = (&state.recorder, recording) {
+                let status = result.as_ref().map_or_else(|r| r.status(), |r| r.status);
+                recorder.record(recording, status.as_u16(), result.as_ref().ok(), started.elapsed());
+            }
+            let mut response = result?;
+            // Streamed responses count with the length they announce
+            let size = match response.stream {
+                Some(_) => response.headers.get(CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok()).unwrap_or(0),
+                None => response.body.len(),
+            };
+            if let Some(bandwidth) = &policy.bandwidth {
+                state.charge(&limits, bandwidth.tokens(size));
+            }
+            if let Some(usage) = &state.usage {
+                usage.record_bytes(key, &policy.name, size as u64, state.clock.now_ms());
+            }
+            state.charge_quota(key, size as u64);
+            if response.status == StatusCode::TOO_MANY_REQUESTS
+                && let Some(penalty) = &policy.penalty
+            {
+                state.charge(&limits[..1], penalty.multiplier * limits[0].capacity as f64);
+            }
+            // Parts of an encoded body cannot be decoded on their own
+            if decompress && response.stream.is_none() && !response.headers.contains_key(CONTENT_RANGE) {
+                decompress_body(&mut response, &encoding).map_err(IntoResponse::into_response)?;
+            }
+            shape_error_body(&mut response, &policy.error_bodies);
+            response
+        },
+    };
+    if let Verdict::Allowed { fill: Some(fill) } | Verdict::Overage { fill: Some(fill) } = verdict {
+        let limit = &limits[0];
+        let remaining = (limit.capacity as f64 - fill).floor().max(0.0) as u64;
+        rate_limit_headers(&mut response.headers, limit.capacity, remaining, (fill.max(0.0) / limit.leak_per_sec).ceil() as u64);
+    }
+    if matches!(verdict, Verdict::Overage { .. }) {
+        response.headers.insert(X_OVERAGE, HeaderValue::from_static("true"));
+    }
+    state.middleware.on_response(ctx, &mut response).await?;
+    Ok(into_response(response))
+}
+
+/// Describes the bucket of a request with the `RateLimit-*` headers, keeping
+/// those a custom rejection sets itself.
+fn rate_limit_headers(headers: &mut HeaderMap, limit: u32, remaining: u64, reset: u64) {
+    for (name, value) in [(RATELIMIT_LIMIT, limit as u64), (RATELIMIT_REMAINING, remaining), (RATELIMIT_RESET, reset)] {
+        headers.entry(name).or_insert(HeaderValue::from(value));
+    }
+}
+
+fn into_response(mut response: DownstreamResponse) -> Response {
+    if let Some(stream) = response.stream {
+        return (response.status, response.headers, stream).into_response();
+    }
+    let Some(trailers) = response.trailers.filter(|t| !t.is_empty()) else {
+        return (response.status, response.headers, response.body).into_response();
+    };
+    // HTTP/1.1 clients only get trailers announced upfront, after a chunked body
+    let names = trailers.keys().map(|name| name.as_str()).collect::<Vec<_>>().join(", ");
+    if let Ok(names) = HeaderValue::from_str(&names) {
+        response.headers.insert(TRAILER, names);
+    }
+    response.headers.remove(CONTENT_LENGTH);
+    let frames = [Ok::<_, Infallible>(Frame::data(response.body)), Ok(Frame::trailers(trailers))];
+    (response.status, response.headers, axum::body::Body::new(StreamBody::new(futures::stream::iter(frames)))).into_response()
+}
 
-    // Validate URL and method (consider allowlists in production)
-    let dest = req.url;
+/// Headers of the call to grenze itself that are sent downstream.
+pub(crate) const CALLER_HEADERS: [HeaderName; 7] = [ACCEPT, RANGE, IF_RANGE, IF_NONE_MATCH, IF_MODIFIED_SINCE, IF_MATCH, IF_UNMODIFIED_SINCE];
+
+/// Downstream response headers always returned to the caller.
+const SAFE_RESPONSE_HEADERS: [HeaderName; 11] = [
+    CONTENT_TYPE,
+    CONTENT_LENGTH,
+    CONTENT_ENCODING,
+    CONTENT_RANGE,
+    ACCEPT_RANGES,
+    CACHE_CONTROL,
+    ETAG,
+    LAST_MODIFIED,
+    TRAILER,
+    // Status of gRPC responses without a body, sent as headers
+    HeaderName::from_static("grpc-status"),
+    HeaderName::from_static("grpc-message"),
+];
+
+/// Decodes a compressed response body, leaving it as it is if its encoding
+/// is not supported.
+fn decompress_body(response: &mut DownstreamResponse, encoding: &EncodingConfig) -> Result<(), ApiError> {
+    let Some(content_encoding) = response.headers.get(CONTENT_ENCODING).and_then(|v| v.to_str().ok()) else {
+        return Ok(());
+    };
+    // 304 and HEAD responses announce an encoding without a body
+    if response.body.is_empty() {
+        return Ok(());
+    }
+    match crate::encoding::decode(content_encoding, &response.body, encoding.max_decompressed_bytes) {
+        Ok(Some(decoded)) => {
+            response.headers.remove(CONTENT_ENCODING);
+            response.headers.insert(CONTENT_LENGTH, decoded.len().into());
+            crate::headers::weaken_etag(&mut response.headers);
+            response.body = decoded.into();
+            Ok(())
+        },
+        Ok(None) => Ok(()),
+        Err(e) => Err(ApiError::new(StatusCode::BAD_GATEWAY, "decompression_failed", format!("{:#}", e))),
+    }
+}
+
+/// Wraps the body of a downstream error response in grenze's error envelope,
+/// or replaces it, as the policy's `error_bodies` say. Bodies still encoded
+/// are dropped.
+fn shape_error_body(response: &mut DownstreamResponse, error_bodies: &ErrorBodies) {
+    let handling = error_bodies.get(response.status);
+    if handling == ErrorBody::Passthrough || response.stream.is_some() {
+        return;
+    }
+    let mut envelope = json!({
+        "error": "downstream_error",
+        "message": format!("Downstream answered {}", response.status),
+        "status": response.status.as_u16()
+    });
+    if handling == ErrorBody::Wrap && !response.body.is_empty() && !response.headers.contains_key(CONTENT_ENCODING) {
+        envelope["body"] = serde_json::from_slice(&response.body).unwrap_or_else(|_| String::from_utf8_lossy(&response.body).into());
+    }
+    for name in [CONTENT_LENGTH, CONTENT_ENCODING, CONTENT_RANGE, ETAG, LAST_MODIFIED] {
+        response.headers.remove(name);
+    }
+    response.headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response.body = serde_json::to_vec(&envelope).unwrap_or_default().into();
+    response.trailers = None;
+}
+
+/// Headers of a request as sent downstream, rewritten by the policy's rules
+/// and encoding.
+fn rewrite_headers(ctx: &Context<'_>, method: &str, url: Option<&reqwest::Url>, mut headers: HashMap<String, MultiValue>) -> HashMap<String, MultiValue> {
+    let policy = ctx.policy;
+    let template_ctx = TemplateContext {
+        key: &ctx.key,
+        method,
+        host: url.and_then(|u| u.host_str()).unwrap_or_default(),
+        path: url.map(|u| u.path()).unwrap_or_default(),
+        policy: &policy.name,
+    };
+    crate::headers::apply(&policy.headers, &mut headers, &template_ctx);
+    if let Some(accept) = policy.encoding.as_ref().and_then(|e| e.accept_header()) {
+        headers.retain(|k, _| !k.eq_ignore_ascii_case("accept-encoding"));
+        headers.insert("accept-encoding".to_string(), MultiValue::One(accept));
+    }
+    headers
+}
+
+/// Sends the request downstream and reads the response.
+async fn call(state: &AppState, ctx: &Context<'_>, mut req: ProxyRequest, headers: &HeaderMap) -> Result<DownstreamResponse, Response> {
+    let (key, policy) = (&ctx.key, ctx.policy);
+    let dest_url = reqwest::Url::parse(&req.url).ok();
+    let host = dest_url.as_ref().and_then(|u| u.host_str());
+
+    // The URL and method were checked before the request took a token
     let method = req.method.to_uppercase();
-    let parsed_method = Method::from_bytes(method.as_bytes()).unwrap_or(Method::POST);
+    let parsed_method = Method::from_bytes(method.as_bytes()).expect("method checked before the request took a token");
+
+    // Build downstream request, merging the query params into the URL's
+    let client = state.upstream_clients.get(&state.client_profiles, policy, host).map_err(|e| {
+        logging::warn("upstream_tls", format_args!("Failed to build the upstream client: {:#}", e));
+        ApiError::new(StatusCode::BAD_GATEWAY, "downstream_error", "The upstream TLS settings are invalid").into_response()
+    })?;
+    let mut builder = match dest_url.clone() {
+        Some(mut url) => {
+            merge_query(&mut url, &req.query);
+            client.request(parsed_method, url)
+        },
+        None => client.request(parsed_method, &req.url),
+    };
 
-    // Build downstream request
-    let mut builder = state.http_client.request(parsed_method, &dest);
+    // Add headers from JSON (string pairs), rewritten by the policy's rules
+    let mut req_headers = rewrite_headers(ctx, &method, dest_url.as_ref(), req.headers);
 
-    // Add query params
-    if !req.query.is_empty() {
-        builder = builder.query(&req.query);
+    // Pass the trace on, from a span of the call if spans are exported and
+    // from the caller's otherwise
+    let tracing = state.tracer.as_ref().zip(ctx.trace.as_ref());
+    let call_span = tracing.filter(|(tracer, trace)| tracer.exports(trace)).map(|_| crate::trace::span_id());
+    if let Some((tracer, trace)) = tracing
+        && let Some(span_id) = call_span.as_deref().or(trace.parent_id.as_deref())
+    {
+        tracer.inject(trace, span_id, &mut req_headers);
     }
 
-    // Add headers from JSON (string pairs)
-    for (k, v) in req.headers {
-        builder = builder.header(k, v);
+    // Cookies from the key's jar, after those sent by the caller
+    let jar_name = crate::cookies::jar_name(&policy.name, key);
+    let mut jar = match (&policy.cookies, &dest_url) {
+        (Some(_), Some(_)) => match crate::cookies::load(&*state.limiter, &jar_name).await {
+            Ok(jar) => Some(jar),
+            Err(e) => {
+                logging::warn("cookies", format_args!("Failed to load cookies of key {}: {:#}", key, e));
+                None
+            },
+        },
+        _ => None,
+    };
+    if let (Some(jar), Some(url)) = (&jar, &dest_url) {
+        let sent = req_headers.keys().find(|k| k.eq_ignore_ascii_case("cookie")).cloned().and_then(|k| req_headers.remove(&k));
+        let sent = sent.map(|v| v.values().join("; "));
+        if let Some(cookie) = crate::cookies::header(jar, url, sent.as_deref()) {
+            req_headers.insert("cookie".to_string(), MultiValue::One(cookie));
+        }
+    }
+    for (k, v) in req_headers {
+        for value in v.values() {
+            builder = builder.header(&k, value);
+        }
     }
 
-    // Pass through Accept if provided by caller as a header
-    if let Some(acc) = headers.get("accept").and_then(|h| h.to_str().ok()) {
-        builder = builder.header("accept", acc);
+    // Pass through Accept, byte ranges and preconditions if provided by caller as a header
+    for name in &CALLER_HEADERS {
+        if let Some(value) = headers.get(name) {
+            builder = builder.header(name, value);
+        }
     }
 
     // Timeout
@@ -79,132 +921,892 @@ pub async fn proxy(
     }
 
     // Body
-    let downstream = match match req.body {
-        Some(b) => builder.json(&b).send().await,
-        None => builder.send().await,
-    } {
-        Ok(r) => r,
-        Err(e) => {
-            return (
-                StatusCode::BAD_GATEWAY,
-                Json(json!({"error":"downstream_error","message": e.to_string()})),
-            )
-                .into_response();
+    if let Some(b) = &req.body {
+        builder = builder.json(b);
+    } else if let Some(raw) = req.raw_body.take() {
+        builder = builder.body(raw);
+    }
+
+    let (started_at, started) = (std::time::SystemTime::now(), std::time::Instant::now());
+    let downstream = send_hedged(state, policy, builder).await;
+    let trace_id = ctx.trace.as_ref().filter(|t| t.sampled).map(|t| t.trace_id.as_str());
+    state.metrics.record_latency(&policy.name, started.elapsed(), trace_id, state.clock.now_ms());
+    if let (Some((tracer, trace)), Some(span_id)) = (tracing, &call_span) {
+        let mut tags = vec![("http.method", method.clone()), ("http.url", dest_url.as_ref().map_or(req.url.clone(), |u| u.to_string()))];
+        match &downstream {
+            Ok(response) => tags.push(("http.status_code", response.status().as_u16().to_string())),
+            Err(response) => tags.push(("error", format!("request failed with {}", response.status()))),
         }
-    };
+        tracer.record(Span {
+            trace,
+            id: span_id,
+            parent_id: Some(&trace.span_id),
+            kind: "CLIENT",
+            name: &method,
+            started: started_at,
+            duration: started.elapsed(),
+            tags,
+        });
+    }
+    if let (Some(slo), Some(host)) = (&state.slo, host) {
+        let ok = downstream.as_ref().is_ok_and(|r| !r.status().is_server_error());
+        slo.record(host, started.elapsed(), ok, state.clock.now_ms());
+    }
+    let downstream = downstream?;
+    if let (Some(config), Some(jar)) = (&policy.cookies, &mut jar)
+        && let Err(e) = crate::cookies::store(&*state.limiter, config, &jar_name, jar, downstream.url(), downstream.headers()).await
+    {
+        logging::warn("cookies", format_args!("Failed to store cookies of key {}: {:#}", key, e));
+    }
 
     let status = StatusCode::from_u16(downstream.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
     let mut resp_headers = HeaderMap::new();
     for (name, value) in downstream.headers().iter() {
-        // pass through limited safe headers
-        if name == CONTENT_TYPE || name == CONTENT_LENGTH || name == CACHE_CONTROL {
-            resp_headers.insert(name.clone(), value.clone());
+        // pass through limited safe headers and those the policy asks for
+        if SAFE_RESPONSE_HEADERS.contains(name) || policy.response_headers.iter().any(|h| h.eq_ignore_ascii_case(name.as_str())) {
+            resp_headers.append(name.clone(), value.clone());
         }
     }
-    let bytes = match downstream.bytes().await {
-        Ok(b) => b,
+    // Partial content is usually part of a large file, so it is streamed
+    if status == StatusCode::PARTIAL_CONTENT {
+        return Ok(DownstreamResponse {
+            status,
+            headers: resp_headers,
+            body: Bytes::new(),
+            // Keeps the body's frames, and with them the trailers
+            stream: Some(axum::body::Body::new(reqwest::Body::from(downstream))),
+            trailers: None,
+        });
+    }
+    let collected = match reqwest::Body::from(downstream).collect().await {
+        Ok(collected) => collected,
         Err(e) => {
-            return (
+            return Err((
                 StatusCode::BAD_GATEWAY,
                 Json(json!({"error":"downstream_read_error","message": e.to_string()})),
             )
-                .into_response();
+                .into_response());
         }
     };
 
-    (status, resp_headers, bytes).into_response()
+    Ok(DownstreamResponse {
+        status,
+        trailers: collected.trailers().cloned(),
+        headers: resp_headers,
+        body: collected.to_bytes(),
+        stream: None,
+    })
+}
+
+/// Re-issues a recorded request, to `upstream`'s scheme, host and port
+/// instead of the recorded ones if given, and returns the downstream's
+/// status. Redacted headers are left out, and the policy's credentials are
+/// only injected for the recorded upstream.
+pub(crate) async fn replay(state: &AppState, recording: &Recording, upstream: Option<&reqwest::Url>) -> Result<StatusCode, String> {
+    let mut url = reqwest::Url::parse(&recording.url).map_err(|e| format!("invalid url: {}", e))?;
+    merge_query(&mut url, &recording.query);
+    if let Some(upstream) = upstream {
+        url.set_scheme(upstream.scheme()).map_err(|_| "invalid upstream scheme".to_string())?;
+        url.set_host(upstream.host_str()).map_err(|e| format!("invalid upstream host: {}", e))?;
+        url.set_port(upstream.port()).map_err(|_| "invalid upstream port".to_string())?;
+    }
+    let method = Method::from_bytes(recording.method.to_uppercase().as_bytes()).map_err(|_| format!("invalid method '{}'", recording.method))?;
+    let policies = state.policies.load();
+    let policy = policies.get(&recording.policy).unwrap_or_else(|| policies.resolve(url.host_str()));
+
+    let mut req_headers = recording.headers.clone();
+    req_headers.retain(|_, v| v.values().iter().all(|v| v != REDACTED));
+    let template_ctx = TemplateContext {
+        key: &recording.key,
+        method: method.as_str(),
+        host: url.host_str().unwrap_or_default(),
+        path: url.path(),
+        policy: &policy.name,
+    };
+    crate::headers::apply(&policy.headers, &mut req_headers, &template_ctx);
+    let client = state.upstream_clients.get(&state.client_profiles, policy, url.host_str()).map_err(|e| format!("{:#}", e))?;
+    let mut builder = client.request(method, url);
+    for (k, v) in req_headers {
+        for value in v.values() {
+            builder = builder.header(&k, value);
+        }
+    }
+    if let Some(body) = &recording.body {
+        builder = builder.json(body);
+    }
+    let response = match upstream {
+        Some(_) => builder.send().await.map_err(|e| e.to_string())?,
+        None => send(state, policy, builder).await.map_err(|r| format!("request failed with {}", r.status()))?,
+    };
+    Ok(StatusCode::from_u16(response.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY))
+}
+
+/// Sends the downstream request, hedging it as the policy's `hedge` says: if
+/// it has not been answered once most recent requests were, a copy goes to
+/// the alternate upstream and the first successful response is returned. The
+/// other request is cancelled.
+async fn send_hedged(state: &AppState, policy: &Policy, builder: reqwest::RequestBuilder) -> Result<reqwest::Response, Response> {
+    let Some(hedge) = &policy.hedge else {
+        return send(state, policy, builder).await;
+    };
+    let (client, request) = builder.build_split();
+    let request = request.map_err(|e| {
+        (
+            StatusCode::BAD_GATEWAY,
+            Json(json!({"error":"downstream_error","message": e.to_string()})),
+        )
+            .into_response()
+    })?;
+    // Streaming bodies cannot be sent twice
+    let alternate = request.try_clone().filter(|r| hedge.hedges(r.method().as_str())).and_then(|mut r| {
+        *r.url_mut() = hedge.alternate_url(r.url())?;
+        Some(r)
+    });
+    let delay = state.latencies.delay(&policy.name, hedge);
+    let started = std::time::Instant::now();
+    let primary = send(state, policy, reqwest::RequestBuilder::from_parts(client.clone(), request));
+    tokio::pin!(primary);
+
+    let Some(alternate) = alternate else {
+        let response = primary.await;
+        state.latencies.record(&policy.name, started.elapsed());
+        return response;
+    };
+    tokio::select! {
+        response = &mut primary => {
+            state.latencies.record(&policy.name, started.elapsed());
+            return response;
+        },
+        _ = tokio::time::sleep(delay) => {},
+    }
+    let secondary = send(state, policy, reqwest::RequestBuilder::from_parts(client, alternate));
+    tokio::pin!(secondary);
+    // Failures wait for the other request
+    let (response, alternate_won) = tokio::select! {
+        response = &mut primary => match response {
+            Ok(response) => (Ok(response), false),
+            Err(_) => ((&mut secondary).await, true),
+        },
+        response = &mut secondary => match response {
+            Ok(response) => (Ok(response), true),
+            Err(_) => ((&mut primary).await, false),
+        },
+    };
+    state.latencies.record(&policy.name, started.elapsed());
+    state.metrics.record_hedge(&policy.name, alternate_won);
+    response
+}
+
+/// Sends the downstream request, injecting the policy's credentials (overriding
+/// any caller supplied ones). If the upstream rejects refreshable credentials
+/// with 401, they are refreshed and the request is retried once.
+async fn send(state: &AppState, policy: &Policy, builder: reqwest::RequestBuilder) -> Result<reqwest::Response, Response> {
+    let downstream_error = |e: reqwest::Error| {
+        (
+            StatusCode::BAD_GATEWAY,
+            Json(json!({"error":"downstream_error","message": e.to_string()})),
+        )
+            .into_response()
+    };
+    let Some(auth) = &policy.auth else {
+        return builder.send().await.map_err(downstream_error);
+    };
+    let authorize = |builder: reqwest::RequestBuilder| async move {
+        match state.secrets.authorization(auth).await {
+            Ok(value) => {
+                let mut auth_headers = HeaderMap::new();
+                auth_headers.insert(AUTHORIZATION, value.clone());
+                Ok((builder.headers(auth_headers), value))
+            }
+            Err(e) => Err((
+                StatusCode::BAD_GATEWAY,
+                Json(json!({"error":"credentials_unavailable","message": e.to_string()})),
+            )
+                .into_response()),
+        }
+    };
+
+    let retry = builder.try_clone();
+    let (builder, used) = authorize(builder).await?;
+    let response = builder.send().await.map_err(downstream_error)?;
+    if response.status() != reqwest::StatusCode::UNAUTHORIZED {
+        return Ok(response);
+    }
+    match retry {
+        Some(retry) if state.secrets.invalidate(auth, &used).await => {
+            let (retry, _) = authorize(retry).await?;
+            retry.send().await.map_err(downstream_error)
+        }
+        _ => Ok(response),
+    }
 }
 
 impl AppState {
-    pub async fn new(rps: u32, redis_url: &str) -> Result<Self> {
-        let http_client = reqwest::Client::builder()
-            .user_agent("grenze-server-proxy/0.0.0")
-            .build()
-            .expect("failed to build reqwest client");
-
-        let client = redis::Client::open(redis_url).expect("invalid redis url");
-        let conn = {
-            let mut attempt: u32 = 0;
-            loop {
-                attempt += 1;
-                match client.get_multiplexed_tokio_connection().await {
-                    Ok(c) => break c,
-                    Err(_e) if attempt < 30 => {
-                        tokio::time::sleep(Duration::from_millis(200 * attempt as u64)).await;
-                    }
-                    Err(e) => return Err(e.into()),
-                }
+    /// Builds the state around the configured bucket store. Buckets hold and
+    /// leak `rps` requests unless the limiter config sizes them. `redis_url`
+    /// is only needed for the Redis store.
+    pub async fn new(rps: u32, redis_url: Option<&str>, config: &Config) -> Result<Self> {
+        let state = Self::connect(rps, redis_url, config).await?;
+        if let Some(etcd) = &config.etcd {
+            etcd::watch(etcd, config, state.policies.clone()).await?;
+        }
+        Ok(state)
+    }
+
+    async fn connect(rps: u32, redis_url: Option<&str>, config: &Config) -> Result<Self> {
+        let limiter: Arc<dyn LimiterStore> = match &config.limiter.store {
+            StoreConfig::Redis => return Self::with_redis(rps, redis_url.context("REDIS_URL must be set")?, config).await,
+            StoreConfig::Memcached(c) => Arc::new(MemcachedStore::connect(c).await?),
+            StoreConfig::Dynamodb(c) => Arc::new(DynamoDbStore::connect(c).await?),
+            StoreConfig::Postgres(c) => {
+                let store = Arc::new(PostgresStore::connect(c).await?);
+                store.spawn_gc();
+                store
+            },
+            StoreConfig::RedisShards(c) => {
+                let store = Arc::new(ShardedStore::connect(c, &config.redis).await?);
+                store.spawn_probe();
+                store
+            },
+        };
+        Self::with_limiter(rps, limiter, Arc::new(SystemClock), config)
+    }
+
+    async fn with_redis(rps: u32, redis_url: &str, config: &Config) -> Result<Self> {
+        let limiter = RedisStore::connect(redis_url, &config.redis).await?;
+        let client = limiter.client().clone();
+        let clock: Arc<dyn Clock> = match config.limiter.clock {
+            ClockSource::System => Arc::new(SystemClock),
+            ClockSource::Redis => Arc::new(limiter.clock(Duration::from_secs(config.limiter.clock_sync_secs.max(1))).await?),
+        };
+        let limiter: Arc<dyn LimiterStore> = match &config.limiter.replication {
+            Some(replication) => {
+                let store = Arc::new(ReplicatedStore::connect(Arc::new(limiter), replication).await?);
+                store.spawn_sync(clock.clone());
+                println!("Reconciling buckets with the global budget every {}ms", replication.sync_interval_ms);
+                store
+            },
+            None => Arc::new(limiter),
+        };
+        let mut state = Self::with_limiter(rps, limiter, clock, config)?;
+        if let Some(recording) = &config.recording
+            && matches!(recording.sink, RecordingSink::Redis { .. })
+        {
+            state.recorder = Some(Arc::new(Recorder::start(recording, Some(client.clone()))?));
+        }
+        if let Some(key_rules) = &config.key_rules {
+            state.rules = Some(Arc::new(KeyRules::start(client.clone(), key_rules, config.redis.prefix())));
+        }
+        if let Some(expiry_events) = &config.limiter.expiry_events {
+            expiry::watch(client, expiry_events, config.redis.prefix(), state.metrics.clone(), state.clock.clone()).await?;
+            println!("Counting bucket expirations from Redis keyspace notifications");
+        }
+        Ok(state)
+    }
+
+    /// Builds the state around the given bucket store and clock instead of
+    /// Redis.
+    pub fn with_limiter(rps: u32, limiter: Arc<dyn LimiterStore>, clock: Arc<dyn Clock>, config: &Config) -> Result<Self> {
+        let breaker = Arc::new(CircuitBreaker::new(&config.limiter.breaker));
+        breaker.spawn_probe(limiter.clone());
+        let limiter: Arc<dyn LimiterStore> = Arc::new(BreakerStore::new(limiter, breaker.clone()));
+        let key_template = KeyTemplate::compile(&config.key)?;
+        let policies = PolicySet::new(config.policies.clone())?;
+
+        let http_client = upstream_tls::builder().build().expect("failed to build reqwest client");
+        let secrets = SecretStore::new(http_client.clone(), config.secrets.clone());
+        let client_profiles = Arc::new(ClientProfiles::new(http_client.clone(), &config.client_profiles)?);
+
+        #[cfg(feature = "wasm")]
+        let plugins = {
+            let plugins = Arc::new(PluginManager::new(&config.plugins)?);
+            if let Some(secs) = config.plugins.reload_interval_secs {
+                plugins.spawn_reloader(Duration::from_secs(secs));
+            }
+            plugins
+        };
+        let mut middleware = MiddlewareRegistry::default();
+        if let Some(chaos) = &config.chaos {
+            println!("Chaos mode enabled: injecting faults for matching requests");
+            middleware.register(Arc::new(chaos.clone()));
+        }
+        middleware.register(Arc::new(config.transforms.clone()));
+        #[cfg(feature = "wasm")]
+        middleware.register(plugins.clone());
+
+        let mut metrics = Metrics::default();
+        if config.limiter.expiry_events.is_some() {
+            metrics = metrics.tracking_inactive();
+        }
+        let metrics = Arc::new(metrics);
+        let statsd = match &config.statsd {
+            Some(c) => {
+                let statsd = Arc::new(StatsdExporter::new(c)?);
+                statsd.spawn(metrics.clone(), clock.clone());
+                Some(statsd)
+            }
+            None => None,
+        };
+        let slo = config.slo.as_ref().map(|c| {
+            let slo = Arc::new(SloTracker::new(c));
+            slo.spawn_alerts(http_client.clone(), clock.clone());
+            slo
+        });
+        let anomalies = config.anomalies.as_ref().map(|c| {
+            let anomalies = Arc::new(AnomalyDetector::new(c));
+            anomalies.spawn_evaluation(http_client.clone(), clock.clone());
+            anomalies
+        });
+
+        let scripts = match &config.script {
+            Some(c) => {
+                let scripts = Arc::new(Scripts::new(c)?);
+                scripts.spawn_reloader();
+                Some(scripts)
             }
+            None => None,
+        };
+        let geoip = match &config.geoip {
+            Some(c) => {
+                let geoip = Arc::new(GeoIp::new(c)?);
+                geoip.spawn_reloader();
+                Some(geoip)
+            },
+            None => None,
         };
 
+        // A bucket has drained once it leaked its capacity
+        let capacity = config.limiter.capacity.unwrap_or(rps);
+        let leak_per_sec = config.limiter.leak_per_sec.unwrap_or(rps as f64);
+        let keys = config.limiter.cardinality.as_ref().map(|c| {
+            let keys = Arc::new(KeyTracker::new(c, Duration::from_secs_f64(capacity as f64 / leak_per_sec)));
+            keys.spawn_gc(clock.clone());
+            keys
+        });
+
+        let scheduler = Arc::new(Scheduler::new(limiter.clone(), &config.leader));
+        let usage = config.usage.as_ref().map(|c| {
+            let usage = Arc::new(UsageTracker::new(c));
+            usage.spawn(limiter.clone(), &scheduler, clock.clone());
+            usage
+        });
+        let billing = match (&config.billing, &config.usage) {
+            (Some(c), Some(usage)) => {
+                let billing = Arc::new(Billing::new(c, http_client.clone()));
+                billing.spawn_reports(limiter.clone(), &scheduler, clock.clone(), usage.settle());
+                Some(billing)
+            },
+            _ => None,
+        };
+
+        let tracer = config.tracing.as_ref().map(|c| Arc::new(Tracer::start(c, http_client.clone())));
+        if let Some(logging) = &config.logging {
+            logging.install();
+        }
+
         Ok(Self {
             http_client,
-            redis: Arc::new(Mutex::new(conn)),
-            capacity: rps,
-            leak_per_sec: rps as f64,
+            limiter,
+            breaker,
+            on_error: config.limiter.on_error,
+            clock,
+            metrics,
+            decisions: broadcast::channel(DECISION_BUFFER).0,
+            statsd,
+            slo,
+            anomalies,
+            usage,
+            billing,
+            capacity,
+            leak_per_sec,
+            key_sizes: Arc::new(config.limiter.keys.clone()),
+            key_template: Arc::new(key_template),
+            policies: Arc::new(Policies::new(policies)),
+            tls: config.tls.clone().map(Arc::new),
+            signing: config.signing.clone().map(Arc::new),
+            secrets: Arc::new(secrets),
+            middleware: Arc::new(middleware),
+            #[cfg(feature = "wasm")]
+            plugins,
+            admin: config.admin.clone().map(Arc::new),
+            reservations: config.reservations.clone().map(Arc::new),
+            passthrough: config.passthrough.clone().map(Arc::new),
+            sidecar: config.sidecar.as_ref().map(|c| c.resolve()).transpose()?.map(Arc::new),
+            overrides: config.overrides.clone().map(Arc::new),
+            geoip,
+            classification: config.classification.clone().map(Arc::new),
+            destinations: Arc::new(config.destinations.clone()),
+            rejections: config.rejections.clone().map(Arc::new),
+            recorder: match &config.recording {
+                Some(c) if matches!(c.sink, RecordingSink::File { .. }) => Some(Arc::new(Recorder::start(c, None)?)),
+                _ => None,
+            },
+            rules: None,
+            tracer,
+            request_log: config.logging.as_ref().map(|c| c.requests.clone()),
+            scripts,
+            keys,
+            scheduler,
+            latencies: Arc::new(Latencies::default()),
+            client_profiles,
+            upstream_clients: Arc::new(UpstreamClients::default()),
+            active_keys: Arc::new(ActiveKeys::default()),
         })
     }
 
-    pub async fn allow(&self, key: &str) -> bool {
-        let bucket_key = format!("rl:{}", key);
-        let now_ms = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .map(|d| d.as_millis() as i64)
-            .unwrap_or(0);
-        let ttl_secs: i64 = ((self.capacity as f64) / self.leak_per_sec).ceil() as i64 + 1;
-
-        // Redis Lua script implementing a leaky bucket
-        // Returns 1 if allowed and increments the bucket, 0 otherwise
-        const LUA: &str = r#"
-local base = KEYS[1]
-local fill_key = base .. ":fill"
-local ts_key = base .. ":ts"
-
-local capacity = tonumber(ARGV[1])
-local leak_per_sec = tonumber(ARGV[2])
-local now_ms = tonumber(ARGV[3])
-local ttl = tonumber(ARGV[4])
-
-local fill = tonumber(redis.call('GET', fill_key) or '0')
-local last = tonumber(redis.call('GET', ts_key) or now_ms)
-local elapsed_ms = now_ms - last
-if elapsed_ms < 0 then elapsed_ms = 0 end
-
-local leaked = (elapsed_ms / 1000.0) * leak_per_sec
-fill = fill - leaked
-if fill < 0 then fill = 0 end
-
-if (fill + 1) > capacity then
-  -- Update timestamp to avoid burst after long idle and set TTLs
-  redis.call('SET', ts_key, now_ms)
-  redis.call('EXPIRE', ts_key, ttl)
-  redis.call('SET', fill_key, tostring(fill))
-  redis.call('EXPIRE', fill_key, ttl)
-  return 0
-end
-
-fill = fill + 1
-redis.call('SET', fill_key, tostring(fill))
-redis.call('EXPIRE', fill_key, ttl)
-redis.call('SET', ts_key, now_ms)
-redis.call('EXPIRE', ts_key, ttl)
-return 1
-"#;
-
-        let script = Script::new(LUA);
-        let mut conn = self.redis.lock().await;
-        match script
-            .key(bucket_key)
-            .arg(self.capacity as i64)
-            .arg(self.leak_per_sec)
-            .arg(now_ms)
-            .arg(ttl_secs)
-            .invoke_async::<i64>(&mut *conn)
-            .await
+    /// Trace context of a request: as the tracing configuration says, else
+    /// the caller's W3C context for metric exemplars.
+    pub fn trace(&self, headers: &HeaderMap) -> Option<TraceContext> {
+        match &self.tracer {
+            Some(tracer) => tracer.extract(headers),
+            None => crate::trace::caller_context(headers),
+        }
+    }
+
+    /// Appends middleware behind the built-in ones.
+    pub fn with_middleware(mut self, middleware: impl ProxyMiddleware + 'static) -> Self {
+        Arc::make_mut(&mut self.middleware).register(Arc::new(middleware));
+        self
+    }
+
+    /// Records a used request signature for as long as it is valid. Returns
+    /// false if the signature was seen before (or the store is unavailable).
+    pub async fn remember_signature(&self, signature: &str, ttl_secs: u64) -> bool {
+        matches!(self.limiter.remember(&format!("sig:{}", signature), ttl_secs).await, Ok(true))
+    }
+
+    /// Checks `bucket` against its tenant's cap on distinct buckets, evicting
+    /// another bucket of the tenant from the store if configured.
+    pub async fn admit_key(&self, ctx: &Context<'_>, bucket: &str) -> bool {
+        let Some(keys) = &self.keys else {
+            return true;
+        };
+        match keys.admit(&ctx.key, bucket, self.clock.now_ms()) {
+            KeyAdmission::Admitted => true,
+            KeyAdmission::Evicted(evicted) => {
+                self.limiter.evict(&evicted).await;
+                true
+            },
+            KeyAdmission::Rejected => false,
+        }
+    }
+
+    /// Buckets a request drawing from `bucket` under `policy` must fit into:
+    /// its own, followed by those of the policy's limits. Its own bucket is
+    /// sized by the key's override, else the policy's, else the limiter's.
+    /// Under a sliding log, its own is the log, its capacity the log's limit
+    /// and its leak rate the limit spread over the window. Under windows, the
+    /// first sizes its own and the others follow the policy's limits.
+    pub fn limits(&self, policy: &Policy, bucket: String, key: &str, authority: Option<&str>) -> Vec<BucketLimit> {
+        let size = self.key_sizes.get(key);
+        let windows: Vec<BucketLimit> = policy
+            .windows
+            .iter()
+            .skip(1)
+            .map(|window| BucketLimit {
+                bucket: format!("{}#{}s", bucket, window.window_secs),
+                capacity: window.limit,
+                leak_per_sec: window.leak_per_sec(),
+            })
+            .collect();
+        let own = match (&policy.sliding_log, policy.windows.first()) {
+            (_, Some(window)) => BucketLimit {
+                bucket,
+                capacity: size.and_then(|s| s.capacity).unwrap_or(window.limit),
+                leak_per_sec: size.and_then(|s| s.leak_per_sec).unwrap_or(window.leak_per_sec()),
+            },
+            (Some(log), None) => {
+                let capacity = size.and_then(|s| s.capacity).unwrap_or(log.limit);
+                BucketLimit {
+                    bucket,
+                    capacity,
+                    leak_per_sec: capacity as f64 / log.window_secs as f64,
+                }
+            },
+            (None, None) => BucketLimit {
+                bucket,
+                capacity: size.and_then(|s| s.capacity).or(policy.capacity).unwrap_or(self.capacity),
+                leak_per_sec: size.and_then(|s| s.leak_per_sec).or(policy.leak_per_sec).unwrap_or(self.leak_per_sec),
+            },
+        };
+        let mut limits = vec![own];
+        limits.extend(policy.limit_buckets(key, authority).into_iter().map(|(bucket, limit)| BucketLimit {
+            bucket,
+            capacity: limit.capacity.unwrap_or(self.capacity),
+            leak_per_sec: limit.leak_per_sec.unwrap_or(self.leak_per_sec),
+        }));
+        limits.extend(windows);
+        limits
+    }
+
+    /// Appends to `limits` the buckets tracking the consumption of `key` in
+    /// the policy's limits with fair queuing, sized to its fair share while
+    /// the shared bucket is contended and to all of it otherwise. Buckets
+    /// whose fill cannot be read count as uncontended.
+    pub async fn share(&self, policy: &Policy, key: &str, authority: Option<&str>, limits: &mut Vec<BucketLimit>) {
+        let now_ms = self.clock.now_ms();
+        let shared: Vec<_> = policy.limit_buckets(key, authority).into_iter().zip(limits.iter().skip(1)).collect();
+        let mut shares = Vec::new();
+        for ((_, limit), shared) in shared {
+            let Some(fair) = &limit.fair else {
+                continue;
+            };
+            let active = self.active_keys.touch(&shared.bucket, key, now_ms, fair);
+            let contended = active > 1
+                && match self.limiter.level(&shared.bucket, shared.leak_per_sec, now_ms).await {
+                    Ok(fill) => fill >= shared.capacity as f64 * fair.contention,
+                    Err(e) => {
+                        logging::warn("fairness", format_args!("Failed to read the fill of bucket {}: {:#}", shared.bucket, e));
+                        false
+                    },
+                };
+            shares.push(fair.share(shared, key, if contended { active } else { 1 }));
+        }
+        limits.extend(shares);
+    }
+
+    /// Rule written for `key` through the admin API. Rules that cannot be
+    /// read are ignored rather than failing requests.
+    pub async fn key_rule(&self, key: &str) -> Option<Arc<KeyRule>> {
+        let rules = self.rules.as_ref()?;
+        match rules.get(key, self.clock.now_ms()).await {
+            Ok(rule) => rule,
+            Err(e) => {
+                logging::warn("key_rules", format_args!("Failed to read the rule of key {}: {:#}", key, e));
+                None
+            },
+        }
+    }
+
+    /// Applies the rule written for `key`: rejects the request if the key is
+    /// banned, else resizes its own bucket as the rule says.
+    pub async fn apply_rule(&self, key: &str, limit: &mut BucketLimit) -> Result<(), ApiError> {
+        let Some(rule) = self.key_rule(key).await else {
+            return Ok(());
+        };
+        if rule.banned {
+            self.metrics.record_rejection(Reason::Banned);
+            let message = rule.reason.clone().unwrap_or_else(|| Reason::Banned.message().to_string());
+            return Err(ApiError::new(StatusCode::FORBIDDEN, Reason::Banned.code(), message));
+        }
+        rule.apply(limit);
+        Ok(())
+    }
+
+    /// Applies the first geo rule matching the client's region: blocks the
+    /// request, or resizes its own bucket or moves it into one of its own for
+    /// the region.
+    pub fn locate(&self, ctx: &Context<'_>, headers: &HeaderMap, limit: &mut BucketLimit) -> Result<(), ApiError> {
+        let Some(geoip) = &self.geoip else {
+            return Ok(());
+        };
+        let key_ctx = KeyContext {
+            key: &ctx.key,
+            peer: Some(ctx.peer.ip()),
+            identity: None,
+            headers,
+        };
+        let Some((rule, region)) = self.key_template.client_ip(&key_ctx).and_then(|ip| geoip.rule(ip, &ctx.policy.name)) else {
+            return Ok(());
+        };
+        if rule.block {
+            return Err(ApiError::new(StatusCode::FORBIDDEN, "geo_blocked", "Requests from this region are not allowed"));
+        }
+        if rule.separate_bucket {
+            limit.bucket = format!("{}@geo:{}", limit.bucket, region.label(rule));
+        }
+        limit.capacity = rule.capacity.unwrap_or(limit.capacity);
+        limit.leak_per_sec = rule.leak_per_sec.unwrap_or(limit.leak_per_sec);
+        Ok(())
+    }
+
+    /// Shrinks the key's own bucket while the key is new under the policy's
+    /// `warmup`. Keeps the full size if the store cannot tell the key's age.
+    pub async fn warm_up(&self, policy: &Policy, limit: &mut BucketLimit) {
+        let Some(warmup) = &policy.warmup else {
+            return;
+        };
+        let now_ms = self.clock.now_ms();
+        let Ok(first_ms) = self.limiter.first_seen(&format!("seen:{}", limit.bucket), now_ms, warmup.forget_after_secs).await else {
+            return;
+        };
+        let factor = warmup.factor(now_ms - first_ms);
+        limit.capacity = ((limit.capacity as f64 * factor).ceil() as u32).max(1);
+        limit.leak_per_sec *= factor;
+    }
+
+    /// Adds `tokens` to every bucket of `limits` in the background, after the
+    /// request they are charged for was admitted.
+    pub fn charge(&self, limits: &[BucketLimit], tokens: f64) {
+        if tokens <= 0.0 {
+            return;
+        }
+        let (limiter, limits, now_ms) = (self.limiter.clone(), limits.to_vec(), self.clock.now_ms());
+        tokio::spawn(async move {
+            for limit in limits {
+                if let Err(e) = limiter.charge(&limit.bucket, tokens, limit.capacity, limit.leak_per_sec, now_ms).await {
+                    logging::warn("charge", format_args!("Failed to charge {} tokens to bucket {}: {:#}", tokens, limit.bucket, e));
+                }
+            }
+        });
+    }
+
+    /// Adds the `bytes` of a response to the key's quota in the background,
+    /// if its plan enforces an allowance of bytes.
+    pub fn charge_quota(&self, key: &str, bytes: u64) {
+        let Some(quota) = self.billing.as_ref().and_then(|b| b.quota(key, self.clock.now_ms())) else {
+            return;
+        };
+        if quota.included_bytes.is_none() || bytes == 0 {
+            return;
+        }
+        let limiter = self.limiter.clone();
+        tokio::spawn(async move {
+            if let Err(e) = limiter.charge_quota(&quota.counter, bytes, quota.until_ms).await {
+                logging::warn("charge", format_args!("Failed to charge {} bytes to quota {}: {:#}", bytes, quota.counter, e));
+            }
+        });
+    }
+
+    /// Admits a request costing `cost` tokens into all of `limits` or none,
+    /// and records the outcome under the first. A request fitting into them
+    /// then draws from the key's sliding log and the allowance of the key's
+    /// plan, if the policy and plan enforce them, and gets its tokens back if
+    /// either rejects it. Requests are answered
+    /// according to `on_error` while the store fails.
+    pub async fn allow(&self, ctx: &Context<'_>, limits: &[BucketLimit], host: Option<&str>, cost: f64) -> Verdict {
+        let now_ms = self.clock.now_ms();
+        let result = match buckets(ctx, limits) {
+            [] => Ok(Vec::new()),
+            [limit] => self.limiter.allow(&limit.bucket, cost, limit.capacity, limit.leak_per_sec, now_ms).await.map(|admission| vec![admission]),
+            buckets => self.limiter.allow_all(buckets, cost, now_ms).await,
+        };
+        let verdict = self.settle(ctx, limits, host, cost, result.ok(), now_ms).await;
+        if let Some(reason) = verdict.reason(ctx.policy, limits) {
+            self.metrics.record_rejection(reason);
+        }
+        verdict
+    }
+
+    /// Admits a batch of independent requests as `allow` would each, taking
+    /// their tokens in one round trip to the store.
+    pub async fn allow_batch(&self, requests: &[(&Context<'_>, &[BucketLimit], Option<&str>, f64)]) -> Vec<Verdict> {
+        let now_ms = self.clock.now_ms();
+        let batch: Vec<(&[BucketLimit], f64)> = requests.iter().map(|(ctx, limits, _, cost)| (buckets(ctx, limits), *cost)).collect();
+        let admissions: Vec<Option<Vec<Admission>>> = match self.limiter.allow_batch(&batch, now_ms).await {
+            Ok(outcomes) => outcomes.into_iter().map(Some).collect(),
+            Err(_) => vec![None; requests.len()],
+        };
+        let mut verdicts = Vec::with_capacity(requests.len());
+        for ((ctx, limits, host, cost), admission) in requests.iter().zip(admissions) {
+            let verdict = self.settle(ctx, limits, *host, *cost, admission, now_ms).await;
+            if let Some(reason) = verdict.reason(ctx.policy, limits) {
+                self.metrics.record_rejection(reason);
+            }
+            verdicts.push(verdict);
+        }
+        verdicts
+    }
+
+    /// Turns the store's admissions of a request into its buckets, `None` if
+    /// the store failed, into a verdict, drawing from the plan's allowance,
+    /// and records it.
+    async fn settle(&self, ctx: &Context<'_>, limits: &[BucketLimit], host: Option<&str>, cost: f64, admissions: Option<Vec<Admission>>, now_ms: i64) -> Verdict {
+        let failed = match self.on_error {
+            FailureMode::FailOpen => Verdict::Allowed { fill: None },
+            FailureMode::FailClosed => Verdict::Unavailable { circuit_open: self.breaker.is_open() },
+        };
+        let Some(admissions) = admissions else {
+            return failed;
+        };
+        let mut admission = combine(&admissions);
+        let fill = ctx.policy.sliding_log.is_none().then_some(admission.fill);
+        let mut verdict = match admission.allowed {
+            true => Verdict::Allowed { fill },
+            false => constrained(buckets(ctx, limits), &admissions, cost, limits.len() - admissions.len()),
+        };
+        let log = ctx.policy.sliding_log.as_ref().map(|log| LogLimit {
+            log: limits[0].bucket.clone(),
+            limit: limits[0].capacity,
+            window_ms: (log.window_secs * 1000) as i64,
+        });
+        if admission.allowed
+            && let Some(log) = &log
         {
-            Ok(1) => true,
-            Ok(_) => false,
-            Err(_) => false,
+            let Ok(logged) = self.limiter.allow_log(log, cost.ceil() as u32, now_ms).await else {
+                return failed;
+            };
+            if !logged.allowed {
+                self.refund(buckets(ctx, limits), cost, now_ms).await;
+                verdict = Verdict::LogFull { until_ms: logged.until_ms };
+            }
+            admission = Admission {
+                allowed: logged.allowed,
+                fill: logged.taken as f64,
+            };
+        }
+        if admission.allowed
+            && let Some(quota) = self.billing.as_ref().and_then(|b| b.quota(&ctx.key, now_ms))
+        {
+            verdict = match self.limiter.allow_quota(&quota, cost, now_ms).await {
+                Ok(QuotaAdmission { allowed: true, overage: false }) => Verdict::Allowed { fill },
+                Ok(QuotaAdmission { allowed: true, overage: true }) => Verdict::Overage { fill },
+                Ok(QuotaAdmission { allowed: false, .. }) => {
+                    self.refund(buckets(ctx, limits), cost, now_ms).await;
+                    if let Some(log) = &log
+                        && let Err(e) = self.limiter.release_log(log, cost.ceil() as u32, now_ms).await
+                    {
+                        logging::warn("refund", format_args!("Failed to refund {} tokens to log {}: {:#}", cost, log.log, e));
+                    }
+                    Verdict::QuotaExceeded { until_ms: quota.until_ms }
+                },
+                Err(_) => return failed,
+            };
+            admission.allowed = verdict.allowed();
+        }
+        let trace_id = ctx.trace.as_ref().filter(|t| t.sampled).map(|t| t.trace_id.as_str());
+        self.metrics.record_admission(&ctx.policy.name, &limits[0], admission, trace_id, now_ms);
+        if let Some(usage) = &self.usage {
+            usage.record(&ctx.key, &ctx.policy.name, admission.allowed, now_ms);
+        }
+        if self.decisions.receiver_count() > 0 {
+            let _ = self.decisions.send(Decision {
+                at_ms: now_ms,
+                key: ctx.key.clone(),
+                policy: ctx.policy.name.clone(),
+                bucket: limits[0].bucket.clone(),
+                allowed: admission.allowed,
+                fill: admission.fill,
+                capacity: limits[0].capacity,
+            });
+        }
+        if let Some(statsd) = &self.statsd {
+            statsd.record_request(&ctx.policy.name, &ctx.key, host, admission.allowed);
+        }
+        if let Some(anomalies) = &self.anomalies {
+            anomalies.record(&ctx.key, host, admission.allowed);
+        }
+        verdict
+    }
+
+    /// Gives back the tokens a request took from `limits` before a later
+    /// check rejected it, so that they are taken from all or none.
+    async fn refund(&self, limits: &[BucketLimit], cost: f64, now_ms: i64) {
+        for limit in limits {
+            if let Err(e) = self.limiter.charge(&limit.bucket, -cost, limit.capacity, limit.leak_per_sec, now_ms).await {
+                logging::warn("refund", format_args!("Failed to refund {} tokens to bucket {}: {:#}", cost, limit.bucket, e));
+            }
+        }
+    }
+
+    /// Response to a rejected request: the rejection of the key's tenant, else
+    /// the policy's, else the default one, else grenze's JSON error.
+    pub fn reject(&self, ctx: &Context<'_>, fields: &RejectionFields) -> Response {
+        let tenant = match &self.keys {
+            Some(keys) => keys.tenant(&ctx.key),
+            None => ctx.key.split_once(':').map_or(ctx.key.as_str(), |(tenant, _)| tenant),
+        };
+        let rejection = match &self.rejections {
+            Some(rejections) => rejections.resolve(tenant, ctx.policy.rejection.as_ref()),
+            None => ctx.policy.rejection.as_ref(),
+        };
+        let mut response = match rejection {
+            Some(rejection) => rejection.respond(fields),
+            None => {
+                let payload = Json(json!({
+                    "error": fields.error,
+                    "message": fields.message
+                }));
+                let status = Reason::from_code(fields.error).map_or(StatusCode::TOO_MANY_REQUESTS, Reason::status);
+                (status, payload).into_response()
+            },
+        };
+        if let Ok(reason) = HeaderValue::from_str(fields.error) {
+            response.headers_mut().insert(X_REJECTION_REASON, reason);
         }
+        rate_limit_headers(response.headers_mut(), fields.limit, fields.remaining, fields.reset);
+        response.headers_mut().entry(RETRY_AFTER).or_insert(HeaderValue::from(fields.retry_after));
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limiter::MemoryStore;
+
+    struct FixedClock(i64);
+
+    impl Clock for FixedClock {
+        fn now_ms(&self) -> i64 {
+            self.0
+        }
+    }
+
+    fn state(config: &Config) -> (AppState, Arc<MemoryStore>) {
+        let clock: Arc<dyn Clock> = Arc::new(FixedClock(1_800_000_000_000));
+        let limiter = Arc::new(MemoryStore::new(clock.clone()));
+        let state = AppState::with_limiter(1, limiter.clone(), clock, config).unwrap();
+        (state, limiter)
+    }
+
+    fn context(policy: &Policy) -> Context<'_> {
+        Context {
+            peer: SocketAddr::from(([127, 0, 0, 1], 40000)),
+            key: "alice".to_string(),
+            policy,
+            trace: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn full_sliding_logs_leave_shared_buckets_untouched() {
+        let config: Config = serde_json::from_value(json!({
+            "policies": [{
+                "name": "api",
+                "sliding_log": {"limit": 1, "window_secs": 60},
+                "limits": [{"scope": {"type": "global"}, "capacity": 10, "leak_per_sec": 1.0}],
+            }],
+        }))
+        .unwrap();
+        let (state, limiter) = state(&config);
+        let policy = &config.policies[0];
+        let ctx = context(policy);
+        let limits = state.limits(policy, policy.bucket_key(&ctx.key, None), &ctx.key, None);
+
+        assert!(state.allow(&ctx, &limits, None, 1.0).await.allowed());
+        assert!(matches!(state.allow(&ctx, &limits, None, 1.0).await, Verdict::LogFull { .. }));
+        assert_eq!(limiter.fill("api#global").await, Some(1.0));
+    }
+
+    #[tokio::test]
+    async fn quota_rejections_leave_buckets_and_logs_untouched() {
+        let config: Config = serde_json::from_value(json!({
+            "policies": [{
+                "name": "api",
+                "sliding_log": {"limit": 5, "window_secs": 60},
+                "limits": [{"scope": {"type": "global"}, "capacity": 10, "leak_per_sec": 1.0}],
+            }],
+            "usage": {},
+            "billing": {
+                "plans": {"trial": {"included_requests": 1, "overage": {"type": "reject"}}},
+                "default_plan": "trial",
+            },
+        }))
+        .unwrap();
+        let (state, limiter) = state(&config);
+        let policy = &config.policies[0];
+        let ctx = context(policy);
+        let limits = state.limits(policy, policy.bucket_key(&ctx.key, None), &ctx.key, None);
+
+        assert!(state.allow(&ctx, &limits, None, 1.0).await.allowed());
+        assert!(matches!(state.allow(&ctx, &limits, None, 1.0).await, Verdict::QuotaExceeded { .. }));
+        assert_eq!(limiter.fill("api#global").await, Some(1.0));
+        let log = LogLimit {
+            log: limits[0].bucket.clone(),
+            limit: 5,
+            window_ms: 60_000,
+        };
+        assert_eq!(limiter.allow_log(&log, 0, state.clock.now_ms()).await.unwrap().taken, 1);
     }
 }
@@ -0,0 +1,300 @@
+//! Two-phase token reservations for orchestrators running multi-step
+//! workflows: `/reserve` takes tokens from the buckets a request would draw
+//! from up front, `/commit` settles a reservation and returns the tokens it
+//! did not use, and `/release` returns all of them.
+//!
+//! Reservation IDs are signed and carry their buckets, tokens and expiry, so
+//! any instance can settle them. The bucket store remembers settled
+//! reservations to reject settling one twice, and the buckets already given
+//! their tokens back, so that settling again after a store failure only
+//! returns the rest. Tokens of reservations left to expire stay consumed and
+//! leak out of the buckets as usual. Policies with a sliding log do not
+//! support reservations.
+
+use anyhow::{bail, Result};
+use axum::{extract::{ConnectInfo, State}, http::{HeaderMap, StatusCode}, response::{IntoResponse, Response}, Extension, Json, Router};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::net::SocketAddr;
+use utoipa::ToSchema;
+
+use super::{check::take_reservable, proxy::AppState, ApiError};
+use crate::{limiter::BucketLimit, rejection::Reason, tls::ClientIdentity};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReservationsConfig {
+    /// Secret signing reservation IDs; all instances must share it.
+    pub secret: String,
+    /// TTL of reservations that do not ask for one.
+    #[serde(default = "default_ttl_secs")]
+    pub default_ttl_secs: u64,
+    #[serde(default = "default_max_ttl_secs")]
+    pub max_ttl_secs: u64,
+}
+
+fn default_ttl_secs() -> u64 {
+    300
+}
+
+fn default_max_ttl_secs() -> u64 {
+    3_600
+}
+
+impl ReservationsConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.secret.is_empty() {
+            bail!("reservations secret must not be empty");
+        }
+        if self.default_ttl_secs == 0 || self.default_ttl_secs > self.max_ttl_secs {
+            bail!("reservations default_ttl_secs must be between 1 and max_ttl_secs");
+        }
+        Ok(())
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::<Sha256>::new_from_slice(self.secret.as_bytes()).expect("hmac accepts keys of any length")
+    }
+
+    /// Encodes `reservation` as `"{base64(json)}.{hex(hmac)}"`.
+    fn sign(&self, reservation: &Reservation) -> String {
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(reservation).expect("reservations serialize"));
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        format!("{}.{}", payload, hex::encode(mac.finalize().into_bytes()))
+    }
+
+    fn verify(&self, id: &str) -> Option<Reservation> {
+        let (payload, signature) = id.split_once('.')?;
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&hex::decode(signature).ok()?).ok()?;
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()
+    }
+}
+
+/// Routes of the reservation API. Only mounted when reservations are
+/// configured.
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/reserve", axum::routing::post(reserve))
+        .route("/commit", axum::routing::post(commit))
+        .route("/release", axum::routing::post(release))
+}
+
+/// What a reservation ID stands for.
+#[derive(Debug, Serialize, Deserialize)]
+struct Reservation {
+    /// Random, identifies the reservation once settled.
+    id: String,
+    /// Buckets the tokens were taken from.
+    limits: Vec<BucketLimit>,
+    tokens: u32,
+    reserved_ms: i64,
+    expires_ms: i64,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ReserveRequest {
+    /// Rate limit key, derived like the proxy's.
+    #[serde(default)]
+    pub key: String,
+    /// Destination the tokens are meant for, selecting policy and bucket as
+    /// it would for a proxied request.
+    #[serde(default)]
+    pub url: Option<String>,
+    pub tokens: u32,
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct CommitRequest {
+    reservation_id: String,
+    /// Tokens actually used; all reserved tokens if unset.
+    #[serde(default)]
+    tokens_used: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct ReleaseRequest {
+    reservation_id: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct Reserved {
+    pub reservation_id: String,
+    pub tokens: u32,
+    pub expires_at_ms: i64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct Settled {
+    pub committed: u32,
+    pub released: u32,
+}
+
+fn invalid(message: &str) -> ApiError {
+    ApiError::new(StatusCode::BAD_REQUEST, "invalid_reservation", message)
+}
+
+#[utoipa::path(
+    post,
+    path = "/reserve",
+    tag = "decisions",
+    request_body = ReserveRequest,
+    responses(
+        (status = 200, description = "Tokens reserved", body = Reserved),
+        (status = 400, description = "Invalid tokens or TTL, or the policy has a sliding log", body = ApiError),
+        (status = 429, description = "Not enough tokens left", body = ApiError),
+    )
+)]
+async fn reserve(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    identity: Option<Extension<ClientIdentity>>,
+    headers: HeaderMap,
+    Json(req): Json<ReserveRequest>,
+) -> Response {
+    let identity = identity.map(|Extension(id)| id);
+    match reserve_tokens(&state, peer, identity.as_ref(), &headers, &req).await {
+        Ok(reserved) => Json(reserved).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Takes `tokens` from every bucket at once if they fit, answering with a
+/// reservation ID to settle them with.
+pub(crate) async fn reserve_tokens(state: &AppState, peer: SocketAddr, identity: Option<&ClientIdentity>, headers: &HeaderMap, req: &ReserveRequest) -> Result<Reserved, ApiError> {
+    let Some(config) = &state.reservations else {
+        return Err(not_enabled());
+    };
+    let ttl_secs = req.ttl_secs.unwrap_or(config.default_ttl_secs);
+    if ttl_secs == 0 || ttl_secs > config.max_ttl_secs {
+        return Err(invalid(&format!("ttl_secs must be between 1 and {}", config.max_ttl_secs)));
+    }
+    let taken = take_reservable(state, peer, identity, headers, &req.key, req.url.as_deref(), req.tokens).await?;
+    if !taken.allowed {
+        return Err(ApiError::new(StatusCode::TOO_MANY_REQUESTS, Reason::RateLimited.code(), "Not enough tokens left to reserve"));
+    }
+
+    let now_ms = state.clock.now_ms();
+    let reservation = Reservation {
+        id: hex::encode(rand::random::<[u8; 16]>()),
+        limits: taken.limits,
+        tokens: req.tokens,
+        reserved_ms: now_ms,
+        expires_ms: now_ms + ttl_secs as i64 * 1000,
+    };
+    Ok(Reserved {
+        reservation_id: config.sign(&reservation),
+        tokens: reservation.tokens,
+        expires_at_ms: reservation.expires_ms,
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/commit",
+    tag = "decisions",
+    request_body = CommitRequest,
+    responses(
+        (status = 200, description = "Reservation settled", body = Settled),
+        (status = 400, description = "Invalid reservation", body = ApiError),
+        (status = 409, description = "Reservation already settled", body = ApiError),
+        (status = 410, description = "Reservation expired", body = ApiError),
+        (status = 503, description = "Bucket store unavailable; settle again", body = ApiError),
+    )
+)]
+async fn commit(State(state): State<AppState>, Json(req): Json<CommitRequest>) -> Response {
+    match settle(&state, &req.reservation_id, req.tokens_used).await {
+        Ok(settled) => Json(settled).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/release",
+    tag = "decisions",
+    request_body = ReleaseRequest,
+    responses(
+        (status = 200, description = "Reservation settled", body = Settled),
+        (status = 400, description = "Invalid reservation", body = ApiError),
+        (status = 409, description = "Reservation already settled", body = ApiError),
+        (status = 410, description = "Reservation expired", body = ApiError),
+        (status = 503, description = "Bucket store unavailable; settle again", body = ApiError),
+    )
+)]
+async fn release(State(state): State<AppState>, Json(req): Json<ReleaseRequest>) -> Response {
+    match settle(&state, &req.reservation_id, Some(0)).await {
+        Ok(settled) => Json(settled).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Settles a reservation, returning the tokens it did not use; all of them
+/// if `used` is zero, none if it is unset. Only what is left of them after
+/// leaking since the reservation goes back into each bucket.
+pub(crate) async fn settle(state: &AppState, id: &str, used: Option<u32>) -> Result<Settled, ApiError> {
+    let Some(config) = &state.reservations else {
+        return Err(not_enabled());
+    };
+    let Some(reservation) = config.verify(id) else {
+        return Err(invalid("Reservation ID is invalid"));
+    };
+    let used = used.unwrap_or(reservation.tokens);
+    if used > reservation.tokens {
+        return Err(invalid("tokens_used exceeds the reserved tokens"));
+    }
+    let now_ms = state.clock.now_ms();
+    if now_ms >= reservation.expires_ms {
+        return Err(ApiError::new(StatusCode::GONE, "reservation_expired", "Reservation has expired; its tokens stay consumed"));
+    }
+    let ttl_secs = ((reservation.expires_ms - now_ms) as u64).div_ceil(1000);
+    let settled = format!("rsv:{}", reservation.id);
+    match state.limiter.remember(&settled, ttl_secs).await {
+        Ok(true) => {}
+        Ok(false) => return Err(ApiError::new(StatusCode::CONFLICT, "reservation_settled", "Reservation has already been committed or released")),
+        Err(e) => return Err(unavailable("Failed to settle the reservation", e)),
+    }
+
+    let unused = reservation.tokens - used;
+    let elapsed_secs = (now_ms - reservation.reserved_ms).max(0) as f64 / 1000.0;
+    for (i, limit) in reservation.limits.iter().enumerate() {
+        let refund = unused as f64 - limit.leak_per_sec * elapsed_secs;
+        if refund <= 0.0 {
+            continue;
+        }
+        // Buckets given their tokens back by an earlier, failed settle are
+        // skipped, so that none gets them twice
+        let returned = format!("{}#{}", settled, i);
+        let result = match state.limiter.remember(&returned, ttl_secs).await {
+            Ok(false) => continue,
+            Ok(true) => state.limiter.charge(&limit.bucket, -refund, limit.capacity, limit.leak_per_sec, now_ms).await.map(drop),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            state.limiter.forget(&returned).await;
+            state.limiter.forget(&settled).await;
+            return Err(unavailable("Failed to return unused tokens; settle the reservation again", e));
+        }
+    }
+    Ok(Settled {
+        committed: used,
+        released: unused,
+    })
+}
+
+fn unavailable(message: &str, e: anyhow::Error) -> ApiError {
+    ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "limiter_unavailable", format!("{}: {:#}", message, e))
+}
+
+fn not_enabled() -> ApiError {
+    ApiError::new(StatusCode::NOT_FOUND, "not_found", "Reservations are not enabled")
+}
@@ -0,0 +1,235 @@
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{fmt, time::{SystemTime, UNIX_EPOCH}};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AwsConfig {
+    pub region: String,
+    /// Overrides the service endpoint, e.g. for localstack.
+    #[serde(default)]
+    pub endpoint: Option<String>,
+}
+
+/// Minimal client for the JSON protocol AWS services (Secrets Manager,
+/// DynamoDB) and S3 uploads, signing requests with SigV4. Credentials are read from the
+/// standard `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+/// `AWS_SESSION_TOKEN` environment variables on every call so rotated
+/// credentials are picked up.
+#[derive(Debug, Clone)]
+pub struct AwsClient {
+    http: reqwest::Client,
+    config: AwsConfig,
+}
+
+impl AwsClient {
+    pub fn new(http: reqwest::Client, config: AwsConfig) -> Self {
+        Self { http, config }
+    }
+
+    /// Invokes `target` (e.g. `secretsmanager.GetSecretValue`) on `service`.
+    pub async fn call(&self, service: &str, json_version: &str, target: &str, body: &serde_json::Value) -> Result<serde_json::Value> {
+        let credentials = Credentials::from_env()?;
+        let endpoint = match &self.config.endpoint {
+            Some(e) => e.clone(),
+            None => format!("https://{}.{}.amazonaws.com/", service, self.config.region),
+        };
+        let url = reqwest::Url::parse(&endpoint)?;
+        let payload = serde_json::to_vec(body)?;
+        let content_type = format!("application/x-amz-json-{}", json_version);
+        let (amz_date, _) = timestamps(SystemTime::now());
+
+        let headers = vec![
+            ("content-type", content_type.clone()),
+            ("host", host(&url)),
+            ("x-amz-date", amz_date.clone()),
+            ("x-amz-target", target.to_string()),
+        ];
+        let path = if url.path().is_empty() { "/" } else { url.path() };
+        let authorization = self.sign(&credentials, service, "POST", path, headers, &hex::encode(Sha256::digest(&payload)));
+
+        let mut request = self
+            .http
+            .post(url)
+            .header("content-type", content_type)
+            .header("x-amz-date", amz_date)
+            .header("x-amz-target", target)
+            .header("authorization", authorization)
+            .body(payload);
+        if let Some(token) = credentials.session_token {
+            request = request.header("x-amz-security-token", token);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        let body: serde_json::Value = response.json().await.unwrap_or(serde_json::Value::Null);
+        if !status.is_success() {
+            let kind = body.get("__type").and_then(|t| t.as_str()).unwrap_or("unknown");
+            return Err(AwsError {
+                target: target.to_string(),
+                status,
+                kind: kind.to_string(),
+            }
+            .into());
+        }
+        Ok(body)
+    }
+
+    /// Uploads `body` to S3 as `key` in `bucket`, addressed by path if an
+    /// endpoint is configured and by virtual host otherwise.
+    pub async fn put_object(&self, bucket: &str, key: &str, body: Vec<u8>, content_type: &str) -> Result<()> {
+        let credentials = Credentials::from_env()?;
+        let key = utf8_percent_encode(key, S3_KEY).to_string();
+        let url = match &self.config.endpoint {
+            Some(e) => format!("{}/{}/{}", e.trim_end_matches('/'), bucket, key),
+            None => format!("https://{}.s3.{}.amazonaws.com/{}", bucket, self.config.region, key),
+        };
+        let url = reqwest::Url::parse(&url)?;
+        let payload_hash = hex::encode(Sha256::digest(&body));
+        let (amz_date, _) = timestamps(SystemTime::now());
+
+        let headers = vec![
+            ("content-type", content_type.to_string()),
+            ("host", host(&url)),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        let authorization = self.sign(&credentials, "s3", "PUT", url.path(), headers, &payload_hash);
+
+        let mut request = self
+            .http
+            .put(url)
+            .header("content-type", content_type)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header("authorization", authorization)
+            .body(body);
+        if let Some(token) = credentials.session_token {
+            request = request.header("x-amz-security-token", token);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            // S3 answers with XML, whose error code is enough to tell what failed
+            let body = response.text().await.unwrap_or_default();
+            let kind = body.split_once("<Code>").and_then(|(_, rest)| rest.split_once("</Code>")).map_or("unknown", |(code, _)| code);
+            return Err(AwsError {
+                target: "s3.PutObject".to_string(),
+                status,
+                kind: kind.to_string(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// SigV4 `authorization` header of a request with the given headers,
+    /// sorted by their lowercase names and including `x-amz-date`, and
+    /// payload hash.
+    fn sign(&self, credentials: &Credentials, service: &str, method: &str, path: &str, mut headers: Vec<(&str, String)>, payload_hash: &str) -> String {
+        let amz_date = headers.iter().find(|(k, _)| *k == "x-amz-date").map(|(_, v)| v.clone()).unwrap_or_default();
+        let date = amz_date.get(..8).unwrap_or_default();
+        if let Some(token) = &credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed_headers = headers.iter().map(|(k, _)| *k).collect::<Vec<_>>().join(";");
+        let canonical_headers: String = headers.iter().map(|(k, v)| format!("{}:{}\n", k, v.trim())).collect();
+        let canonical_request = format!("{}\n{}\n\n{}\n{}\n{}", method, path, canonical_headers, signed_headers, payload_hash);
+
+        let scope = format!("{}/{}/{}/aws4_request", date, self.config.region, service);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let mut key = hmac(format!("AWS4{}", credentials.secret_key).as_bytes(), date.as_bytes());
+        for part in [self.config.region.as_str(), service, "aws4_request"] {
+            key = hmac(&key, part.as_bytes());
+        }
+        let signature = hex::encode(hmac(&key, string_to_sign.as_bytes()));
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            credentials.access_key, scope, signed_headers, signature
+        )
+    }
+}
+
+/// Characters of S3 object keys that are percent-encoded; slashes separate
+/// the key's segments.
+const S3_KEY: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~').remove(b'/');
+
+struct Credentials {
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+impl Credentials {
+    fn from_env() -> Result<Self> {
+        Ok(Self {
+            access_key: std::env::var("AWS_ACCESS_KEY_ID").context("AWS_ACCESS_KEY_ID must be set")?,
+            secret_key: std::env::var("AWS_SECRET_ACCESS_KEY").context("AWS_SECRET_ACCESS_KEY must be set")?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+/// `host` header of `url`, with its port unless it is the default.
+fn host(url: &reqwest::Url) -> String {
+    match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    }
+}
+
+/// Error response of an AWS API call.
+#[derive(Debug)]
+pub struct AwsError {
+    pub target: String,
+    pub status: reqwest::StatusCode,
+    /// Error type, e.g. `com.amazonaws.dynamodb.v20120810#ConditionalCheckFailedException`.
+    pub kind: String,
+}
+
+impl AwsError {
+    /// Whether the error type is `name`, with or without its namespace.
+    pub fn is(&self, name: &str) -> bool {
+        self.kind.rsplit('#').next() == Some(name)
+    }
+}
+
+impl fmt::Display for AwsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed with {}: {}", self.target, self.status, self.kind)
+    }
+}
+
+impl std::error::Error for AwsError {}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Formats `now` as SigV4 timestamp (`YYYYMMDDTHHMMSSZ`) and date (`YYYYMMDD`).
+pub(crate) fn timestamps(now: SystemTime) -> (String, String) {
+    let secs = now.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    let (days, rem) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let time = format!("{:02}{:02}{:02}", rem / 3600, rem % 3600 / 60, rem % 60);
+    (format!("{}T{}Z", date, time), date)
+}
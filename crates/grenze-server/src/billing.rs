@@ -0,0 +1,356 @@
+//! Billing reports: the usage of every key over a calendar month or UTC day,
+//! as rolled up by the usage history, compared against the allowance of the
+//! key's plan. The leader pushes the report of every period once it closed.
+//! Plans may also enforce their allowance as a quota, counted in the store as
+//! requests are admitted.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, UNIX_EPOCH}};
+use utoipa::ToSchema;
+
+use crate::{aws::{self, AwsClient, AwsConfig}, leader::Scheduler, limiter::{BucketLimit, BucketSize, Clock, LimiterStore, QuotaLimit, QuotaOverage}, usage::{csv_field, Granularity}};
+
+/// How long a pushed period is remembered, so it is pushed only once.
+const PUSHED_TTL_SECS: u64 = 400 * 86_400;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BillingConfig {
+    /// Length of the billing periods.
+    #[serde(default)]
+    pub period: BillingPeriod,
+    /// Plans by name.
+    #[serde(default)]
+    pub plans: HashMap<String, Plan>,
+    /// Plan by rate limit key.
+    #[serde(default)]
+    pub keys: HashMap<String, String>,
+    /// Plan of the keys not listed in `keys`; without one, they have no
+    /// allowance.
+    #[serde(default)]
+    pub default_plan: Option<String>,
+    /// Receives the report of every closed period as JSON.
+    #[serde(default)]
+    pub webhook: Option<String>,
+    /// Bucket the report of every closed period is uploaded to as CSV.
+    #[serde(default)]
+    pub s3: Option<BillingS3Config>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BillingPeriod {
+    /// Calendar month in UTC, named like `2026-10`.
+    #[default]
+    Month,
+    /// UTC day, named like `2026-10-16`.
+    Day,
+}
+
+/// Allowance of a plan per billing period; unlimited if unset.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Plan {
+    #[serde(default)]
+    pub included_requests: Option<u64>,
+    #[serde(default)]
+    pub included_bytes: Option<u64>,
+    /// What happens to requests once the allowance is used up; without it,
+    /// they are only billed as overage.
+    #[serde(default)]
+    pub overage: Option<Overage>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Overage {
+    /// Rejects them.
+    Reject,
+    /// Admits them, marked as overage to be billed.
+    Allow,
+    /// Admits them at a reduced rate, marked as overage to be billed.
+    Throttle { capacity: u32, leak_per_sec: f64 },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BillingS3Config {
+    pub bucket: String,
+    pub region: String,
+    /// Prepended to the object name `billing-{period}.csv`.
+    #[serde(default)]
+    pub prefix: String,
+    /// Overrides the S3 endpoint, e.g. for MinIO.
+    #[serde(default)]
+    pub endpoint: Option<String>,
+}
+
+impl BillingConfig {
+    pub fn validate(&self) -> Result<()> {
+        for (name, plan) in &self.plans {
+            if let Some(Overage::Throttle { capacity, leak_per_sec }) = &plan.overage {
+                BucketSize::validate(Some(*capacity), Some(*leak_per_sec)).with_context(|| format!("billing plan '{}' overage", name))?;
+            }
+        }
+        for (key, plan) in &self.keys {
+            if !self.plans.contains_key(plan) {
+                bail!("billing plan '{}' of key '{}' is not defined", plan, key);
+            }
+        }
+        if let Some(plan) = &self.default_plan
+            && !self.plans.contains_key(plan)
+        {
+            bail!("billing default_plan '{}' is not defined", plan);
+        }
+        if let Some(webhook) = &self.webhook {
+            reqwest::Url::parse(webhook).context("billing webhook")?;
+        }
+        if let Some(s3) = &self.s3
+            && s3.bucket.is_empty()
+        {
+            bail!("billing s3 bucket must not be empty");
+        }
+        Ok(())
+    }
+
+    /// Name and plan of the plan `key` is on.
+    fn plan(&self, key: &str) -> Option<(&str, &Plan)> {
+        let name = self.keys.get(key).or(self.default_plan.as_ref())?;
+        Some((name, self.plans.get(name)?))
+    }
+}
+
+/// A billing period, e.g. `2026-10`, spanning `from_ms..to_ms`.
+#[derive(Debug, Clone)]
+pub struct Period {
+    pub name: String,
+    pub from_ms: i64,
+    pub to_ms: i64,
+}
+
+impl BillingPeriod {
+    /// The period named `name`, if it is one of this length.
+    pub fn parse(self, name: &str) -> Option<Period> {
+        let mut parts = name.split('-').map(|p| p.parse::<i64>().ok());
+        let (year, month) = (parts.next()??, parts.next()??);
+        let day = parts.next();
+        if parts.next().is_some() || !(1..=12).contains(&month) {
+            return None;
+        }
+        let (from_days, to_days) = match (self, day) {
+            (BillingPeriod::Month, None) => {
+                let (next_year, next_month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+                (days_from_civil(year, month, 1), days_from_civil(next_year, next_month, 1))
+            },
+            (BillingPeriod::Day, Some(Some(day))) => {
+                let days = days_from_civil(year, month, day);
+                (days, days + 1)
+            },
+            _ => return None,
+        };
+        // Rejects days past the end of their month and unpadded names
+        if self.name(from_days * 86_400_000) != name {
+            return None;
+        }
+        Some(Period {
+            name: name.to_string(),
+            from_ms: from_days * 86_400_000,
+            to_ms: to_days * 86_400_000,
+        })
+    }
+
+    /// Name of the period containing `at_ms`.
+    fn name(self, at_ms: i64) -> String {
+        let (_, date) = aws::timestamps(UNIX_EPOCH + Duration::from_millis(at_ms.max(0) as u64));
+        match self {
+            BillingPeriod::Month => format!("{}-{}", &date[..4], &date[4..6]),
+            BillingPeriod::Day => format!("{}-{}-{}", &date[..4], &date[4..6], &date[6..]),
+        }
+    }
+
+    /// The period containing `at_ms`.
+    fn current(self, at_ms: i64) -> Period {
+        self.parse(&self.name(at_ms)).expect("period names parse")
+    }
+
+    /// The last period that ended at or before `at_ms`.
+    fn last_closed(self, at_ms: i64) -> Period {
+        self.current(self.current(at_ms).from_ms - 1)
+    }
+}
+
+/// Days since the epoch of a civil date (Howard Hinnant's algorithm).
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BillingReport {
+    pub period: String,
+    pub from_ms: i64,
+    pub to_ms: i64,
+    pub keys: Vec<KeyBill>,
+}
+
+/// Billable usage of a key in a period.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct KeyBill {
+    pub key: String,
+    pub plan: Option<String>,
+    /// Allowed requests.
+    pub requests: u64,
+    pub limited: u64,
+    /// Size of the downstream responses.
+    pub bytes: u64,
+    /// Requests and bytes beyond the plan's allowance.
+    pub overage_requests: u64,
+    pub overage_bytes: u64,
+}
+
+impl BillingReport {
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("key,plan,requests,limited,bytes,overage_requests,overage_bytes\n");
+        for bill in &self.keys {
+            csv.push_str(&format!(
+                "{},{},{},{},{},{},{}\n",
+                csv_field(&bill.key),
+                csv_field(bill.plan.as_deref().unwrap_or_default()),
+                bill.requests,
+                bill.limited,
+                bill.bytes,
+                bill.overage_requests,
+                bill.overage_bytes
+            ));
+        }
+        csv
+    }
+}
+
+pub struct Billing {
+    config: BillingConfig,
+    http_client: reqwest::Client,
+    s3: Option<AwsClient>,
+}
+
+impl Billing {
+    pub fn new(config: &BillingConfig, http_client: reqwest::Client) -> Self {
+        let s3 = config.s3.as_ref().map(|s3| {
+            let aws = AwsConfig {
+                region: s3.region.clone(),
+                endpoint: s3.endpoint.clone(),
+            };
+            AwsClient::new(http_client.clone(), aws)
+        });
+        Self {
+            config: config.clone(),
+            http_client,
+            s3,
+        }
+    }
+
+    /// The period named `name`, if it is one of the configured length.
+    pub fn period(&self, name: &str) -> Option<Period> {
+        self.config.period.parse(name)
+    }
+
+    /// The allowance `key` draws from at `now_ms`, if its plan enforces one.
+    pub fn quota(&self, key: &str, now_ms: i64) -> Option<QuotaLimit> {
+        let (_, plan) = self.config.plan(key)?;
+        let overage = match plan.overage.as_ref()? {
+            Overage::Reject => QuotaOverage::Reject,
+            Overage::Allow => QuotaOverage::Allow,
+            Overage::Throttle { capacity, leak_per_sec } => QuotaOverage::Throttle(BucketLimit {
+                bucket: format!("overage:{}", key),
+                capacity: *capacity,
+                leak_per_sec: *leak_per_sec,
+            }),
+        };
+        let period = self.config.period.current(now_ms);
+        Some(QuotaLimit {
+            counter: format!("quota:{}:{}", period.name, key),
+            included_requests: plan.included_requests,
+            included_bytes: plan.included_bytes,
+            until_ms: period.to_ms,
+            overage,
+        })
+    }
+
+    /// Usage of every key with usage in `period`, from its daily rollups.
+    pub async fn report(&self, limiter: &dyn LimiterStore, period: &Period) -> Result<BillingReport> {
+        let mut keys = Vec::new();
+        for key in limiter.usage_keys(Granularity::Day, period.from_ms, period.to_ms).await? {
+            let usage = limiter.usage(&key, Granularity::Day, period.from_ms, period.to_ms).await?;
+            let plan = self.config.plan(&key);
+            let requests = usage.iter().map(|c| c.allowed).sum();
+            let bytes = usage.iter().map(|c| c.bytes).sum();
+            let included_requests = plan.and_then(|(_, p)| p.included_requests);
+            let included_bytes = plan.and_then(|(_, p)| p.included_bytes);
+            keys.push(KeyBill {
+                plan: plan.map(|(name, _)| name.to_string()),
+                requests,
+                limited: usage.iter().map(|c| c.limited).sum(),
+                bytes,
+                overage_requests: included_requests.map_or(0, |i| requests.saturating_sub(i)),
+                overage_bytes: included_bytes.map_or(0, |i| bytes.saturating_sub(i)),
+                key,
+            });
+        }
+        Ok(BillingReport {
+            period: period.name.clone(),
+            from_ms: period.from_ms,
+            to_ms: period.to_ms,
+            keys,
+        })
+    }
+
+    /// Pushes the report of every period once it closed and its usage is
+    /// rolled up, `settle` after its end, if a webhook or S3 is configured.
+    /// The instances agree through the store on which one pushes a period;
+    /// that one retries while the push fails.
+    pub fn spawn_reports(self: &Arc<Self>, limiter: Arc<dyn LimiterStore>, scheduler: &Scheduler, clock: Arc<dyn Clock>, settle: Duration) {
+        if self.config.webhook.is_none() && self.s3.is_none() {
+            return;
+        }
+        let billing = self.clone();
+        let unsent: Arc<Mutex<Vec<Period>>> = Arc::default();
+        scheduler.every("billing report", Duration::from_secs(60), move || {
+            let (billing, limiter, unsent) = (billing.clone(), limiter.clone(), unsent.clone());
+            let closed = billing.config.period.last_closed(clock.now_ms() - settle.as_millis() as i64);
+            async move {
+                if matches!(limiter.remember(&format!("billing:{}", closed.name), PUSHED_TTL_SECS).await, Ok(true)) {
+                    unsent.lock().expect("billing lock poisoned").push(closed);
+                }
+                let periods = std::mem::take(&mut *unsent.lock().expect("billing lock poisoned"));
+                let mut failed = Vec::new();
+                let mut result = Ok(());
+                for period in periods {
+                    if let Err(e) = billing.push(limiter.as_ref(), &period).await {
+                        result = Err(e).with_context(|| format!("failed to push billing report {}", period.name));
+                        failed.push(period);
+                    }
+                }
+                unsent.lock().expect("billing lock poisoned").extend(failed);
+                result
+            }
+        });
+    }
+
+    async fn push(&self, limiter: &dyn LimiterStore, period: &Period) -> Result<()> {
+        let report = self.report(limiter, period).await?;
+        if let Some(webhook) = &self.config.webhook {
+            self.http_client.post(webhook).json(&report).send().await?.error_for_status()?;
+        }
+        if let (Some(s3), Some(config)) = (&self.s3, &self.config.s3) {
+            let object = format!("{}billing-{}.csv", config.prefix, report.period);
+            s3.put_object(&config.bucket, &object, report.to_csv().into_bytes(), "text/csv").await?;
+        }
+        println!("Pushed billing report {} of {} keys", report.period, report.keys.len());
+        Ok(())
+    }
+}
@@ -0,0 +1,305 @@
+//! Circuit breaker around the bucket store, so an unavailable Redis costs one
+//! timeout per probe instead of one per request.
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::{sync::{atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering}, Arc}, time::Duration};
+
+use crate::{erasure::{Erasure, Tenant}, limiter::{Admission, BucketLimit, BucketState, LimiterStore, LogAdmission, LogLimit, QuotaAdmission, QuotaLimit, QuotaState}, usage::{Granularity, UsageCount}};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BreakerConfig {
+    /// Consecutive failed store calls that open the breaker.
+    pub failure_threshold: u32,
+    /// Time a store call may take before it counts as failed.
+    pub timeout_ms: u64,
+    /// How often an open breaker checks whether the store is back.
+    pub probe_interval_ms: u64,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            timeout_ms: 500,
+            probe_interval_ms: 5_000,
+        }
+    }
+}
+
+/// How requests are answered while the bucket store fails.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureMode {
+    /// Rejects requests with `circuit_open`, or `store_unavailable` before
+    /// the breaker opens.
+    #[default]
+    FailClosed,
+    /// Forwards requests without rate limiting.
+    FailOpen,
+}
+
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    open: AtomicBool,
+    failures: AtomicU32,
+    errors: AtomicU64,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &BreakerConfig) -> Self {
+        Self {
+            config: config.clone(),
+            open: AtomicBool::new(false),
+            failures: AtomicU32::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    /// Seconds until an open breaker next checks whether the store is back,
+    /// at most.
+    pub fn probe_interval_secs(&self) -> u64 {
+        self.config.probe_interval_ms.div_ceil(1000)
+    }
+
+    /// Failed store calls since startup, including ones rejected while open.
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    fn record_success(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+    }
+
+    fn record_failure(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= self.config.failure_threshold.max(1) && !self.open.swap(true, Ordering::Relaxed) {
+            println!("Limiter circuit breaker opened after {} failed calls", failures);
+        }
+    }
+
+    /// Pings `store` every `probe_interval_ms` while open, closing the breaker
+    /// once it answers.
+    pub fn spawn_probe(self: &Arc<Self>, store: Arc<dyn LimiterStore>) {
+        let breaker = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_millis(breaker.config.probe_interval_ms.max(100)));
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if !breaker.is_open() {
+                    continue;
+                }
+                let timeout = Duration::from_millis(breaker.config.timeout_ms);
+                if let Ok(Ok(())) = tokio::time::timeout(timeout, store.ping()).await {
+                    breaker.failures.store(0, Ordering::Relaxed);
+                    breaker.open.store(false, Ordering::Relaxed);
+                    println!("Limiter circuit breaker closed, the store is reachable again");
+                }
+            }
+        });
+    }
+}
+
+/// Store whose bucket admissions pass through a [`CircuitBreaker`]. Calls
+/// fail immediately while it is open.
+pub struct BreakerStore {
+    inner: Arc<dyn LimiterStore>,
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl BreakerStore {
+    pub fn new(inner: Arc<dyn LimiterStore>, breaker: Arc<CircuitBreaker>) -> Self {
+        Self { inner, breaker }
+    }
+
+    /// Runs an admission through the breaker, counting it as failed if it
+    /// errors or times out.
+    async fn guard<T>(&self, call: impl Future<Output = Result<T>>) -> Result<T> {
+        if self.breaker.is_open() {
+            self.breaker.errors.fetch_add(1, Ordering::Relaxed);
+            bail!("limiter circuit breaker is open");
+        }
+        let timeout = Duration::from_millis(self.breaker.config.timeout_ms);
+        let result = match tokio::time::timeout(timeout, call).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("limiter call timed out after {}ms", self.breaker.config.timeout_ms)),
+        };
+        match &result {
+            Ok(_) => self.breaker.record_success(),
+            Err(_) => self.breaker.record_failure(),
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl LimiterStore for BreakerStore {
+    async fn allow(&self, bucket: &str, cost: f64, capacity: u32, leak_per_sec: f64, now_ms: i64) -> Result<Admission> {
+        self.guard(self.inner.allow(bucket, cost, capacity, leak_per_sec, now_ms)).await
+    }
+
+    async fn allow_all(&self, limits: &[BucketLimit], cost: f64, now_ms: i64) -> Result<Vec<Admission>> {
+        self.guard(self.inner.allow_all(limits, cost, now_ms)).await
+    }
+
+    async fn allow_batch(&self, requests: &[(&[BucketLimit], f64)], now_ms: i64) -> Result<Vec<Vec<Admission>>> {
+        self.guard(self.inner.allow_batch(requests, now_ms)).await
+    }
+
+    async fn remember(&self, key: &str, ttl_secs: u64) -> Result<bool> {
+        self.guard(self.inner.remember(key, ttl_secs)).await
+    }
+
+    async fn forget(&self, key: &str) {
+        self.inner.forget(key).await
+    }
+
+    async fn evict(&self, bucket: &str) {
+        self.inner.evict(bucket).await
+    }
+
+    async fn charge(&self, bucket: &str, amount: f64, capacity: u32, leak_per_sec: f64, now_ms: i64) -> Result<f64> {
+        if self.breaker.is_open() {
+            bail!("limiter circuit breaker is open");
+        }
+        self.inner.charge(bucket, amount, capacity, leak_per_sec, now_ms).await
+    }
+
+    async fn level(&self, bucket: &str, leak_per_sec: f64, now_ms: i64) -> Result<f64> {
+        if self.breaker.is_open() {
+            bail!("limiter circuit breaker is open");
+        }
+        self.inner.level(bucket, leak_per_sec, now_ms).await
+    }
+
+    async fn first_seen(&self, key: &str, now_ms: i64, ttl_secs: u64) -> Result<i64> {
+        if self.breaker.is_open() {
+            bail!("limiter circuit breaker is open");
+        }
+        self.inner.first_seen(key, now_ms, ttl_secs).await
+    }
+
+    async fn cookie_jar(&self, key: &str) -> Result<Option<String>> {
+        if self.breaker.is_open() {
+            bail!("limiter circuit breaker is open");
+        }
+        self.inner.cookie_jar(key).await
+    }
+
+    async fn store_cookie_jar(&self, key: &str, jar: &str, ttl_secs: u64) -> Result<()> {
+        if self.breaker.is_open() {
+            bail!("limiter circuit breaker is open");
+        }
+        self.inner.store_cookie_jar(key, jar, ttl_secs).await
+    }
+
+    async fn lock(&self, name: &str, holder: &str, ttl_ms: u64) -> Result<bool> {
+        if self.breaker.is_open() {
+            bail!("limiter circuit breaker is open");
+        }
+        self.inner.lock(name, holder, ttl_ms).await
+    }
+
+    async fn unlock(&self, name: &str, holder: &str) -> Result<()> {
+        if self.breaker.is_open() {
+            bail!("limiter circuit breaker is open");
+        }
+        self.inner.unlock(name, holder).await
+    }
+
+    async fn record_usage(&self, counts: &[UsageCount], ttl_secs: u64) -> Result<()> {
+        if self.breaker.is_open() {
+            bail!("limiter circuit breaker is open");
+        }
+        self.inner.record_usage(counts, ttl_secs).await
+    }
+
+    async fn take_usage(&self, before_ms: i64) -> Result<Vec<UsageCount>> {
+        if self.breaker.is_open() {
+            bail!("limiter circuit breaker is open");
+        }
+        self.inner.take_usage(before_ms).await
+    }
+
+    async fn roll_up_usage(&self, granularity: Granularity, counts: &[UsageCount], retention_secs: u64) -> Result<()> {
+        if self.breaker.is_open() {
+            bail!("limiter circuit breaker is open");
+        }
+        self.inner.roll_up_usage(granularity, counts, retention_secs).await
+    }
+
+    async fn usage(&self, key: &str, granularity: Granularity, from_ms: i64, to_ms: i64) -> Result<Vec<UsageCount>> {
+        if self.breaker.is_open() {
+            bail!("limiter circuit breaker is open");
+        }
+        self.inner.usage(key, granularity, from_ms, to_ms).await
+    }
+
+    async fn usage_keys(&self, granularity: Granularity, from_ms: i64, to_ms: i64) -> Result<Vec<String>> {
+        if self.breaker.is_open() {
+            bail!("limiter circuit breaker is open");
+        }
+        self.inner.usage_keys(granularity, from_ms, to_ms).await
+    }
+
+    async fn purge_usage(&self, granularity: Option<Granularity>, before_ms: i64) -> Result<u64> {
+        if self.breaker.is_open() {
+            bail!("limiter circuit breaker is open");
+        }
+        self.inner.purge_usage(granularity, before_ms).await
+    }
+
+    async fn allow_quota(&self, quota: &QuotaLimit, cost: f64, now_ms: i64) -> Result<QuotaAdmission> {
+        self.guard(self.inner.allow_quota(quota, cost, now_ms)).await
+    }
+
+    async fn charge_quota(&self, counter: &str, bytes: u64, until_ms: i64) -> Result<()> {
+        if self.breaker.is_open() {
+            bail!("limiter circuit breaker is open");
+        }
+        self.inner.charge_quota(counter, bytes, until_ms).await
+    }
+
+    async fn allow_log(&self, log: &LogLimit, cost: u32, now_ms: i64) -> Result<LogAdmission> {
+        self.guard(self.inner.allow_log(log, cost, now_ms)).await
+    }
+
+    async fn release_log(&self, log: &LogLimit, cost: u32, now_ms: i64) -> Result<()> {
+        self.guard(self.inner.release_log(log, cost, now_ms)).await
+    }
+
+    async fn erase(&self, tenant: &Tenant) -> Result<Erasure> {
+        if self.breaker.is_open() {
+            bail!("limiter circuit breaker is open");
+        }
+        self.inner.erase(tenant).await
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.inner.ping().await
+    }
+
+    async fn export(&self) -> Result<Vec<BucketState>> {
+        self.inner.export().await
+    }
+
+    async fn import(&self, buckets: &[BucketState], capacity: u32, leak_per_sec: f64) -> Result<()> {
+        self.inner.import(buckets, capacity, leak_per_sec).await
+    }
+
+    async fn export_quotas(&self, now_ms: i64) -> Result<Vec<QuotaState>> {
+        self.inner.export_quotas(now_ms).await
+    }
+
+    async fn import_quotas(&self, quotas: &[QuotaState]) -> Result<()> {
+        self.inner.import_quotas(quotas).await
+    }
+}
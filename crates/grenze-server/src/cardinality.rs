@@ -0,0 +1,152 @@
+//! Caps on the number of distinct buckets per tenant, so a client generating
+//! random keys cannot grow the store without limit.
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+
+use crate::{erasure::Tenant, limiter::Clock};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CardinalityConfig {
+    /// Most buckets a tenant may hold requests in at once.
+    pub max_keys_per_tenant: usize,
+    /// The tenant is the rate limit key up to the first occurrence of this
+    /// delimiter, or the whole key without one.
+    #[serde(default = "default_tenant_delimiter")]
+    pub tenant_delimiter: String,
+    #[serde(default)]
+    pub on_limit: CardinalityAction,
+    /// How often buckets that have drained are forgotten.
+    #[serde(default = "default_gc_interval_secs")]
+    pub gc_interval_secs: u64,
+}
+
+fn default_tenant_delimiter() -> String {
+    ":".to_string()
+}
+
+fn default_gc_interval_secs() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CardinalityAction {
+    /// Rejects requests for new buckets of a tenant at its cap.
+    #[default]
+    Reject,
+    /// Evicts the tenant's least recently used bucket to make room.
+    Evict,
+}
+
+impl CardinalityConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_keys_per_tenant == 0 {
+            bail!("cardinality max_keys_per_tenant must be positive");
+        }
+        if self.tenant_delimiter.is_empty() {
+            bail!("cardinality tenant_delimiter must not be empty");
+        }
+        Ok(())
+    }
+}
+
+/// Decision for a request to a bucket.
+#[derive(Debug, PartialEq, Eq)]
+pub enum KeyAdmission {
+    Admitted,
+    /// Admitted after evicting the given bucket, which must be dropped from
+    /// the store.
+    Evicted(String),
+    Rejected,
+}
+
+/// Buckets seen per tenant, with the time of their last request.
+pub struct KeyTracker {
+    config: CardinalityConfig,
+    /// How long after its last request a bucket has certainly drained.
+    idle_ms: i64,
+    tenants: Mutex<HashMap<String, HashMap<String, i64>>>,
+}
+
+impl KeyTracker {
+    pub fn new(config: &CardinalityConfig, idle: Duration) -> Self {
+        Self {
+            config: config.clone(),
+            idle_ms: idle.as_millis() as i64,
+            tenants: Mutex::default(),
+        }
+    }
+
+    pub fn tenant<'k>(&self, key: &'k str) -> &'k str {
+        match key.split_once(self.config.tenant_delimiter.as_str()) {
+            Some((tenant, _)) => tenant,
+            None => key,
+        }
+    }
+
+    pub fn delimiter(&self) -> &str {
+        &self.config.tenant_delimiter
+    }
+
+    /// Forgets the buckets of `tenant`.
+    pub fn forget(&self, tenant: &Tenant) {
+        let mut tenants = self.tenants.lock().expect("key tracker lock poisoned");
+        tenants.retain(|_, buckets| {
+            buckets.retain(|bucket, _| !tenant.owns_bucket(bucket));
+            !buckets.is_empty()
+        });
+    }
+
+    /// Records a request for `bucket` on behalf of `key` at `now_ms`.
+    pub fn admit(&self, key: &str, bucket: &str, now_ms: i64) -> KeyAdmission {
+        let mut tenants = self.tenants.lock().expect("key tracker lock poisoned");
+        let buckets = tenants.entry(self.tenant(key).to_string()).or_default();
+        if let Some(seen_ms) = buckets.get_mut(bucket) {
+            *seen_ms = now_ms;
+            return KeyAdmission::Admitted;
+        }
+
+        if buckets.len() >= self.config.max_keys_per_tenant {
+            buckets.retain(|_, seen_ms| now_ms - *seen_ms < self.idle_ms);
+        }
+        let mut admission = KeyAdmission::Admitted;
+        if buckets.len() >= self.config.max_keys_per_tenant {
+            match self.config.on_limit {
+                CardinalityAction::Reject => return KeyAdmission::Rejected,
+                CardinalityAction::Evict => {
+                    let oldest = buckets.iter().min_by_key(|(_, seen_ms)| **seen_ms).map(|(b, _)| b.clone());
+                    if let Some(oldest) = oldest {
+                        buckets.remove(&oldest);
+                        admission = KeyAdmission::Evicted(oldest);
+                    }
+                },
+            }
+        }
+        buckets.insert(bucket.to_string(), now_ms);
+        admission
+    }
+
+    /// Forgets drained buckets every `gc_interval_secs` in the background.
+    pub fn spawn_gc(self: &Arc<Self>, clock: Arc<dyn Clock>) {
+        let tracker = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(tracker.config.gc_interval_secs.max(1)));
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                tracker.collect(clock.now_ms());
+            }
+        });
+    }
+
+    fn collect(&self, now_ms: i64) {
+        let mut tenants = self.tenants.lock().expect("key tracker lock poisoned");
+        tenants.retain(|_, buckets| {
+            buckets.retain(|_, seen_ms| now_ms - *seen_ms < self.idle_ms);
+            !buckets.is_empty()
+        });
+    }
+}
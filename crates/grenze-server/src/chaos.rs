@@ -0,0 +1,107 @@
+//! Fault injection for testing clients: added latency, spurious 429s and
+//! downstream failures for selected keys. Never enable this in production.
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use axum::{http::StatusCode, response::{IntoResponse, Response}, Json};
+use serde::Deserialize;
+use serde_json::json;
+use std::time::Duration;
+
+use crate::{api::proxy::ProxyRequest, middleware::{Context, ProxyMiddleware}};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChaosConfig {
+    /// Rules in priority order; the first one matching a request applies.
+    pub rules: Vec<ChaosRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChaosRule {
+    /// Keys the rule applies to; a trailing `*` matches by prefix. Empty
+    /// matches every key.
+    #[serde(default)]
+    pub keys: Vec<String>,
+    /// Policies the rule applies to. Empty matches every policy.
+    #[serde(default)]
+    pub policies: Vec<String>,
+    /// Latency added before the request is rate limited and forwarded.
+    #[serde(default)]
+    pub latency_ms: u64,
+    /// Upper bound of random latency added on top of `latency_ms`.
+    #[serde(default)]
+    pub latency_jitter_ms: u64,
+    /// Probability of answering with a spurious 429.
+    #[serde(default)]
+    pub rate_limit_probability: f64,
+    /// Probability of answering as if the downstream failed.
+    #[serde(default)]
+    pub failure_probability: f64,
+    #[serde(default = "default_failure_status")]
+    pub failure_status: u16,
+}
+
+fn default_failure_status() -> u16 {
+    503
+}
+
+impl ChaosRule {
+    fn matches(&self, key: &str, policy: &str) -> bool {
+        let key_matches = self.keys.is_empty()
+            || self.keys.iter().any(|k| match k.strip_suffix('*') {
+                Some(prefix) => key.starts_with(prefix),
+                None => key == k,
+            });
+        key_matches && (self.policies.is_empty() || self.policies.iter().any(|p| p == policy))
+    }
+}
+
+impl ChaosConfig {
+    pub fn validate(&self) -> Result<()> {
+        for (i, rule) in self.rules.iter().enumerate() {
+            for (name, p) in [("rate_limit_probability", rule.rate_limit_probability), ("failure_probability", rule.failure_probability)] {
+                if !(0.0..=1.0).contains(&p) {
+                    bail!("chaos rule {}: {} must be between 0 and 1", i, name);
+                }
+            }
+            if StatusCode::from_u16(rule.failure_status).is_err() {
+                bail!("chaos rule {}: invalid failure_status {}", i, rule.failure_status);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ProxyMiddleware for ChaosConfig {
+    async fn on_request(&self, ctx: &Context<'_>, _request: &mut ProxyRequest) -> Result<(), Response> {
+        let Some(rule) = self.rules.iter().find(|r| r.matches(&ctx.key, &ctx.policy.name)) else {
+            return Ok(());
+        };
+        let jitter = match rule.latency_jitter_ms {
+            0 => 0,
+            max => rand::random_range(0..=max),
+        };
+        if rule.latency_ms + jitter > 0 {
+            tokio::time::sleep(Duration::from_millis(rule.latency_ms + jitter)).await;
+        }
+        if rand::random_bool(rule.rate_limit_probability) {
+            let payload = Json(json!({
+                "error": "rate_limited",
+                "message": "Too many requests"
+            }));
+            return Err((StatusCode::TOO_MANY_REQUESTS, payload).into_response());
+        }
+        if rand::random_bool(rule.failure_probability) {
+            let status = StatusCode::from_u16(rule.failure_status).unwrap_or(StatusCode::SERVICE_UNAVAILABLE);
+            let payload = Json(json!({
+                "error": "downstream_error",
+                "message": "Injected failure"
+            }));
+            return Err((status, payload).into_response());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,100 @@
+//! Classification of requests by their headers, `User-Agent` and path, so
+//! traffic such as obvious bots can be blocked outright or limited under a
+//! stricter policy than the product's own SDKs.
+
+use anyhow::{anyhow, bail, Context, Result};
+use axum::http::{header::USER_AGENT, HeaderMap, HeaderName};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::{mock::glob, policy::PolicySet};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClassificationConfig {
+    /// Rules in order; the first matching one applies.
+    pub rules: Vec<ClassificationRule>,
+}
+
+/// Rule assigning matching requests a policy or blocking them. A request
+/// matches if it meets every condition set. Patterns use `*` for any
+/// sequence of characters.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClassificationRule {
+    /// Pattern for the `User-Agent` header, matched case-insensitively.
+    /// Requests without one do not match.
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    /// Patterns for header values by name, matched case-insensitively.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Headers the request must not carry.
+    #[serde(default)]
+    pub missing_headers: Vec<String>,
+    /// Pattern for the path of the destination URL.
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Methods matched; any if empty.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Policy matching requests are limited under instead of their host's.
+    #[serde(default)]
+    pub policy: Option<String>,
+    /// Rejects matching requests.
+    #[serde(default)]
+    pub block: bool,
+}
+
+impl ClassificationConfig {
+    /// Checks the rules, and that the policies they assign are in `policies`
+    /// unless those may still change at runtime.
+    pub fn validate(&self, policies: Option<&PolicySet>) -> Result<()> {
+        for (i, rule) in self.rules.iter().enumerate() {
+            rule.validate().with_context(|| format!("classification rule {}", i))?;
+            if let (Some(policies), Some(policy)) = (policies, &rule.policy)
+                && policies.get(policy).is_none()
+            {
+                bail!("classification rule {}: unknown policy '{}'", i, policy);
+            }
+        }
+        Ok(())
+    }
+
+    /// First rule matching a request to `url`. Rules restricted to methods
+    /// never match if the method is unknown.
+    pub fn classify(&self, method: Option<&str>, url: Option<&str>, headers: &HeaderMap) -> Option<&ClassificationRule> {
+        let path = url.and_then(|u| reqwest::Url::parse(u).ok()).map(|u| u.path().to_string());
+        self.rules.iter().find(|rule| rule.matches(method, path.as_deref(), headers))
+    }
+}
+
+impl ClassificationRule {
+    fn validate(&self) -> Result<()> {
+        if self.user_agent.is_none() && self.headers.is_empty() && self.missing_headers.is_empty() && self.path.is_none() && self.methods.is_empty() {
+            bail!("rule must set at least one condition");
+        }
+        if self.block == self.policy.is_some() {
+            bail!("rule must either block or assign a policy");
+        }
+        for name in self.headers.keys().chain(&self.missing_headers) {
+            HeaderName::try_from(name.as_str()).map_err(|_| anyhow!("invalid header name '{}'", name))?;
+        }
+        Ok(())
+    }
+
+    fn matches(&self, method: Option<&str>, path: Option<&str>, headers: &HeaderMap) -> bool {
+        let header = |name: &str, pattern: &str| {
+            headers
+                .get_all(name)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .any(|v| glob(&pattern.to_ascii_lowercase(), &v.to_ascii_lowercase()))
+        };
+        (self.methods.is_empty() || method.is_some_and(|m| self.methods.iter().any(|allowed| allowed.eq_ignore_ascii_case(m))))
+            && self.path.as_ref().is_none_or(|pattern| path.is_some_and(|p| glob(pattern, p)))
+            && self.user_agent.as_ref().is_none_or(|pattern| header(USER_AGENT.as_str(), pattern))
+            && self.headers.iter().all(|(name, pattern)| header(name, pattern))
+            && self.missing_headers.iter().all(|name| !headers.contains_key(name.as_str()))
+    }
+}
@@ -0,0 +1,41 @@
+use serde::Deserialize;
+use tower_http::compression::{predicate::{NotForContentType, Predicate, SizeAbove}, CompressionLayer};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    /// Responses with smaller bodies are sent uncompressed.
+    pub min_bytes: u16,
+    pub gzip: bool,
+    pub br: bool,
+    pub zstd: bool,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            min_bytes: 1024,
+            gzip: true,
+            br: true,
+            zstd: true,
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// Compresses responses in the encoding the client prefers among the
+    /// enabled ones. Responses already encoded, such as compressed
+    /// downstream responses, and gRPC, image and event stream responses are
+    /// left as they are.
+    pub fn layer(&self) -> CompressionLayer<impl Predicate + use<>> {
+        let predicate = SizeAbove::new(self.min_bytes)
+            .and(NotForContentType::GRPC)
+            .and(NotForContentType::IMAGES)
+            .and(NotForContentType::SSE);
+        CompressionLayer::new()
+            .gzip(self.gzip)
+            .br(self.br)
+            .zstd(self.zstd)
+            .compress_when(predicate)
+    }
+}
@@ -0,0 +1,97 @@
+//! Cap on the requests proxied at once, so grenze degrades predictably under
+//! overload. Requests past the cap wait in a bounded queue for a slot; those
+//! finding the queue full, or waiting too long, are answered 503 with a
+//! `Retry-After`.
+
+use anyhow::{bail, Result};
+use axum::{extract::{Request, State}, http::{header::RETRY_AFTER, HeaderValue, StatusCode}, middleware::Next, response::{IntoResponse, Response}, Json};
+use serde::Deserialize;
+use serde_json::json;
+use std::{sync::{atomic::{AtomicUsize, Ordering}, Arc}, time::Duration};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{metrics::Metrics, rejection::{Reason, X_REJECTION_REASON}};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConcurrencyConfig {
+    /// Most requests proxied at once.
+    pub max_in_flight: usize,
+    /// Requests waiting for a slot while all are taken; further ones are
+    /// rejected right away.
+    #[serde(default)]
+    pub max_queued: usize,
+    /// Longest a request waits in the queue before it is rejected.
+    #[serde(default = "default_queue_timeout_ms")]
+    pub queue_timeout_ms: u64,
+    /// Sent as `Retry-After` with rejections.
+    #[serde(default = "default_retry_after_secs")]
+    pub retry_after_secs: u64,
+}
+
+fn default_queue_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_retry_after_secs() -> u64 {
+    1
+}
+
+impl ConcurrencyConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_in_flight == 0 {
+            bail!("concurrency max_in_flight must be positive");
+        }
+        if self.max_queued > 0 && self.queue_timeout_ms == 0 {
+            bail!("concurrency queue_timeout_ms must be positive with a queue");
+        }
+        Ok(())
+    }
+}
+
+pub struct ConcurrencyLimit {
+    config: ConcurrencyConfig,
+    slots: Arc<Semaphore>,
+    queued: AtomicUsize,
+    metrics: Arc<Metrics>,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(config: &ConcurrencyConfig, metrics: Arc<Metrics>) -> Self {
+        Self {
+            config: config.clone(),
+            slots: Arc::new(Semaphore::new(config.max_in_flight)),
+            queued: AtomicUsize::new(0),
+            metrics,
+        }
+    }
+
+    /// Takes a slot, queueing for one while all are taken. None if the queue
+    /// is full or no slot frees up in time.
+    async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        if let Ok(permit) = self.slots.clone().try_acquire_owned() {
+            return Some(permit);
+        }
+        if self.queued.fetch_add(1, Ordering::SeqCst) >= self.config.max_queued {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        let permit = tokio::time::timeout(Duration::from_millis(self.config.queue_timeout_ms), self.slots.clone().acquire_owned()).await;
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        permit.ok()?.ok()
+    }
+}
+
+/// Runs the request while holding a slot, or answers 503 without one.
+pub async fn limit(State(limit): State<Arc<ConcurrencyLimit>>, req: Request, next: Next) -> Response {
+    let Some(_permit) = limit.acquire().await else {
+        limit.metrics.record_rejection(Reason::Overloaded);
+        let payload = Json(json!({
+            "error": Reason::Overloaded.code(),
+            "message": Reason::Overloaded.message()
+        }));
+        let headers = [(RETRY_AFTER, HeaderValue::from(limit.config.retry_after_secs)), (X_REJECTION_REASON, HeaderValue::from_static(Reason::Overloaded.code()))];
+        return (StatusCode::SERVICE_UNAVAILABLE, headers, payload).into_response();
+    };
+    next.run(req).await
+}
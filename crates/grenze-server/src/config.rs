@@ -0,0 +1,341 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::time::Duration;
+
+#[cfg(feature = "wasm")]
+use crate::plugin::{PluginManager, PluginsConfig};
+use crate::{anomaly::AnomalyConfig, api::{admin::AdminConfig, passthrough::PassthroughConfig, reservations::ReservationsConfig}, billing::BillingConfig, chaos::ChaosConfig, classify::ClassificationConfig, client_profiles::{self, ClientProfile}, compression::CompressionConfig, concurrency::ConcurrencyConfig, cors::CorsConfig, credentials::{SecretStore, SecretsConfig}, destinations::DestinationsConfig, etcd::EtcdConfig, geoip::{GeoIp, GeoIpConfig}, key::{KeyConfig, KeyTemplate}, leader::LeaderConfig, limiter::{BucketSize, LimiterConfig, RedisConfig, StoreConfig}, logging::LoggingConfig, overrides::OverridesConfig, policy::{Policy, PolicySet}, recording::{RecordingConfig, RecordingSink}, rejection::RejectionsConfig, rules::KeyRulesConfig, script::{ScriptConfig, Scripts}, server::ServerConfig, sidecar::SidecarConfig, signing::SigningConfig, slo::SloConfig, statsd::StatsdConfig, tls::TlsConfig, trace::TracingConfig, transform::TransformRegistry, usage::UsageConfig};
+
+/// Server configuration, read from the JSON file referenced by `GRENZE_CONFIG`.
+/// Every section is optional; a missing file keeps the built-in defaults.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub key: KeyConfig,
+    /// Bucket storage and its time source.
+    pub limiter: LimiterConfig,
+    /// Credentials and connection settings for `REDIS_URL`.
+    pub redis: RedisConfig,
+    /// Retries while Redis is not reachable at startup.
+    pub startup: StartupConfig,
+    /// Socket options of the listener and the runtime serving it.
+    pub server: ServerConfig,
+    /// Cap on the requests proxied at once; unlimited when absent.
+    pub concurrency: Option<ConcurrencyConfig>,
+    /// Election of the instance running background tasks.
+    pub leader: LeaderConfig,
+    pub policies: Vec<Policy>,
+    /// Schemes and ports proxied requests may go to.
+    pub destinations: DestinationsConfig,
+    /// Clients for upstreams needing other timeouts, pools, proxies, TLS
+    /// settings or HTTP versions than the default client.
+    pub client_profiles: Vec<ClientProfile>,
+    /// Policies loaded from etcd and kept up to date while running.
+    pub etcd: Option<EtcdConfig>,
+    /// CORS handling for browser clients; disabled when absent.
+    pub cors: Option<CorsConfig>,
+    /// Compression of responses for clients accepting it; disabled when
+    /// absent.
+    pub compression: Option<CompressionConfig>,
+    /// Serve over TLS, optionally requiring client certificates.
+    pub tls: Option<TlsConfig>,
+    /// HMAC request signing with per-key shared secrets.
+    pub signing: Option<SigningConfig>,
+    /// Secret stores downstream credentials are fetched from.
+    pub secrets: SecretsConfig,
+    /// WASM plugins run on every request and response.
+    #[cfg(feature = "wasm")]
+    pub plugins: PluginsConfig,
+    /// Enables the admin API below `/admin`.
+    pub admin: Option<AdminConfig>,
+    /// Enables reserving tokens ahead of use via `/reserve`.
+    pub reservations: Option<ReservationsConfig>,
+    /// Enables proxying without the JSON envelope below `/p/`.
+    pub passthrough: Option<PassthroughConfig>,
+    /// Forwards all other requests to one upstream under the pod's key.
+    pub sidecar: Option<SidecarConfig>,
+    /// Blocks or limits requests by the country and autonomous system of
+    /// the client.
+    pub geoip: Option<GeoIpConfig>,
+    /// Blocks requests, or assigns them a policy, by their headers and path.
+    pub classification: Option<ClassificationConfig>,
+    /// Lets trusted callers size buckets per request.
+    pub overrides: Option<OverridesConfig>,
+    /// Rhai script computing keys, policies or destinations.
+    pub script: Option<ScriptConfig>,
+    /// Pushes metrics to a StatsD or DogStatsD agent.
+    pub statsd: Option<StatsdConfig>,
+    /// Tracks success rate and latency of destinations against objectives.
+    pub slo: Option<SloConfig>,
+    /// Flags keys deviating from their usual behavior, optionally limiting
+    /// them under a stricter policy for a while.
+    pub anomalies: Option<AnomalyConfig>,
+    /// Usage history per key, rolled up by minute, hour and day.
+    pub usage: Option<UsageConfig>,
+    /// Plans and billing reports, computed from the usage history.
+    pub billing: Option<BillingConfig>,
+    /// Responses to rate limited requests by tenant, and for policies
+    /// without their own.
+    pub rejections: Option<RejectionsConfig>,
+    /// Records proxied requests for replay through the admin API.
+    pub recording: Option<RecordingConfig>,
+    /// Trace context propagation and export of spans to Zipkin or Jaeger.
+    pub tracing: Option<TracingConfig>,
+    /// Bans and bucket sizes of single keys written through the admin API,
+    /// stored in Redis.
+    pub key_rules: Option<KeyRulesConfig>,
+    /// Sampling of request logs by outcome and suppression of repeated
+    /// warnings.
+    pub logging: Option<LoggingConfig>,
+    /// Fault injection for testing clients; never enable in production.
+    pub chaos: Option<ChaosConfig>,
+    /// Custom response transformations registered in code, which policies
+    /// reference by name.
+    #[serde(skip)]
+    pub transforms: TransformRegistry,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StartupConfig {
+    /// Attempts before giving up; retries forever if 0.
+    pub max_attempts: u32,
+    /// Delay after the first failed attempt, doubling after every further one.
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Time an attempt may take before it counts as failed.
+    pub attempt_timeout_ms: u64,
+}
+
+impl Default for StartupConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            initial_backoff_ms: 300,
+            max_backoff_ms: 10_000,
+            attempt_timeout_ms: 10_000,
+        }
+    }
+}
+
+impl StartupConfig {
+    /// Delay after the failed `attempt`, counting from 1.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u64.saturating_pow(attempt.saturating_sub(1));
+        Duration::from_millis(self.initial_backoff_ms.saturating_mul(factor).min(self.max_backoff_ms))
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        match std::env::var("GRENZE_CONFIG") {
+            Ok(path) => Self::from_file(&path),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<Self> {
+        Self::from_file_with_transforms(path, TransformRegistry::default())
+    }
+
+    /// Reads the config file at `path`, whose policies may reference the
+    /// custom transformations in `transforms`.
+    pub fn from_file_with_transforms(path: &str, transforms: TransformRegistry) -> Result<Self> {
+        let raw = std::fs::read_to_string(path).with_context(|| format!("failed to read config file {}", path))?;
+        let mut config: Self = serde_json::from_str(&raw).with_context(|| format!("invalid config file {}", path))?;
+        config.transforms = transforms;
+        config.validate().with_context(|| format!("invalid config file {}", path))?;
+        Ok(config)
+    }
+
+    /// Checks the parts of the configuration serde cannot, so mistakes surface at
+    /// startup instead of on the first request.
+    pub fn validate(&self) -> Result<()> {
+        KeyTemplate::compile(&self.key)?;
+        PolicySet::new(self.policies.clone())?;
+        for policy in &self.policies {
+            self.validate_policy(policy)?;
+        }
+        self.destinations.validate()?;
+        self.secrets.validate()?;
+        client_profiles::validate(&self.client_profiles)?;
+        if let Some(cors) = &self.cors {
+            let _ = cors.layer()?;
+        }
+        #[cfg(feature = "wasm")]
+        PluginManager::new(&self.plugins)?;
+        if let Some(script) = &self.script {
+            Scripts::new(script)?;
+        }
+        self.redis.validate()?;
+        self.server.validate()?;
+        if let Some(concurrency) = &self.concurrency {
+            concurrency.validate()?;
+        }
+        self.leader.validate()?;
+        if let Some(sidecar) = &self.sidecar {
+            sidecar.validate()?;
+        }
+        self.limiter.validate()?;
+        if let Some(statsd) = &self.statsd {
+            statsd.validate()?;
+        }
+        if let Some(slo) = &self.slo {
+            slo.validate()?;
+        }
+        if let Some(usage) = &self.usage {
+            usage.validate()?;
+            if !matches!(self.limiter.store, StoreConfig::Redis | StoreConfig::RedisShards(_)) {
+                bail!("usage history requires a redis store");
+            }
+        }
+        if let Some(billing) = &self.billing {
+            billing.validate()?;
+            if self.usage.is_none() {
+                bail!("billing requires the usage history");
+            }
+        }
+        if let Some(rejections) = &self.rejections {
+            rejections.validate()?;
+        }
+        if let Some(recording) = &self.recording {
+            recording.validate()?;
+            if matches!(recording.sink, RecordingSink::Redis { .. }) && !matches!(self.limiter.store, StoreConfig::Redis) {
+                bail!("recording to redis requires the redis store");
+            }
+        }
+        if let Some(tracing) = &self.tracing {
+            tracing.validate()?;
+        }
+        if let Some(logging) = &self.logging {
+            logging.validate()?;
+        }
+        if let Some(key_rules) = &self.key_rules {
+            key_rules.validate()?;
+            if !matches!(self.limiter.store, StoreConfig::Redis) {
+                bail!("key_rules requires the redis store");
+            }
+        }
+        if let Some(chaos) = &self.chaos {
+            chaos.validate()?;
+        }
+        if let Some(tls) = &self.tls {
+            tls.server_config()?;
+        }
+        if let Some(etcd) = &self.etcd {
+            etcd.validate()?;
+        }
+        if let Some(reservations) = &self.reservations {
+            reservations.validate()?;
+            // Settling returns tokens, which only the redis stores can
+            if !matches!(self.limiter.store, StoreConfig::Redis | StoreConfig::RedisShards(_)) {
+                bail!("reservations require a redis store");
+            }
+        }
+        if let Some(passthrough) = &self.passthrough {
+            passthrough.validate()?;
+        }
+        if let Some(overrides) = &self.overrides {
+            overrides.validate()?;
+        }
+        if let Some(geoip) = &self.geoip {
+            geoip.validate()?;
+            GeoIp::new(geoip)?;
+        }
+        // Policies from etcd may only appear at runtime
+        let policies = PolicySet::new(self.policies.clone())?;
+        let known = self.etcd.is_none().then_some(&policies);
+        if let Some(classification) = &self.classification {
+            classification.validate(known)?;
+        }
+        if let Some(anomalies) = &self.anomalies {
+            anomalies.validate(known)?;
+        }
+        Ok(())
+    }
+
+    /// Checks a policy against the rest of the configuration, e.g. that the
+    /// secret stores its credentials come from are configured.
+    pub fn validate_policy(&self, policy: &Policy) -> Result<()> {
+        if policy.name.contains('#') {
+            bail!("policy '{}': names must not contain '#', which separates them from the rest of bucket names", policy.name);
+        }
+        BucketSize::validate(policy.capacity, policy.leak_per_sec).with_context(|| format!("policy '{}'", policy.name))?;
+        if let Some(auth) = &policy.auth {
+            SecretStore::validate(&self.secrets, auth).with_context(|| format!("policy '{}'", policy.name))?;
+        }
+        self.transforms
+            .validate(&policy.transform)
+            .with_context(|| format!("policy '{}'", policy.name))?;
+        for mock in &policy.mocks {
+            mock.validate().with_context(|| format!("policy '{}'", policy.name))?;
+        }
+        for limit in &policy.limits {
+            limit.validate().with_context(|| format!("policy '{}'", policy.name))?;
+        }
+        if !policy.limits.is_empty() && !matches!(self.limiter.store, StoreConfig::Redis) {
+            bail!("policy '{}': limits require the redis store", policy.name);
+        }
+        for window in &policy.windows {
+            window.validate().with_context(|| format!("policy '{}'", policy.name))?;
+            if policy.windows.iter().filter(|w| w.window_secs == window.window_secs).count() > 1 {
+                bail!("policy '{}': windows must differ in window_secs", policy.name);
+            }
+        }
+        if !policy.windows.is_empty() {
+            if policy.capacity.is_some() || policy.leak_per_sec.is_some() || policy.sliding_log.is_some() {
+                bail!("policy '{}': windows replace capacity, leak_per_sec and sliding_log", policy.name);
+            }
+            if policy.windows.len() > 1 && !matches!(self.limiter.store, StoreConfig::Redis) {
+                bail!("policy '{}': several windows require the redis store", policy.name);
+            }
+        }
+        if let Some(log) = &policy.sliding_log {
+            log.validate().with_context(|| format!("policy '{}'", policy.name))?;
+            if !matches!(self.limiter.store, StoreConfig::Redis) {
+                bail!("policy '{}': sliding_log requires the redis store", policy.name);
+            }
+            if policy.penalty.is_some() {
+                bail!("policy '{}': penalty does not apply to a sliding_log", policy.name);
+            }
+        }
+        if let Some(bandwidth) = &policy.bandwidth {
+            bandwidth.validate().with_context(|| format!("policy '{}'", policy.name))?;
+        }
+        if let Some(penalty) = &policy.penalty {
+            penalty.validate().with_context(|| format!("policy '{}'", policy.name))?;
+        }
+        if let Some(warmup) = &policy.warmup {
+            warmup.validate().with_context(|| format!("policy '{}'", policy.name))?;
+        }
+        if let Some(cookies) = &policy.cookies {
+            cookies.validate().with_context(|| format!("policy '{}'", policy.name))?;
+        }
+        if let Some(encoding) = &policy.encoding {
+            encoding.validate().with_context(|| format!("policy '{}'", policy.name))?;
+        }
+        if let Some(rejection) = &policy.rejection {
+            rejection.validate().with_context(|| format!("policy '{}'", policy.name))?;
+        }
+        if let Some(hedge) = &policy.hedge {
+            hedge.validate().with_context(|| format!("policy '{}'", policy.name))?;
+        }
+        if let Some(validation) = &policy.validation {
+            validation.validate().with_context(|| format!("policy '{}'", policy.name))?;
+        }
+        if let Some(upstream_tls) = &policy.upstream_tls {
+            upstream_tls.validate().with_context(|| format!("policy '{}'", policy.name))?;
+        }
+        if let Some(profile) = &policy.client_profile
+            && !self.client_profiles.iter().any(|p| &p.name == profile)
+        {
+            bail!("policy '{}': client profile '{}' is not defined", policy.name, profile);
+        }
+        if (policy.bandwidth.is_some() || policy.penalty.is_some() || policy.warmup.is_some() || policy.cookies.is_some())
+            && !matches!(self.limiter.store, StoreConfig::Redis | StoreConfig::RedisShards(_))
+        {
+            bail!("policy '{}': bandwidth charges, penalties, warm-up and cookie jars require a redis store", policy.name);
+        }
+        Ok(())
+    }
+}
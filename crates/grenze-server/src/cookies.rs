@@ -0,0 +1,82 @@
+//! Cookie jars per rate limit key, for upstreams whose request sequences
+//! depend on session cookies. Cookies the downstream sets are kept in the
+//! bucket store, shared by all instances, and sent along with the key's
+//! following requests to matching URLs.
+
+use anyhow::{anyhow, bail, Result};
+use axum::http::{header::SET_COOKIE, HeaderMap};
+use cookie_store::{CookieStore, RawCookie};
+use serde::Deserialize;
+
+use crate::limiter::LimiterStore;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CookieJarConfig {
+    /// A key's jar is dropped once no cookie was set for this long.
+    #[serde(default = "default_ttl_secs")]
+    pub ttl_secs: u64,
+}
+
+fn default_ttl_secs() -> u64 {
+    3600
+}
+
+impl CookieJarConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.ttl_secs == 0 {
+            bail!("cookies ttl_secs must be positive");
+        }
+        Ok(())
+    }
+}
+
+/// Name of the jar of `key` under `policy`. Policies do not share jars.
+pub fn jar_name(policy: &str, key: &str) -> String {
+    format!("jar:{}:{}", policy, key)
+}
+
+/// The jar named `name`, empty if there is none. Unexpired session cookies
+/// are kept, as the jar stands in for the client's session.
+pub async fn load(limiter: &dyn LimiterStore, name: &str) -> Result<CookieStore> {
+    match limiter.cookie_jar(name).await? {
+        Some(jar) => cookie_store::serde::json::load(jar.as_bytes()).map_err(|e| anyhow!("invalid cookie jar {}: {}", name, e)),
+        None => Ok(CookieStore::default()),
+    }
+}
+
+/// Adds the cookies `set_cookie` headers from `url` set to `jar` and saves
+/// it, if there are any.
+pub async fn store(limiter: &dyn LimiterStore, config: &CookieJarConfig, name: &str, jar: &mut CookieStore, url: &reqwest::Url, headers: &HeaderMap) -> Result<()> {
+    let cookies: Vec<_> = headers
+        .get_all(SET_COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .filter_map(|v| RawCookie::parse(v.to_string()).ok())
+        .collect();
+    if cookies.is_empty() {
+        return Ok(());
+    }
+    jar.store_response_cookies(cookies.into_iter(), url);
+    let mut saved = Vec::new();
+    cookie_store::serde::json::save_incl_expired_and_nonpersistent(jar, &mut saved).map_err(|e| anyhow!("{}", e))?;
+    limiter.store_cookie_jar(name, &String::from_utf8_lossy(&saved), config.ttl_secs).await
+}
+
+/// Value of the `Cookie` header for a request to `url`: the cookies the
+/// caller `sent`, followed by those from `jar` the caller did not send
+/// itself.
+pub fn header(jar: &CookieStore, url: &reqwest::Url, sent: Option<&str>) -> Option<String> {
+    let sent_names: Vec<&str> = sent
+        .into_iter()
+        .flat_map(|s| s.split(';'))
+        .filter_map(|pair| pair.split_once('=').map(|(name, _)| name.trim()))
+        .collect();
+    let mut pairs: Vec<String> = sent.filter(|s| !s.trim().is_empty()).map(|s| s.trim().to_string()).into_iter().collect();
+    pairs.extend(
+        jar.get_request_values(url)
+            .filter(|(name, _)| !sent_names.contains(name))
+            .map(|(name, value)| format!("{}={}", name, value)),
+    );
+    (!pairs.is_empty()).then(|| pairs.join("; "))
+}
@@ -0,0 +1,72 @@
+use anyhow::{bail, Context, Result};
+use axum::http::{HeaderName, HeaderValue, Method};
+use serde::Deserialize;
+use std::time::Duration;
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins allowed to call grenze; `*` allows any origin.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    /// Request headers browsers may send; `*` allows any header.
+    pub allowed_headers: Vec<String>,
+    /// How long browsers may cache preflight responses.
+    pub max_age_secs: u64,
+    pub allow_credentials: bool,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+            allowed_headers: vec!["content-type".to_string(), "authorization".to_string()],
+            max_age_secs: 600,
+            allow_credentials: false,
+        }
+    }
+}
+
+impl CorsConfig {
+    pub fn layer(&self) -> Result<CorsLayer> {
+        let wildcard = |v: &[String]| v.iter().any(|s| s == "*");
+        if self.allow_credentials && (wildcard(&self.allowed_origins) || wildcard(&self.allowed_headers)) {
+            bail!("cors: allow_credentials cannot be combined with wildcard origins or headers");
+        }
+
+        let origins = if wildcard(&self.allowed_origins) {
+            AllowOrigin::any()
+        } else {
+            let origins = self
+                .allowed_origins
+                .iter()
+                .map(|o| HeaderValue::from_str(o).with_context(|| format!("cors: invalid origin '{}'", o)))
+                .collect::<Result<Vec<_>>>()?;
+            AllowOrigin::list(origins)
+        };
+        let methods = self
+            .allowed_methods
+            .iter()
+            .map(|m| Method::from_bytes(m.to_uppercase().as_bytes()).with_context(|| format!("cors: invalid method '{}'", m)))
+            .collect::<Result<Vec<_>>>()?;
+        let headers = if wildcard(&self.allowed_headers) {
+            AllowHeaders::any()
+        } else {
+            let headers = self
+                .allowed_headers
+                .iter()
+                .map(|h| HeaderName::from_bytes(h.as_bytes()).with_context(|| format!("cors: invalid header '{}'", h)))
+                .collect::<Result<Vec<_>>>()?;
+            AllowHeaders::list(headers)
+        };
+
+        Ok(CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(AllowMethods::list(methods))
+            .allow_headers(headers)
+            .allow_credentials(self.allow_credentials)
+            .max_age(Duration::from_secs(self.max_age_secs)))
+    }
+}
@@ -0,0 +1,261 @@
+use anyhow::{anyhow, bail, Context, Result};
+use axum::http::HeaderValue;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
+use tokio::sync::Mutex;
+
+use crate::{aws::{AwsClient, AwsConfig}, logging, oauth::{OAuth2Config, TokenManager}};
+
+/// Credentials grenze attaches to downstream requests of a policy, so clients
+/// never handle upstream secrets themselves.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum DownstreamAuth {
+    Bearer(SecretRef),
+    Basic { username: String, password: SecretRef },
+    /// Bearer token obtained through the OAuth2 client credentials grant.
+    Oauth2(OAuth2Config),
+}
+
+/// A secret value, either inline or fetched from a secret store.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum SecretRef {
+    Static(String),
+    Vault {
+        vault: VaultRef,
+    },
+    AwsSecretsManager {
+        aws_secrets_manager: AwsSecretRef,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VaultRef {
+    /// Secret path below `/v1/`, e.g. `secret/data/partner`.
+    pub path: String,
+    pub field: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AwsSecretRef {
+    pub secret_id: String,
+    /// Field of a JSON secret; the whole secret string is used if unset.
+    #[serde(default)]
+    pub field: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecretsConfig {
+    pub vault: Option<VaultConfig>,
+    pub aws: Option<AwsConfig>,
+    /// How long fetched secrets are cached before they are fetched again,
+    /// picking up rotated values.
+    pub cache_ttl_secs: u64,
+    /// Time fetching a secret from a store may take.
+    pub fetch_timeout_ms: u64,
+}
+
+impl Default for SecretsConfig {
+    fn default() -> Self {
+        Self {
+            vault: None,
+            aws: None,
+            cache_ttl_secs: 300,
+            fetch_timeout_ms: 5_000,
+        }
+    }
+}
+
+impl SecretsConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.fetch_timeout_ms == 0 {
+            bail!("secrets fetch_timeout_ms must be positive");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VaultConfig {
+    pub address: String,
+    /// Vault token; read from `VAULT_TOKEN` if unset.
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default)]
+    pub namespace: Option<String>,
+}
+
+impl DownstreamAuth {
+    fn secret(&self) -> &SecretRef {
+        match self {
+            Self::Bearer(s) => s,
+            Self::Basic { password, .. } => password,
+            Self::Oauth2(oauth) => &oauth.client_secret,
+        }
+    }
+}
+
+struct Cached {
+    value: String,
+    fetched: Instant,
+}
+
+/// Cache entry of one secret, locked while it is fetched so concurrent
+/// requests for it wait for that fetch instead of starting their own.
+type Slot = Arc<Mutex<Option<Cached>>>;
+
+/// Resolves secret references, caching fetched values for the configured TTL.
+/// If refreshing a secret fails, the previous value is kept in use.
+pub struct SecretStore {
+    http: reqwest::Client,
+    config: SecretsConfig,
+    aws: Option<AwsClient>,
+    cache: Mutex<HashMap<String, Slot>>,
+    tokens: TokenManager,
+}
+
+impl SecretStore {
+    pub fn new(http: reqwest::Client, config: SecretsConfig) -> Self {
+        let aws = config.aws.clone().map(|c| AwsClient::new(http.clone(), c));
+        Self {
+            http,
+            config,
+            aws,
+            cache: Mutex::new(HashMap::new()),
+            tokens: TokenManager::default(),
+        }
+    }
+
+    /// Checks that every secret store referenced by `auth` is configured.
+    pub fn validate(config: &SecretsConfig, auth: &DownstreamAuth) -> Result<()> {
+        match auth.secret() {
+            SecretRef::Static(_) => Ok(()),
+            SecretRef::Vault { .. } if config.vault.is_none() => bail!("vault secret referenced but secrets.vault is not configured"),
+            SecretRef::AwsSecretsManager { .. } if config.aws.is_none() => bail!("AWS secret referenced but secrets.aws is not configured"),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fetches the secret of `auth`, failing if it does not resolve.
+    pub async fn check(&self, auth: &DownstreamAuth) -> Result<()> {
+        self.resolve(auth.secret()).await.map(|_| ())
+    }
+
+    /// Value of the `Authorization` header for `auth`.
+    pub async fn authorization(&self, auth: &DownstreamAuth) -> Result<HeaderValue> {
+        let secret = self.resolve(auth.secret()).await?;
+        let value = match auth {
+            DownstreamAuth::Bearer(_) => format!("Bearer {}", secret),
+            DownstreamAuth::Basic { username, .. } => format!("Basic {}", STANDARD.encode(format!("{}:{}", username, secret))),
+            DownstreamAuth::Oauth2(oauth) => format!("Bearer {}", self.tokens.token(&self.http, oauth, &secret).await?),
+        };
+        let mut value = HeaderValue::from_str(&value).context("credential is not a valid header value")?;
+        value.set_sensitive(true);
+        Ok(value)
+    }
+
+    /// Called when the upstream rejected `rejected` with 401. Returns whether
+    /// a retry may succeed with fresh credentials.
+    pub async fn invalidate(&self, auth: &DownstreamAuth, rejected: &HeaderValue) -> bool {
+        let DownstreamAuth::Oauth2(oauth) = auth else {
+            return false;
+        };
+        if let Some(token) = rejected.to_str().ok().and_then(|v| v.strip_prefix("Bearer ")) {
+            self.tokens.invalidate(oauth, token).await;
+        }
+        true
+    }
+
+    pub async fn resolve(&self, secret: &SecretRef) -> Result<String> {
+        let cache_key = match secret {
+            SecretRef::Static(value) => return Ok(value.clone()),
+            SecretRef::Vault { vault } => format!("vault:{}#{}", vault.path, vault.field),
+            SecretRef::AwsSecretsManager { aws_secrets_manager: r } => {
+                format!("aws:{}#{}", r.secret_id, r.field.as_deref().unwrap_or_default())
+            },
+        };
+
+        // A slow store only holds up the requests needing the same secret
+        let slot = self.cache.lock().await.entry(cache_key.clone()).or_default().clone();
+        let mut cached = slot.lock().await;
+        let ttl = Duration::from_secs(self.config.cache_ttl_secs);
+        if let Some(cached) = cached.as_ref()
+            && cached.fetched.elapsed() < ttl
+        {
+            return Ok(cached.value.clone());
+        }
+        let fetch = async {
+            match secret {
+                SecretRef::Static(_) => unreachable!(),
+                SecretRef::Vault { vault } => self.fetch_vault(vault).await,
+                SecretRef::AwsSecretsManager { aws_secrets_manager } => self.fetch_aws(aws_secrets_manager).await,
+            }
+        };
+        let timeout = Duration::from_millis(self.config.fetch_timeout_ms);
+        let fetched = tokio::time::timeout(timeout, fetch)
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("fetching the secret timed out after {}ms", self.config.fetch_timeout_ms)));
+        match fetched {
+            Ok(value) => {
+                *cached = Some(Cached {
+                    value: value.clone(),
+                    fetched: Instant::now(),
+                });
+                Ok(value)
+            },
+            Err(e) => match cached.as_ref() {
+                Some(stale) => {
+                    logging::warn("secrets", format_args!("Failed to refresh secret {}, keeping previous value: {}", cache_key, e));
+                    Ok(stale.value.clone())
+                },
+                None => Err(e),
+            },
+        }
+    }
+
+    async fn fetch_vault(&self, secret: &VaultRef) -> Result<String> {
+        let vault = self.config.vault.as_ref().ok_or_else(|| anyhow!("vault is not configured"))?;
+        let token = match &vault.token {
+            Some(t) => t.clone(),
+            None => std::env::var("VAULT_TOKEN").context("VAULT_TOKEN must be set")?,
+        };
+        let url = format!("{}/v1/{}", vault.address.trim_end_matches('/'), secret.path.trim_start_matches('/'));
+        let mut request = self.http.get(url).header("x-vault-token", token);
+        if let Some(ns) = &vault.namespace {
+            request = request.header("x-vault-namespace", ns);
+        }
+        let body: serde_json::Value = request.send().await?.error_for_status()?.json().await?;
+        // KV v2 nests the secret below data.data, KV v1 below data
+        let data = &body["data"];
+        data["data"][&secret.field]
+            .as_str()
+            .or_else(|| data[&secret.field].as_str())
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("vault secret {} has no field {}", secret.path, secret.field))
+    }
+
+    async fn fetch_aws(&self, secret: &AwsSecretRef) -> Result<String> {
+        let aws = self.aws.as_ref().ok_or_else(|| anyhow!("AWS is not configured"))?;
+        let body = serde_json::json!({ "SecretId": secret.secret_id });
+        let response = aws.call("secretsmanager", "1.1", "secretsmanager.GetSecretValue", &body).await?;
+        let value = response["SecretString"]
+            .as_str()
+            .ok_or_else(|| anyhow!("AWS secret {} has no SecretString", secret.secret_id))?;
+        match &secret.field {
+            None => Ok(value.to_string()),
+            Some(field) => {
+                let parsed: serde_json::Value = serde_json::from_str(value)?;
+                parsed[field]
+                    .as_str()
+                    .map(str::to_string)
+                    .ok_or_else(|| anyhow!("AWS secret {} has no field {}", secret.secret_id, field))
+            },
+        }
+    }
+}
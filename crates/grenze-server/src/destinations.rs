@@ -0,0 +1,65 @@
+//! Restrictions on where proxied requests may go. Destination URLs are parsed
+//! and checked against the allowed schemes and ports before a request takes a
+//! token, so malformed or disallowed ones are refused with a clear error
+//! instead of failing once sent.
+
+use anyhow::{bail, Result};
+use axum::http::StatusCode;
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::api::ApiError;
+
+/// Schemes the downstream client speaks.
+const SUPPORTED_SCHEMES: [&str; 2] = ["http", "https"];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DestinationsConfig {
+    /// Schemes destination URLs may use; `["https"]` refuses plain HTTP.
+    pub schemes: Vec<String>,
+    /// Ports destination URLs may use, given or implied by the scheme; any
+    /// if empty.
+    pub ports: Vec<u16>,
+}
+
+impl Default for DestinationsConfig {
+    fn default() -> Self {
+        Self {
+            schemes: SUPPORTED_SCHEMES.map(str::to_string).to_vec(),
+            ports: Vec::new(),
+        }
+    }
+}
+
+impl DestinationsConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.schemes.is_empty() {
+            bail!("destinations need at least one scheme");
+        }
+        if let Some(scheme) = self.schemes.iter().find(|s| !SUPPORTED_SCHEMES.contains(&s.as_str())) {
+            bail!("destination scheme '{}' is not supported; use http or https", scheme);
+        }
+        if self.ports.contains(&0) {
+            bail!("destination port 0 is not a port");
+        }
+        Ok(())
+    }
+
+    /// Parses the destination `url` of a request and checks it may be
+    /// called.
+    pub fn parse(&self, url: &str) -> Result<Url, ApiError> {
+        let url = Url::parse(url).map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, "invalid_url", format!("url '{}' is invalid: {}", url, e)))?;
+        if !SUPPORTED_SCHEMES.contains(&url.scheme()) || url.host_str().is_none_or(str::is_empty) {
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, "invalid_url", "url must be an absolute http(s) URL with a host"));
+        }
+        if !self.schemes.iter().any(|s| s == url.scheme()) {
+            return Err(ApiError::new(StatusCode::FORBIDDEN, "destination_not_allowed", format!("Destinations must use {}", self.schemes.join(" or "))));
+        }
+        let port = url.port_or_known_default().expect("http(s) has a default port");
+        if !self.ports.is_empty() && !self.ports.contains(&port) {
+            return Err(ApiError::new(StatusCode::FORBIDDEN, "destination_not_allowed", format!("Destination port {} is not allowed", port)));
+        }
+        Ok(url)
+    }
+}
@@ -0,0 +1,149 @@
+//! Bucket store over DynamoDB, for AWS-native deployments without Redis.
+//!
+//! The table needs a string partition key `pk`. Items carry their expiry in
+//! `expires_at` (seconds since the epoch); enabling DynamoDB TTL on that
+//! attribute removes idle buckets.
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{aws::{AwsClient, AwsConfig, AwsError}, limiter::{bucket_ttl_ms, Admission, Bucket, Clock, LimiterStore, SystemClock}};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DynamoDbConfig {
+    pub table: String,
+    pub region: String,
+    /// Overrides the DynamoDB endpoint, e.g. for DynamoDB Local.
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// Attempts at updating a bucket that other instances keep changing.
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+}
+
+fn default_attempts() -> u32 {
+    10
+}
+
+/// Buckets shared by all instances through a DynamoDB table, updated with
+/// conditional writes on a per-item version.
+pub struct DynamoDbStore {
+    client: AwsClient,
+    config: DynamoDbConfig,
+}
+
+impl DynamoDbStore {
+    pub async fn connect(config: &DynamoDbConfig) -> Result<Self> {
+        let aws = AwsConfig {
+            region: config.region.clone(),
+            endpoint: config.endpoint.clone(),
+        };
+        let store = Self {
+            client: AwsClient::new(reqwest::Client::new(), aws),
+            config: config.clone(),
+        };
+        store.ping().await?;
+        Ok(store)
+    }
+
+    async fn call(&self, operation: &str, body: Value) -> Result<Value> {
+        self.client.call("dynamodb", "1.0", &format!("DynamoDB_20120810.{}", operation), &body).await
+    }
+}
+
+/// Whether a conditional write lost against another writer.
+fn is_condition_failure(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<AwsError>().is_some_and(|e| e.is("ConditionalCheckFailedException"))
+}
+
+fn number(item: &Value, attribute: &str) -> Option<String> {
+    item.get(attribute)?.get("N")?.as_str().map(str::to_string)
+}
+
+#[async_trait]
+impl LimiterStore for DynamoDbStore {
+    async fn allow(&self, bucket: &str, cost: f64, capacity: u32, leak_per_sec: f64, now_ms: i64) -> Result<Admission> {
+        let pk = format!("rl:{}", bucket);
+        for _ in 0..self.config.attempts.max(1) {
+            let current = self
+                .call(
+                    "GetItem",
+                    json!({"TableName": self.config.table, "Key": {"pk": {"S": pk}}, "ConsistentRead": true}),
+                )
+                .await?;
+            let item = current.get("Item");
+            let version = item.and_then(|i| number(i, "version"));
+            let previous = item.and_then(|i| {
+                Some(Bucket {
+                    fill: number(i, "fill")?.parse().ok()?,
+                    last_ms: number(i, "last_ms")?.parse().ok()?,
+                    leak_per_sec,
+                })
+            });
+            let (allowed, next) = Bucket::admit(previous.as_ref(), cost, capacity as f64, leak_per_sec, now_ms);
+            let expires_at = (now_ms + bucket_ttl_ms(next.fill, leak_per_sec) + 999) / 1000;
+
+            let next_version = version.as_deref().and_then(|v| v.parse::<u64>().ok()).unwrap_or(0) + 1;
+            let mut put = json!({
+                "TableName": self.config.table,
+                "Item": {
+                    "pk": {"S": pk},
+                    "fill": {"N": next.fill.to_string()},
+                    "last_ms": {"N": next.last_ms.to_string()},
+                    "version": {"N": next_version.to_string()},
+                    "expires_at": {"N": expires_at.to_string()},
+                },
+            });
+            match &version {
+                Some(version) => {
+                    put["ConditionExpression"] = json!("version = :version");
+                    put["ExpressionAttributeValues"] = json!({":version": {"N": version}});
+                },
+                None => put["ConditionExpression"] = json!("attribute_not_exists(pk)"),
+            }
+            match self.call("PutItem", put).await {
+                Ok(_) => return Ok(Admission { allowed, fill: next.fill }),
+                Err(e) if is_condition_failure(&e) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        bail!("bucket {} kept changing during {} update attempts", bucket, self.config.attempts)
+    }
+
+    async fn remember(&self, key: &str, ttl_secs: u64) -> Result<bool> {
+        // TTL deletion lags behind, so expired items count as absent
+        let now = SystemClock.now_ms() / 1000;
+        let put = json!({
+            "TableName": self.config.table,
+            "Item": {
+                "pk": {"S": key},
+                "expires_at": {"N": (now + ttl_secs.max(1) as i64).to_string()},
+            },
+            "ConditionExpression": "attribute_not_exists(pk) OR expires_at < :now",
+            "ExpressionAttributeValues": {":now": {"N": now.to_string()}},
+        });
+        match self.call("PutItem", put).await {
+            Ok(_) => Ok(true),
+            Err(e) if is_condition_failure(&e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn forget(&self, key: &str) {
+        let _ = self.call("DeleteItem", json!({"TableName": self.config.table, "Key": {"pk": {"S": key}}})).await;
+    }
+
+    async fn evict(&self, bucket: &str) {
+        let _ = self
+            .call("DeleteItem", json!({"TableName": self.config.table, "Key": {"pk": {"S": format!("rl:{}", bucket)}}}))
+            .await;
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.call("DescribeTable", json!({"TableName": self.config.table})).await?;
+        Ok(())
+    }
+}
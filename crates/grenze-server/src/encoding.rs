@@ -0,0 +1,119 @@
+//! Content encodings of downstream responses: which ones grenze asks the
+//! downstream for, and decoding compressed responses before they are
+//! returned.
+
+use anyhow::{bail, Result};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use serde::Deserialize;
+use std::io::Read;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentEncoding {
+    Gzip,
+    Deflate,
+}
+
+impl ContentEncoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Self::Gzip),
+            "deflate" => Some(Self::Deflate),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncodingConfig {
+    /// Encodings requested from the downstream, most preferred first,
+    /// replacing the caller's `Accept-Encoding`; an empty list asks for
+    /// uncompressed responses. The caller's header is sent if unset.
+    #[serde(default)]
+    pub accept: Option<Vec<ContentEncoding>>,
+    /// Whether compressed responses are decoded before they are returned.
+    #[serde(default)]
+    pub decompress: bool,
+    /// Largest decoded body; responses decoding to more are rejected.
+    #[serde(default = "default_max_decompressed_bytes")]
+    pub max_decompressed_bytes: usize,
+}
+
+fn default_max_decompressed_bytes() -> usize {
+    16 * 1024 * 1024
+}
+
+impl Default for EncodingConfig {
+    fn default() -> Self {
+        Self {
+            accept: None,
+            decompress: false,
+            max_decompressed_bytes: default_max_decompressed_bytes(),
+        }
+    }
+}
+
+impl EncodingConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_decompressed_bytes == 0 {
+            bail!("encoding max_decompressed_bytes must be positive");
+        }
+        Ok(())
+    }
+
+    /// `Accept-Encoding` sent downstream, if the policy sets it.
+    pub fn accept_header(&self) -> Option<String> {
+        let accept = self.accept.as_ref()?;
+        if accept.is_empty() {
+            return Some("identity".to_string());
+        }
+        // Decreasing weights keep the configured preference
+        let weighted = accept
+            .iter()
+            .enumerate()
+            .map(|(i, e)| match i {
+                0 => e.name().to_string(),
+                i => format!("{};q={:.1}", e.name(), (1.0 - i as f64 * 0.1).max(0.1)),
+            })
+            .collect::<Vec<_>>();
+        Some(weighted.join(", "))
+    }
+}
+
+/// Decodes `body` sent with `Content-Encoding: content_encoding`, undoing
+/// the listed encodings in reverse order. `None` if one of them is not
+/// supported, in which case the body is returned as it is.
+pub fn decode(content_encoding: &str, body: &[u8], max_bytes: usize) -> Result<Option<Vec<u8>>> {
+    let mut encodings = Vec::new();
+    for name in content_encoding.split(',').filter(|n| !n.trim().is_empty()) {
+        if name.trim().eq_ignore_ascii_case("identity") {
+            continue;
+        }
+        let Some(encoding) = ContentEncoding::parse(name) else {
+            return Ok(None);
+        };
+        encodings.push(encoding);
+    }
+    let mut decoded = body.to_vec();
+    for encoding in encodings.iter().rev() {
+        let reader: Box<dyn Read> = match encoding {
+            ContentEncoding::Gzip => Box::new(GzDecoder::new(decoded.as_slice())),
+            ContentEncoding::Deflate => Box::new(ZlibDecoder::new(decoded.as_slice())),
+        };
+        let mut out = Vec::new();
+        reader.take(max_bytes as u64 + 1).read_to_end(&mut out)?;
+        if out.len() > max_bytes {
+            bail!("response decodes to more than {} bytes", max_bytes);
+        }
+        decoded = out;
+    }
+    Ok(Some(decoded))
+}
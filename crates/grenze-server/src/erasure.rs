@@ -0,0 +1,140 @@
+//! Erasure of everything stored about a tenant, e.g. to honor a deletion
+//! request under the GDPR. A tenant owns the rate limit key equal to its id
+//! and the keys starting with its id and the tenant delimiter, along with
+//! the buckets, markers, quotas and usage recorded for them.
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// Matches the names a tenant's data is stored under.
+#[derive(Debug, Clone)]
+pub struct Tenant {
+    pub id: String,
+    delimiter: String,
+}
+
+/// Kind of a record stored by name through the limiter store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Record {
+    FirstSeen,
+    CookieJar,
+    Quota,
+}
+
+impl Tenant {
+    pub fn new(id: &str, delimiter: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            delimiter: delimiter.to_string(),
+        }
+    }
+
+    /// Whether the rate limit key `key` is the tenant's.
+    pub fn owns_key(&self, key: &str) -> bool {
+        key.strip_prefix(self.id.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with(self.delimiter.as_str()))
+    }
+
+    /// Whether `bucket` only holds requests of the tenant's keys: the bucket
+    /// of a key, also per destination host, the buckets of its further
+    /// windows, its overage bucket, its fair share of a shared bucket, or the
+    /// limit bucket of a key prefix.
+    pub fn owns_bucket(&self, bucket: &str) -> bool {
+        if let Some(key) = bucket.strip_prefix("overage:") {
+            return self.owns_key(key);
+        }
+        // Bucket names start with their policy's, which holds no '#'
+        let Some((_, scope)) = bucket.split_once('#') else {
+            return false;
+        };
+        if let Some(key) = scope.strip_prefix("key:") {
+            let key = window_base(key);
+            return self.owns_key(key) || key.strip_prefix(self.id.as_str()).is_some_and(|rest| rest.starts_with('@'));
+        }
+        match scope.split_once("#fair:") {
+            Some((_, key)) => self.owns_key(key),
+            None => scope.strip_prefix("prefix:").is_some_and(|prefix| self.owns_key(prefix)),
+        }
+    }
+
+    /// Kind of the record stored as `name` if it is the tenant's: the first
+    /// seen time of a bucket (`seen:{bucket}`), a cookie jar
+    /// (`jar:{policy}:{key}`) or a quota counter (`quota:{period}:{key}`).
+    pub fn owns_record(&self, name: &str) -> Option<Record> {
+        if let Some(bucket) = name.strip_prefix("seen:") {
+            return self.owns_bucket(bucket).then_some(Record::FirstSeen);
+        }
+        if let Some(jar) = name.strip_prefix("jar:") {
+            // Policy names may contain colons too
+            return jar.match_indices(':').any(|(i, _)| self.owns_key(&jar[i + 1..])).then_some(Record::CookieJar);
+        }
+        if let Some((_, key)) = name.strip_prefix("quota:").and_then(|counter| counter.split_once(':')) {
+            return self.owns_key(key).then_some(Record::Quota);
+        }
+        None
+    }
+}
+
+/// `bucket` without the `#{N}s` suffix naming the bucket of a further window
+/// of its policy, if it has one.
+fn window_base(bucket: &str) -> &str {
+    match bucket.rsplit_once('#') {
+        Some((base, window)) if window.strip_suffix('s').is_some_and(|secs| !secs.is_empty() && secs.bytes().all(|b| b.is_ascii_digit())) => base,
+        _ => bucket,
+    }
+}
+
+/// Data erased, by kind.
+#[derive(Debug, Clone, Copy, Default, Serialize, ToSchema)]
+pub struct Erasure {
+    /// Leaky buckets, including overage, window, fair share and key prefix
+    /// buckets, and sliding logs.
+    pub buckets: u64,
+    /// First seen times of warming up buckets.
+    pub first_seen: u64,
+    pub cookie_jars: u64,
+    /// Quota counters of billing periods.
+    pub quotas: u64,
+    /// Usage rollups of every granularity.
+    pub usage_rollups: u64,
+    /// Raw usage counters not rolled up yet.
+    pub raw_usage: u64,
+}
+
+impl Erasure {
+    pub fn count(&mut self, record: Record) {
+        match record {
+            Record::FirstSeen => self.first_seen += 1,
+            Record::CookieJar => self.cookie_jars += 1,
+            Record::Quota => self.quotas += 1,
+        }
+    }
+
+    pub fn add(&mut self, other: &Erasure) {
+        self.buckets += other.buckets;
+        self.first_seen += other.first_seen;
+        self.cookie_jars += other.cookie_jars;
+        self.quotas += other.quotas;
+        self.usage_rollups += other.usage_rollups;
+        self.raw_usage += other.raw_usage;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.buckets + self.first_seen + self.cookie_jars + self.quotas + self.usage_rollups + self.raw_usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn owns_the_buckets_of_its_keys() {
+        let acme = Tenant::new("acme", ":");
+        for bucket in ["api#key:acme", "api#key:acme:alice@api.example.com", "api#key:acme:alice#60s", "api#prefix:acme", "api#global#fair:acme:alice", "overage:acme:alice"] {
+            assert!(acme.owns_bucket(bucket), "{}", bucket);
+        }
+        for bucket in ["api#key:acmeco", "api#global", "api#host:acme", "api#key:mallory#fair:acme", "acme"] {
+            assert!(!acme.owns_bucket(bucket), "{}", bucket);
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Policies kept in etcd, so a fleet of instances converges on policy changes
+//! within seconds without distributing config files.
+//!
+//! Every key below `prefix` holds one policy as JSON. They are evaluated
+//! before the config file's policies, in key order, and an etcd policy
+//! replaces the file's policy of the same name. Changes are picked up through
+//! a watch on the prefix via etcd's JSON gateway.
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+
+use crate::{config::Config, policy::{Policies, Policy, PolicySet}};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EtcdConfig {
+    /// Base URLs of the etcd members, e.g. `http://etcd-0:2379`, tried in
+    /// order.
+    pub endpoints: Vec<String>,
+    /// Key prefix the policies are stored under.
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Password of `username`; read from `ETCD_PASSWORD` if unset.
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+fn default_prefix() -> String {
+    "/grenze/policies/".to_string()
+}
+
+impl EtcdConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.endpoints.is_empty() {
+            bail!("etcd requires at least one endpoint");
+        }
+        for endpoint in &self.endpoints {
+            reqwest::Url::parse(endpoint).with_context(|| format!("invalid etcd endpoint {}", endpoint))?;
+        }
+        if self.prefix.is_empty() {
+            bail!("etcd prefix must not be empty");
+        }
+        Ok(())
+    }
+}
+
+/// Loads the policies from etcd into `policies` and keeps them up to date in
+/// the background, rewatching 5s after a failure. Fails if the initial load
+/// does, so instances do not start without their policies.
+pub async fn watch(config: &EtcdConfig, base: &Config, policies: Arc<Policies>) -> Result<()> {
+    let client = EtcdClient::new(config);
+    let mut revision = reload(&client, base, &policies).await?;
+    let base = base.clone();
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = client.follow(&mut revision, &base, &policies).await {
+                println!("Etcd policy watch failed: {:#}", e);
+            }
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            // Changes may have been missed meanwhile
+            match reload(&client, &base, &policies).await {
+                Ok(current) => revision = current,
+                Err(e) => println!("Failed to reload policies from etcd: {:#}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Replaces the active policies with the file's merged with etcd's, returning
+/// the etcd revision they were read at. Invalid policies in etcd reject the
+/// whole update.
+async fn reload(client: &EtcdClient, base: &Config, policies: &Policies) -> Result<i64> {
+    let (revision, values) = client.range().await?;
+    let mut remote = Vec::with_capacity(values.len());
+    for (key, value) in values {
+        let policy: Policy = serde_json::from_slice(&value).with_context(|| format!("invalid policy in etcd key {}", key))?;
+        base.validate_policy(&policy).with_context(|| format!("etcd key {}", key))?;
+        remote.push(policy);
+    }
+    let count = remote.len();
+    let local: Vec<Policy> = base.policies.iter().filter(|p| !remote.iter().any(|r| r.name == p.name)).cloned().collect();
+    remote.extend(local);
+    policies.replace(PolicySet::new(remote)?);
+    println!("Loaded {} policies from etcd at revision {}", count, revision);
+    Ok(revision)
+}
+
+struct EtcdClient {
+    http: reqwest::Client,
+    config: EtcdConfig,
+    /// Auth token, if the cluster requires authentication.
+    token: Mutex<Option<String>>,
+}
+
+impl EtcdClient {
+    fn new(config: &EtcdConfig) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            config: config.clone(),
+            token: Mutex::new(None),
+        }
+    }
+
+    /// Sends `body` to `path` (e.g. `kv/range`) on the first endpoint that
+    /// answers, authenticating first if credentials are configured.
+    async fn post(&self, path: &str, body: &Value, timeout: Option<Duration>) -> Result<reqwest::Response> {
+        let mut last_error = None;
+        for endpoint in &self.config.endpoints {
+            let url = format!("{}/v3/{}", endpoint.trim_end_matches('/'), path);
+            let mut request = self.http.post(&url).json(body);
+            if let Some(timeout) = timeout {
+                request = request.timeout(timeout);
+            }
+            match self.token(endpoint).await {
+                Ok(Some(token)) => request = request.header("authorization", token),
+                Ok(None) => {},
+                Err(e) => {
+                    last_error = Some(e);
+                    continue;
+                },
+            }
+            match request.send().await {
+                Ok(response) if response.status() == reqwest::StatusCode::UNAUTHORIZED => {
+                    // The token expired; fetch a new one on the next call
+                    *self.token.lock().await = None;
+                    last_error = Some(anyhow!("etcd at {} rejected the auth token", endpoint));
+                },
+                Ok(response) if !response.status().is_success() => {
+                    let status = response.status();
+                    let message = response.text().await.unwrap_or_default();
+                    bail!("etcd {} failed with {}: {}", path, status, message);
+                },
+                Ok(response) => return Ok(response),
+                Err(e) => last_error = Some(anyhow::Error::new(e).context(format!("etcd at {} is not reachable", endpoint))),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("no etcd endpoints configured")))
+    }
+
+    async fn token(&self, endpoint: &str) -> Result<Option<String>> {
+        let Some(username) = &self.config.username else {
+            return Ok(None);
+        };
+        let mut token = self.token.lock().await;
+        if token.is_none() {
+            let password = match &self.config.password {
+                Some(password) => password.clone(),
+                None => std::env::var("ETCD_PASSWORD").context("etcd password must be set in the config or ETCD_PASSWORD")?,
+            };
+            let url = format!("{}/v3/auth/authenticate", endpoint.trim_end_matches('/'));
+            let response = self
+                .http
+                .post(&url)
+                .json(&json!({"name": username, "password": password}))
+                .timeout(Duration::from_secs(10))
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                bail!("etcd rejected the credentials of {} with {}", username, response.status());
+            }
+            let body: Value = response.json().await?;
+            *token = body.get("token").and_then(Value::as_str).map(str::to_string);
+        }
+        Ok(token.clone())
+    }
+
+    /// Keys and values below the prefix in key order, with the revision they
+    /// were read at.
+    async fn range(&self) -> Result<(i64, Vec<(String, Vec<u8>)>)> {
+        let body = json!({
+            "key": STANDARD.encode(&self.config.prefix),
+            "range_end": STANDARD.encode(prefix_end(self.config.prefix.as_bytes())),
+        });
+        let response: Value = self.post("kv/range", &body, Some(Duration::from_secs(10))).await?.json().await?;
+        let revision = int(&response["header"]["revision"]).context("etcd range response lacks a revision")?;
+        let mut values = Vec::new();
+        for kv in response["kvs"].as_array().into_iter().flatten() {
+            let key = String::from_utf8_lossy(&decode(&kv["key"])?).into_owned();
+            values.push((key, decode(&kv["value"])?));
+        }
+        Ok((revision, values))
+    }
+
+    /// Watches the prefix for changes after `revision`, reloading the policies
+    /// on every change. Returns when the watch ends.
+    async fn follow(&self, revision: &mut i64, base: &Config, policies: &Policies) -> Result<()> {
+        let body = json!({
+            "create_request": {
+                "key": STANDARD.encode(&self.config.prefix),
+                "range_end": STANDARD.encode(prefix_end(self.config.prefix.as_bytes())),
+                "start_revision": (*revision + 1).to_string(),
+            }
+        });
+        let mut response = self.post("watch", &body, None).await?;
+        // The gateway streams one JSON message per line
+        let mut buffer = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            buffer.extend_from_slice(&chunk);
+            while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                let message: Value = serde_json::from_slice(&line).context("invalid etcd watch message")?;
+                if let Some(error) = message.get("error") {
+                    bail!("etcd watch failed: {}", error);
+                }
+                let result = &message["result"];
+                if int(&result["compact_revision"]).is_some_and(|r| r > 0) {
+                    bail!("etcd compacted the watched revision");
+                }
+                if result["events"].as_array().is_some_and(|e| !e.is_empty()) {
+                    match reload(self, base, policies).await {
+                        Ok(current) => *revision = current,
+                        Err(e) => println!("Failed to reload policies from etcd: {:#}", e),
+                    }
+                }
+            }
+        }
+        bail!("etcd closed the watch")
+    }
+}
+
+/// First key after all keys starting with `prefix`.
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    vec![0]
+}
+
+/// The gateway encodes 64-bit integers as strings.
+fn int(value: &Value) -> Option<i64> {
+    match value {
+        Value::String(s) => s.parse().ok(),
+        other => other.as_i64(),
+    }
+}
+
+fn decode(value: &Value) -> Result<Vec<u8>> {
+    Ok(STANDARD.decode(value.as_str().unwrap_or_default())?)
+}
@@ -0,0 +1,84 @@
+//! Redis keyspace notifications for expired buckets, to understand key churn
+//! and tune bucket TTLs.
+
+use anyhow::Result;
+use futures::StreamExt;
+use serde::Deserialize;
+use std::{sync::{atomic::{AtomicU64, Ordering}, Arc}, time::Duration};
+
+use crate::{limiter::Clock, metrics::Metrics};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExpiryEventsConfig {
+    /// Enable expiry notifications on the Redis server (`notify-keyspace-events
+    /// Ex`) instead of relying on it being configured already. Managed Redis
+    /// offerings often forbid `CONFIG SET`.
+    pub configure: bool,
+    /// How often expiry counts are logged; never if 0.
+    pub log_interval_secs: u64,
+}
+
+impl Default for ExpiryEventsConfig {
+    fn default() -> Self {
+        Self {
+            configure: false,
+            log_interval_secs: 60,
+        }
+    }
+}
+
+/// Subscribes to expiry events of the Redis database and counts expired
+/// buckets, whose keys start with `prefix`, in the background, resubscribing
+/// after connection loss. Churn is logged every `log_interval_secs`.
+pub async fn watch(client: redis::Client, config: &ExpiryEventsConfig, prefix: String, metrics: Arc<Metrics>, clock: Arc<dyn Clock>) -> Result<()> {
+    if config.configure {
+        let mut conn = client.get_multiplexed_tokio_connection().await?;
+        redis::cmd("CONFIG").arg("SET").arg("notify-keyspace-events").arg("Ex").query_async::<()>(&mut conn).await?;
+    }
+    let channel = format!("__keyevent@{}__:expired", client.get_connection_info().redis.db);
+    let expired = Arc::new(AtomicU64::new(0));
+
+    if config.log_interval_secs > 0 {
+        let expired = expired.clone();
+        let metrics = metrics.clone();
+        let interval = Duration::from_secs(config.log_interval_secs);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let count = expired.swap(0, Ordering::Relaxed);
+                let active = metrics.fill_summary(clock.now_ms()).active;
+                let inactive = metrics.inactive_buckets().unwrap_or_default();
+                println!("Buckets: {} expired in the last {}s, {} active, {} drained awaiting expiry", count, interval.as_secs(), active, inactive);
+            }
+        });
+    }
+
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = listen(&client, &channel, &prefix, &expired, &metrics).await {
+                println!("Bucket expiry subscription failed: {}", e);
+            }
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+    });
+    Ok(())
+}
+
+async fn listen(client: &redis::Client, channel: &str, prefix: &str, expired: &AtomicU64, metrics: &Metrics) -> redis::RedisResult<()> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(channel).await?;
+    let mut messages = pubsub.on_message();
+    while let Some(msg) = messages.next().await {
+        let Ok(key) = msg.get_payload::<String>() else {
+            continue;
+        };
+        if let Some(bucket) = key.strip_prefix(prefix).and_then(|k| k.strip_prefix("rl:")) {
+            expired.fetch_add(1, Ordering::Relaxed);
+            metrics.record_expiry(bucket);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,93 @@
+//! Fair queuing within buckets shared by several keys, such as an
+//! organization's: each key's consumption of a shared bucket is tracked in a
+//! bucket of its own, which, while the shared bucket is contended, is sized to
+//! an equal share among the keys active in it, so one noisy key cannot starve
+//! its siblings.
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::limiter::BucketLimit;
+
+/// Most shared buckets whose active keys are tracked per instance; all are
+/// forgotten when more are seen.
+const MAX_SHARED_BUCKETS: usize = 10_000;
+
+/// How often the keys of a shared bucket that went quiet are forgotten.
+const PRUNE_INTERVAL_MS: i64 = 1000;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FairShare {
+    /// Fill of the shared bucket, as a share of its capacity, from which keys
+    /// are held to their fair share.
+    pub contention: f64,
+    /// How long a key counts as active in the bucket after its last request.
+    pub active_secs: u64,
+}
+
+impl Default for FairShare {
+    fn default() -> Self {
+        Self {
+            contention: 0.8,
+            active_secs: 60,
+        }
+    }
+}
+
+impl FairShare {
+    pub fn validate(&self) -> Result<()> {
+        if !(self.contention > 0.0 && self.contention <= 1.0) {
+            bail!("fair contention must be above 0 and at most 1");
+        }
+        if self.active_secs == 0 {
+            bail!("fair active_secs must be positive");
+        }
+        Ok(())
+    }
+
+    /// Bucket tracking the consumption of `shared` by `key`, holding it to
+    /// an equal share among `keys` keys; to all of it for a single key.
+    pub fn share(&self, shared: &BucketLimit, key: &str, keys: usize) -> BucketLimit {
+        let keys = keys.max(1);
+        BucketLimit {
+            bucket: format!("{}#fair:{}", shared.bucket, key),
+            capacity: (shared.capacity / keys as u32).max(1),
+            leak_per_sec: shared.leak_per_sec / keys as f64,
+        }
+    }
+}
+
+/// Keys recently seen in each shared bucket with fair queuing, as seen by
+/// this instance.
+#[derive(Default)]
+pub struct ActiveKeys {
+    buckets: Mutex<HashMap<String, Shared>>,
+}
+
+#[derive(Default)]
+struct Shared {
+    /// Last request of every active key.
+    keys: HashMap<String, i64>,
+    pruned_ms: i64,
+}
+
+impl ActiveKeys {
+    /// Records a request of `key` in `bucket` at `now_ms`. Returns the number
+    /// of keys active in the bucket, including `key`.
+    pub fn touch(&self, bucket: &str, key: &str, now_ms: i64, config: &FairShare) -> usize {
+        let mut buckets = self.buckets.lock().expect("active keys lock poisoned");
+        if !buckets.contains_key(bucket) && buckets.len() >= MAX_SHARED_BUCKETS {
+            buckets.clear();
+        }
+        let shared = buckets.entry(bucket.to_string()).or_default();
+        shared.keys.insert(key.to_string(), now_ms);
+        if now_ms - shared.pruned_ms >= PRUNE_INTERVAL_MS {
+            let since_ms = now_ms - config.active_secs as i64 * 1000;
+            shared.keys.retain(|_, seen_ms| *seen_ms > since_ms);
+            shared.pruned_ms = now_ms;
+        }
+        shared.keys.len()
+    }
+}
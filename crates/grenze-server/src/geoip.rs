@@ -0,0 +1,386 @@
+//! GeoIP-aware limiting. Client addresses are looked up in MaxMind-format
+//! databases, such as GeoLite2 Country and ASN, for their country and
+//! autonomous system. Rules matching them block requests, resize the bucket
+//! they draw from or give them a bucket of their own per region. The
+//! databases are reloaded when their files change.
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::{net::IpAddr, sync::{Arc, RwLock}, time::{Duration, SystemTime}};
+
+use crate::limiter::BucketSize;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GeoIpConfig {
+    /// Database with the `country.iso_code` of networks, e.g. GeoLite2
+    /// Country or City.
+    #[serde(default)]
+    pub country_db: Option<String>,
+    /// Database with the `autonomous_system_number` of networks, e.g.
+    /// GeoLite2 ASN.
+    #[serde(default)]
+    pub asn_db: Option<String>,
+    /// How often the database files are checked for changes.
+    #[serde(default = "default_reload_interval_secs")]
+    pub reload_interval_secs: u64,
+    /// Rules by client region; the first matching one applies.
+    #[serde(default)]
+    pub rules: Vec<GeoRule>,
+}
+
+fn default_reload_interval_secs() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GeoRule {
+    /// ISO 3166-1 alpha-2 codes of the countries matched.
+    #[serde(default)]
+    pub countries: Vec<String>,
+    /// Autonomous system numbers matched.
+    #[serde(default)]
+    pub asns: Vec<u32>,
+    /// Policies the rule applies under; all if empty.
+    #[serde(default)]
+    pub policies: Vec<String>,
+    /// Rejects matching requests.
+    #[serde(default)]
+    pub block: bool,
+    /// Size of the bucket matching requests draw from; the key's if unset.
+    #[serde(default)]
+    pub capacity: Option<u32>,
+    #[serde(default)]
+    pub leak_per_sec: Option<f64>,
+    /// Draws matching requests from a bucket of their own per key and
+    /// region instead of the key's.
+    #[serde(default)]
+    pub separate_bucket: bool,
+}
+
+impl GeoIpConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.country_db.is_none() && self.asn_db.is_none() {
+            bail!("geoip requires a country_db or an asn_db");
+        }
+        if self.reload_interval_secs == 0 {
+            bail!("geoip reload_interval_secs must be positive");
+        }
+        for (i, rule) in self.rules.iter().enumerate() {
+            rule.validate().with_context(|| format!("geoip rule {}", i))?;
+            if !rule.countries.is_empty() && self.country_db.is_none() {
+                bail!("geoip rule {} matches countries without a country_db", i);
+            }
+            if !rule.asns.is_empty() && self.asn_db.is_none() {
+                bail!("geoip rule {} matches asns without an asn_db", i);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl GeoRule {
+    fn validate(&self) -> Result<()> {
+        if self.countries.is_empty() && self.asns.is_empty() {
+            bail!("rule must match countries or asns");
+        }
+        if self.countries.iter().any(|c| c.len() != 2) {
+            bail!("countries must be two-letter ISO codes");
+        }
+        let resizes = self.capacity.is_some() || self.leak_per_sec.is_some() || self.separate_bucket;
+        if self.block == resizes {
+            bail!("rule must either block or set capacity, leak_per_sec or separate_bucket");
+        }
+        BucketSize::validate(self.capacity, self.leak_per_sec)
+    }
+
+    fn matches(&self, region: &Region, policy: &str) -> bool {
+        (self.policies.is_empty() || self.policies.iter().any(|p| p == policy))
+            && (region.country.as_ref().is_some_and(|c| self.countries.iter().any(|m| m.eq_ignore_ascii_case(c)))
+                || region.asn.is_some_and(|a| self.asns.contains(&a)))
+    }
+}
+
+/// Country and autonomous system of a client address, where known.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Region {
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+impl Region {
+    /// Name of the region in bucket names, by the part `rule` matched.
+    pub fn label(&self, rule: &GeoRule) -> String {
+        match (&self.country, self.asn) {
+            (Some(country), _) if rule.countries.iter().any(|m| m.eq_ignore_ascii_case(country)) => country.to_ascii_uppercase(),
+            (_, Some(asn)) => format!("AS{}", asn),
+            (country, None) => country.clone().unwrap_or_default(),
+        }
+    }
+}
+
+pub struct GeoIp {
+    config: GeoIpConfig,
+    country: Option<RwLock<Loaded>>,
+    asn: Option<RwLock<Loaded>>,
+}
+
+struct Loaded {
+    db: Arc<Mmdb>,
+    modified: Option<SystemTime>,
+}
+
+impl GeoIp {
+    pub fn new(config: &GeoIpConfig) -> Result<Self> {
+        Ok(Self {
+            config: config.clone(),
+            country: config.country_db.as_deref().map(load).transpose()?.map(RwLock::new),
+            asn: config.asn_db.as_deref().map(load).transpose()?.map(RwLock::new),
+        })
+    }
+
+    /// Region of `ip`. Lookups failing on a corrupt database count as
+    /// unknown.
+    pub fn region(&self, ip: IpAddr) -> Region {
+        let lookup = |db: &Option<RwLock<Loaded>>| {
+            let db = db.as_ref()?.read().expect("geoip lock poisoned").db.clone();
+            db.lookup(ip).ok().flatten()
+        };
+        Region {
+            country: lookup(&self.country).and_then(|v| v.get("country")?.get("iso_code")?.as_str().map(str::to_string)),
+            asn: lookup(&self.asn).and_then(|v| v.get("autonomous_system_number")?.as_u64()).and_then(|n| u32::try_from(n).ok()),
+        }
+    }
+
+    /// First rule matching the region of `ip` under `policy`, with the region.
+    pub fn rule(&self, ip: IpAddr, policy: &str) -> Option<(&GeoRule, Region)> {
+        if self.config.rules.is_empty() {
+            return None;
+        }
+        let region = self.region(ip);
+        self.config.rules.iter().find(|r| r.matches(&region, policy)).map(|r| (r, region))
+    }
+
+    fn reload_changed(&self) {
+        for (db, path) in [(&self.country, &self.config.country_db), (&self.asn, &self.config.asn_db)] {
+            let (Some(db), Some(path)) = (db, path) else {
+                continue;
+            };
+            let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+            if modified == db.read().expect("geoip lock poisoned").modified {
+                continue;
+            }
+            match load(path) {
+                Ok(loaded) => {
+                    *db.write().expect("geoip lock poisoned") = loaded;
+                    println!("Reloaded geoip database {}", path);
+                },
+                Err(e) => println!("Failed to reload geoip database {}: {:#}", path, e),
+            }
+        }
+    }
+
+    pub fn spawn_reloader(self: &Arc<Self>) {
+        let geoip = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(geoip.config.reload_interval_secs));
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                geoip.reload_changed();
+            }
+        });
+    }
+}
+
+fn load(path: &str) -> Result<Loaded> {
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let data = std::fs::read(path).with_context(|| format!("failed to read geoip database {}", path))?;
+    let db = Mmdb::parse(data).with_context(|| format!("invalid geoip database {}", path))?;
+    Ok(Loaded { db: Arc::new(db), modified })
+}
+
+/// Start of the metadata at the end of a MaxMind DB file.
+const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+
+/// Reader of the MaxMind DB format: a binary search tree over the address
+/// bits whose leaves point into a section of typed, JSON-like records.
+pub struct Mmdb {
+    data: Vec<u8>,
+    node_count: usize,
+    /// Bits per record; every node holds two.
+    record_size: usize,
+    ip_version: u64,
+    /// Node IPv4 addresses start at in an IPv6 tree.
+    ipv4_start: usize,
+    data_start: usize,
+}
+
+impl Mmdb {
+    pub fn parse(data: Vec<u8>) -> Result<Self> {
+        let marker = data
+            .windows(METADATA_MARKER.len())
+            .rposition(|w| w == METADATA_MARKER)
+            .ok_or_else(|| anyhow!("metadata not found"))?;
+        let metadata_start = marker + METADATA_MARKER.len();
+        let (metadata, _) = Decoder { data: &data, base: metadata_start }.decode(metadata_start, 0)?;
+        let field = |name: &str| metadata.get(name).and_then(Value::as_u64).ok_or_else(|| anyhow!("metadata lacks {}", name));
+        let (node_count, record_size, ip_version) = (field("node_count")? as usize, field("record_size")? as usize, field("ip_version")?);
+        if ![24, 28, 32].contains(&record_size) {
+            bail!("unsupported record size {}", record_size);
+        }
+        if ![4, 6].contains(&ip_version) {
+            bail!("unsupported ip version {}", ip_version);
+        }
+        let data_start = node_count * record_size / 4 + 16;
+        if data_start > marker {
+            bail!("search tree exceeds the file");
+        }
+        let mut db = Self {
+            data,
+            node_count,
+            record_size,
+            ip_version,
+            ipv4_start: 0,
+            data_start,
+        };
+        if ip_version == 6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = db.record(node, 0)?;
+            }
+            db.ipv4_start = node;
+        }
+        Ok(db)
+    }
+
+    /// Record of the network containing `ip`, if any.
+    pub fn lookup(&self, ip: IpAddr) -> Result<Option<Value>> {
+        let (bits, len, mut node) = match ip {
+            IpAddr::V4(v4) => (u32::from(v4) as u128, 32, self.ipv4_start),
+            IpAddr::V6(v6) if self.ip_version == 6 => (u128::from(v6), 128, 0),
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => (u32::from(v4) as u128, 32, 0),
+                None => return Ok(None),
+            },
+        };
+        for i in (0..len).rev() {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, ((bits >> i) & 1) as usize)?;
+        }
+        if node <= self.node_count {
+            return Ok(None);
+        }
+        let offset = self.data_start + (node - self.node_count - 16);
+        let (value, _) = Decoder { data: &self.data, base: self.data_start }.decode(offset, 0)?;
+        Ok(Some(value))
+    }
+
+    /// Left (`side` 0) or right record of `node`.
+    fn record(&self, node: usize, side: usize) -> Result<usize> {
+        let at = node * self.record_size / 4;
+        let bytes = self.data.get(at..at + self.record_size / 4).ok_or_else(|| anyhow!("node {} out of bounds", node))?;
+        let uint = |b: &[u8]| b.iter().fold(0usize, |n, &b| n << 8 | b as usize);
+        Ok(match (self.record_size, side) {
+            (24, 0) => uint(&bytes[..3]),
+            (24, _) => uint(&bytes[3..]),
+            (28, 0) => (bytes[3] as usize & 0xF0) << 20 | uint(&bytes[..3]),
+            (28, _) => (bytes[3] as usize & 0x0F) << 24 | uint(&bytes[4..]),
+            (_, 0) => uint(&bytes[..4]),
+            (_, _) => uint(&bytes[4..]),
+        })
+    }
+}
+
+/// Decoder of the data section, whose pointers are relative to `base`.
+struct Decoder<'a> {
+    data: &'a [u8],
+    base: usize,
+}
+
+impl Decoder<'_> {
+    fn bytes(&self, at: usize, len: usize) -> Result<&[u8]> {
+        self.data.get(at..at + len).ok_or_else(|| anyhow!("data at {} out of bounds", at))
+    }
+
+    fn uint(&self, at: usize, len: usize) -> Result<u128> {
+        if len > 16 {
+            bail!("integer of {} bytes", len);
+        }
+        Ok(self.bytes(at, len)?.iter().fold(0u128, |n, &b| n << 8 | b as u128))
+    }
+
+    /// Value at `at` and the offset following it.
+    fn decode(&self, mut at: usize, depth: u32) -> Result<(Value, usize)> {
+        if depth > 64 {
+            bail!("data nested too deeply");
+        }
+        let control = self.bytes(at, 1)?[0];
+        at += 1;
+        let mut kind = control >> 5;
+        if kind == 1 {
+            let high = (control & 0x07) as usize;
+            let (pointer, len) = match (control >> 3) & 0x03 {
+                0 => (high << 8 | self.uint(at, 1)? as usize, 1),
+                1 => ((high << 16 | self.uint(at, 2)? as usize) + 2048, 2),
+                2 => ((high << 24 | self.uint(at, 3)? as usize) + 526_336, 3),
+                _ => (self.uint(at, 4)? as usize, 4),
+            };
+            let (value, _) = self.decode(self.base + pointer, depth + 1)?;
+            return Ok((value, at + len));
+        }
+        if kind == 0 {
+            kind = 7 + self.bytes(at, 1)?[0];
+            at += 1;
+        }
+        let mut size = (control & 0x1F) as usize;
+        let extra = size.saturating_sub(28);
+        if extra > 0 {
+            size = [29, 285, 65_821][extra - 1] + self.uint(at, extra)? as usize;
+            at += extra;
+        }
+        let value = match kind {
+            2 => Value::String(std::str::from_utf8(self.bytes(at, size)?)?.to_string()),
+            3 if size == 8 => Value::from(f64::from_be_bytes(self.bytes(at, 8)?.try_into()?)),
+            4 => Value::String(hex::encode(self.bytes(at, size)?)),
+            5 | 6 | 9 | 10 => match u64::try_from(self.uint(at, size)?) {
+                Ok(n) => Value::from(n),
+                Err(_) => Value::String(self.uint(at, size)?.to_string()),
+            },
+            8 if size <= 4 => Value::from(self.uint(at, size)? as u32 as i32),
+            14 => return Ok((Value::Bool(size != 0), at)),
+            15 if size == 4 => Value::from(f32::from_be_bytes(self.bytes(at, 4)?.try_into()?) as f64),
+            7 => {
+                let mut map = Map::new();
+                for _ in 0..size {
+                    let (key, next) = self.decode(at, depth + 1)?;
+                    let (value, next) = self.decode(next, depth + 1)?;
+                    let Value::String(key) = key else {
+                        bail!("map key at {} is not a string", at);
+                    };
+                    map.insert(key, value);
+                    at = next;
+                }
+                return Ok((Value::Object(map), at));
+            },
+            11 => {
+                let mut items = Vec::with_capacity(size.min(1024));
+                for _ in 0..size {
+                    let (item, next) = self.decode(at, depth + 1)?;
+                    items.push(item);
+                    at = next;
+                }
+                return Ok((Value::Array(items), at));
+            },
+            kind => bail!("unsupported data type {} of size {} at {}", kind, size, at),
+        };
+        Ok((value, at + size))
+    }
+}
@@ -0,0 +1,106 @@
+//! HTTP Archive (HAR 1.2) export of recorded requests, so proxied traffic can
+//! be loaded into browser devtools and API tooling.
+
+use axum::http::StatusCode;
+use serde_json::{json, Value};
+use std::{collections::HashMap, time::{Duration, UNIX_EPOCH}};
+
+use crate::{api::proxy::{self, MultiValue}, aws, recording::Recording};
+
+/// Archive holding `recordings` as its entries.
+pub fn archive(recordings: &[Recording]) -> Value {
+    json!({
+        "log": {
+            "version": "1.2",
+            "creator": { "name": "grenze", "version": env!("CARGO_PKG_VERSION") },
+            "entries": recordings.iter().map(entry).collect::<Vec<_>>(),
+        }
+    })
+}
+
+fn entry(recording: &Recording) -> Value {
+    // The URL as sent, with the query parameters merged in
+    let url = reqwest::Url::parse(&recording.url).ok().map(|mut url| {
+        proxy::merge_query(&mut url, &recording.query);
+        url
+    });
+    let query: Vec<Value> = url.iter().flat_map(|u| u.query_pairs()).map(|(name, value)| json!({ "name": name, "value": value })).collect();
+    let mut request = json!({
+        "method": recording.method.to_uppercase(),
+        "url": url.as_ref().map_or(recording.url.clone(), |u| u.to_string()),
+        "httpVersion": "HTTP/1.1",
+        "cookies": [],
+        "headers": pairs(&recording.headers).collect::<Vec<_>>(),
+        "queryString": query,
+        "headersSize": -1,
+        "bodySize": -1,
+    });
+    if let Some(body) = &recording.body {
+        let text = body.to_string();
+        request["bodySize"] = text.len().into();
+        request["postData"] = json!({ "mimeType": "application/json", "text": text });
+    }
+
+    let status = StatusCode::from_u16(recording.status).ok();
+    let mut content = json!({ "size": 0, "mimeType": "" });
+    let (headers, body_size) = match &recording.response {
+        Some(response) => {
+            let mime_type = response.headers.iter().find(|(name, _)| name.eq_ignore_ascii_case("content-type"));
+            content["size"] = response.body_size.into();
+            content["mimeType"] = mime_type.and_then(|(_, v)| v.values().first().cloned()).unwrap_or_default().into();
+            if let Some(body) = &response.body {
+                content["text"] = body.as_str().into();
+                if response.base64 {
+                    content["encoding"] = "base64".into();
+                }
+            }
+            (pairs(&response.headers).collect::<Vec<_>>(), response.body_size as i64)
+        },
+        None => (Vec::new(), -1),
+    };
+    json!({
+        "startedDateTime": iso8601(recording.at_ms),
+        "time": recording.latency_ms,
+        "request": request,
+        "response": {
+            "status": recording.status,
+            "statusText": status.and_then(|s| s.canonical_reason()).unwrap_or_default(),
+            "httpVersion": "HTTP/1.1",
+            "cookies": [],
+            "headers": headers,
+            "content": content,
+            "redirectURL": "",
+            "headersSize": -1,
+            "bodySize": body_size,
+        },
+        "cache": {},
+        "timings": { "send": 0, "wait": recording.latency_ms, "receive": 0 },
+        "comment": format!("key {}, policy {}, recording {}", recording.key, recording.policy, recording.id),
+    })
+}
+
+/// Name/value pairs of `values`, sorted by name.
+fn pairs(values: &HashMap<String, MultiValue>) -> impl Iterator<Item = Value> {
+    let mut sorted: Vec<_> = values.iter().collect();
+    sorted.sort_by_key(|(name, _)| name.to_ascii_lowercase());
+    sorted
+        .into_iter()
+        .flat_map(|(name, value)| value.values().iter().map(move |v| json!({ "name": name, "value": v })))
+        .collect::<Vec<_>>()
+        .into_iter()
+}
+
+/// `at_ms` as an ISO 8601 date and time in UTC with milliseconds.
+fn iso8601(at_ms: i64) -> String {
+    let (stamp, _) = aws::timestamps(UNIX_EPOCH + Duration::from_millis(at_ms.max(0) as u64));
+    format!(
+        "{}-{}-{}T{}:{}:{}.{:03}Z",
+        &stamp[..4],
+        &stamp[4..6],
+        &stamp[6..8],
+        &stamp[9..11],
+        &stamp[11..13],
+        &stamp[13..15],
+        at_ms.rem_euclid(1000)
+    )
+}
@@ -0,0 +1,117 @@
+use axum::http::{header::ETAG, HeaderMap, HeaderValue};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::api::proxy::MultiValue;
+
+/// Declarative rewrite of the headers sent downstream. Values are templates
+/// that may reference request fields: `{key}` (the rate limit key),
+/// `{method}`, `{host}`, `{path}` and `{policy}`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum HeaderRule {
+    /// Sets the header, replacing any value the client sent.
+    Set { name: String, value: String },
+    /// Adds the header unless the client already sent it.
+    Add { name: String, value: String },
+    Remove { name: String },
+    Rename { from: String, to: String },
+}
+
+/// Request fields available to header templates.
+pub struct TemplateContext<'a> {
+    pub key: &'a str,
+    pub method: &'a str,
+    pub host: &'a str,
+    pub path: &'a str,
+    pub policy: &'a str,
+}
+
+impl TemplateContext<'_> {
+    pub fn render(&self, template: &str) -> String {
+        template
+            .replace("{key}", self.key)
+            .replace("{method}", self.method)
+            .replace("{host}", self.host)
+            .replace("{path}", self.path)
+            .replace("{policy}", self.policy)
+    }
+}
+
+/// Applies the rules in order. Header names are matched case-insensitively.
+pub fn apply(rules: &[HeaderRule], headers: &mut HashMap<String, MultiValue>, ctx: &TemplateContext) {
+    for rule in rules {
+        match rule {
+            HeaderRule::Set { name, value } => {
+                remove(headers, name);
+                headers.insert(name.clone(), MultiValue::One(ctx.render(value)));
+            },
+            HeaderRule::Add { name, value } => {
+                if !headers.keys().any(|k| k.eq_ignore_ascii_case(name)) {
+                    headers.insert(name.clone(), MultiValue::One(ctx.render(value)));
+                }
+            },
+            HeaderRule::Remove { name } => {
+                remove(headers, name);
+            },
+            HeaderRule::Rename { from, to } => {
+                if let Some(value) = remove(headers, from) {
+                    remove(headers, to);
+                    headers.insert(to.clone(), value);
+                }
+            },
+        }
+    }
+}
+
+fn remove(headers: &mut HashMap<String, MultiValue>, name: &str) -> Option<MultiValue> {
+    let existing = headers.keys().find(|k| k.eq_ignore_ascii_case(name))?.clone();
+    headers.remove(&existing)
+}
+
+/// Headers by name, with the values of repeated headers grouped. Values that
+/// are not valid UTF-8 are left out.
+pub fn to_map(headers: &HeaderMap) -> HashMap<String, MultiValue> {
+    let mut map: HashMap<String, MultiValue> = HashMap::new();
+    for (name, value) in headers {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        match map.get_mut(name.as_str()) {
+            Some(values) => values.push(value.to_string()),
+            None => {
+                map.insert(name.to_string(), MultiValue::One(value.to_string()));
+            },
+        }
+    }
+    map
+}
+
+/// Inverse of [`to_map`], repeating headers with several values. Invalid
+/// names and values are left out.
+pub fn from_map(headers: &HashMap<String, MultiValue>) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    for (name, values) in headers {
+        let Ok(name) = name.parse::<axum::http::HeaderName>() else {
+            continue;
+        };
+        for value in values.values() {
+            if let Ok(value) = value.parse() {
+                map.append(name.clone(), value);
+            }
+        }
+    }
+    map
+}
+
+/// Turns a strong `ETag` into a weak one once grenze changed the body, as
+/// the bytes no longer match the downstream's. Conditional requests keep
+/// working since `If-None-Match` compares weakly.
+pub fn weaken_etag(headers: &mut HeaderMap) {
+    if let Some(etag) = headers.get(ETAG).and_then(|v| v.to_str().ok())
+        && !etag.starts_with("W/")
+        && let Ok(weak) = HeaderValue::from_str(&format!("W/{}", etag))
+    {
+        headers.insert(ETAG, weak);
+    }
+}
@@ -0,0 +1,155 @@
+//! Hedged requests for latency-sensitive upstreams: when the downstream takes
+//! longer than most of its recent responses did, a copy of the request goes
+//! to an alternate upstream, and whichever answers first is returned.
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::{collections::{HashMap, VecDeque}, sync::Mutex, time::Duration};
+
+/// Latencies kept per policy to derive the hedging delay from.
+const WINDOW: usize = 1000;
+/// Latencies needed before the percentile is trusted over `min_delay_ms`.
+const MIN_SAMPLES: usize = 20;
+/// Latencies recorded between updates of the sorted copy percentiles are
+/// read from.
+const REFRESH: usize = 50;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HedgeConfig {
+    /// Origin hedged requests go to, e.g. `https://eu.api.example.com`; the
+    /// request's path and query are kept.
+    pub alternate: String,
+    /// Percentile of the policy's recent downstream latencies after which
+    /// the request is hedged.
+    #[serde(default = "default_percentile")]
+    pub percentile: f64,
+    /// Bounds of the delay; the lower one applies until enough latencies
+    /// were seen.
+    #[serde(default = "default_min_delay_ms")]
+    pub min_delay_ms: u64,
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+    /// Methods of requests that are hedged. Only idempotent requests are safe
+    /// to send twice.
+    #[serde(default = "default_methods")]
+    pub methods: Vec<String>,
+}
+
+fn default_percentile() -> f64 {
+    95.0
+}
+
+fn default_min_delay_ms() -> u64 {
+    10
+}
+
+fn default_max_delay_ms() -> u64 {
+    2000
+}
+
+fn default_methods() -> Vec<String> {
+    ["GET", "HEAD", "OPTIONS"].map(String::from).to_vec()
+}
+
+impl HedgeConfig {
+    pub fn validate(&self) -> Result<()> {
+        let url = reqwest::Url::parse(&self.alternate).context("hedge alternate")?;
+        if !matches!(url.scheme(), "http" | "https") || url.path() != "/" || url.query().is_some() {
+            bail!("hedge alternate must be an http(s) origin without path or query");
+        }
+        if !(self.percentile > 0.0 && self.percentile <= 100.0) {
+            bail!("hedge percentile must be in (0, 100]");
+        }
+        if self.min_delay_ms > self.max_delay_ms {
+            bail!("hedge min_delay_ms must not exceed max_delay_ms");
+        }
+        Ok(())
+    }
+
+    pub fn hedges(&self, method: &str) -> bool {
+        self.methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+    }
+
+    /// `url` pointed at the alternate origin.
+    pub fn alternate_url(&self, url: &reqwest::Url) -> Option<reqwest::Url> {
+        let origin = reqwest::Url::parse(&self.alternate).ok()?;
+        let mut alternate = url.clone();
+        alternate.set_scheme(origin.scheme()).ok()?;
+        alternate.set_host(origin.host_str()).ok()?;
+        alternate.set_port(origin.port()).ok()?;
+        Some(alternate)
+    }
+}
+
+/// Recent downstream latencies of a policy, with a sorted copy refreshed
+/// every `REFRESH` latencies, so that percentiles are looked up rather than
+/// sorted for every request.
+#[derive(Default)]
+struct Window {
+    samples: VecDeque<u64>,
+    sorted: Vec<u64>,
+    stale: usize,
+}
+
+/// Recent downstream latencies of policies hedging their requests.
+#[derive(Default)]
+pub struct Latencies {
+    policies: Mutex<HashMap<String, Window>>,
+}
+
+impl Latencies {
+    pub fn record(&self, policy: &str, latency: Duration) {
+        let mut policies = self.policies.lock().expect("latencies lock poisoned");
+        let window = policies.entry(policy.to_string()).or_default();
+        if window.samples.len() >= WINDOW {
+            window.samples.pop_front();
+        }
+        window.samples.push_back(latency.as_millis() as u64);
+        window.stale += 1;
+        if window.stale >= REFRESH || window.samples.len() == MIN_SAMPLES {
+            window.sorted.clear();
+            window.sorted.extend(window.samples.iter().copied());
+            window.sorted.sort_unstable();
+            window.stale = 0;
+        }
+    }
+
+    /// Time after which a request under `policy` is hedged.
+    pub fn delay(&self, policy: &str, config: &HedgeConfig) -> Duration {
+        let policies = self.policies.lock().expect("latencies lock poisoned");
+        let ms = match policies.get(policy).map(|w| &w.sorted).filter(|sorted| sorted.len() >= MIN_SAMPLES) {
+            Some(sorted) => {
+                let rank = ((config.percentile / 100.0 * sorted.len() as f64).ceil() as usize).clamp(1, sorted.len());
+                sorted[rank - 1].clamp(config.min_delay_ms, config.max_delay_ms)
+            },
+            None => config.min_delay_ms,
+        };
+        Duration::from_millis(ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> HedgeConfig {
+        serde_json::from_value(serde_json::json!({"alternate": "https://eu.api.example.com", "percentile": 90.0, "min_delay_ms": 1})).unwrap()
+    }
+
+    #[test]
+    fn delays_by_the_percentile_of_recent_latencies() {
+        let latencies = Latencies::default();
+        for ms in 1..MIN_SAMPLES as u64 {
+            latencies.record("api", Duration::from_millis(ms * 10));
+        }
+        assert_eq!(latencies.delay("api", &config()), Duration::from_millis(1));
+        latencies.record("api", Duration::from_millis(200));
+        assert_eq!(latencies.delay("api", &config()), Duration::from_millis(180));
+        // Slower latencies show once the sorted copy is refreshed
+        for _ in 0..REFRESH {
+            latencies.record("api", Duration::from_millis(500));
+        }
+        assert_eq!(latencies.delay("api", &config()), Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,172 @@
+use anyhow::{bail, Result};
+use axum::http::{header::AUTHORIZATION, HeaderMap};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::Deserialize;
+use std::{collections::HashMap, net::IpAddr};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyConfig {
+    /// Template the limiter key is rendered from, e.g. `{tenant}:{ip}`.
+    /// `{key}` (the client supplied key), `{ip}` and `{cert}` (the client
+    /// certificate identity) are always available.
+    pub template: String,
+    /// Additional named placeholders usable in the template.
+    pub vars: HashMap<String, KeySource>,
+    /// Header carrying the client address when grenze runs behind a trusted
+    /// reverse proxy. Entries are read from the right, since the left ones
+    /// are whatever the client sent.
+    pub ip_header: Option<String>,
+    /// Trusted proxies appending to `ip_header` behind the one in front of
+    /// grenze, whose entries at the end are skipped. 0 takes the rightmost
+    /// entry.
+    pub trusted_hops: usize,
+}
+
+impl Default for KeyConfig {
+    fn default() -> Self {
+        Self {
+            template: "{key}".to_string(),
+            vars: HashMap::new(),
+            ip_header: None,
+            trusted_hops: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum KeySource {
+    /// The `key` field of the proxy request.
+    Key,
+    /// The client IP address.
+    Ip,
+    /// Identity of the client certificate when mutual TLS is enabled.
+    Cert,
+    /// Value of a request header.
+    Header(String),
+    /// Claim of the bearer JWT in the `Authorization` header. The token is
+    /// decoded but not verified; authentication is expected to happen in front
+    /// of grenze.
+    Claim(String),
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Var(KeySource),
+}
+
+/// Compiled form of [`KeyConfig`], rendering limiter keys for incoming requests.
+#[derive(Debug, Clone)]
+pub struct KeyTemplate {
+    segments: Vec<Segment>,
+    ip_header: Option<String>,
+    trusted_hops: usize,
+}
+
+/// Request data a key can be derived from.
+pub struct KeyContext<'a> {
+    pub key: &'a str,
+    pub peer: Option<IpAddr>,
+    pub identity: Option<&'a str>,
+    pub headers: &'a HeaderMap,
+}
+
+impl KeyTemplate {
+    pub fn compile(config: &KeyConfig) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut rest = config.template.as_str();
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+            let Some(len) = rest[start..].find('}') else {
+                bail!("unterminated placeholder in key template '{}'", config.template);
+            };
+            let name = &rest[start + 1..start + len];
+            let source = match (name, config.vars.get(name)) {
+                (_, Some(source)) => source.clone(),
+                ("key", None) => KeySource::Key,
+                ("ip", None) => KeySource::Ip,
+                ("cert", None) => KeySource::Cert,
+                (_, None) => bail!("unknown placeholder '{{{}}}' in key template '{}'", name, config.template),
+            };
+            segments.push(Segment::Var(source));
+            rest = &rest[start + len + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+        if !segments.iter().any(|s| matches!(s, Segment::Var(_))) {
+            bail!("key template '{}' must contain at least one placeholder", config.template);
+        }
+        Ok(Self {
+            segments,
+            ip_header: config.ip_header.clone(),
+            trusted_hops: config.trusted_hops,
+        })
+    }
+
+    /// Renders the limiter key. On failure, returns a message describing which
+    /// part of the request was missing.
+    pub fn derive(&self, ctx: &KeyContext) -> Result<String, String> {
+        let mut out = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(s) => out.push_str(s),
+                Segment::Var(source) => out.push_str(&self.resolve(source, ctx)?),
+            }
+        }
+        Ok(out)
+    }
+
+    fn resolve(&self, source: &KeySource, ctx: &KeyContext) -> Result<String, String> {
+        match source {
+            KeySource::Key => {
+                let key = ctx.key.trim();
+                if key.is_empty() {
+                    return Err("Request must include non-empty 'key'".to_string());
+                }
+                Ok(key.to_string())
+            },
+            KeySource::Ip => self.client_ip(ctx).map(|ip| ip.to_string()).ok_or_else(|| "Client address unavailable".to_string()),
+            KeySource::Cert => ctx.identity.map(str::to_string).ok_or_else(|| "Request must present a client certificate".to_string()),
+            KeySource::Header(name) => ctx
+                .headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(str::to_string)
+                .ok_or_else(|| format!("Request must include header '{}'", name)),
+            KeySource::Claim(name) => {
+                bearer_claim(ctx.headers, name).ok_or_else(|| format!("Bearer token must carry claim '{}'", name))
+            },
+        }
+    }
+
+    /// Client address, taken from the configured forwarding header if present
+    /// and falling back to the peer address of the connection. The entry
+    /// `trusted_hops` from the right is used, or the leftmost of shorter
+    /// chains, which trusted proxies wrote all of.
+    pub fn client_ip(&self, ctx: &KeyContext) -> Option<IpAddr> {
+        let forwarded = self.ip_header.as_ref().and_then(|h| {
+            let entries: Vec<&str> = ctx.headers.get_all(h).iter().filter_map(|v| v.to_str().ok()).flat_map(|v| v.split(',')).collect();
+            let index = entries.len().checked_sub(1)?.saturating_sub(self.trusted_hops);
+            entries[index].trim().parse().ok()
+        });
+        forwarded.or(ctx.peer)
+    }
+}
+
+fn bearer_claim(headers: &HeaderMap, name: &str) -> Option<String> {
+    let token = headers.get(AUTHORIZATION)?.to_str().ok()?.strip_prefix("Bearer ")?;
+    let payload = token.split('.').nth(1)?;
+    let claims: serde_json::Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+    match claims.get(name)? {
+        serde_json::Value::String(s) if !s.is_empty() => Some(s.clone()),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
@@ -0,0 +1,131 @@
+//! Background tasks run by exactly one instance. The instances sharing the
+//! bucket store elect a leader through a lock in the store, which the leader
+//! keeps renewing, and scheduled tasks only run on the leader. Should the
+//! leader stop renewing the lock, e.g. because it crashed, another instance
+//! takes over once the lock expired.
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::{future::Future, sync::{atomic::{AtomicBool, Ordering}, Arc, OnceLock}, time::Duration};
+use tokio::sync::watch;
+
+use crate::{limiter::LimiterStore, logging};
+
+/// Lock held by the leader.
+const LEADER_LOCK: &str = "grenze:leader";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LeaderConfig {
+    /// Time after which leadership passes on if the leader stops renewing
+    /// it. The leader renews it every third of this time.
+    pub ttl_ms: u64,
+}
+
+impl Default for LeaderConfig {
+    fn default() -> Self {
+        Self { ttl_ms: 15_000 }
+    }
+}
+
+impl LeaderConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.ttl_ms < 300 {
+            bail!("leader ttl_ms must be at least 300");
+        }
+        Ok(())
+    }
+}
+
+/// Runs tasks on the elected leader. The election only starts with the first
+/// scheduled task.
+pub struct Scheduler {
+    limiter: Arc<dyn LimiterStore>,
+    ttl_ms: u64,
+    /// Identifies this instance as the holder of the leader lock.
+    holder: String,
+    leading: OnceLock<watch::Receiver<bool>>,
+    resigned: Arc<AtomicBool>,
+}
+
+impl Scheduler {
+    pub fn new(limiter: Arc<dyn LimiterStore>, config: &LeaderConfig) -> Self {
+        Self {
+            limiter,
+            ttl_ms: config.ttl_ms,
+            holder: format!("{}-{:016x}", std::process::id(), rand::random::<u64>()),
+            leading: OnceLock::new(),
+            resigned: Arc::default(),
+        }
+    }
+
+    /// Whether this instance leads, if the election has started.
+    pub fn is_leader(&self) -> Option<bool> {
+        self.leading.get().map(|leading| *leading.borrow())
+    }
+
+    /// Runs `task` every `interval` while this instance leads. Runs are
+    /// skipped while a previous one is still going.
+    pub fn every<F, Fut>(&self, name: &'static str, interval: Duration, task: F)
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send,
+    {
+        let leading = self.election();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if !*leading.borrow() {
+                    continue;
+                }
+                if let Err(e) = task().await {
+                    logging::warn("scheduled_task", format_args!("Scheduled task {} failed: {:#}", name, e));
+                }
+            }
+        });
+    }
+
+    /// Gives up leadership, so another instance takes over without waiting
+    /// for the lock to expire.
+    pub async fn resign(&self) {
+        self.resigned.store(true, Ordering::SeqCst);
+        if self.is_leader() == Some(true)
+            && let Err(e) = self.limiter.unlock(LEADER_LOCK, &self.holder).await
+        {
+            println!("Failed to give up leadership: {:#}", e);
+        }
+    }
+
+    fn election(&self) -> watch::Receiver<bool> {
+        self.leading
+            .get_or_init(|| {
+                let (tx, rx) = watch::channel(false);
+                let limiter = self.limiter.clone();
+                let holder = self.holder.clone();
+                let ttl_ms = self.ttl_ms;
+                let resigned = self.resigned.clone();
+                tokio::spawn(async move {
+                    while !resigned.load(Ordering::SeqCst) {
+                        let leading = match limiter.lock(LEADER_LOCK, &holder, ttl_ms).await {
+                            Ok(leading) => leading,
+                            Err(e) => {
+                                // Without a renewal, another instance may take over any moment
+                                logging::warn("leadership", format_args!("Failed to renew leadership: {:#}", e));
+                                false
+                            },
+                        };
+                        if leading != *tx.borrow() {
+                            println!("{}", if leading { "Elected leader" } else { "No longer leader" });
+                        }
+                        tx.send_replace(leading);
+                        tokio::time::sleep(Duration::from_millis(ttl_ms / 3)).await;
+                    }
+                });
+                rx
+            })
+            .clone()
+    }
+}
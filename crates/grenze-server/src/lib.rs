@@ -0,0 +1,62 @@
+pub mod anomaly;
+pub mod api;
+pub mod aws;
+pub mod billing;
+pub mod breaker;
+pub mod cardinality;
+pub mod chaos;
+pub mod classify;
+pub mod client_profiles;
+pub mod compression;
+pub mod concurrency;
+pub mod config;
+pub mod cookies;
+pub mod cors;
+pub mod credentials;
+pub mod destinations;
+pub mod dynamodb;
+pub mod encoding;
+pub mod erasure;
+pub mod etcd;
+pub mod expiry;
+pub mod fairness;
+pub mod geoip;
+pub mod har;
+pub mod headers;
+pub mod hedge;
+pub mod key;
+pub mod leader;
+pub mod limiter;
+pub mod lint;
+pub mod logging;
+pub mod memcached;
+pub mod metrics;
+pub mod middleware;
+pub mod migrate;
+pub mod mock;
+pub mod oauth;
+pub mod overrides;
+pub mod panics;
+#[cfg(feature = "wasm")]
+pub mod plugin;
+pub mod policy;
+pub mod postgres;
+pub mod recording;
+pub mod rejection;
+pub mod replication;
+pub mod rules;
+pub mod schema;
+pub mod script;
+pub mod server;
+pub mod shards;
+pub mod sidecar;
+pub mod signing;
+pub mod slo;
+pub mod statsd;
+pub mod systemd;
+pub mod tls;
+pub mod trace;
+pub mod transform;
+pub mod upgrade;
+pub mod upstream_tls;
+pub mod usage;
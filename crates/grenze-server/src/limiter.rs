@@ -0,0 +1,1975 @@
+//! Storage of the leaky buckets and of other short-lived markers, such as used
+//! request signatures.
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use redis::{ConnectionAddr, ErrorKind, IntoConnectionInfo, RedisError, Script, TlsCertificates};
+use serde::{Deserialize, Serialize};
+use std::{collections::{HashMap, VecDeque}, sync::{atomic::{AtomicI64, Ordering}, Arc}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use tokio::sync::Mutex;
+use utoipa::ToSchema;
+
+pub use grenze_core::{simulate, Bucket, TraceEntry, TraceOutcome};
+
+use crate::{breaker::{BreakerConfig, FailureMode}, cardinality::CardinalityConfig, dynamodb::DynamoDbConfig, erasure::{Erasure, Tenant}, expiry::ExpiryEventsConfig, logging, memcached::MemcachedConfig, postgres::PostgresConfig, replication::ReplicationConfig, shards::RedisShardsConfig, usage::{Granularity, UsageCount, UsageId}};
+
+/// Capacity and leak per second of the buckets the limiter config does not
+/// size.
+pub const DEFAULT_RATE: u32 = 1;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimiterConfig {
+    /// Requests a bucket holds, i.e. the burst allowed after idling; the
+    /// server's rate if unset.
+    pub capacity: Option<u32>,
+    /// Requests per second a bucket drains, i.e. the sustained rate; the
+    /// server's rate if unset.
+    pub leak_per_sec: Option<f64>,
+    /// Bucket sizes of specific rate limit keys, overriding their policy's.
+    pub keys: HashMap<String, BucketSize>,
+    /// Where buckets are stored.
+    pub store: StoreConfig,
+    /// Time source of the leak math.
+    pub clock: ClockSource,
+    /// How often the offset to the Redis clock is measured.
+    pub clock_sync_secs: u64,
+    /// Subscribe to Redis keyspace notifications to count expired buckets.
+    pub expiry_events: Option<ExpiryEventsConfig>,
+    /// Cap on the distinct buckets per tenant.
+    pub cardinality: Option<CardinalityConfig>,
+    /// Reconcile with a global budget shared across regions.
+    pub replication: Option<ReplicationConfig>,
+    /// Stops calling the store after repeated failures.
+    pub breaker: BreakerConfig,
+    /// How requests are answered while the store fails.
+    pub on_error: FailureMode,
+}
+
+impl Default for LimiterConfig {
+    fn default() -> Self {
+        Self {
+            capacity: None,
+            leak_per_sec: None,
+            keys: HashMap::new(),
+            store: StoreConfig::Redis,
+            clock: ClockSource::System,
+            clock_sync_secs: 30,
+            expiry_events: None,
+            cardinality: None,
+            replication: None,
+            breaker: BreakerConfig::default(),
+            on_error: FailureMode::FailClosed,
+        }
+    }
+}
+
+impl LimiterConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !matches!(self.store, StoreConfig::Redis) {
+            if self.clock == ClockSource::Redis {
+                bail!("limiter clock 'redis' requires the redis store");
+            }
+            if self.expiry_events.is_some() {
+                bail!("limiter expiry_events require the redis store");
+            }
+            if self.replication.is_some() {
+                bail!("limiter replication requires the redis store");
+            }
+        }
+        BucketSize::validate(self.capacity, self.leak_per_sec)?;
+        for (key, size) in &self.keys {
+            BucketSize::validate(size.capacity, size.leak_per_sec).with_context(|| format!("limiter key '{}'", key))?;
+        }
+        match &self.store {
+            StoreConfig::Postgres(postgres) => postgres.validate()?,
+            StoreConfig::RedisShards(shards) => shards.validate()?,
+            _ => {},
+        }
+        if let Some(cardinality) = &self.cardinality {
+            cardinality.validate()?;
+        }
+        if let Some(replication) = &self.replication {
+            replication.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// Size of a bucket; unset fields fall back to the broader setting.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BucketSize {
+    pub capacity: Option<u32>,
+    pub leak_per_sec: Option<f64>,
+}
+
+impl BucketSize {
+    pub fn validate(capacity: Option<u32>, leak_per_sec: Option<f64>) -> Result<()> {
+        if capacity == Some(0) {
+            bail!("capacity must be positive");
+        }
+        if leak_per_sec.is_some_and(|l| l <= 0.0 || !l.is_finite()) {
+            bail!("leak_per_sec must be positive");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StoreConfig {
+    /// Redis at `REDIS_URL`.
+    #[default]
+    Redis,
+    Memcached(MemcachedConfig),
+    Dynamodb(DynamoDbConfig),
+    Postgres(PostgresConfig),
+    /// Several Redis servers, each holding the buckets hashed to it.
+    RedisShards(RedisShardsConfig),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClockSource {
+    /// The local system clock.
+    #[default]
+    System,
+    /// The Redis server's clock, so instances with skewed clocks agree on
+    /// how much a bucket has leaked.
+    Redis,
+}
+
+#[async_trait]
+pub trait LimiterStore: Send + Sync {
+    /// Admits a request costing `cost` tokens into `bucket` at `now_ms` if it
+    /// has room after leaking `leak_per_sec` since the previous request.
+    async fn allow(&self, bucket: &str, cost: f64, capacity: u32, leak_per_sec: f64, now_ms: i64) -> Result<Admission>;
+
+    /// Admits a request costing `cost` tokens into every bucket of `limits`
+    /// at `now_ms` if it fits into all of them, and into none otherwise.
+    /// Returns the outcome per bucket, `allowed` telling whether it had room.
+    async fn allow_all(&self, _limits: &[BucketLimit], _cost: f64, _now_ms: i64) -> Result<Vec<Admission>> {
+        bail!("this limiter store does not support multiple limits")
+    }
+
+    /// Admits a batch of independent requests at `now_ms`, each with its
+    /// limits and cost and admitted as by `allow_all`, in one round trip if
+    /// the store can. Returns the outcomes per request and bucket.
+    async fn allow_batch(&self, requests: &[(&[BucketLimit], f64)], now_ms: i64) -> Result<Vec<Vec<Admission>>> {
+        let mut outcomes = Vec::with_capacity(requests.len());
+        for (limits, cost) in requests {
+            outcomes.push(match limits {
+                [limit] => vec![self.allow(&limit.bucket, *cost, limit.capacity, limit.leak_per_sec, now_ms).await?],
+                _ => self.allow_all(limits, *cost, now_ms).await?,
+            });
+        }
+        Ok(outcomes)
+    }
+
+    /// Records `key` for `ttl_secs`. Returns false if it is already recorded.
+    async fn remember(&self, key: &str, ttl_secs: u64) -> Result<bool>;
+
+    /// Drops `key` recorded by [`remember`](Self::remember), e.g. when what
+    /// it marked as done failed after all.
+    async fn forget(&self, key: &str);
+
+    /// Drops `bucket`, as if it had drained.
+    async fn evict(&self, bucket: &str);
+
+    /// Adds `amount` to `bucket` at `now_ms` after leaking it, even beyond
+    /// its capacity; a negative amount returns tokens. Returns the resulting
+    /// fill.
+    async fn charge(&self, _bucket: &str, _amount: f64, _capacity: u32, _leak_per_sec: f64, _now_ms: i64) -> Result<f64> {
+        bail!("this limiter store does not support charging buckets")
+    }
+
+    /// Fill level of `bucket` at `now_ms` after leaking, leaving it as it is.
+    async fn level(&self, _bucket: &str, _leak_per_sec: f64, _now_ms: i64) -> Result<f64> {
+        bail!("this limiter store does not support reading buckets")
+    }
+
+    /// Time `key` was first recorded, recording it at `now_ms` if it is not.
+    /// The record expires once unused for `ttl_secs`.
+    async fn first_seen(&self, _key: &str, _now_ms: i64, _ttl_secs: u64) -> Result<i64> {
+        bail!("this limiter store does not support tracking keys")
+    }
+
+    /// Cookie jar saved as `key`, if there is one.
+    async fn cookie_jar(&self, _key: &str) -> Result<Option<String>> {
+        bail!("this limiter store does not support cookie jars")
+    }
+
+    /// Saves `jar` as `key`, expiring after `ttl_secs`.
+    async fn store_cookie_jar(&self, _key: &str, _jar: &str, _ttl_secs: u64) -> Result<()> {
+        bail!("this limiter store does not support cookie jars")
+    }
+
+    /// Takes the lock `name` for `holder` for `ttl_ms`, or extends it if the
+    /// holder already has it. False if another holder has it.
+    async fn lock(&self, _name: &str, _holder: &str, _ttl_ms: u64) -> Result<bool> {
+        bail!("this limiter store does not support locks")
+    }
+
+    /// Releases the lock `name` if `holder` has it.
+    async fn unlock(&self, _name: &str, _holder: &str) -> Result<()> {
+        bail!("this limiter store does not support locks")
+    }
+
+    /// Adds `counts` to the raw usage of their minutes, which expires after
+    /// `ttl_secs` unless taken before.
+    async fn record_usage(&self, _counts: &[UsageCount], _ttl_secs: u64) -> Result<()> {
+        bail!("this limiter store does not support usage history")
+    }
+
+    /// Removes and returns the raw usage of the minutes starting before
+    /// `before_ms`.
+    async fn take_usage(&self, _before_ms: i64) -> Result<Vec<UsageCount>> {
+        bail!("this limiter store does not support usage history")
+    }
+
+    /// Adds `counts` to the rollups of `granularity`, which expire
+    /// `retention_secs` after their period started.
+    async fn roll_up_usage(&self, _granularity: Granularity, _counts: &[UsageCount], _retention_secs: u64) -> Result<()> {
+        bail!("this limiter store does not support usage history")
+    }
+
+    /// Rollups of `key` for the periods of `granularity` starting within
+    /// `from_ms..to_ms`.
+    async fn usage(&self, _key: &str, _granularity: Granularity, _from_ms: i64, _to_ms: i64) -> Result<Vec<UsageCount>> {
+        bail!("this limiter store does not support usage history")
+    }
+
+    /// Keys with rollups of `granularity` for periods starting within
+    /// `from_ms..to_ms`.
+    async fn usage_keys(&self, _granularity: Granularity, _from_ms: i64, _to_ms: i64) -> Result<Vec<String>> {
+        bail!("this limiter store does not support usage history")
+    }
+
+    /// Deletes the rollups of `granularity` for periods starting before
+    /// `before_ms`, or the raw usage of the minutes before it without a
+    /// granularity. Returns the number of records deleted.
+    async fn purge_usage(&self, _granularity: Option<Granularity>, _before_ms: i64) -> Result<u64> {
+        bail!("this limiter store does not support usage history")
+    }
+
+    /// Counts a request against the allowance of `quota`, handling it by the
+    /// quota's overage if the allowance is used up, all in one step. A
+    /// throttled request costs `cost` tokens.
+    async fn allow_quota(&self, _quota: &QuotaLimit, _cost: f64, _now_ms: i64) -> Result<QuotaAdmission> {
+        bail!("this limiter store does not support quotas")
+    }
+
+    /// Adds `bytes` of a response to the quota counter `counter`, which
+    /// expires at `until_ms`.
+    async fn charge_quota(&self, _counter: &str, _bytes: u64, _until_ms: i64) -> Result<()> {
+        bail!("this limiter store does not support quotas")
+    }
+
+    /// Admits a request costing `cost` tokens into the sliding log `log` at
+    /// `now_ms` if fewer tokens were taken within its window than it allows,
+    /// logging each token. A cost of 0 reads the log.
+    async fn allow_log(&self, _log: &LogLimit, _cost: u32, _now_ms: i64) -> Result<LogAdmission> {
+        bail!("this limiter store does not support sliding logs")
+    }
+
+    /// Takes `cost` tokens logged at `now_ms` out of the sliding log `log`
+    /// again, e.g. when a later check rejected the request they were taken
+    /// for.
+    async fn release_log(&self, _log: &LogLimit, _cost: u32, _now_ms: i64) -> Result<()> {
+        bail!("this limiter store does not support sliding logs")
+    }
+
+    /// Deletes the buckets, markers, quotas and usage of `tenant`.
+    async fn erase(&self, _tenant: &Tenant) -> Result<Erasure> {
+        bail!("this limiter store does not support erasing tenants")
+    }
+
+    /// Checks that the store is reachable.
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// State of every bucket currently holding requests.
+    async fn export(&self) -> Result<Vec<BucketState>> {
+        bail!("this limiter store does not support snapshots")
+    }
+
+    /// Overwrites the given buckets, e.g. from another store's export.
+    async fn import(&self, _buckets: &[BucketState], _capacity: u32, _leak_per_sec: f64) -> Result<()> {
+        bail!("this limiter store does not support snapshots")
+    }
+
+    /// Counters of every quota whose period has not ended at `now_ms`.
+    async fn export_quotas(&self, _now_ms: i64) -> Result<Vec<QuotaState>> {
+        bail!("this limiter store does not support quotas")
+    }
+
+    /// Overwrites the given quota counters, e.g. from another store's export.
+    async fn import_quotas(&self, _quotas: &[QuotaState]) -> Result<()> {
+        bail!("this limiter store does not support quotas")
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Admission {
+    pub allowed: bool,
+    /// Fill level of the bucket after the request.
+    pub fill: f64,
+}
+
+/// A bucket a request must fit into, with its size.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BucketLimit {
+    pub bucket: String,
+    pub capacity: u32,
+    pub leak_per_sec: f64,
+}
+
+/// A key's allowance in the current period of its plan.
+#[derive(Debug, Clone)]
+pub struct QuotaLimit {
+    /// Counter of the key's requests and response bytes in the period.
+    pub counter: String,
+    pub included_requests: Option<u64>,
+    pub included_bytes: Option<u64>,
+    /// End of the period, when the counter expires.
+    pub until_ms: i64,
+    pub overage: QuotaOverage,
+}
+
+/// Requests and response bytes counted against a quota.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaState {
+    pub counter: String,
+    pub requests: u64,
+    pub bytes: u64,
+    /// End of the quota's period, when the counter expires.
+    pub until_ms: i64,
+}
+
+/// How requests are handled once a quota's allowance is used up.
+#[derive(Debug, Clone)]
+pub enum QuotaOverage {
+    Reject,
+    Allow,
+    /// Admitted if they fit into the bucket.
+    Throttle(BucketLimit),
+}
+
+/// A log of the tokens taken within a sliding window, limiting them
+/// exactly rather than approximating them by a leaky bucket.
+#[derive(Debug, Clone)]
+pub struct LogLimit {
+    pub log: String,
+    /// Tokens taken within the window at most.
+    pub limit: u32,
+    pub window_ms: i64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LogAdmission {
+    pub allowed: bool,
+    /// Tokens taken within the window, including the request's if admitted.
+    pub taken: u32,
+    /// When the window has room for the request if it was rejected, else
+    /// when its oldest token leaves it.
+    pub until_ms: i64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QuotaAdmission {
+    pub allowed: bool,
+    /// Whether the allowance was used up before the request.
+    pub overage: bool,
+}
+
+/// Persisted state of one bucket, as of `last_ms`.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct BucketState {
+    pub bucket: String,
+    pub fill: f64,
+    pub last_ms: i64,
+}
+
+/// Longest a bucket is kept, a year, so the TTLs of buckets leaking
+/// extremely slowly stay within what stores accept.
+pub(crate) const MAX_BUCKET_TTL_MS: i64 = 365 * 24 * 3600 * 1000;
+
+/// Grace added to bucket TTLs, covering clock differences between instances.
+pub(crate) const BUCKET_TTL_GRACE_MS: i64 = 1000;
+
+/// Milliseconds until an untouched bucket holding `fill` has fully leaked,
+/// plus a grace period. Buckets may be overfilled by charges, so this is
+/// computed from their fill rather than their capacity.
+pub(crate) fn bucket_ttl_ms(fill: f64, leak_per_sec: f64) -> i64 {
+    let drain_ms = (fill.max(0.0) * 1000.0 / leak_per_sec).ceil();
+    (drain_ms + BUCKET_TTL_GRACE_MS as f64).min(MAX_BUCKET_TTL_MS as f64) as i64
+}
+
+/// `bucket_ttl_ms` rounded up to seconds, for stores expiring with second
+/// precision.
+pub(crate) fn bucket_ttl_secs(fill: f64, leak_per_sec: f64) -> i64 {
+    (bucket_ttl_ms(fill, leak_per_sec) + 999) / 1000
+}
+
+/// Source of the current time in milliseconds since the Unix epoch.
+pub trait Clock: Send + Sync {
+    fn now_ms(&self) -> i64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or(0)
+    }
+}
+
+/// The Redis server's clock, read through a periodically measured offset to
+/// the local clock instead of a round trip per request.
+pub struct RedisClock {
+    offset_ms: Arc<AtomicI64>,
+}
+
+impl RedisClock {
+    pub async fn start(mut conn: redis::aio::MultiplexedConnection, sync_interval: Duration) -> Result<Self> {
+        let offset_ms = Arc::new(AtomicI64::new(redis_offset(&mut conn).await?));
+        let offset = offset_ms.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(sync_interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                match redis_offset(&mut conn).await {
+                    Ok(o) => offset.store(o, Ordering::Relaxed),
+                    Err(e) => logging::warn("redis_clock", format_args!("Failed to read the Redis clock: {}", e)),
+                }
+            }
+        });
+        Ok(Self { offset_ms })
+    }
+}
+
+impl Clock for RedisClock {
+    fn now_ms(&self) -> i64 {
+        SystemClock.now_ms() + self.offset_ms.load(Ordering::Relaxed)
+    }
+}
+
+/// Offset of the Redis clock to the local one, assuming symmetric latency.
+async fn redis_offset(conn: &mut redis::aio::MultiplexedConnection) -> redis::RedisResult<i64> {
+    let before = SystemClock.now_ms();
+    let (secs, micros): (i64, i64) = redis::cmd("TIME").query_async(conn).await?;
+    let after = SystemClock.now_ms();
+    Ok(secs * 1000 + micros / 1000 - (before + after) / 2)
+}
+
+/// Connection settings applied on top of `REDIS_URL`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedisConfig {
+    /// ACL user, instead of the one in the URL.
+    pub username: Option<String>,
+    /// Password, instead of the one in the URL; read from `REDIS_PASSWORD`
+    /// if unset.
+    pub password: Option<String>,
+    /// Database index, instead of the one in the URL.
+    pub db: Option<i64>,
+    /// PEM file with the CA certificates trusted for `rediss://` URLs instead
+    /// of the system's.
+    pub ca_file: Option<String>,
+    /// Name reported for grenze's connections in `CLIENT LIST`.
+    pub client_name: String,
+    /// Prefix of every key grenze stores, followed by a colon, so several
+    /// environments or tenants can share a Redis database, e.g. `prod-eu`.
+    pub namespace: Option<String>,
+    /// Installs the bucket logic as a Redis Function library at startup and
+    /// calls it with `FCALL` instead of evaluating scripts; needs Redis 7.
+    pub functions: bool,
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        Self {
+            username: None,
+            password: None,
+            db: None,
+            ca_file: None,
+            client_name: "grenze".to_string(),
+            namespace: None,
+            functions: false,
+        }
+    }
+}
+
+impl RedisConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(ca_file) = &self.ca_file {
+            std::fs::read(ca_file).with_context(|| format!("failed to read redis ca_file {}", ca_file))?;
+        }
+        if self.client_name.contains(char::is_whitespace) {
+            bail!("redis client_name must not contain whitespace");
+        }
+        if let Some(namespace) = &self.namespace
+            && (namespace.is_empty() || namespace.contains(|c: char| c.is_whitespace() || "*?[]\\".contains(c)))
+        {
+            bail!("redis namespace must not be empty or contain whitespace or glob characters");
+        }
+        Ok(())
+    }
+
+    /// Prefix of every key grenze stores, empty without a namespace.
+    pub fn prefix(&self) -> String {
+        self.namespace.as_ref().map(|ns| format!("{}:", ns)).unwrap_or_default()
+    }
+
+    /// Builds a client for `redis_url` with these settings applied.
+    pub fn client(&self, redis_url: &str) -> Result<redis::Client> {
+        let mut info = redis_url.into_connection_info()?;
+        if let Some(username) = &self.username {
+            info.redis.username = Some(username.clone());
+        }
+        if let Some(password) = self.password.clone().or_else(|| std::env::var("REDIS_PASSWORD").ok()) {
+            info.redis.password = Some(password);
+        }
+        if let Some(db) = self.db {
+            info.redis.db = db;
+        }
+        let Some(ca_file) = &self.ca_file else {
+            return Ok(redis::Client::open(info)?);
+        };
+        if !matches!(info.addr, ConnectionAddr::TcpTls { .. }) {
+            return Err(RedisError::from((ErrorKind::InvalidClientConfig, "redis ca_file requires a rediss:// url")).into());
+        }
+        let root_cert = std::fs::read(ca_file).with_context(|| format!("failed to read redis ca_file {}", ca_file))?;
+        let certs = TlsCertificates {
+            client_tls: None,
+            root_cert: Some(root_cert),
+        };
+        Ok(redis::Client::build_with_tls(info, certs)?)
+    }
+}
+
+/// Whether starting over cannot fix `err`, such as rejected credentials or
+/// missing ACL permissions.
+pub fn is_permanent(err: &anyhow::Error) -> bool {
+    err.chain().filter_map(|e| e.downcast_ref::<RedisError>()).any(|e| {
+        matches!(e.kind(), ErrorKind::AuthenticationFailed | ErrorKind::InvalidClientConfig) || e.code() == Some("NOPERM")
+    })
+}
+
+/// Buckets shared by all instances through Redis.
+pub struct RedisStore {
+    client: redis::Client,
+    client_name: String,
+    conn: Mutex<redis::aio::MultiplexedConnection>,
+    /// Whether the bucket logic is called as functions of the library.
+    functions: bool,
+    /// Prefix of every key, from the namespace.
+    prefix: String,
+}
+
+impl RedisStore {
+    pub async fn connect(redis_url: &str, config: &RedisConfig) -> Result<Self> {
+        let client = config.client(redis_url)?;
+        let mut conn = client
+            .get_multiplexed_tokio_connection()
+            .await
+            .map_err(|e| match e.kind() {
+                ErrorKind::AuthenticationFailed => anyhow::Error::new(e).context("redis rejected the credentials"),
+                _ => e.into(),
+            })?;
+        redis::cmd("CLIENT").arg("SETNAME").arg(&config.client_name).query_async::<()>(&mut conn).await?;
+        if config.functions {
+            load_functions(&mut conn).await.context("failed to load the grenze function library; redis functions need Redis 7")?;
+        }
+        check_permissions(&mut conn, config.functions, &config.prefix()).await?;
+        Ok(Self {
+            client,
+            client_name: config.client_name.clone(),
+            conn: Mutex::new(conn),
+            functions: config.functions,
+            prefix: config.prefix(),
+        })
+    }
+
+    /// Name in Redis of the key `name`, within the namespace.
+    fn key(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, name)
+    }
+
+    /// Name in Redis of the hash holding the `fill` and timestamp `ts` of
+    /// `bucket`, always written together.
+    fn bucket_key(&self, bucket: &str) -> String {
+        format!("{}rl:{}", self.prefix, bucket)
+    }
+
+    /// Name in Redis of the sorted set holding the tokens of the sliding log
+    /// `log`.
+    fn log_key(&self, log: &str) -> String {
+        format!("{}log:{}", self.prefix, log)
+    }
+
+    /// Client the store's connection was opened with.
+    pub fn client(&self) -> &redis::Client {
+        &self.client
+    }
+
+    /// Starts a clock following the Redis server's time.
+    pub async fn clock(&self, sync_interval: Duration) -> Result<RedisClock> {
+        let conn = self.conn.lock().await.clone();
+        RedisClock::start(conn, sync_interval).await
+    }
+}
+
+/// Redis Lua script implementing a leaky bucket.
+/// Returns 1 if allowed and adds the cost to the bucket, 0 otherwise,
+/// followed by the resulting fill level. The bucket's keys expire once it has
+/// fully leaked, as by `bucket_ttl_ms`, but no later than after `ARGV[4]`
+/// milliseconds.
+const LEAKY_BUCKET_LUA: &str = r#"
+local key = KEYS[1]
+
+local capacity = tonumber(ARGV[1])
+local leak_per_sec = tonumber(ARGV[2])
+local now_ms = tonumber(ARGV[3])
+local max_ttl_ms = tonumber(ARGV[4])
+local cost = tonumber(ARGV[5])
+
+local state = redis.call('HMGET', key, 'fill', 'ts')
+local fill = tonumber(state[1] or '0')
+local last = tonumber(state[2] or now_ms)
+local elapsed_ms = now_ms - last
+if elapsed_ms < 0 then elapsed_ms = 0 end
+
+local leaked = (elapsed_ms / 1000.0) * leak_per_sec
+fill = fill - leaked
+if fill < 0 then fill = 0 end
+
+local allowed = 0
+if (fill + cost) <= capacity then
+  fill = fill + cost
+  allowed = 1
+end
+
+-- Update timestamp to avoid burst after long idle and set the TTL
+local ttl_ms = math.min(math.ceil(fill * 1000 / leak_per_sec) + 1000, max_ttl_ms)
+redis.call('HSET', key, 'fill', tostring(fill), 'ts', now_ms)
+redis.call('PEXPIRE', key, ttl_ms)
+return {allowed, tostring(fill)}
+"#;
+
+/// Redis Lua script admitting a request into several leaky buckets at once,
+/// adding the cost to all of them only if it fits into each. `ARGV` holds the
+/// time, cost and longest TTL in milliseconds, followed by capacity and leak
+/// rate of every key. Returns whether each bucket had room and its resulting
+/// fill level, in turns.
+const MULTI_BUCKET_LUA: &str = r#"
+local now_ms = tonumber(ARGV[1])
+local cost = tonumber(ARGV[2])
+local max_ttl_ms = tonumber(ARGV[3])
+
+local fills = {}
+local fits = true
+for i, key in ipairs(KEYS) do
+  local capacity = tonumber(ARGV[i * 2 + 2])
+  local leak_per_sec = tonumber(ARGV[i * 2 + 3])
+  local state = redis.call('HMGET', key, 'fill', 'ts')
+  local fill = tonumber(state[1] or '0')
+  local last = tonumber(state[2] or now_ms)
+  local elapsed_ms = now_ms - last
+  if elapsed_ms < 0 then elapsed_ms = 0 end
+  fill = fill - (elapsed_ms / 1000.0) * leak_per_sec
+  if fill < 0 then fill = 0 end
+  fills[i] = fill
+  if (fill + cost) > capacity then fits = false end
+end
+
+local result = {}
+for i, key in ipairs(KEYS) do
+  local capacity = tonumber(ARGV[i * 2 + 2])
+  local leak_per_sec = tonumber(ARGV[i * 2 + 3])
+  local fill = fills[i]
+  if (fill + cost) <= capacity then result[i * 2 - 1] = 1 else result[i * 2 - 1] = 0 end
+  if fits then fill = fill + cost end
+  local ttl_ms = math.min(math.ceil(fill * 1000 / leak_per_sec) + 1000, max_ttl_ms)
+  redis.call('HSET', key, 'fill', tostring(fill), 'ts', now_ms)
+  redis.call('PEXPIRE', key, ttl_ms)
+  result[i * 2] = tostring(fill)
+end
+return result
+"#;
+
+/// Name of the Redis Function library holding the bucket logic.
+const FUNCTION_LIBRARY: &str = "grenze";
+
+/// Installs the bucket scripts as functions of the library, replacing the
+/// version loaded by an older grenze.
+async fn load_functions(conn: &mut redis::aio::MultiplexedConnection) -> redis::RedisResult<()> {
+    let library = format!(
+        "#!lua name={}\nredis.register_function('{}', function(KEYS, ARGV)\n{}\nend)\nredis.register_function('{}', function(KEYS, ARGV)\n{}\nend)\n",
+        FUNCTION_LIBRARY,
+        BucketCall::ADMIT.1,
+        BucketCall::ADMIT.0,
+        BucketCall::ADMIT_ALL.1,
+        BucketCall::ADMIT_ALL.0
+    );
+    redis::cmd("FUNCTION").arg("LOAD").arg("REPLACE").arg(library).query_async(conn).await
+}
+
+/// Invocation of a bucket script, evaluated as a script or called as a
+/// function of the library.
+struct BucketCall {
+    /// Script and name of the function running it.
+    logic: (&'static str, &'static str),
+    keys: Vec<String>,
+    args: Vec<String>,
+}
+
+impl BucketCall {
+    const ADMIT: (&'static str, &'static str) = (LEAKY_BUCKET_LUA, "grenze_admit");
+    const ADMIT_ALL: (&'static str, &'static str) = (MULTI_BUCKET_LUA, "grenze_admit_all");
+
+    fn new(logic: (&'static str, &'static str)) -> Self {
+        Self { logic, keys: Vec::new(), args: Vec::new() }
+    }
+
+    fn key(&mut self, key: String) -> &mut Self {
+        self.keys.push(key);
+        self
+    }
+
+    fn arg(&mut self, arg: impl ToString) -> &mut Self {
+        self.args.push(arg.to_string());
+        self
+    }
+
+    /// Call of one bucket, as by `LEAKY_BUCKET_LUA`.
+    fn admit(bucket_key: String, cost: f64, capacity: u32, leak_per_sec: f64, now_ms: i64) -> Self {
+        let mut call = Self::new(Self::ADMIT);
+        call.key(bucket_key).arg(capacity).arg(leak_per_sec).arg(now_ms).arg(MAX_BUCKET_TTL_MS).arg(cost);
+        call
+    }
+
+    /// Call of several buckets at once, as by `MULTI_BUCKET_LUA`, their keys
+    /// prefixed with `prefix`.
+    fn admit_all(prefix: &str, limits: &[BucketLimit], cost: f64, now_ms: i64) -> Self {
+        let mut call = Self::new(Self::ADMIT_ALL);
+        call.arg(now_ms).arg(cost).arg(MAX_BUCKET_TTL_MS);
+        for limit in limits {
+            call.key(format!("{}rl:{}", prefix, limit.bucket)).arg(limit.capacity).arg(limit.leak_per_sec);
+        }
+        call
+    }
+}
+
+/// Admission from what a bucket script returns for a bucket.
+fn admission((allowed, fill): (i64, String)) -> Admission {
+    Admission {
+        allowed: allowed == 1,
+        fill: fill.parse().unwrap_or(0.0),
+    }
+}
+
+/// Runs bucket calls in one round trip, loading the scripts, or the library,
+/// if Redis lost them, e.g. after a restart.
+async fn run<T: redis::FromRedisValue>(conn: &mut redis::aio::MultiplexedConnection, functions: bool, calls: &[BucketCall]) -> redis::RedisResult<Vec<T>> {
+    let scripts: Vec<Script> = calls.iter().map(|call| Script::new(call.logic.0)).collect();
+    let mut pipe = redis::pipe();
+    for (call, script) in calls.iter().zip(&scripts) {
+        if functions {
+            pipe.cmd("FCALL").arg(call.logic.1).arg(call.keys.len()).arg(&call.keys).arg(&call.args);
+        } else {
+            let mut invocation = script.prepare_invoke();
+            for key in &call.keys {
+                invocation.key(key);
+            }
+            for arg in &call.args {
+                invocation.arg(arg);
+            }
+            pipe.invoke_script(&invocation);
+        }
+    }
+    match pipe.query_async(conn).await {
+        Err(e) if functions && e.detail().is_some_and(|d| d.starts_with("Function not found")) => {
+            load_functions(conn).await?;
+            pipe.query_async(conn).await
+        },
+        // Pipelined scripts are not loaded on demand like single invocations
+        Err(e) if e.kind() == ErrorKind::NoScriptError => {
+            for script in &scripts {
+                script.prepare_invoke().load_async(conn).await?;
+            }
+            pipe.query_async(conn).await
+        },
+        result => result,
+    }
+}
+
+/// Redis Lua script taking a lock for a holder, or extending it if the holder
+/// already has it. Returns 1 if the holder has the lock.
+const LOCK_LUA: &str = r#"
+local holder = redis.call('GET', KEYS[1])
+if holder == false or holder == ARGV[1] then
+    redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+    return 1
+end
+return 0
+"#;
+
+/// Redis Lua script releasing a lock if the holder has it.
+const UNLOCK_LUA: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    redis.call('DEL', KEYS[1])
+end
+return 0
+"#;
+
+/// Sorted set of the minutes holding raw usage, each in a hash named after
+/// the set and the minute.
+pub(crate) const RAW_USAGE: &str = "usage:raw";
+
+/// Redis Lua script counting a request against a quota. `KEYS[1]` is the
+/// counter hash and `KEYS[2]` the bucket throttling requests past the
+/// allowance. `ARGV` holds the included requests and bytes (negative if
+/// unlimited), the counter's expiry in seconds, the overage (`reject`,
+/// `allow` or `throttle`), and the bucket's capacity, leak rate and longest
+/// TTL in milliseconds, the time and the cost. Returns whether the request is admitted and
+/// whether the allowance was used up.
+const QUOTA_LUA: &str = r#"
+local included_requests = tonumber(ARGV[1])
+local included_bytes = tonumber(ARGV[2])
+local overage = ARGV[4]
+
+local requests = tonumber(redis.call('HGET', KEYS[1], 'requests') or '0')
+local bytes = tonumber(redis.call('HGET', KEYS[1], 'bytes') or '0')
+local over = (included_requests >= 0 and requests >= included_requests) or (included_bytes >= 0 and bytes >= included_bytes)
+
+if over and overage == 'reject' then
+  return {0, 1}
+end
+if over and overage == 'throttle' then
+  local capacity = tonumber(ARGV[5])
+  local leak_per_sec = tonumber(ARGV[6])
+  local max_ttl_ms = tonumber(ARGV[7])
+  local now_ms = tonumber(ARGV[8])
+  local cost = tonumber(ARGV[9])
+  local state = redis.call('HMGET', KEYS[2], 'fill', 'ts')
+  local fill = tonumber(state[1] or '0')
+  local last = tonumber(state[2] or now_ms)
+  local elapsed_ms = now_ms - last
+  if elapsed_ms < 0 then elapsed_ms = 0 end
+  fill = fill - (elapsed_ms / 1000.0) * leak_per_sec
+  if fill < 0 then fill = 0 end
+  local fits = (fill + cost) <= capacity
+  if fits then fill = fill + cost end
+  local ttl_ms = math.min(math.ceil(fill * 1000 / leak_per_sec) + 1000, max_ttl_ms)
+  redis.call('HSET', KEYS[2], 'fill', tostring(fill), 'ts', now_ms)
+  redis.call('PEXPIRE', KEYS[2], ttl_ms)
+  if not fits then
+    return {0, 1}
+  end
+end
+
+redis.call('HINCRBY', KEYS[1], 'requests', 1)
+redis.call('EXPIREAT', KEYS[1], ARGV[3])
+if over then return {1, 1} end
+return {1, 0}
+"#;
+
+/// Redis Lua script admitting a request into a sliding log. `KEYS[1]` is the
+/// sorted set of the tokens taken, scored by time. `ARGV` holds the limit,
+/// the window in milliseconds, the time, the cost and an ID unique to the
+/// request. Returns whether the request is admitted, the tokens taken within
+/// the window and when it has room for the request, or for the next token if
+/// admitted.
+const SLIDING_LOG_LUA: &str = r#"
+local limit = tonumber(ARGV[1])
+local window_ms = tonumber(ARGV[2])
+local now_ms = tonumber(ARGV[3])
+local cost = tonumber(ARGV[4])
+
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', now_ms - window_ms)
+local taken = redis.call('ZCARD', KEYS[1])
+local allowed = taken + cost <= limit
+if allowed and cost > 0 then
+  for i = 1, cost do
+    redis.call('ZADD', KEYS[1], now_ms, ARGV[5] .. ':' .. i)
+  end
+  taken = taken + cost
+  redis.call('PEXPIRE', KEYS[1], window_ms)
+end
+
+-- Room frees up as tokens leave the window, oldest first
+local index = 0
+if not allowed then index = math.min(taken + cost - limit, taken) - 1 end
+local oldest = redis.call('ZRANGE', KEYS[1], index, index, 'WITHSCORES')
+local until_ms = now_ms
+if oldest[2] then until_ms = tonumber(oldest[2]) + window_ms end
+if allowed then return {1, taken, until_ms} end
+return {0, taken, until_ms}
+"#;
+
+/// Redis Lua script removing up to `ARGV[2]` tokens logged at `ARGV[1]` from
+/// the sliding log `KEYS[1]`.
+const RELEASE_LOG_LUA: &str = r#"
+local taken = redis.call('ZRANGEBYSCORE', KEYS[1], ARGV[1], ARGV[1], 'LIMIT', 0, tonumber(ARGV[2]))
+if #taken > 0 then
+  redis.call('ZREM', KEYS[1], unpack(taken))
+end
+return #taken
+"#;
+
+/// Redis Lua script adding to a leaky bucket regardless of its capacity.
+/// Returns the resulting fill level.
+const CHARGE_LUA: &str = r#"
+local key = KEYS[1]
+
+local amount = tonumber(ARGV[1])
+local leak_per_sec = tonumber(ARGV[2])
+local now_ms = tonumber(ARGV[3])
+local max_ttl_ms = tonumber(ARGV[4])
+
+local state = redis.call('HMGET', key, 'fill', 'ts')
+local fill = tonumber(state[1] or '0')
+local last = tonumber(state[2] or now_ms)
+local elapsed_ms = now_ms - last
+if elapsed_ms < 0 then elapsed_ms = 0 end
+
+fill = fill - (elapsed_ms / 1000.0) * leak_per_sec
+if fill < 0 then fill = 0 end
+fill = fill + amount
+if fill < 0 then fill = 0 end
+
+-- An overfilled bucket takes longer than its capacity to drain
+local ttl_ms = math.min(math.ceil(fill * 1000 / leak_per_sec) + 1000, max_ttl_ms)
+redis.call('HSET', key, 'fill', tostring(fill), 'ts', now_ms)
+redis.call('PEXPIRE', key, ttl_ms)
+return tostring(fill)
+"#;
+
+/// Names of the keys matching `pattern`.
+pub(crate) async fn scan(conn: &mut redis::aio::MultiplexedConnection, pattern: &str) -> redis::RedisResult<Vec<String>> {
+    let mut names = Vec::new();
+    let mut cursor: u64 = 0;
+    loop {
+        let (next, batch): (u64, Vec<String>) = redis::cmd("SCAN").arg(cursor).arg("MATCH").arg(pattern).arg("COUNT").arg(1000).query_async(conn).await?;
+        names.extend(batch);
+        if next == 0 {
+            return Ok(names);
+        }
+        cursor = next;
+    }
+}
+
+/// Runs the commands the limiter relies on once, so an ACL user lacking
+/// permissions fails at startup rather than on every request.
+async fn check_permissions(conn: &mut redis::aio::MultiplexedConnection, functions: bool, prefix: &str) -> Result<()> {
+    let bucket_key = format!("{}rl:grenze:permission-check", prefix);
+    let checks = async {
+        run::<(i64, String)>(conn, functions, &[BucketCall::admit(bucket_key.clone(), 1.0, 1, 1.0, SystemClock.now_ms())]).await?;
+        redis::cmd("DEL").arg(&bucket_key).query_async::<()>(conn).await?;
+        redis::cmd("TIME").query_async::<(i64, i64)>(conn).await?;
+        redis::RedisResult::Ok(())
+    };
+    let commands = if functions { "FCALL, FUNCTION LOAD" } else { "EVALSHA, SCRIPT LOAD" };
+    checks.await.with_context(|| format!("redis permission check failed; grenze needs {}, HMGET, HSET, PEXPIRE, DEL and TIME", commands))
+}
+
+#[async_trait]
+impl LimiterStore for RedisStore {
+    async fn allow(&self, bucket: &str, cost: f64, capacity: u32, leak_per_sec: f64, now_ms: i64) -> Result<Admission> {
+        let call = BucketCall::admit(self.bucket_key(bucket), cost, capacity, leak_per_sec, now_ms);
+        let mut conn = self.conn.lock().await;
+        let outcomes: Vec<(i64, String)> = run(&mut conn, self.functions, &[call]).await?;
+        outcomes.into_iter().map(admission).next().context("redis answered no admission")
+    }
+
+    async fn allow_all(&self, limits: &[BucketLimit], cost: f64, now_ms: i64) -> Result<Vec<Admission>> {
+        let mut conn = self.conn.lock().await;
+        let outcomes: Vec<Vec<(i64, String)>> = run(&mut conn, self.functions, &[BucketCall::admit_all(&self.prefix, limits, cost, now_ms)]).await?;
+        Ok(outcomes.into_iter().flatten().map(admission).collect())
+    }
+
+    async fn allow_batch(&self, requests: &[(&[BucketLimit], f64)], now_ms: i64) -> Result<Vec<Vec<Admission>>> {
+        let calls: Vec<BucketCall> = requests.iter().map(|(limits, cost)| BucketCall::admit_all(&self.prefix, limits, *cost, now_ms)).collect();
+        let mut conn = self.conn.lock().await;
+        let outcomes: Vec<Vec<(i64, String)>> = run(&mut conn, self.functions, &calls).await?;
+        Ok(outcomes.into_iter().map(|admissions| admissions.into_iter().map(admission).collect()).collect())
+    }
+
+    async fn remember(&self, key: &str, ttl_secs: u64) -> Result<bool> {
+        let mut conn = self.conn.lock().await;
+        let set: Option<String> = redis::cmd("SET")
+            .arg(self.key(key))
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl_secs.max(1))
+            .query_async(&mut *conn)
+            .await?;
+        Ok(set.is_some())
+    }
+
+    async fn forget(&self, key: &str) {
+        let mut conn = self.conn.lock().await;
+        let _: redis::RedisResult<()> = redis::cmd("DEL").arg(self.key(key)).query_async(&mut *conn).await;
+    }
+
+    async fn first_seen(&self, key: &str, now_ms: i64, ttl_secs: u64) -> Result<i64> {
+        let key = self.key(key);
+        let mut conn = self.conn.lock().await;
+        let (first_ms,): (i64,) = redis::pipe()
+            .cmd("SET")
+            .arg(&key)
+            .arg(now_ms)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl_secs.max(1))
+            .ignore()
+            .cmd("GET")
+            .arg(&key)
+            .cmd("EXPIRE")
+            .arg(&key)
+            .arg(ttl_secs.max(1))
+            .ignore()
+            .query_async(&mut *conn)
+            .await?;
+        Ok(first_ms)
+    }
+
+    async fn cookie_jar(&self, key: &str) -> Result<Option<String>> {
+        let mut conn = self.conn.lock().await;
+        Ok(redis::cmd("GET").arg(self.key(key)).query_async(&mut *conn).await?)
+    }
+
+    async fn store_cookie_jar(&self, key: &str, jar: &str, ttl_secs: u64) -> Result<()> {
+        let mut conn = self.conn.lock().await;
+        redis::cmd("SET").arg(self.key(key)).arg(jar).arg("EX").arg(ttl_secs.max(1)).query_async::<()>(&mut *conn).await?;
+        Ok(())
+    }
+
+    async fn lock(&self, name: &str, holder: &str, ttl_ms: u64) -> Result<bool> {
+        let mut conn = self.conn.lock().await;
+        let held: i64 = Script::new(LOCK_LUA).key(self.key(name)).arg(holder).arg(ttl_ms.max(1)).invoke_async(&mut *conn).await?;
+        Ok(held == 1)
+    }
+
+    async fn unlock(&self, name: &str, holder: &str) -> Result<()> {
+        let mut conn = self.conn.lock().await;
+        Script::new(UNLOCK_LUA).key(self.key(name)).arg(holder).invoke_async::<i64>(&mut *conn).await?;
+        Ok(())
+    }
+
+    async fn record_usage(&self, counts: &[UsageCount], ttl_secs: u64) -> Result<()> {
+        let mut pipe = redis::pipe();
+        let periods = self.key(RAW_USAGE);
+        for count in counts {
+            let raw = format!("{}:{}", periods, count.period_ms);
+            for (field, n) in usage_fields(count) {
+                if n > 0 {
+                    pipe.cmd("HINCRBY").arg(&raw).arg(format!("{}|{}|{}", field, count.policy, count.key)).arg(n).ignore();
+                }
+            }
+            // Kept a while in case no leader rolls it up
+            pipe.cmd("EXPIRE").arg(&raw).arg(ttl_secs).ignore();
+            pipe.cmd("ZADD").arg(&periods).arg(count.period_ms).arg(count.period_ms).ignore();
+        }
+        let mut conn = self.conn.lock().await;
+        pipe.query_async::<()>(&mut *conn).await?;
+        Ok(())
+    }
+
+    async fn take_usage(&self, before_ms: i64) -> Result<Vec<UsageCount>> {
+        let periods = self.key(RAW_USAGE);
+        let mut conn = self.conn.lock().await;
+        let minutes: Vec<i64> = redis::cmd("ZRANGEBYSCORE")
+            .arg(&periods)
+            .arg("-inf")
+            .arg(format!("({}", before_ms))
+            .query_async(&mut *conn)
+            .await?;
+        let mut counts = Vec::new();
+        for period_ms in minutes {
+            let raw = format!("{}:{}", periods, period_ms);
+            let (fields,): (HashMap<String, u64>,) = redis::pipe()
+                .atomic()
+                .cmd("HGETALL")
+                .arg(&raw)
+                .cmd("DEL")
+                .arg(&raw)
+                .ignore()
+                .cmd("ZREM")
+                .arg(&periods)
+                .arg(period_ms)
+                .ignore()
+                .query_async(&mut *conn)
+                .await?;
+            let mut by_key: HashMap<(&str, &str), UsageCount> = HashMap::new();
+            for (field, n) in &fields {
+                let mut parts = field.splitn(3, '|');
+                let (Some(field), Some(policy), Some(key)) = (parts.next(), parts.next(), parts.next()) else {
+                    continue;
+                };
+                let count = by_key.entry((key, policy)).or_insert_with(|| UsageCount::new((period_ms, key.to_string(), policy.to_string())));
+                add_usage_field(count, field, *n);
+            }
+            counts.extend(by_key.into_values());
+        }
+        Ok(counts)
+    }
+
+    async fn roll_up_usage(&self, granularity: Granularity, counts: &[UsageCount], retention_secs: u64) -> Result<()> {
+        let mut pipe = redis::pipe();
+        for count in counts {
+            let rollup = self.key(&usage_rollup(granularity, count.period_ms, &count.key));
+            let expires_at = count.period_ms / 1000 + retention_secs as i64;
+            for (field, n) in usage_fields(count) {
+                pipe.cmd("HINCRBY").arg(&rollup).arg(format!("{}|{}", field, count.policy)).arg(n).ignore();
+            }
+            pipe.cmd("EXPIREAT").arg(&rollup).arg(expires_at).ignore();
+            // Indexes the keys with usage in the period
+            let index = self.key(&usage_index(granularity, count.period_ms));
+            pipe.cmd("SADD").arg(&index).arg(&count.key).ignore();
+            pipe.cmd("EXPIREAT").arg(&index).arg(expires_at).ignore();
+        }
+        let mut conn = self.conn.lock().await;
+        pipe.query_async::<()>(&mut *conn).await?;
+        Ok(())
+    }
+
+    async fn usage(&self, key: &str, granularity: Granularity, from_ms: i64, to_ms: i64) -> Result<Vec<UsageCount>> {
+        let periods = periods(granularity, from_ms, to_ms);
+        if periods.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut pipe = redis::pipe();
+        for period_ms in &periods {
+            pipe.cmd("HGETALL").arg(self.key(&usage_rollup(granularity, *period_ms, key)));
+        }
+        let mut conn = self.conn.lock().await;
+        let rollups: Vec<HashMap<String, u64>> = pipe.query_async(&mut *conn).await?;
+        let mut counts = Vec::new();
+        for (period_ms, fields) in periods.into_iter().zip(rollups) {
+            let mut by_policy: HashMap<&str, UsageCount> = HashMap::new();
+            for (field, n) in &fields {
+                let Some((field, policy)) = field.split_once('|') else {
+                    continue;
+                };
+                let count = by_policy.entry(policy).or_insert_with(|| UsageCount::new((period_ms, key.to_string(), policy.to_string())));
+                add_usage_field(count, field, *n);
+            }
+            let mut policies: Vec<UsageCount> = by_policy.into_values().collect();
+            policies.sort_by(|a, b| a.policy.cmp(&b.policy));
+            counts.extend(policies);
+        }
+        Ok(counts)
+    }
+
+    async fn usage_keys(&self, granularity: Granularity, from_ms: i64, to_ms: i64) -> Result<Vec<String>> {
+        let indexes: Vec<String> = periods(granularity, from_ms, to_ms).into_iter().map(|p| self.key(&usage_index(granularity, p))).collect();
+        if indexes.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut conn = self.conn.lock().await;
+        let mut keys: Vec<String> = redis::cmd("SUNION").arg(&indexes).query_async(&mut *conn).await?;
+        keys.sort();
+        Ok(keys)
+    }
+
+    async fn purge_usage(&self, granularity: Option<Granularity>, before_ms: i64) -> Result<u64> {
+        let Some(granularity) = granularity else {
+            let periods = self.key(RAW_USAGE);
+            let mut conn = self.conn.lock().await;
+            let minutes: Vec<i64> = redis::cmd("ZRANGEBYSCORE")
+                .arg(&periods)
+                .arg("-inf")
+                .arg(format!("({}", before_ms))
+                .query_async(&mut *conn)
+                .await?;
+            if minutes.is_empty() {
+                return Ok(0);
+            }
+            let raw: Vec<String> = minutes.iter().map(|m| format!("{}:{}", periods, m)).collect();
+            redis::pipe()
+                .cmd("DEL")
+                .arg(&raw)
+                .ignore()
+                .cmd("ZREMRANGEBYSCORE")
+                .arg(&periods)
+                .arg("-inf")
+                .arg(format!("({}", before_ms))
+                .ignore()
+                .query_async::<()>(&mut *conn)
+                .await?;
+            return Ok(minutes.len() as u64);
+        };
+        // Scan on a clone so requests are not blocked meanwhile
+        let mut conn = self.conn.lock().await.clone();
+        let prefix = self.key(&format!("usage:keys:{}:", granularity.as_str()));
+        let mut purged = 0;
+        for index in scan(&mut conn, &format!("{}*", prefix)).await? {
+            let Some(period_ms) = index.strip_prefix(prefix.as_str()).and_then(|p| p.parse::<i64>().ok()) else {
+                continue;
+            };
+            if period_ms >= before_ms {
+                continue;
+            }
+            let keys: Vec<String> = redis::cmd("SMEMBERS").arg(&index).query_async(&mut conn).await?;
+            let mut doomed: Vec<String> = keys.iter().map(|key| self.key(&usage_rollup(granularity, period_ms, key))).collect();
+            purged += doomed.len() as u64;
+            doomed.push(index);
+            for chunk in doomed.chunks(500) {
+                redis::cmd("DEL").arg(chunk).query_async::<()>(&mut conn).await?;
+            }
+        }
+        Ok(purged)
+    }
+
+    async fn allow_quota(&self, quota: &QuotaLimit, cost: f64, now_ms: i64) -> Result<QuotaAdmission> {
+        let (overage, throttle) = match &quota.overage {
+            QuotaOverage::Reject => ("reject", None),
+            QuotaOverage::Allow => ("allow", None),
+            QuotaOverage::Throttle(limit) => ("throttle", Some(limit)),
+        };
+        let script = Script::new(QUOTA_LUA);
+        let mut invocation = script.prepare_invoke();
+        invocation
+            .key(self.key(&quota.counter))
+            .key(self.bucket_key(throttle.map_or("", |l| l.bucket.as_str())))
+            .arg(quota.included_requests.map_or(-1, |n| n as i64))
+            .arg(quota.included_bytes.map_or(-1, |n| n as i64))
+            .arg(quota.until_ms / 1000)
+            .arg(overage)
+            .arg(throttle.map_or(0, |l| l.capacity as i64))
+            .arg(throttle.map_or(1.0, |l| l.leak_per_sec))
+            .arg(MAX_BUCKET_TTL_MS)
+            .arg(now_ms)
+            .arg(cost);
+        let mut conn = self.conn.lock().await;
+        let (allowed, overage): (i64, i64) = invocation.invoke_async(&mut *conn).await?;
+        Ok(QuotaAdmission {
+            allowed: allowed == 1,
+            overage: overage == 1,
+        })
+    }
+
+    async fn charge_quota(&self, counter: &str, bytes: u64, until_ms: i64) -> Result<()> {
+        let counter = self.key(counter);
+        let mut conn = self.conn.lock().await;
+        redis::pipe()
+            .cmd("HINCRBY")
+            .arg(&counter)
+            .arg("bytes")
+            .arg(bytes)
+            .ignore()
+            .cmd("EXPIREAT")
+            .arg(&counter)
+            .arg(until_ms / 1000)
+            .ignore()
+            .query_async::<()>(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn allow_log(&self, log: &LogLimit, cost: u32, now_ms: i64) -> Result<LogAdmission> {
+        let script = Script::new(SLIDING_LOG_LUA);
+        let mut invocation = script.prepare_invoke();
+        invocation
+            .key(self.log_key(&log.log))
+            .arg(log.limit)
+            .arg(log.window_ms)
+            .arg(now_ms)
+            .arg(cost)
+            .arg(format!("{:016x}", rand::random::<u64>()));
+        let mut conn = self.conn.lock().await;
+        let (allowed, taken, until_ms): (i64, u32, i64) = invocation.invoke_async(&mut *conn).await?;
+        Ok(LogAdmission {
+            allowed: allowed == 1,
+            taken,
+            until_ms,
+        })
+    }
+
+    async fn release_log(&self, log: &LogLimit, cost: u32, now_ms: i64) -> Result<()> {
+        let mut conn = self.conn.lock().await;
+        Script::new(RELEASE_LOG_LUA)
+            .key(self.log_key(&log.log))
+            .arg(now_ms)
+            .arg(cost)
+            .invoke_async::<i64>(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn erase(&self, tenant: &Tenant) -> Result<Erasure> {
+        // Scan on a clone so requests are not blocked meanwhile
+        let mut conn = self.conn.lock().await.clone();
+        let mut erasure = Erasure::default();
+        let mut doomed = Vec::new();
+        for buckets in [self.bucket_key(""), self.log_key("")] {
+            for name in scan(&mut conn, &format!("{}*", buckets)).await? {
+                if tenant.owns_bucket(name.strip_prefix(buckets.as_str()).unwrap_or_default()) {
+                    doomed.push(name);
+                    erasure.buckets += 1;
+                }
+            }
+        }
+        for pattern in ["seen:*", "jar:*", "quota:*"] {
+            for name in scan(&mut conn, &self.key(pattern)).await? {
+                if let Some(record) = name.strip_prefix(self.prefix.as_str()).and_then(|n| tenant.owns_record(n)) {
+                    erasure.count(record);
+                    doomed.push(name);
+                }
+            }
+        }
+        for key in scan(&mut conn, &self.key("usage:*")).await? {
+            let name = key.strip_prefix(self.prefix.as_str()).unwrap_or_default();
+            if name == RAW_USAGE {
+                continue;
+            }
+            if name.starts_with("usage:keys:") {
+                let keys: Vec<String> = redis::cmd("SMEMBERS").arg(&key).query_async(&mut conn).await?;
+                let owned: Vec<&String> = keys.iter().filter(|k| tenant.owns_key(k)).collect();
+                if !owned.is_empty() {
+                    redis::cmd("SREM").arg(&key).arg(&owned).query_async::<()>(&mut conn).await?;
+                }
+            } else if name.starts_with(RAW_USAGE) {
+                let fields: Vec<String> = redis::cmd("HKEYS").arg(&key).query_async(&mut conn).await?;
+                let owned: Vec<&String> = fields.iter().filter(|f| f.splitn(3, '|').nth(2).is_some_and(|k| tenant.owns_key(k))).collect();
+                if !owned.is_empty() {
+                    redis::cmd("HDEL").arg(&key).arg(&owned).query_async::<()>(&mut conn).await?;
+                    erasure.raw_usage += owned.len() as u64;
+                }
+            } else if name.splitn(4, ':').nth(3).is_some_and(|k| tenant.owns_key(k)) {
+                doomed.push(key);
+                erasure.usage_rollups += 1;
+            }
+        }
+        for chunk in doomed.chunks(500) {
+            redis::cmd("DEL").arg(chunk).query_async::<()>(&mut conn).await?;
+        }
+        Ok(erasure)
+    }
+
+    async fn evict(&self, bucket: &str) {
+        let mut conn = self.conn.lock().await;
+        let _: redis::RedisResult<()> = redis::cmd("DEL").arg(self.bucket_key(bucket)).query_async(&mut *conn).await;
+    }
+
+    async fn charge(&self, bucket: &str, amount: f64, _capacity: u32, leak_per_sec: f64, now_ms: i64) -> Result<f64> {
+        let mut conn = self.conn.lock().await;
+        let fill: String = Script::new(CHARGE_LUA)
+            .key(self.bucket_key(bucket))
+            .arg(amount)
+            .arg(leak_per_sec)
+            .arg(now_ms)
+            .arg(MAX_BUCKET_TTL_MS)
+            .invoke_async(&mut *conn)
+            .await?;
+        Ok(fill.parse().unwrap_or(0.0))
+    }
+
+    async fn level(&self, bucket: &str, leak_per_sec: f64, now_ms: i64) -> Result<f64> {
+        let mut conn = self.conn.lock().await;
+        let (fill, last_ms): (Option<f64>, Option<i64>) = redis::cmd("HMGET")
+            .arg(self.bucket_key(bucket))
+            .arg("fill")
+            .arg("ts")
+            .query_async(&mut *conn)
+            .await?;
+        Ok(match (fill, last_ms) {
+            (Some(fill), Some(last_ms)) => Bucket { fill, last_ms, leak_per_sec }.level(now_ms),
+            _ => 0.0,
+        })
+    }
+
+    /// Reopens the connection if Redis dropped it, e.g. after a restart.
+    async fn ping(&self) -> Result<()> {
+        let mut conn = self.conn.lock().await;
+        match redis::cmd("PING").query_async::<()>(&mut *conn).await {
+            Err(e) if e.is_unrecoverable_error() => {
+                let mut reopened = self.client.get_multiplexed_tokio_connection().await?;
+                redis::cmd("CLIENT").arg("SETNAME").arg(&self.client_name).query_async::<()>(&mut reopened).await?;
+                *conn = reopened;
+                Ok(())
+            },
+            result => Ok(result?),
+        }
+    }
+
+    async fn export(&self) -> Result<Vec<BucketState>> {
+        // Scan on a clone so requests are not blocked meanwhile
+        let mut conn = self.conn.lock().await.clone();
+        let prefix = self.bucket_key("");
+        let mut buckets = Vec::new();
+        let mut cursor: u64 = 0;
+        loop {
+            // Only hashes, skipping the separate fill and timestamp keys of
+            // buckets written by older versions
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(format!("{}*", prefix))
+                .arg("COUNT")
+                .arg(1000)
+                .arg("TYPE")
+                .arg("hash")
+                .query_async(&mut conn)
+                .await?;
+            if !keys.is_empty() {
+                let mut pipe = redis::pipe();
+                for key in &keys {
+                    pipe.cmd("HMGET").arg(key).arg("fill").arg("ts");
+                }
+                let states: Vec<(Option<f64>, Option<i64>)> = pipe.query_async(&mut conn).await?;
+                for (key, (fill, last_ms)) in keys.iter().zip(states) {
+                    let name = key.strip_prefix(prefix.as_str()).unwrap_or(key);
+                    if let (Some(fill), Some(last_ms)) = (fill, last_ms) {
+                        buckets.push(BucketState {
+                            bucket: name.to_string(),
+                            fill,
+                            last_ms,
+                        });
+                    }
+                }
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        Ok(buckets)
+    }
+
+    async fn import(&self, buckets: &[BucketState], _capacity: u32, leak_per_sec: f64) -> Result<()> {
+        let mut conn = self.conn.lock().await.clone();
+        for chunk in buckets.chunks(500) {
+            let mut pipe = redis::pipe();
+            for b in chunk {
+                let key = self.bucket_key(&b.bucket);
+                pipe.cmd("HSET").arg(&key).arg("fill").arg(b.fill.to_string()).arg("ts").arg(b.last_ms).ignore();
+                pipe.cmd("PEXPIRE").arg(&key).arg(bucket_ttl_ms(b.fill, leak_per_sec)).ignore();
+            }
+            pipe.query_async::<()>(&mut conn).await?;
+        }
+        Ok(())
+    }
+
+    async fn export_quotas(&self, now_ms: i64) -> Result<Vec<QuotaState>> {
+        // Scan on a clone so requests are not blocked meanwhile
+        let mut conn = self.conn.lock().await.clone();
+        let mut quotas = Vec::new();
+        for chunk in scan(&mut conn, &self.key("quota:*")).await?.chunks(500) {
+            let mut pipe = redis::pipe();
+            for key in chunk {
+                pipe.cmd("HMGET").arg(key).arg("requests").arg("bytes");
+                pipe.cmd("PTTL").arg(key);
+            }
+            let states: Vec<redis::Value> = pipe.query_async(&mut conn).await?;
+            for (key, state) in chunk.iter().zip(states.chunks(2)) {
+                let (requests, bytes): (Option<u64>, Option<u64>) = redis::from_redis_value(&state[0])?;
+                let ttl_ms: i64 = redis::from_redis_value(&state[1])?;
+                // Expired since the scan
+                if ttl_ms == -2 || (requests, bytes) == (None, None) {
+                    continue;
+                }
+                quotas.push(QuotaState {
+                    counter: key.strip_prefix(self.prefix.as_str()).unwrap_or(key).to_string(),
+                    requests: requests.unwrap_or(0),
+                    bytes: bytes.unwrap_or(0),
+                    until_ms: if ttl_ms < 0 { i64::MAX } else { now_ms + ttl_ms },
+                });
+            }
+        }
+        Ok(quotas)
+    }
+
+    async fn import_quotas(&self, quotas: &[QuotaState]) -> Result<()> {
+        let mut conn = self.conn.lock().await.clone();
+        for chunk in quotas.chunks(500) {
+            let mut pipe = redis::pipe();
+            for q in chunk {
+                let key = self.key(&q.counter);
+                pipe.cmd("HSET").arg(&key).arg("requests").arg(q.requests).arg("bytes").arg(q.bytes).ignore();
+                if q.until_ms != i64::MAX {
+                    pipe.cmd("PEXPIREAT").arg(&key).arg(q.until_ms).ignore();
+                }
+            }
+            pipe.query_async::<()>(&mut conn).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Hash fields of the counters of a usage count.
+fn usage_fields(count: &UsageCount) -> [(&'static str, u64); 3] {
+    [("allowed", count.allowed), ("limited", count.limited), ("bytes", count.bytes)]
+}
+
+fn add_usage_field(count: &mut UsageCount, field: &str, n: u64) {
+    match field {
+        "allowed" => count.allowed += n,
+        "limited" => count.limited += n,
+        "bytes" => count.bytes += n,
+        _ => {},
+    }
+}
+
+/// Hash of the usage of a key rolled up into a period, by counter and policy.
+fn usage_rollup(granularity: Granularity, period_ms: i64, key: &str) -> String {
+    format!("usage:{}:{}:{}", granularity.as_str(), period_ms, key)
+}
+
+/// Set of the keys with usage rolled up into a period.
+fn usage_index(granularity: Granularity, period_ms: i64) -> String {
+    format!("usage:keys:{}:{}", granularity.as_str(), period_ms)
+}
+
+/// Starts of the periods of `granularity` within `from_ms..to_ms`.
+fn periods(granularity: Granularity, from_ms: i64, to_ms: i64) -> Vec<i64> {
+    let first = granularity.start(from_ms + granularity.period_ms() - 1);
+    (first..to_ms).step_by(granularity.period_ms() as usize).collect()
+}
+
+/// Buckets kept in process memory, for tests. Follows the same leak math as
+/// the Redis script.
+pub struct MemoryStore {
+    clock: Arc<dyn Clock>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    remembered: Mutex<HashMap<String, i64>>,
+    /// First seen and expiry time by key.
+    seen: Mutex<HashMap<String, (i64, i64)>>,
+    /// Cookie jars and their expiry time by key.
+    jars: Mutex<HashMap<String, (String, i64)>>,
+    /// Holders of locks and their expiry time by name.
+    locks: Mutex<HashMap<String, (String, i64)>>,
+    raw_usage: Mutex<Vec<UsageCount>>,
+    /// Usage rollups and their expiry time by granularity, period, key and
+    /// policy.
+    rollups: Mutex<HashMap<(Granularity, UsageId), (UsageCount, i64)>>,
+    /// Requests and bytes counted against quotas, and their expiry time, by
+    /// counter.
+    quotas: Mutex<HashMap<String, (u64, u64, i64)>>,
+    /// Times of the tokens taken by sliding log, oldest first, and the log's
+    /// expiry time.
+    logs: Mutex<HashMap<String, (VecDeque<i64>, i64)>>,
+}
+
+impl MemoryStore {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            buckets: Mutex::default(),
+            remembered: Mutex::default(),
+            seen: Mutex::default(),
+            jars: Mutex::default(),
+            locks: Mutex::default(),
+            raw_usage: Mutex::default(),
+            rollups: Mutex::default(),
+            quotas: Mutex::default(),
+            logs: Mutex::default(),
+        }
+    }
+
+    /// Current fill level of `bucket`, or `None` if it holds no requests.
+    pub async fn fill(&self, bucket: &str) -> Option<f64> {
+        let now_ms = self.clock.now_ms();
+        let level = self.buckets.lock().await.get(bucket)?.level(now_ms);
+        (level > 0.0).then_some(level)
+    }
+
+    /// Forgets all buckets and remembered keys.
+    pub async fn clear(&self) {
+        self.buckets.lock().await.clear();
+        self.remembered.lock().await.clear();
+        self.seen.lock().await.clear();
+        self.jars.lock().await.clear();
+        self.raw_usage.lock().await.clear();
+        self.rollups.lock().await.clear();
+        self.quotas.lock().await.clear();
+        self.logs.lock().await.clear();
+    }
+}
+
+#[async_trait]
+impl LimiterStore for MemoryStore {
+    async fn allow(&self, bucket: &str, cost: f64, capacity: u32, leak_per_sec: f64, now_ms: i64) -> Result<Admission> {
+        let mut buckets = self.buckets.lock().await;
+        buckets.retain(|_, b| b.level(now_ms) > 0.0);
+        let (allowed, next) = Bucket::admit(buckets.get(bucket), cost, capacity as f64, leak_per_sec, now_ms);
+        buckets.insert(bucket.to_string(), next);
+        Ok(Admission { allowed, fill: next.fill })
+    }
+
+    async fn allow_all(&self, limits: &[BucketLimit], cost: f64, now_ms: i64) -> Result<Vec<Admission>> {
+        let mut buckets = self.buckets.lock().await;
+        buckets.retain(|_, b| b.level(now_ms) > 0.0);
+        let outcomes: Vec<(bool, Bucket)> = limits
+            .iter()
+            .map(|l| Bucket::admit(buckets.get(&l.bucket), cost, l.capacity as f64, l.leak_per_sec, now_ms))
+            .collect();
+        let fits = outcomes.iter().all(|(allowed, _)| *allowed);
+        let mut admissions = Vec::with_capacity(limits.len());
+        for (limit, (allowed, next)) in limits.iter().zip(outcomes) {
+            // Without room in every bucket, only leak them
+            let next = if fits { next } else { Bucket::charge(buckets.get(&limit.bucket), 0.0, limit.leak_per_sec, now_ms) };
+            buckets.insert(limit.bucket.clone(), next);
+            admissions.push(Admission { allowed, fill: next.fill });
+        }
+        Ok(admissions)
+    }
+
+    async fn remember(&self, key: &str, ttl_secs: u64) -> Result<bool> {
+        let now_ms = self.clock.now_ms();
+        let mut remembered = self.remembered.lock().await;
+        remembered.retain(|_, expires_ms| *expires_ms > now_ms);
+        if remembered.contains_key(key) {
+            return Ok(false);
+        }
+        remembered.insert(key.to_string(), now_ms + ttl_secs.max(1) as i64 * 1000);
+        Ok(true)
+    }
+
+    async fn forget(&self, key: &str) {
+        self.remembered.lock().await.remove(key);
+    }
+
+    async fn first_seen(&self, key: &str, now_ms: i64, ttl_secs: u64) -> Result<i64> {
+        let mut seen = self.seen.lock().await;
+        seen.retain(|_, (_, expires_ms)| *expires_ms > now_ms);
+        let entry = seen.entry(key.to_string()).or_insert((now_ms, 0));
+        entry.1 = now_ms + ttl_secs.max(1) as i64 * 1000;
+        Ok(entry.0)
+    }
+
+    async fn cookie_jar(&self, key: &str) -> Result<Option<String>> {
+        let now_ms = self.clock.now_ms();
+        let jars = self.jars.lock().await;
+        Ok(jars.get(key).filter(|(_, expires_ms)| *expires_ms > now_ms).map(|(jar, _)| jar.clone()))
+    }
+
+    async fn store_cookie_jar(&self, key: &str, jar: &str, ttl_secs: u64) -> Result<()> {
+        let now_ms = self.clock.now_ms();
+        let mut jars = self.jars.lock().await;
+        jars.retain(|_, (_, expires_ms)| *expires_ms > now_ms);
+        jars.insert(key.to_string(), (jar.to_string(), now_ms + ttl_secs.max(1) as i64 * 1000));
+        Ok(())
+    }
+
+    async fn lock(&self, name: &str, holder: &str, ttl_ms: u64) -> Result<bool> {
+        let now_ms = self.clock.now_ms();
+        let mut locks = self.locks.lock().await;
+        if let Some((current, expires_ms)) = locks.get(name)
+            && current != holder
+            && *expires_ms > now_ms
+        {
+            return Ok(false);
+        }
+        locks.insert(name.to_string(), (holder.to_string(), now_ms + ttl_ms.max(1) as i64));
+        Ok(true)
+    }
+
+    async fn unlock(&self, name: &str, holder: &str) -> Result<()> {
+        let mut locks = self.locks.lock().await;
+        if locks.get(name).is_some_and(|(current, _)| current == holder) {
+            locks.remove(name);
+        }
+        Ok(())
+    }
+
+    async fn record_usage(&self, counts: &[UsageCount], _ttl_secs: u64) -> Result<()> {
+        self.raw_usage.lock().await.extend_from_slice(counts);
+        Ok(())
+    }
+
+    async fn take_usage(&self, before_ms: i64) -> Result<Vec<UsageCount>> {
+        let mut raw = self.raw_usage.lock().await;
+        let (taken, kept) = std::mem::take(&mut *raw).into_iter().partition(|c| c.period_ms < before_ms);
+        *raw = kept;
+        Ok(taken)
+    }
+
+    async fn roll_up_usage(&self, granularity: Granularity, counts: &[UsageCount], retention_secs: u64) -> Result<()> {
+        let now_ms = self.clock.now_ms();
+        let mut rollups = self.rollups.lock().await;
+        rollups.retain(|_, (_, expires_ms)| *expires_ms > now_ms);
+        for count in counts {
+            let expires_ms = count.period_ms + retention_secs as i64 * 1000;
+            let (rollup, _) = rollups.entry((granularity, count.id())).or_insert_with_key(|(_, id)| (UsageCount::new(id.clone()), expires_ms));
+            rollup.add(count);
+        }
+        Ok(())
+    }
+
+    async fn usage(&self, key: &str, granularity: Granularity, from_ms: i64, to_ms: i64) -> Result<Vec<UsageCount>> {
+        let now_ms = self.clock.now_ms();
+        let rollups = self.rollups.lock().await;
+        let mut counts: Vec<UsageCount> = rollups
+            .iter()
+            .filter(|((g, (period_ms, k, _)), (_, expires_ms))| *g == granularity && k == key && (from_ms..to_ms).contains(period_ms) && *expires_ms > now_ms)
+            .map(|(_, (rollup, _))| rollup.clone())
+            .collect();
+        counts.sort_by(|a, b| (a.period_ms, &a.policy).cmp(&(b.period_ms, &b.policy)));
+        Ok(counts)
+    }
+
+    async fn usage_keys(&self, granularity: Granularity, from_ms: i64, to_ms: i64) -> Result<Vec<String>> {
+        let now_ms = self.clock.now_ms();
+        let rollups = self.rollups.lock().await;
+        let mut keys: Vec<String> = rollups
+            .iter()
+            .filter(|((g, (period_ms, _, _)), (_, expires_ms))| *g == granularity && (from_ms..to_ms).contains(period_ms) && *expires_ms > now_ms)
+            .map(|((_, (_, key, _)), _)| key.clone())
+            .collect();
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    async fn purge_usage(&self, granularity: Option<Granularity>, before_ms: i64) -> Result<u64> {
+        let mut purged = 0;
+        match granularity {
+            Some(granularity) => self.rollups.lock().await.retain(|(g, (period_ms, _, _)), _| {
+                let expired = *g == granularity && *period_ms < before_ms;
+                purged += expired as u64;
+                !expired
+            }),
+            None => self.raw_usage.lock().await.retain(|count| {
+                let expired = count.period_ms < before_ms;
+                purged += expired as u64;
+                !expired
+            }),
+        }
+        Ok(purged)
+    }
+
+    async fn allow_quota(&self, quota: &QuotaLimit, cost: f64, now_ms: i64) -> Result<QuotaAdmission> {
+        let mut quotas = self.quotas.lock().await;
+        quotas.retain(|_, (_, _, expires_ms)| *expires_ms > now_ms);
+        let (requests, bytes, _) = quotas.entry(quota.counter.clone()).or_insert((0, 0, quota.until_ms));
+        let overage = quota.included_requests.is_some_and(|n| *requests >= n) || quota.included_bytes.is_some_and(|n| *bytes >= n);
+        let allowed = match (&quota.overage, overage) {
+            (_, false) | (QuotaOverage::Allow, true) => true,
+            (QuotaOverage::Reject, true) => false,
+            (QuotaOverage::Throttle(limit), true) => {
+                let mut buckets = self.buckets.lock().await;
+                let (allowed, next) = Bucket::admit(buckets.get(&limit.bucket), cost, limit.capacity as f64, limit.leak_per_sec, now_ms);
+                buckets.insert(limit.bucket.clone(), next);
+                allowed
+            },
+        };
+        if allowed {
+            *requests += 1;
+        }
+        Ok(QuotaAdmission { allowed, overage })
+    }
+
+    async fn charge_quota(&self, counter: &str, bytes: u64, until_ms: i64) -> Result<()> {
+        let mut quotas = self.quotas.lock().await;
+        quotas.entry(counter.to_string()).or_insert((0, 0, until_ms)).1 += bytes;
+        Ok(())
+    }
+
+    async fn allow_log(&self, log: &LogLimit, cost: u32, now_ms: i64) -> Result<LogAdmission> {
+        let mut logs = self.logs.lock().await;
+        logs.retain(|_, (_, expires_ms)| *expires_ms > now_ms);
+        let (tokens, expires_ms) = logs.entry(log.log.clone()).or_default();
+        while tokens.front().is_some_and(|taken_ms| *taken_ms <= now_ms - log.window_ms) {
+            tokens.pop_front();
+        }
+        let allowed = tokens.len() as u64 + cost as u64 <= log.limit as u64;
+        if allowed && cost > 0 {
+            tokens.extend(std::iter::repeat_n(now_ms, cost as usize));
+            *expires_ms = now_ms + log.window_ms;
+        }
+        // Room frees up as tokens leave the window, oldest first
+        let index = if allowed { 0 } else { (tokens.len() + cost as usize).saturating_sub(log.limit as usize).min(tokens.len()).saturating_sub(1) };
+        Ok(LogAdmission {
+            allowed,
+            taken: tokens.len() as u32,
+            until_ms: tokens.get(index).map_or(now_ms, |taken_ms| taken_ms + log.window_ms),
+        })
+    }
+
+    async fn release_log(&self, log: &LogLimit, cost: u32, now_ms: i64) -> Result<()> {
+        let mut logs = self.logs.lock().await;
+        if let Some((tokens, _)) = logs.get_mut(&log.log) {
+            for _ in 0..cost {
+                match tokens.iter().rposition(|taken_ms| *taken_ms == now_ms) {
+                    Some(index) => tokens.remove(index),
+                    None => break,
+                };
+            }
+        }
+        Ok(())
+    }
+
+    async fn erase(&self, tenant: &Tenant) -> Result<Erasure> {
+        let mut erasure = Erasure::default();
+        self.buckets.lock().await.retain(|bucket, _| {
+            let owned = tenant.owns_bucket(bucket);
+            erasure.buckets += owned as u64;
+            !owned
+        });
+        self.logs.lock().await.retain(|log, _| {
+            let owned = tenant.owns_bucket(log);
+            erasure.buckets += owned as u64;
+            !owned
+        });
+        let mut seen = self.seen.lock().await;
+        let mut jars = self.jars.lock().await;
+        let mut quotas = self.quotas.lock().await;
+        let names = seen.keys().chain(jars.keys()).chain(quotas.keys()).cloned().collect::<Vec<_>>();
+        for name in names {
+            if let Some(record) = tenant.owns_record(&name) {
+                erasure.count(record);
+                seen.remove(&name);
+                jars.remove(&name);
+                quotas.remove(&name);
+            }
+        }
+        self.raw_usage.lock().await.retain(|count| {
+            let owned = tenant.owns_key(&count.key);
+            erasure.raw_usage += owned as u64;
+            !owned
+        });
+        self.rollups.lock().await.retain(|(_, (_, key, _)), _| {
+            let owned = tenant.owns_key(key);
+            erasure.usage_rollups += owned as u64;
+            !owned
+        });
+        Ok(erasure)
+    }
+
+    async fn evict(&self, bucket: &str) {
+        self.buckets.lock().await.remove(bucket);
+    }
+
+    async fn charge(&self, bucket: &str, amount: f64, _capacity: u32, leak_per_sec: f64, now_ms: i64) -> Result<f64> {
+        let mut buckets = self.buckets.lock().await;
+        let next = Bucket::charge(buckets.get(bucket), amount, leak_per_sec, now_ms);
+        buckets.insert(bucket.to_string(), next);
+        Ok(next.fill)
+    }
+
+    async fn level(&self, bucket: &str, _leak_per_sec: f64, now_ms: i64) -> Result<f64> {
+        Ok(self.buckets.lock().await.get(bucket).map_or(0.0, |b| b.level(now_ms)))
+    }
+
+    async fn export(&self) -> Result<Vec<BucketState>> {
+        let now_ms = self.clock.now_ms();
+        let buckets = self.buckets.lock().await;
+        Ok(buckets
+            .iter()
+            .filter(|(_, b)| b.level(now_ms) > 0.0)
+            .map(|(name, b)| BucketState {
+                bucket: name.clone(),
+                fill: b.fill,
+                last_ms: b.last_ms,
+            })
+            .collect())
+    }
+
+    async fn import(&self, imported: &[BucketState], _capacity: u32, leak_per_sec: f64) -> Result<()> {
+        let mut buckets = self.buckets.lock().await;
+        for b in imported {
+            buckets.insert(
+                b.bucket.clone(),
+                Bucket {
+                    fill: b.fill,
+                    last_ms: b.last_ms,
+                    leak_per_sec,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    async fn export_quotas(&self, now_ms: i64) -> Result<Vec<QuotaState>> {
+        let quotas = self.quotas.lock().await;
+        Ok(quotas
+            .iter()
+            .filter(|(_, (_, _, expires_ms))| *expires_ms > now_ms)
+            .map(|(counter, (requests, bytes, expires_ms))| QuotaState {
+                counter: counter.clone(),
+                requests: *requests,
+                bytes: *bytes,
+                until_ms: *expires_ms,
+            })
+            .collect())
+    }
+
+    async fn import_quotas(&self, imported: &[QuotaState]) -> Result<()> {
+        let mut quotas = self.quotas.lock().await;
+        for q in imported {
+            quotas.insert(q.counter.clone(), (q.requests, q.bytes, q.until_ms));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /// Leaks slow enough that buckets outlive `MAX_BUCKET_TTL_MS` are left
+    /// out; their TTL is capped on purpose.
+    fn leak_per_sec() -> impl Strategy<Value = f64> {
+        0.01f64..10_000.0
+    }
+
+    proptest! {
+        #[test]
+        fn buckets_do_not_expire_while_filled(fill in 0.0f64..100_000.0, leak_per_sec in leak_per_sec(), elapsed_ms in 0i64..MAX_BUCKET_TTL_MS) {
+            let bucket = Bucket { fill, last_ms: 0, leak_per_sec };
+            let ttl_ms = bucket_ttl_ms(fill, leak_per_sec);
+            prop_assume!(ttl_ms < MAX_BUCKET_TTL_MS);
+            prop_assert_eq!(bucket.level(ttl_ms), 0.0);
+            if bucket.level(elapsed_ms) > 0.0 {
+                prop_assert!(elapsed_ms < ttl_ms);
+            }
+            prop_assert!(bucket_ttl_secs(fill, leak_per_sec) * 1000 >= ttl_ms);
+        }
+
+        #[test]
+        fn bucket_ttls_are_capped(fill in 0.0f64..1e12, leak_per_sec in 1e-9f64..10_000.0) {
+            let ttl_ms = bucket_ttl_ms(fill, leak_per_sec);
+            prop_assert!(ttl_ms > 0 && ttl_ms <= MAX_BUCKET_TTL_MS);
+        }
+
+        /// Dropping buckets once their TTL has passed, as the stores do,
+        /// leaves the same fills and admissions as keeping them forever.
+        #[test]
+        fn expiry_does_not_change_admissions(
+            capacity in 1.0f64..1_000.0,
+            leak_per_sec in leak_per_sec(),
+            requests in prop::collection::vec((0.0f64..1.5, 0.0f64..1.0), 1..200),
+        ) {
+            // Gaps and costs relative to the capacity, so requests also land
+            // just before and after buckets have drained
+            let drain_ms = capacity * 1000.0 / leak_per_sec;
+            let mut kept: Option<Bucket> = None;
+            let mut expiring: Option<(Bucket, i64)> = None;
+            let mut now_ms = 0;
+            for (gap, cost) in requests {
+                now_ms += (gap * drain_ms) as i64;
+                let cost = cost * capacity;
+                let (allowed, next) = Bucket::admit(kept.as_ref(), cost, capacity, leak_per_sec, now_ms);
+                kept = Some(next);
+                let current = expiring.filter(|(_, expires_ms)| now_ms < *expires_ms).map(|(b, _)| b);
+                let (expiring_allowed, next) = Bucket::admit(current.as_ref(), cost, capacity, leak_per_sec, now_ms);
+                expiring = Some((next, now_ms + bucket_ttl_ms(next.fill, leak_per_sec)));
+                prop_assert_eq!(allowed, expiring_allowed);
+                prop_assert_eq!(kept.map(|b| b.fill), Some(next.fill));
+            }
+        }
+    }
+}
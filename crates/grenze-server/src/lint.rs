@@ -0,0 +1,135 @@
+//! Checks of configurations beyond validation, run by `grenze-server
+//! check-config`: settings that are valid but contradict each other, such as
+//! limits that can never reject a request, and references to secrets and
+//! upstreams that do not resolve.
+
+use crate::{config::Config, credentials::SecretStore, limiter::DEFAULT_RATE, policy::Policy, sidecar::SidecarUpstream};
+
+/// Findings about limits that contradict each other or other settings, each
+/// saying how to resolve it.
+pub fn lint(config: &Config) -> Vec<String> {
+    let mut findings = Vec::new();
+    let capacity = config.limiter.capacity.unwrap_or(DEFAULT_RATE);
+    let leak_per_sec = config.limiter.leak_per_sec.unwrap_or(DEFAULT_RATE as f64);
+    burst(&mut findings, "limiter", capacity, leak_per_sec);
+    let mut keys: Vec<_> = config.limiter.keys.iter().collect();
+    keys.sort_by_key(|(key, _)| *key);
+    for (key, size) in keys {
+        burst(&mut findings, &format!("limiter key '{}'", key), size.capacity.unwrap_or(capacity), size.leak_per_sec.unwrap_or(leak_per_sec));
+    }
+    for policy in &config.policies {
+        let scope = format!("policy '{}'", policy.name);
+        if policy.windows.is_empty() && policy.sliding_log.is_none() && (policy.capacity.is_some() || policy.leak_per_sec.is_some()) {
+            burst(&mut findings, &scope, policy.capacity.unwrap_or(capacity), policy.leak_per_sec.unwrap_or(leak_per_sec));
+        }
+        for (i, limit) in policy.limits.iter().enumerate() {
+            if limit.capacity.is_some() || limit.leak_per_sec.is_some() {
+                burst(&mut findings, &format!("{} limit {}", scope, i + 1), limit.capacity.unwrap_or(capacity), limit.leak_per_sec.unwrap_or(leak_per_sec));
+            }
+        }
+        windows(&mut findings, policy);
+    }
+    if let Some(billing) = &config.billing {
+        let burst = |policy: &Policy| match (policy.windows.first(), &policy.sliding_log) {
+            (Some(window), _) => window.limit,
+            (None, Some(log)) => log.limit,
+            (None, None) => policy.capacity.unwrap_or(capacity),
+        };
+        let (largest, policy) = config
+            .policies
+            .iter()
+            .map(|p| (burst(p), p.name.as_str()))
+            .chain([(capacity, "the limiter")])
+            .max_by_key(|(burst, _)| *burst)
+            .expect("the limiter's size is always there");
+        let mut plans: Vec<_> = billing.plans.iter().collect();
+        plans.sort_by_key(|(name, _)| *name);
+        for (name, plan) in plans {
+            if let Some(included) = plan.included_requests
+                && included < largest as u64
+            {
+                findings.push(format!(
+                    "billing plan '{}' includes {} requests per period, fewer than the {} a bucket of {} admits at once, so a single burst uses up the quota; raise included_requests or lower the bucket's capacity",
+                    name, included, largest, policy
+                ));
+            }
+        }
+    }
+    findings
+}
+
+/// A bucket holding less than one second of its leak can never admit the
+/// sustained rate in a burst.
+fn burst(findings: &mut Vec<String>, scope: &str, capacity: u32, leak_per_sec: f64) {
+    if (capacity as f64) < leak_per_sec {
+        findings.push(format!(
+            "{}: capacity {} is below leak_per_sec {}, so bursts hold less than one second of the sustained rate; raise capacity to at least {} or lower leak_per_sec",
+            scope,
+            capacity,
+            leak_per_sec,
+            leak_per_sec.ceil()
+        ));
+    }
+}
+
+/// A window that admits as many requests as the shorter windows allow within
+/// it never rejects any.
+fn windows(findings: &mut Vec<String>, policy: &Policy) {
+    let mut windows: Vec<_> = policy.windows.iter().collect();
+    windows.sort_by_key(|w| w.window_secs);
+    for (i, long) in windows.iter().enumerate() {
+        for short in &windows[..i] {
+            let allowed = short.limit as u64 * long.window_secs.div_ceil(short.window_secs);
+            if long.limit as u64 >= allowed {
+                findings.push(format!(
+                    "policy '{}': the {}s window admits {} requests, but the {}s window already caps them at {}, so it never rejects any; lower its limit below {} or remove it",
+                    policy.name, long.window_secs, long.limit, short.window_secs, allowed, allowed
+                ));
+            }
+        }
+    }
+}
+
+/// References that do not resolve: secrets the secret stores cannot return
+/// and upstream and proxy hosts without an address.
+pub async fn resolve(config: &Config) -> Vec<String> {
+    let mut failures = Vec::new();
+    let secrets = SecretStore::new(reqwest::Client::new(), config.secrets.clone());
+    for policy in &config.policies {
+        if let Some(auth) = &policy.auth
+            && let Err(e) = secrets.check(auth).await
+        {
+            failures.push(format!("policy '{}': secret does not resolve: {:#}", policy.name, e));
+        }
+    }
+
+    let mut upstreams: Vec<(String, &str)> = Vec::new();
+    if let Some(passthrough) = &config.passthrough {
+        upstreams.extend(passthrough.upstreams.iter().map(|(name, base)| (format!("passthrough upstream '{}'", name), base.as_str())));
+    }
+    if let Some(sidecar) = &config.sidecar
+        && let SidecarUpstream::Url(base) = &sidecar.upstream
+    {
+        upstreams.push(("sidecar upstream".to_string(), base));
+    }
+    for policy in &config.policies {
+        if let Some(hedge) = &policy.hedge {
+            upstreams.push((format!("policy '{}' hedge alternate", policy.name), &hedge.alternate));
+        }
+    }
+    for profile in &config.client_profiles {
+        if let Some(proxy) = &profile.proxy {
+            upstreams.push((format!("client profile '{}' proxy", profile.name), proxy));
+        }
+    }
+    upstreams.sort();
+    for (scope, base) in upstreams {
+        let Ok(url) = reqwest::Url::parse(base) else {
+            continue;
+        };
+        if let Err(e) = url.socket_addrs(|| None) {
+            failures.push(format!("{}: host {} does not resolve: {}", scope, url.host_str().unwrap_or_default(), e));
+        }
+    }
+    failures
+}
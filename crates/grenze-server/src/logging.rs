@@ -0,0 +1,143 @@
+//! Sampled logging of proxied requests by outcome, and suppression of
+//! warnings repeating within a window, such as a store failing on every tick,
+//! so logging stays readable in production.
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::{collections::BTreeMap, fmt, sync::{Mutex, OnceLock}, time::{Duration, Instant}};
+
+use crate::middleware::Context;
+
+/// Window repeated warnings are suppressed in, set once from the logging
+/// configuration; without one every warning is logged.
+static REPEAT_WINDOW: OnceLock<Duration> = OnceLock::new();
+/// Last logged warning of each topic, with the number suppressed since.
+static REPEATS: Mutex<BTreeMap<&'static str, (Instant, u64)>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Share of proxied requests logged, by outcome.
+    #[serde(default)]
+    pub requests: RequestSampling,
+    /// A warning of the same topic is logged at most once per window, with
+    /// the number of warnings suppressed in between.
+    #[serde(default = "default_repeat_window_secs")]
+    pub repeat_window_secs: u64,
+}
+
+fn default_repeat_window_secs() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RequestSampling {
+    /// Requests forwarded downstream and answered below 500.
+    pub allowed: f64,
+    /// Requests refused by grenze, e.g. rate limited or badly signed.
+    pub rejected: f64,
+    /// Requests answered with a 5xx, by grenze or downstream.
+    pub errors: f64,
+}
+
+impl Default for RequestSampling {
+    fn default() -> Self {
+        Self { allowed: 0.01, rejected: 1.0, errors: 1.0 }
+    }
+}
+
+impl LoggingConfig {
+    pub fn validate(&self) -> Result<()> {
+        let RequestSampling { allowed, rejected, errors } = self.requests;
+        if [allowed, rejected, errors].iter().any(|rate| !(0.0..=1.0).contains(rate)) {
+            bail!("logging request sample rates must be between 0 and 1");
+        }
+        Ok(())
+    }
+
+    /// Starts suppressing repeated warnings; only the first call has effect.
+    pub fn install(&self) {
+        let _ = REPEAT_WINDOW.set(Duration::from_secs(self.repeat_window_secs));
+    }
+}
+
+/// What became of a proxied request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Allowed,
+    Rejected,
+    Error,
+}
+
+impl Outcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Allowed => "Allowed",
+            Outcome::Rejected => "Rejected",
+            Outcome::Error => "Failed",
+        }
+    }
+}
+
+impl RequestSampling {
+    fn rate(&self, outcome: Outcome) -> f64 {
+        match outcome {
+            Outcome::Allowed => self.allowed,
+            Outcome::Rejected => self.rejected,
+            Outcome::Error => self.errors,
+        }
+    }
+
+    /// Logs a proxied request if its outcome is sampled, with the reason of
+    /// its rejection. `target` is the destination without its query, which
+    /// may carry credentials.
+    #[allow(clippy::too_many_arguments)]
+    pub fn log(&self, outcome: Outcome, reason: Option<&str>, ctx: &Context, method: &str, target: &str, status: u16, elapsed: Duration) {
+        let rate = self.rate(outcome);
+        if rate > 0.0 && (rate >= 1.0 || rand::random::<f64>() < rate) {
+            println!(
+                "{} {} {} for key {} under policy {}: {}{} in {}ms",
+                outcome.as_str(),
+                method,
+                target,
+                ctx.key,
+                ctx.policy.name,
+                status,
+                reason.map(|r| format!(" ({})", r)).unwrap_or_default(),
+                elapsed.as_millis()
+            );
+        }
+    }
+}
+
+/// Logs a warning unless one of the same topic was logged within the repeat
+/// window, in which case it is counted and reported with the next one logged.
+pub fn warn(topic: &'static str, message: fmt::Arguments) {
+    let Some(window) = REPEAT_WINDOW.get() else {
+        println!("{}", message);
+        return;
+    };
+    let now = Instant::now();
+    let suppressed = {
+        let mut repeats = REPEATS.lock().expect("log repeats lock poisoned");
+        match repeats.get_mut(topic) {
+            Some((logged, suppressed)) if now.duration_since(*logged) < *window => {
+                *suppressed += 1;
+                return;
+            },
+            Some((logged, suppressed)) => {
+                *logged = now;
+                std::mem::take(suppressed)
+            },
+            None => {
+                repeats.insert(topic, (now, 0));
+                0
+            },
+        }
+    };
+    match suppressed {
+        0 => println!("{}", message),
+        n => println!("{} ({} similar warnings suppressed)", message, n),
+    }
+}
@@ -1,33 +1,148 @@
-use anyhow::Result;
-use axum::{routing::{get, post}, Router};
-
-pub mod api;
-
-#[tokio::main]
-async fn main() -> Result<()> {
-    let redis_url = std::env::var("REDIS_URL").expect("REDIS_URL must be set");
-    let state = loop {
-        match api::proxy::AppState::new(1, &redis_url).await {
-            Ok(s) => break s,
-            Err(_) => {
-                tokio::time::sleep(std::time::Duration::from_millis(300)).await;
-            }
-        }
-    };
-    let app = Router::new()
-        .route("/health", get(api::health::health))
-        .route("/proxy", post(api::proxy::proxy))
-        .with_state(state);
+use anyhow::{anyhow, bail, Result};
+use grenze_server::{api, config, limiter, lint, migrate, panics, server, systemd, tls, upgrade};
+use std::{net::SocketAddr, os::fd::AsRawFd, time::Duration};
+
+fn main() -> Result<()> {
+    panics::install_hook();
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        None => {},
+        Some("check-config") => std::process::exit(check_config(args.collect())),
+        Some("migrate") => return migrate(args.collect()),
+        Some(other) => bail!("unknown command '{}'; usage: grenze-server [check-config [--resolve] [FILE] | migrate --from FILE --to FILE [...]]", other),
+    }
+    let config = config::Config::load()?;
+    let mut server = config.server.clone();
+    // A sidecar only serves its pod, for which one thread is plenty
+    if config.sidecar.is_some() {
+        server.worker_threads.get_or_insert(1);
+    }
+    server.runtime()?.block_on(run(config))
+}
+
+async fn run(config: config::Config) -> Result<()> {
+    let redis_url = std::env::var("REDIS_URL").ok();
+    if matches!(config.limiter.store, limiter::StoreConfig::Redis) && redis_url.is_none() {
+        bail!("REDIS_URL must be set");
+    }
+    let state = start(redis_url.as_deref(), &config).await?;
+    let (scheduler, usage, limiter) = (state.scheduler.clone(), state.usage.clone(), state.limiter.clone());
+    let app = api::router(&config, state)?;
 
     println!("Starting server on 0.0.0.0:8080");
-    let listener = tokio::net::TcpListener::bind(("0.0.0.0", 8080)).await?;
-    axum::serve(listener, app)
-        .with_graceful_shutdown(signals())
-        .await?;
+    let listener = config.server.bind(SocketAddr::from(([0, 0, 0, 0], 8080)))?;
+    upgrade::take_over();
+    upgrade::spawn_on_signal(listener.as_raw_fd());
+    systemd::notify("READY=1");
+    systemd::spawn_watchdog();
+    match &config.tls {
+        Some(tls_config) => tls::serve(listener, app, tls_config, signals()).await?,
+        None => server::serve(listener, app, signals()).await?,
+    }
+    if let Some(usage) = usage {
+        usage.flush(limiter.as_ref()).await;
+    }
+    scheduler.resign().await;
     println!("Server has shut down gracefully");
     Ok(())
 }
 
+/// Validates the config file given, or the one referenced by `GRENZE_CONFIG`,
+/// and lints it, resolving secrets and upstream hosts with `--resolve`.
+/// Returns the exit status: 1 if any problem was found.
+fn check_config(args: Vec<String>) -> i32 {
+    let resolve = args.iter().any(|a| a == "--resolve");
+    let path = args.into_iter().find(|a| a != "--resolve").or_else(|| std::env::var("GRENZE_CONFIG").ok());
+    let Some(path) = path else {
+        eprintln!("error: no config file given, as argument or GRENZE_CONFIG");
+        return 1;
+    };
+    let config = match config::Config::from_file(&path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {:#}", e);
+            return 1;
+        },
+    };
+    let mut problems: Vec<_> = lint::lint(&config).into_iter().map(|finding| format!("warning: {}", finding)).collect();
+    if resolve {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().expect("failed to build runtime");
+        problems.extend(runtime.block_on(lint::resolve(&config)).into_iter().map(|failure| format!("error: {}", failure)));
+    }
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+    if problems.is_empty() {
+        println!("{} is valid", path);
+        0
+    } else {
+        eprintln!("{}: {} problem(s) found", path, problems.len());
+        1
+    }
+}
+
+/// Copies limiter state between the stores of two config files. Redis URLs
+/// default to `REDIS_URL`.
+fn migrate(args: Vec<String>) -> Result<()> {
+    let usage = "usage: grenze-server migrate --from FILE --to FILE [--from-redis-url URL] [--to-redis-url URL] [--checkpoint FILE] [--dry-run]";
+    let mut options = std::collections::HashMap::new();
+    let mut dry_run = false;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--from" | "--to" | "--from-redis-url" | "--to-redis-url" | "--checkpoint" => {
+                let value = args.next().ok_or_else(|| anyhow!("{} needs a value; {}", arg, usage))?;
+                options.insert(arg, value);
+            },
+            _ => bail!("unknown argument '{}'; {}", arg, usage),
+        }
+    }
+    let redis_url = std::env::var("REDIS_URL").ok();
+    let mut endpoint = |side: &str| -> Result<migrate::Endpoint> {
+        let path = options.remove(&format!("--{}", side)).ok_or_else(|| anyhow!("--{} is required; {}", side, usage))?;
+        Ok(migrate::Endpoint {
+            config: config::Config::from_file(&path)?,
+            redis_url: options.remove(&format!("--{}-redis-url", side)).or_else(|| redis_url.clone()),
+        })
+    };
+    let migration = migrate::Migration {
+        from: endpoint("from")?,
+        to: endpoint("to")?,
+        dry_run,
+        checkpoint: options.remove("--checkpoint").map(Into::into),
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    runtime.block_on(migrate::run(&migration))
+}
+
+/// Builds the state, retrying with exponential backoff while the bucket store
+/// or etcd is unreachable.
+async fn start(redis_url: Option<&str>, config: &config::Config) -> Result<api::proxy::AppState> {
+    let startup = &config.startup;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let timeout = Duration::from_millis(startup.attempt_timeout_ms);
+        let err = match tokio::time::timeout(timeout, api::proxy::AppState::new(limiter::DEFAULT_RATE, redis_url, config)).await {
+            Ok(Ok(state)) => return Ok(state),
+            Ok(Err(e)) if limiter::is_permanent(&e) => return Err(e.context("failed to connect to redis")),
+            Ok(Err(e)) => e,
+            Err(_) => anyhow!("timed out after {}ms", startup.attempt_timeout_ms),
+        };
+        if startup.max_attempts > 0 && attempt >= startup.max_attempts {
+            println!("Startup attempt {}/{} failed, giving up: {:#}", attempt, startup.max_attempts, err);
+            return Err(err.context(format!("bucket store not available after {} attempts", attempt)));
+        }
+        let backoff = startup.backoff(attempt);
+        match startup.max_attempts {
+            0 => println!("Startup attempt {} failed, retrying in {}ms: {:#}", attempt, backoff.as_millis(), err),
+            max => println!("Startup attempt {}/{} failed, retrying in {}ms: {:#}", attempt, max, backoff.as_millis(), err),
+        }
+        tokio::time::sleep(backoff).await;
+    }
+}
+
 async fn signals() {
     use tokio::signal::unix::{signal, SignalKind};
 
@@ -42,4 +157,7 @@ async fn signals() {
             println!("Received SIGTERM. Shutting down...");
         }
     }
+    if !upgrade::upgrading() {
+        systemd::notify("STOPPING=1");
+    }
 }
@@ -0,0 +1,199 @@
+//! Bucket store over Memcached, for deployments that run memcached rather
+//! than Redis. Buckets are updated with compare-and-swap.
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::{io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader}, net::TcpStream, sync::Mutex};
+
+use crate::limiter::{bucket_ttl_secs, Admission, Bucket, LimiterStore};
+
+/// Longest key memcached accepts.
+const MAX_KEY_BYTES: usize = 250;
+/// Longest expiration time memcached takes as relative, 30 days.
+const MAX_RELATIVE_EXPTIME: i64 = 30 * 24 * 3600;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MemcachedConfig {
+    /// Server address, e.g. `127.0.0.1:11211`.
+    pub address: String,
+    /// Prefix of every key grenze stores.
+    pub key_prefix: String,
+    /// Attempts at updating a bucket that other instances keep changing.
+    pub cas_attempts: u32,
+}
+
+impl Default for MemcachedConfig {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1:11211".to_string(),
+            key_prefix: "rl:".to_string(),
+            cas_attempts: 10,
+        }
+    }
+}
+
+/// Buckets shared by all instances through Memcached. Snapshots are not
+/// supported, as memcached cannot list its keys.
+pub struct MemcachedStore {
+    config: MemcachedConfig,
+    conn: Mutex<Option<BufReader<TcpStream>>>,
+}
+
+/// Reply to a storage command.
+#[derive(Debug, PartialEq, Eq)]
+enum Stored {
+    Stored,
+    /// `NOT_STORED`, `EXISTS` or `NOT_FOUND`: someone else got there first.
+    Conflict,
+}
+
+impl MemcachedStore {
+    pub async fn connect(config: &MemcachedConfig) -> Result<Self> {
+        let store = Self {
+            config: config.clone(),
+            conn: Mutex::new(None),
+        };
+        store.ping().await.with_context(|| format!("failed to reach memcached at {}", config.address))?;
+        Ok(store)
+    }
+
+    /// Key of `name`, hashed if memcached would not accept it verbatim.
+    fn key(&self, name: &str) -> String {
+        let key = format!("{}{}", self.config.key_prefix, name);
+        if key.len() <= MAX_KEY_BYTES && !key.bytes().any(|b| b.is_ascii_whitespace() || b.is_ascii_control()) {
+            return key;
+        }
+        format!("{}sha256:{}", self.config.key_prefix, hex::encode(Sha256::digest(name.as_bytes())))
+    }
+
+    /// Runs `f` on the connection, opening it if needed. The connection is
+    /// dropped after a failure, as its state is unknown.
+    async fn with_conn<T>(&self, f: impl AsyncFnOnce(&mut BufReader<TcpStream>) -> Result<T>) -> Result<T> {
+        let mut guard = self.conn.lock().await;
+        let conn = match guard.as_mut() {
+            Some(conn) => conn,
+            None => guard.insert(BufReader::new(TcpStream::connect(&self.config.address).await?)),
+        };
+        let result = f(conn).await;
+        if result.is_err() {
+            *guard = None;
+        }
+        result
+    }
+}
+
+/// Sends `command` and reads the reply line.
+async fn request(conn: &mut BufReader<TcpStream>, command: &[u8]) -> Result<String> {
+    conn.get_mut().write_all(command).await?;
+    read_line(conn).await
+}
+
+async fn read_line(conn: &mut BufReader<TcpStream>) -> Result<String> {
+    let mut line = String::new();
+    if conn.read_line(&mut line).await? == 0 {
+        bail!("memcached closed the connection");
+    }
+    let line = line.trim_end().to_string();
+    if line == "ERROR" || line.starts_with("CLIENT_ERROR") || line.starts_with("SERVER_ERROR") {
+        bail!("memcached: {}", line);
+    }
+    Ok(line)
+}
+
+/// Fetches `key` with its CAS token.
+async fn gets(conn: &mut BufReader<TcpStream>, key: &str) -> Result<Option<(String, u64)>> {
+    let line = request(conn, format!("gets {}\r\n", key).as_bytes()).await?;
+    if line == "END" {
+        return Ok(None);
+    }
+    // VALUE <key> <flags> <bytes> <cas>
+    let fields: Vec<&str> = line.split(' ').collect();
+    let [_, _, _, len, cas] = fields[..] else {
+        bail!("unexpected memcached reply: {}", line);
+    };
+    let mut data = vec![0; len.parse::<usize>()? + 2];
+    conn.read_exact(&mut data).await?;
+    data.truncate(data.len() - 2);
+    let end = read_line(conn).await?;
+    if end != "END" {
+        bail!("unexpected memcached reply: {}", end);
+    }
+    Ok(Some((String::from_utf8(data)?, cas.parse()?)))
+}
+
+/// Runs `add` (without a CAS token) or `cas` for `key`.
+async fn store(conn: &mut BufReader<TcpStream>, key: &str, value: &str, ttl_secs: i64, cas: Option<u64>) -> Result<Stored> {
+    // Memcached takes expiration times beyond 30 days as Unix timestamps
+    let exptime = match ttl_secs {
+        ..=MAX_RELATIVE_EXPTIME => ttl_secs,
+        _ => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64) + ttl_secs,
+    };
+    let command = match cas {
+        Some(cas) => format!("cas {} 0 {} {} {}\r\n{}\r\n", key, exptime, value.len(), cas, value),
+        None => format!("add {} 0 {} {}\r\n{}\r\n", key, exptime, value.len(), value),
+    };
+    match request(conn, command.as_bytes()).await?.as_str() {
+        "STORED" => Ok(Stored::Stored),
+        "NOT_STORED" | "EXISTS" | "NOT_FOUND" => Ok(Stored::Conflict),
+        other => bail!("unexpected memcached reply: {}", other),
+    }
+}
+
+#[async_trait]
+impl LimiterStore for MemcachedStore {
+    async fn allow(&self, bucket: &str, cost: f64, capacity: u32, leak_per_sec: f64, now_ms: i64) -> Result<Admission> {
+        let key = self.key(bucket);
+        self.with_conn(async |conn| {
+            for _ in 0..self.config.cas_attempts.max(1) {
+                // Stored as "<fill> <last_ms>"
+                let current = gets(conn, &key).await?;
+                let previous = current.as_ref().and_then(|(value, _)| {
+                    let (fill, last_ms) = value.split_once(' ')?;
+                    Some(Bucket {
+                        fill: fill.parse().ok()?,
+                        last_ms: last_ms.parse().ok()?,
+                        leak_per_sec,
+                    })
+                });
+                let (allowed, next) = Bucket::admit(previous.as_ref(), cost, capacity as f64, leak_per_sec, now_ms);
+                let value = format!("{} {}", next.fill, next.last_ms);
+                if store(conn, &key, &value, bucket_ttl_secs(next.fill, leak_per_sec), current.map(|(_, cas)| cas)).await? == Stored::Stored {
+                    return Ok(Admission { allowed, fill: next.fill });
+                }
+            }
+            bail!("bucket {} kept changing during {} update attempts", bucket, self.config.cas_attempts)
+        })
+        .await
+    }
+
+    async fn remember(&self, key: &str, ttl_secs: u64) -> Result<bool> {
+        let key = self.key(key);
+        let added = self.with_conn(async |conn| store(conn, &key, "1", ttl_secs.max(1) as i64, None).await).await;
+        Ok(matches!(added?, Stored::Stored))
+    }
+
+    async fn forget(&self, key: &str) {
+        let key = self.key(key);
+        let _ = self.with_conn(async |conn| request(conn, format!("delete {}\r\n", key).as_bytes()).await).await;
+    }
+
+    async fn evict(&self, bucket: &str) {
+        let key = self.key(bucket);
+        let _ = self.with_conn(async |conn| request(conn, format!("delete {}\r\n", key).as_bytes()).await).await;
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.with_conn(async |conn| {
+            let line = request(conn, b"version\r\n").await?;
+            if !line.starts_with("VERSION") {
+                bail!("unexpected memcached reply: {}", line);
+            }
+            Ok(())
+        })
+        .await
+    }
+}
@@ -0,0 +1,380 @@
+//! Counters, gauges and histograms exported in the Prometheus text format on
+//! `/metrics`, or as OpenMetrics with exemplars linking them to traces.
+
+use std::{collections::{HashMap, HashSet}, fmt::Write, sync::{atomic::{AtomicU64, Ordering}, Mutex}, time::Duration};
+
+use crate::{breaker::CircuitBreaker, erasure::Tenant, limiter::{Admission, BucketLimit}, rejection::Reason};
+
+/// Upper bound of buckets whose fill level is tracked, so random keys cannot
+/// grow the gauges without limit.
+const MAX_TRACKED_BUCKETS: usize = 100_000;
+
+/// Upper bounds of the downstream latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Outcome {
+    Allowed,
+    Limited,
+}
+
+impl Outcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Allowed => "allowed",
+            Outcome::Limited => "limited",
+        }
+    }
+}
+
+/// A rate limit decision, as streamed to admin watchers.
+#[derive(Debug, Clone)]
+pub struct Decision {
+    pub at_ms: i64,
+    pub key: String,
+    pub policy: String,
+    pub bucket: String,
+    pub allowed: bool,
+    pub fill: f64,
+    pub capacity: u32,
+}
+
+/// Fill level of a bucket as of its last request.
+struct FillSample {
+    policy: String,
+    fill: f64,
+    at_ms: i64,
+    capacity: f64,
+    leak_per_sec: f64,
+}
+
+/// Observation of a traced request, linking a series to its trace.
+#[derive(Debug, Clone)]
+struct Exemplar {
+    trace_id: String,
+    value: f64,
+    at_ms: i64,
+}
+
+impl Exemplar {
+    /// The exemplar as appended to a sample line.
+    fn render(&self) -> String {
+        format!(" # {{trace_id=\"{}\"}} {} {}.{:03}", self.trace_id, self.value, self.at_ms / 1000, self.at_ms % 1000)
+    }
+}
+
+/// Downstream latencies of a policy, with the latest traced one per bucket.
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, the last one past the largest bound.
+    counts: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: f64,
+    exemplars: [Option<Exemplar>; LATENCY_BUCKETS.len() + 1],
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<HashMap<(String, Outcome), u64>>,
+    /// Latest traced request per policy and outcome.
+    exemplars: Mutex<HashMap<(String, Outcome), Exemplar>>,
+    latencies: Mutex<HashMap<String, Histogram>>,
+    buckets: Mutex<HashMap<String, FillSample>>,
+    expirations: AtomicU64,
+    /// Hedged requests by policy and whether the alternate answered first.
+    hedges: Mutex<HashMap<(String, bool), u64>>,
+    rejections: Mutex<HashMap<Reason, u64>>,
+    /// Drained buckets whose keys have not expired yet; only tracked when
+    /// expiry events are received.
+    inactive: Option<Mutex<HashSet<String>>>,
+}
+
+impl Metrics {
+    /// Tracks drained buckets until their keys expire, as reported by
+    /// [`Metrics::record_expiry`].
+    pub fn tracking_inactive(mut self) -> Self {
+        self.inactive = Some(Mutex::default());
+        self
+    }
+
+    /// Records the limiter's decision for a request to the bucket of `limit`
+    /// at `now_ms`, belonging to the trace `trace_id`.
+    pub fn record_admission(&self, policy: &str, limit: &BucketLimit, admission: Admission, trace_id: Option<&str>, now_ms: i64) {
+        let bucket = limit.bucket.as_str();
+        let outcome = if admission.allowed { Outcome::Allowed } else { Outcome::Limited };
+        *self.requests.lock().expect("metrics lock poisoned").entry((policy.to_string(), outcome)).or_default() += 1;
+        if let Some(trace_id) = trace_id {
+            let exemplar = Exemplar {
+                trace_id: trace_id.to_string(),
+                value: 1.0,
+                at_ms: now_ms,
+            };
+            self.exemplars.lock().expect("metrics lock poisoned").insert((policy.to_string(), outcome), exemplar);
+        }
+
+        let mut buckets = self.buckets.lock().expect("metrics lock poisoned");
+        if let Some(inactive) = &self.inactive {
+            inactive.lock().expect("metrics lock poisoned").remove(bucket);
+        }
+        if buckets.len() >= MAX_TRACKED_BUCKETS && !buckets.contains_key(bucket) {
+            self.prune(&mut buckets, now_ms);
+            if buckets.len() >= MAX_TRACKED_BUCKETS {
+                return;
+            }
+        }
+        buckets.insert(
+            bucket.to_string(),
+            FillSample {
+                policy: policy.to_string(),
+                fill: admission.fill,
+                at_ms: now_ms,
+                capacity: limit.capacity as f64,
+                leak_per_sec: limit.leak_per_sec,
+            },
+        );
+    }
+
+    /// Records that the keys of `bucket` expired in the store.
+    pub fn record_expiry(&self, bucket: &str) {
+        self.expirations.fetch_add(1, Ordering::Relaxed);
+        let mut buckets = self.buckets.lock().expect("metrics lock poisoned");
+        buckets.remove(bucket);
+        if let Some(inactive) = &self.inactive {
+            inactive.lock().expect("metrics lock poisoned").remove(bucket);
+        }
+    }
+
+    /// Forgets the fill levels of `tenant`'s buckets.
+    pub fn forget(&self, tenant: &Tenant) {
+        self.buckets.lock().expect("metrics lock poisoned").retain(|bucket, _| !tenant.owns_bucket(bucket));
+        if let Some(inactive) = &self.inactive {
+            inactive.lock().expect("metrics lock poisoned").retain(|bucket| !tenant.owns_bucket(bucket));
+        }
+    }
+
+    /// Records how long the downstream took to answer a request of `policy`.
+    pub fn record_latency(&self, policy: &str, latency: Duration, trace_id: Option<&str>, now_ms: i64) {
+        let seconds = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(LATENCY_BUCKETS.len());
+        let mut latencies = self.latencies.lock().expect("metrics lock poisoned");
+        let histogram = latencies.entry(policy.to_string()).or_default();
+        histogram.counts[bucket] += 1;
+        histogram.sum += seconds;
+        if let Some(trace_id) = trace_id {
+            histogram.exemplars[bucket] = Some(Exemplar {
+                trace_id: trace_id.to_string(),
+                value: seconds,
+                at_ms: now_ms,
+            });
+        }
+    }
+
+    pub fn record_hedge(&self, policy: &str, alternate_won: bool) {
+        *self.hedges.lock().expect("metrics lock poisoned").entry((policy.to_string(), alternate_won)).or_default() += 1;
+    }
+
+    /// Records a request rejected for `reason`.
+    pub fn record_rejection(&self, reason: Reason) {
+        *self.rejections.lock().expect("metrics lock poisoned").entry(reason).or_default() += 1;
+    }
+
+    /// Number of drained buckets whose keys have not expired yet, if tracked.
+    pub fn inactive_buckets(&self) -> Option<usize> {
+        self.inactive.as_ref().map(|i| i.lock().expect("metrics lock poisoned").len())
+    }
+
+    /// Drops drained buckets, remembering them as inactive if tracked.
+    fn prune(&self, buckets: &mut HashMap<String, FillSample>, now_ms: i64) {
+        let mut inactive = self.inactive.as_ref().map(|i| i.lock().expect("metrics lock poisoned"));
+        buckets.retain(|bucket, s| {
+            let active = self.level(s, now_ms) > 0.0;
+            if !active && let Some(inactive) = inactive.as_mut() && inactive.len() < MAX_TRACKED_BUCKETS {
+                inactive.insert(bucket.clone());
+            }
+            active
+        });
+    }
+
+    fn level(&self, sample: &FillSample, now_ms: i64) -> f64 {
+        let elapsed_ms = (now_ms - sample.at_ms).max(0);
+        (sample.fill - (elapsed_ms as f64 / 1000.0) * sample.leak_per_sec).max(0.0)
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format, with
+    /// the exemplars of traced requests if `exemplars` is set. Fill levels are
+    /// leaked to `now_ms`; drained buckets are dropped.
+    pub fn render(&self, now_ms: i64, breaker: &CircuitBreaker, exemplars: bool) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP grenze_requests_total Requests checked against the limiter, by policy and outcome.\n");
+        out.push_str("# TYPE grenze_requests_total counter\n");
+        let requests = self.requests.lock().expect("metrics lock poisoned");
+        let traced = self.exemplars.lock().expect("metrics lock poisoned");
+        let mut counters: Vec<_> = requests.iter().collect();
+        counters.sort_by(|a, b| (&a.0.0, a.0.1.as_str()).cmp(&(&b.0.0, b.0.1.as_str())));
+        for (series, count) in counters {
+            let exemplar = traced.get(series).filter(|_| exemplars).map(Exemplar::render).unwrap_or_default();
+            let _ = writeln!(out, "grenze_requests_total{{policy=\"{}\",outcome=\"{}\"}} {}{}", escape(&series.0), series.1.as_str(), count, exemplar);
+        }
+        drop(traced);
+        drop(requests);
+
+        out.push_str("# HELP grenze_rejections_total Rejected requests, by reason.\n");
+        out.push_str("# TYPE grenze_rejections_total counter\n");
+        let rejections = self.rejections.lock().expect("metrics lock poisoned");
+        let mut counters: Vec<_> = rejections.iter().collect();
+        counters.sort();
+        for (reason, count) in counters {
+            let _ = writeln!(out, "grenze_rejections_total{{reason=\"{}\"}} {}", reason.code(), count);
+        }
+        drop(rejections);
+
+        out.push_str("# HELP grenze_downstream_duration_seconds Time the downstream took to answer, by policy.\n");
+        out.push_str("# TYPE grenze_downstream_duration_seconds histogram\n");
+        let latencies = self.latencies.lock().expect("metrics lock poisoned");
+        let mut histograms: Vec<_> = latencies.iter().collect();
+        histograms.sort_by(|a, b| a.0.cmp(b.0));
+        for (policy, histogram) in histograms {
+            let policy = escape(policy);
+            let mut cumulative = 0;
+            for (i, count) in histogram.counts.iter().enumerate() {
+                cumulative += count;
+                let le = LATENCY_BUCKETS.get(i).map_or("+Inf".to_string(), |bound| bound.to_string());
+                let exemplar = histogram.exemplars[i].as_ref().filter(|_| exemplars).map(Exemplar::render).unwrap_or_default();
+                let _ = writeln!(out, "grenze_downstream_duration_seconds_bucket{{policy=\"{}\",le=\"{}\"}} {}{}", policy, le, cumulative, exemplar);
+            }
+            let _ = writeln!(out, "grenze_downstream_duration_seconds_sum{{policy=\"{}\"}} {}", policy, histogram.sum);
+            let _ = writeln!(out, "grenze_downstream_duration_seconds_count{{policy=\"{}\"}} {}", policy, cumulative);
+        }
+        drop(latencies);
+
+        out.push_str("# HELP grenze_hedged_requests_total Requests hedged to an alternate upstream, by policy and which upstream answered first.\n");
+        out.push_str("# TYPE grenze_hedged_requests_total counter\n");
+        let hedges = self.hedges.lock().expect("metrics lock poisoned");
+        let mut counters: Vec<_> = hedges.iter().collect();
+        counters.sort();
+        for ((policy, alternate_won), count) in counters {
+            let winner = if *alternate_won { "alternate" } else { "primary" };
+            let _ = writeln!(out, "grenze_hedged_requests_total{{policy=\"{}\",winner=\"{}\"}} {}", escape(policy), winner, count);
+        }
+        drop(hedges);
+
+        let fill = self.fill_summary(now_ms);
+        out.push_str("# HELP grenze_active_buckets Buckets currently holding requests.\n");
+        out.push_str("# TYPE grenze_active_buckets gauge\n");
+        let _ = writeln!(out, "grenze_active_buckets {}", fill.active);
+
+        out.push_str("# HELP grenze_bucket_fill_ratio Fill level relative to capacity across active buckets.\n");
+        out.push_str("# TYPE grenze_bucket_fill_ratio gauge\n");
+        for (q, ratio) in &fill.quantiles {
+            let _ = writeln!(out, "grenze_bucket_fill_ratio{{quantile=\"{}\"}} {}", q, ratio);
+        }
+
+        out.push_str("# HELP grenze_policy_active_buckets Buckets currently holding requests, by policy.\n");
+        out.push_str("# TYPE grenze_policy_active_buckets gauge\n");
+        for p in &fill.policies {
+            let _ = writeln!(out, "grenze_policy_active_buckets{{policy=\"{}\"}} {}", escape(&p.policy), p.active);
+        }
+        out.push_str("# HELP grenze_policy_bucket_fill_ratio Mean and maximum fill ratio of active buckets, by policy.\n");
+        out.push_str("# TYPE grenze_policy_bucket_fill_ratio gauge\n");
+        for p in &fill.policies {
+            let _ = writeln!(out, "grenze_policy_bucket_fill_ratio{{policy=\"{}\",stat=\"mean\"}} {}", escape(&p.policy), p.mean);
+            let _ = writeln!(out, "grenze_policy_bucket_fill_ratio{{policy=\"{}\",stat=\"max\"}} {}", escape(&p.policy), p.max);
+        }
+
+        out.push_str("# HELP grenze_limiter_errors_total Failed bucket store calls, including ones skipped by the open circuit breaker.\n");
+        out.push_str("# TYPE grenze_limiter_errors_total counter\n");
+        let _ = writeln!(out, "grenze_limiter_errors_total {}", breaker.errors());
+        out.push_str("# HELP grenze_limiter_breaker_open Whether the circuit breaker around the bucket store is open.\n");
+        out.push_str("# TYPE grenze_limiter_breaker_open gauge\n");
+        let _ = writeln!(out, "grenze_limiter_breaker_open {}", breaker.is_open() as u8);
+
+        if let Some(inactive) = self.inactive_buckets() {
+            out.push_str("# HELP grenze_bucket_expirations_total Buckets whose keys expired in the store.\n");
+            out.push_str("# TYPE grenze_bucket_expirations_total counter\n");
+            let _ = writeln!(out, "grenze_bucket_expirations_total {}", self.expirations.load(Ordering::Relaxed));
+            out.push_str("# HELP grenze_inactive_buckets Drained buckets whose keys have not expired yet.\n");
+            out.push_str("# TYPE grenze_inactive_buckets gauge\n");
+            let _ = writeln!(out, "grenze_inactive_buckets {}", inactive);
+        }
+        out
+    }
+
+    /// Fill ratios of the active buckets, leaked to `now_ms`. Drained buckets
+    /// are dropped.
+    pub fn fill_summary(&self, now_ms: i64) -> FillSummary {
+        let mut buckets = self.buckets.lock().expect("metrics lock poisoned");
+        self.prune(&mut buckets, now_ms);
+        let mut ratios = Vec::with_capacity(buckets.len());
+        let mut per_policy: HashMap<&str, (usize, f64, f64)> = HashMap::new();
+        for sample in buckets.values() {
+            let ratio = self.level(sample, now_ms) / sample.capacity;
+            ratios.push(ratio);
+            let entry = per_policy.entry(&sample.policy).or_default();
+            entry.0 += 1;
+            entry.1 += ratio;
+            entry.2 = entry.2.max(ratio);
+        }
+        ratios.sort_by(f64::total_cmp);
+
+        let mut policies: Vec<PolicyFill> = per_policy
+            .into_iter()
+            .map(|(policy, (active, sum, max))| PolicyFill {
+                policy: policy.to_string(),
+                active,
+                mean: sum / active as f64,
+                max,
+            })
+            .collect();
+        policies.sort_by(|a, b| a.policy.cmp(&b.policy));
+        FillSummary {
+            active: ratios.len(),
+            quantiles: [0.5, 0.9, 0.99, 1.0].map(|q| (q, quantile(&ratios, q))),
+            policies,
+        }
+    }
+}
+
+pub struct FillSummary {
+    pub active: usize,
+    /// Fill ratio by quantile; the 1.0 quantile is the maximum.
+    pub quantiles: [(f64, f64); 4],
+    pub policies: Vec<PolicyFill>,
+}
+
+pub struct PolicyFill {
+    pub policy: String,
+    pub active: usize,
+    pub mean: f64,
+    pub max: f64,
+}
+
+/// Nearest-rank quantile of sorted values; 0 when empty.
+fn quantile(sorted: &[f64], q: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = ((q * sorted.len() as f64).ceil() as usize).clamp(1, sorted.len());
+    sorted[rank - 1]
+}
+
+pub(crate) fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Converts metrics in the Prometheus text format to OpenMetrics, whose
+/// counter families are named without the `_total` suffix of their samples.
+pub fn openmetrics(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 8);
+    for line in text.lines() {
+        let counter = ["# HELP ", "# TYPE "].iter().find_map(|prefix| {
+            let (name, tail) = line.strip_prefix(prefix)?.split_once(' ')?;
+            Some((prefix, name.strip_suffix("_total")?, tail))
+        });
+        let _ = match counter {
+            Some((prefix, family, tail)) => writeln!(out, "{}{} {}", prefix, family, tail),
+            None => writeln!(out, "{}", line),
+        };
+    }
+    out.push_str("# EOF\n");
+    out
+}
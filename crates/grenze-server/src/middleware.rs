@@ -0,0 +1,89 @@
+//! Compile-time extension point of the proxy pipeline.
+//!
+//! A request passes through the registered [`ProxyMiddleware`]s in
+//! registration order: `on_request` once its key and policy are known and
+//! before it is rate limited, `on_response` once the downstream answered, and
+//! `on_reject` whenever grenze answers the request itself instead.
+
+use async_trait::async_trait;
+use axum::{body::{Body, Bytes}, http::{HeaderMap, StatusCode}, response::Response};
+use std::{net::SocketAddr, sync::Arc};
+
+use crate::{api::proxy::ProxyRequest, policy::Policy, trace::TraceContext};
+
+/// What is known about a request once its key and policy are resolved.
+pub struct Context<'a> {
+    pub peer: SocketAddr,
+    pub key: String,
+    pub policy: &'a Policy,
+    /// Trace the request belongs to, if it is traced.
+    pub trace: Option<TraceContext>,
+}
+
+/// Downstream response as it will be returned to the client.
+pub struct DownstreamResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    /// Body of a response streamed to the client as it arrives, such as
+    /// partial content of a large file; `body` is empty then. Middleware
+    /// replacing the body clears it.
+    pub stream: Option<Body>,
+    /// Trailers the downstream sent after a buffered body.
+    pub trailers: Option<HeaderMap>,
+}
+
+#[async_trait]
+pub trait ProxyMiddleware: Send + Sync {
+    /// Inspects or modifies the request before it is rate limited and
+    /// forwarded. Returning a response rejects the request with it.
+    async fn on_request(&self, _ctx: &Context<'_>, _request: &mut ProxyRequest) -> Result<(), Response> {
+        Ok(())
+    }
+
+    /// Inspects or modifies the downstream response. Returning a response
+    /// replaces it.
+    async fn on_response(&self, _ctx: &Context<'_>, _response: &mut DownstreamResponse) -> Result<(), Response> {
+        Ok(())
+    }
+
+    /// Observes or modifies a response grenze produced itself, e.g. because the
+    /// request was rate limited or vetoed. `ctx` is `None` if the request was
+    /// rejected before its key and policy were known.
+    async fn on_reject(&self, _ctx: Option<&Context<'_>>, _response: &mut Response) {}
+}
+
+/// Middleware in the order it runs.
+#[derive(Clone, Default)]
+pub struct MiddlewareRegistry {
+    middleware: Vec<Arc<dyn ProxyMiddleware>>,
+}
+
+impl MiddlewareRegistry {
+    pub fn register(&mut self, middleware: Arc<dyn ProxyMiddleware>) {
+        self.middleware.push(middleware);
+    }
+
+    /// Runs the request hooks, stopping at the first rejection.
+    pub async fn on_request(&self, ctx: &Context<'_>, request: &mut ProxyRequest) -> Result<(), Response> {
+        for m in &self.middleware {
+            m.on_request(ctx, request).await?;
+        }
+        Ok(())
+    }
+
+    /// Runs the response hooks, stopping at the first replacement.
+    pub async fn on_response(&self, ctx: &Context<'_>, response: &mut DownstreamResponse) -> Result<(), Response> {
+        for m in &self.middleware {
+            m.on_response(ctx, response).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn on_reject(&self, ctx: Option<&Context<'_>>, mut response: Response) -> Response {
+        for m in &self.middleware {
+            m.on_reject(ctx, &mut response).await;
+        }
+        response
+    }
+}
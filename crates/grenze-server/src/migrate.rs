@@ -0,0 +1,210 @@
+//! Copying of limiter state from one store to another, run by `grenze-server
+//! migrate`, e.g. from a single Redis to Redis shards or to Postgres. Buckets,
+//! then quota counters, are copied in name order and in batches, each
+//! recorded in a checkpoint file so an interrupted migration resumes after
+//! the last batch copied, and read back from the destination to verify them.
+//! Key rules are copied too when both sides keep them in Redis.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::Arc};
+
+use crate::{config::Config, dynamodb::DynamoDbStore, limiter::{self, BucketState, Clock, LimiterStore, QuotaState, RedisStore, StoreConfig, SystemClock, DEFAULT_RATE}, memcached::MemcachedStore, postgres::PostgresStore, rules::KeyRules, shards::ShardedStore};
+
+/// Buckets or quota counters imported at once, and between checkpoints.
+const BATCH: usize = 1000;
+
+/// Difference of fill levels below which a copied bucket counts as equal.
+const FILL_EPSILON: f64 = 1e-6;
+
+/// Side of a migration: its config and, for Redis, the server's URL.
+pub struct Endpoint {
+    pub config: Config,
+    pub redis_url: Option<String>,
+}
+
+pub struct Migration {
+    pub from: Endpoint,
+    pub to: Endpoint,
+    /// Only reports what would be copied.
+    pub dry_run: bool,
+    /// File holding the last bucket copied, read to resume.
+    pub checkpoint: Option<PathBuf>,
+}
+
+/// Progress saved in the checkpoint file: the last bucket copied and, once
+/// all buckets are, the last quota counter copied.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Checkpoint {
+    bucket: Option<String>,
+    quota: Option<String>,
+}
+
+impl Checkpoint {
+    fn read(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("failed to read checkpoint {}", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("invalid checkpoint {}", path.display()))
+    }
+
+    fn write(&self, path: Option<&Path>) -> Result<()> {
+        let Some(path) = path else {
+            return Ok(());
+        };
+        std::fs::write(path, serde_json::to_string(self)?).with_context(|| format!("failed to write checkpoint {}", path.display()))
+    }
+}
+
+/// Opens the store of `endpoint` without the background tasks of a server.
+async fn open(endpoint: &Endpoint) -> Result<Arc<dyn LimiterStore>> {
+    let config = &endpoint.config;
+    Ok(match &config.limiter.store {
+        StoreConfig::Redis => Arc::new(RedisStore::connect(redis_url(endpoint)?, &config.redis).await?),
+        StoreConfig::Memcached(c) => Arc::new(MemcachedStore::connect(c).await?),
+        StoreConfig::Dynamodb(c) => Arc::new(DynamoDbStore::connect(c).await?),
+        StoreConfig::Postgres(c) => Arc::new(PostgresStore::connect(c).await?),
+        StoreConfig::RedisShards(c) => Arc::new(ShardedStore::connect(c, &config.redis).await?),
+    })
+}
+
+fn redis_url(endpoint: &Endpoint) -> Result<&str> {
+    endpoint.redis_url.as_deref().context("a redis url is required for the redis store")
+}
+
+/// Whether the store of `endpoint` keeps quota counters.
+fn keeps_quotas(endpoint: &Endpoint) -> bool {
+    matches!(endpoint.config.limiter.store, StoreConfig::Redis | StoreConfig::RedisShards(_))
+}
+
+/// Key rules of `endpoint`, if it keeps any.
+fn rules(endpoint: &Endpoint) -> Result<Option<KeyRules>> {
+    let config = &endpoint.config;
+    match (&config.key_rules, &config.limiter.store) {
+        (Some(key_rules), StoreConfig::Redis) => {
+            let client = config.redis.client(redis_url(endpoint)?)?;
+            Ok(Some(KeyRules::start(client, key_rules, config.redis.prefix())))
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Copies the state, or reports what would be copied on a dry run. Fails if
+/// copied buckets differ in the destination.
+pub async fn run(migration: &Migration) -> Result<()> {
+    let source = open(&migration.from).await.context("failed to open the source store")?;
+    let destination = open(&migration.to).await.context("failed to open the destination store")?;
+    let now_ms = SystemClock.now_ms();
+
+    let mut buckets = source.export().await.context("failed to export the source buckets")?;
+    buckets.sort_by(|a, b| a.bucket.cmp(&b.bucket));
+    let mut quotas = match keeps_quotas(&migration.from) {
+        true => source.export_quotas(now_ms).await.context("failed to export the source quota counters")?,
+        false => Vec::new(),
+    };
+    quotas.sort_by(|a, b| a.counter.cmp(&b.counter));
+    if !quotas.is_empty() && !keeps_quotas(&migration.to) {
+        bail!("the source has {} quota counters, but the destination does not keep quotas; they need the redis store", quotas.len());
+    }
+    let mut checkpoint = Checkpoint::default();
+    if let Some(path) = &migration.checkpoint
+        && path.exists()
+    {
+        checkpoint = Checkpoint::read(path)?;
+        if let Some(after) = &checkpoint.bucket {
+            let skipped = buckets.partition_point(|b| &b.bucket <= after);
+            buckets.drain(..skipped);
+            println!("Resuming after bucket '{}', skipping {} buckets", after, skipped);
+        }
+        if let Some(after) = &checkpoint.quota {
+            let skipped = quotas.partition_point(|q| &q.counter <= after);
+            quotas.drain(..skipped);
+            println!("Resuming after quota counter '{}', skipping {} quota counters", after, skipped);
+        }
+    }
+
+    let size = &migration.to.config.limiter;
+    let leak_per_sec = size.leak_per_sec.unwrap_or(DEFAULT_RATE as f64);
+    let capacity = size.capacity.unwrap_or(DEFAULT_RATE);
+    let source_rules = rules(&migration.from)?;
+    let destination_rules = rules(&migration.to)?;
+    let key_rules = match &source_rules {
+        Some(rules) => rules.export(now_ms).await.context("failed to export the source key rules")?,
+        None => Vec::new(),
+    };
+    if !key_rules.is_empty() && destination_rules.is_none() {
+        bail!("the source has {} key rules, but the destination does not keep key rules; they need key_rules and the redis store", key_rules.len());
+    }
+
+    if migration.dry_run {
+        let held: HashMap<String, BucketState> = destination.export().await.context("failed to export the destination buckets")?.into_iter().map(|b| (b.bucket.clone(), b)).collect();
+        let overwritten = buckets.iter().filter(|b| held.contains_key(&b.bucket)).count();
+        let held_quotas = match keeps_quotas(&migration.to) {
+            true => destination.export_quotas(now_ms).await.context("failed to export the destination quota counters")?,
+            false => Vec::new(),
+        };
+        let overwritten_quotas = quotas.iter().filter(|q| held_quotas.iter().any(|h| h.counter == q.counter)).count();
+        println!(
+            "Would copy {} buckets, overwriting {} the destination holds, {} quota counters, overwriting {}, and {} key rules",
+            buckets.len(),
+            overwritten,
+            quotas.len(),
+            overwritten_quotas,
+            key_rules.len()
+        );
+        return Ok(());
+    }
+
+    let mut copied = 0;
+    for batch in buckets.chunks(BATCH) {
+        destination.import(batch, capacity, leak_per_sec).await.context("failed to import buckets into the destination")?;
+        copied += batch.len();
+        checkpoint.bucket = Some(batch.last().expect("chunks are never empty").bucket.clone());
+        checkpoint.write(migration.checkpoint.as_deref())?;
+        println!("Copied {}/{} buckets", copied, buckets.len());
+    }
+    let mut copied = 0;
+    for batch in quotas.chunks(BATCH) {
+        destination.import_quotas(batch).await.context("failed to import quota counters into the destination")?;
+        copied += batch.len();
+        checkpoint.quota = Some(batch.last().expect("chunks are never empty").counter.clone());
+        checkpoint.write(migration.checkpoint.as_deref())?;
+        println!("Copied {}/{} quota counters", copied, quotas.len());
+    }
+    if let Some(rules) = &destination_rules
+        && !key_rules.is_empty()
+    {
+        rules.import(&key_rules, now_ms).await.context("failed to import key rules into the destination")?;
+        println!("Copied {} key rules", key_rules.len());
+    }
+
+    let held: HashMap<String, BucketState> = destination.export().await.context("failed to read back the destination buckets")?.into_iter().map(|b| (b.bucket.clone(), b)).collect();
+    let now_ms = SystemClock.now_ms();
+    let mismatched: Vec<&str> = buckets
+        .iter()
+        .filter(|b| match held.get(&b.bucket) {
+            Some(copy) => copy.last_ms != b.last_ms || (copy.fill - b.fill).abs() > FILL_EPSILON,
+            // Drained and expired since
+            None => b.last_ms + limiter::bucket_ttl_ms(b.fill, leak_per_sec) > now_ms,
+        })
+        .map(|b| b.bucket.as_str())
+        .collect();
+    if !mismatched.is_empty() {
+        bail!("{} buckets differ in the destination, e.g. '{}'; remove the checkpoint and migrate again", mismatched.len(), mismatched[0]);
+    }
+    if !quotas.is_empty() {
+        let held: HashMap<String, QuotaState> = destination.export_quotas(now_ms).await.context("failed to read back the destination quota counters")?.into_iter().map(|q| (q.counter.clone(), q)).collect();
+        let mismatched: Vec<&str> = quotas
+            .iter()
+            .filter(|q| match held.get(&q.counter) {
+                Some(copy) => (copy.requests, copy.bytes) != (q.requests, q.bytes),
+                // The period ended since
+                None => q.until_ms > now_ms,
+            })
+            .map(|q| q.counter.as_str())
+            .collect();
+        if !mismatched.is_empty() {
+            bail!("{} quota counters differ in the destination, e.g. '{}'; remove the checkpoint and migrate again", mismatched.len(), mismatched[0]);
+        }
+    }
+    println!("Verified {} buckets and {} quota counters in the destination", buckets.len(), quotas.len());
+    Ok(())
+}
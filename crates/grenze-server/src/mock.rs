@@ -0,0 +1,100 @@
+//! Canned downstream responses, so CI environments can run against grenze
+//! without access to the real upstreams.
+
+use anyhow::{anyhow, Result};
+use axum::http::{header::CONTENT_TYPE, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{collections::HashMap, time::Duration};
+
+use crate::middleware::DownstreamResponse;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Mock {
+    /// Destination URL pattern, where `*` matches any sequence of characters.
+    pub url: String,
+    /// Only mock this method; any method if unset.
+    #[serde(default)]
+    pub method: Option<String>,
+    #[serde(default = "default_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Response body. Strings are returned verbatim, anything else as JSON.
+    #[serde(default)]
+    pub body: Option<Value>,
+    /// Delay before the response is returned.
+    #[serde(default)]
+    pub latency_ms: u64,
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+impl Mock {
+    pub fn validate(&self) -> Result<()> {
+        StatusCode::from_u16(self.status).map_err(|_| anyhow!("mock for '{}': invalid status {}", self.url, self.status))?;
+        for (name, value) in &self.headers {
+            HeaderName::try_from(name.as_str()).map_err(|_| anyhow!("mock for '{}': invalid header name '{}'", self.url, name))?;
+            HeaderValue::try_from(value.as_str()).map_err(|_| anyhow!("mock for '{}': invalid value for header '{}'", self.url, name))?;
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, method: &str, url: &str) -> bool {
+        self.method.as_ref().is_none_or(|m| m.eq_ignore_ascii_case(method)) && glob(&self.url, url)
+    }
+
+    /// Waits for the configured latency and builds the response.
+    pub async fn respond(&self) -> DownstreamResponse {
+        if self.latency_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(self.latency_ms)).await;
+        }
+        let mut headers = HeaderMap::new();
+        let body = match &self.body {
+            None => Vec::new(),
+            Some(Value::String(text)) => {
+                headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+                text.clone().into_bytes()
+            },
+            Some(json) => {
+                headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                serde_json::to_vec(json).unwrap_or_default()
+            },
+        };
+        for (name, value) in &self.headers {
+            if let (Ok(name), Ok(value)) = (HeaderName::try_from(name.as_str()), HeaderValue::try_from(value.as_str())) {
+                headers.insert(name, value);
+            }
+        }
+        DownstreamResponse {
+            status: StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK),
+            headers,
+            body: body.into(),
+            stream: None,
+            trailers: None,
+        }
+    }
+}
+
+/// Matches `text` against `pattern`, where `*` matches any sequence.
+pub(crate) fn glob(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
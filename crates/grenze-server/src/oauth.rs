@@ -0,0 +1,150 @@
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
+use tokio::sync::Mutex;
+
+use crate::credentials::SecretRef;
+
+/// OAuth2 client credentials grant used to obtain tokens for an upstream.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OAuth2Config {
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: SecretRef,
+    #[serde(default)]
+    pub scope: Option<String>,
+    #[serde(default)]
+    pub audience: Option<String>,
+    /// How the client authenticates against the token endpoint.
+    #[serde(default)]
+    pub client_auth: ClientAuth,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuth {
+    /// HTTP basic authentication (`client_secret_basic`).
+    #[default]
+    Basic,
+    /// Credentials in the form body (`client_secret_post`).
+    Post,
+}
+
+impl OAuth2Config {
+    /// Grants with the same key share a cached token, so everything that
+    /// changes the token minted goes into it.
+    fn cache_key(&self) -> String {
+        format!(
+            "{}#{}#{}#{}",
+            self.token_url,
+            self.client_id,
+            self.scope.as_deref().unwrap_or_default(),
+            self.audience.as_deref().unwrap_or_default()
+        )
+    }
+}
+
+struct Token {
+    access_token: String,
+    expires_at: Instant,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+/// Refresh tokens this long before they expire.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+
+/// Obtains and caches client credentials tokens. Each grant has its own slot
+/// whose lock is held while refreshing, so concurrent requests needing a new
+/// token wait for a single refresh instead of each fetching one.
+#[derive(Default)]
+pub struct TokenManager {
+    slots: Mutex<HashMap<String, Arc<Mutex<Option<Token>>>>>,
+}
+
+impl TokenManager {
+    async fn slot(&self, config: &OAuth2Config) -> Arc<Mutex<Option<Token>>> {
+        self.slots.lock().await.entry(config.cache_key()).or_default().clone()
+    }
+
+    pub async fn token(&self, http: &reqwest::Client, config: &OAuth2Config, client_secret: &str) -> Result<String> {
+        let slot = self.slot(config).await;
+        let mut token = slot.lock().await;
+        if let Some(t) = token.as_ref()
+            && t.expires_at > Instant::now()
+        {
+            return Ok(t.access_token.clone());
+        }
+
+        let fresh = fetch(http, config, client_secret).await?;
+        let access_token = fresh.access_token.clone();
+        *token = Some(fresh);
+        Ok(access_token)
+    }
+
+    /// Drops the cached token after the upstream rejected it. Only the token
+    /// that was actually rejected is dropped, so concurrent rejections of the
+    /// same token trigger one refresh.
+    pub async fn invalidate(&self, config: &OAuth2Config, rejected: &str) {
+        let slot = self.slot(config).await;
+        let mut token = slot.lock().await;
+        if token.as_ref().is_some_and(|t| t.access_token == rejected) {
+            *token = None;
+        }
+    }
+}
+
+async fn fetch(http: &reqwest::Client, config: &OAuth2Config, client_secret: &str) -> Result<Token> {
+    let mut form = vec![("grant_type", "client_credentials")];
+    if let Some(scope) = &config.scope {
+        form.push(("scope", scope));
+    }
+    if let Some(audience) = &config.audience {
+        form.push(("audience", audience));
+    }
+    let mut request = http.post(&config.token_url);
+    match config.client_auth {
+        ClientAuth::Basic => request = request.basic_auth(&config.client_id, Some(client_secret)),
+        ClientAuth::Post => {
+            form.push(("client_id", &config.client_id));
+            form.push(("client_secret", client_secret));
+        },
+    }
+
+    let response = request.form(&form).send().await?;
+    if !response.status().is_success() {
+        bail!("token endpoint {} responded with {}", config.token_url, response.status());
+    }
+    let body: TokenResponse = response.json().await?;
+    let lifetime = Duration::from_secs(body.expires_in.unwrap_or(300));
+    Ok(Token {
+        access_token: body.access_token,
+        expires_at: Instant::now() + lifetime.saturating_sub(EXPIRY_MARGIN),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grants_for_other_audiences_do_not_share_tokens() {
+        let grant = |audience: &str| -> OAuth2Config {
+            serde_json::from_value(serde_json::json!({
+                "token_url": "https://auth.example.com/token",
+                "client_id": "grenze",
+                "client_secret": "secret",
+                "audience": audience,
+            }))
+            .unwrap()
+        };
+        assert_ne!(grant("https://billing.example.com").cache_key(), grant("https://orders.example.com").cache_key());
+        assert_eq!(grant("https://billing.example.com").cache_key(), grant("https://billing.example.com").cache_key());
+    }
+}
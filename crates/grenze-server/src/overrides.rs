@@ -0,0 +1,68 @@
+use anyhow::{bail, Result};
+use axum::http::HeaderMap;
+use serde::Deserialize;
+
+use crate::{api::admin::constant_time_eq, limiter::BucketLimit, tls::ClientIdentity};
+
+/// Per-request bucket sizes for trusted callers such as internal batch jobs.
+///
+/// Callers holding the override scope, granted by a token in `header` or by
+/// their client certificate identity, may set `capacity` and `leak_per_sec`
+/// on proxy requests. Requested sizes are clamped to the configured maximums.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OverridesConfig {
+    /// Header carrying a token granting the override scope.
+    #[serde(default = "default_header")]
+    pub header: String,
+    #[serde(default)]
+    pub tokens: Vec<String>,
+    /// Client certificate identities granted the override scope.
+    #[serde(default)]
+    pub identities: Vec<String>,
+    pub max_capacity: u32,
+    pub max_leak_per_sec: f64,
+}
+
+fn default_header() -> String {
+    "x-grenze-override-token".to_string()
+}
+
+impl OverridesConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.tokens.is_empty() && self.identities.is_empty() {
+            bail!("overrides must grant the scope to at least one token or identity");
+        }
+        if self.tokens.iter().any(String::is_empty) {
+            bail!("overrides tokens must not be empty");
+        }
+        if self.max_capacity == 0 {
+            bail!("overrides max_capacity must be at least 1");
+        }
+        if !self.max_leak_per_sec.is_finite() || self.max_leak_per_sec <= 0.0 {
+            bail!("overrides max_leak_per_sec must be positive");
+        }
+        Ok(())
+    }
+
+    /// Whether the caller holds the override scope.
+    pub fn permits(&self, identity: Option<&ClientIdentity>, headers: &HeaderMap) -> bool {
+        if identity.is_some_and(|id| self.identities.contains(&id.0)) {
+            return true;
+        }
+        let Some(token) = headers.get(&self.header).and_then(|v| v.to_str().ok()) else {
+            return false;
+        };
+        self.tokens.iter().any(|t| constant_time_eq(t.as_bytes(), token.as_bytes()))
+    }
+
+    /// Resizes `limit` to the requested size, clamped to the maximums.
+    pub fn apply(&self, limit: &mut BucketLimit, capacity: Option<u32>, leak_per_sec: Option<f64>) {
+        if let Some(capacity) = capacity {
+            limit.capacity = capacity.clamp(1, self.max_capacity);
+        }
+        if let Some(leak) = leak_per_sec {
+            limit.leak_per_sec = leak.min(self.max_leak_per_sec);
+        }
+    }
+}
@@ -0,0 +1,52 @@
+//! Recovery from panics in request handlers. A panic is answered with a
+//! structured 500 carrying the request's ID and logged with the ID and a
+//! backtrace, instead of the connection being torn down silently.
+
+use axum::{extract::Request, http::{HeaderName, HeaderValue, StatusCode}, middleware::Next, response::{IntoResponse, Response}, Json};
+use futures::FutureExt;
+use serde_json::json;
+use std::{backtrace::Backtrace, panic::AssertUnwindSafe};
+
+/// ID of a request, taken from the client or generated.
+const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+tokio::task_local! {
+    /// ID of the request handled by the current task.
+    static REQUEST_ID: String;
+}
+
+/// Logs panics with a backtrace, and with the ID of the request being handled
+/// if they happen in a handler.
+pub fn install_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let backtrace = Backtrace::force_capture();
+        match REQUEST_ID.try_with(Clone::clone) {
+            Ok(id) => println!("Panic while handling request {}: {}\n{}", id, info, backtrace),
+            Err(_) => println!("Panic: {}\n{}", info, backtrace),
+        }
+    }));
+}
+
+/// Runs the request, answering a panic with a 500 naming the request's ID,
+/// which is taken from its `X-Request-Id` header if it sends a usable one.
+pub async fn catch_panic(req: Request, next: Next) -> Response {
+    let id = req
+        .headers()
+        .get(X_REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+    match REQUEST_ID.scope(id.clone(), AssertUnwindSafe(next.run(req)).catch_unwind()).await {
+        Ok(response) => response,
+        Err(_) => {
+            let payload = Json(json!({
+                "error": "internal_error",
+                "message": "The request could not be handled",
+                "request_id": id
+            }));
+            let header = HeaderValue::from_str(&id).map(|id| [(X_REQUEST_ID, id)]).ok();
+            (StatusCode::INTERNAL_SERVER_ERROR, header, payload).into_response()
+        },
+    }
+}
@@ -0,0 +1,479 @@
+//! WASM plugins inspecting, mutating or vetoing proxied requests and responses.
+//!
+//! # Host ABI (version 1)
+//!
+//! A plugin is a core WASM module without imports exporting
+//!
+//! - `memory`
+//! - `grenze_alloc(len: i32) -> i32`, returning a buffer the host writes the
+//!   hook input into
+//! - optionally `grenze_on_request(ptr: i32, len: i32) -> i64`
+//! - optionally `grenze_on_response(ptr: i32, len: i32) -> i64`
+//!
+//! Hooks receive a JSON document and return `(ptr << 32) | len` of a JSON
+//! document in their memory, or `0` to continue unchanged. The output is one
+//! of `{"action": "continue"}`, `{"action": "modify", ...}` carrying the fields
+//! to replace, or `{"action": "reject", "status": 403, "body": {...}}`.
+//! Request hooks see `key`, `policy`, `method`, `url`, `headers`, `query` and
+//! `body`; response hooks see `key`, `policy`, `status`, `headers` and the
+//! base64 encoded `body`.
+//!
+//! Every invocation runs in a fresh instance, so plugins cannot keep state
+//! between requests. Its CPU time is bounded by a fuel budget and its memory
+//! by `max_memory_bytes`; `memory.grow` beyond the limit fails.
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use axum::{http::StatusCode, response::{IntoResponse, Response}, Json};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc}, time::{Duration, Instant, SystemTime}};
+use tokio::sync::RwLock;
+use wasmtime::{Config as EngineConfig, Engine, ExternType, InstancePre, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::{api::proxy::{MultiValue, ProxyRequest}, headers, logging, middleware::{self, DownstreamResponse, ProxyMiddleware}};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PluginConfig {
+    pub name: String,
+    pub path: String,
+    /// Policies the plugin runs for; empty runs it for every request.
+    #[serde(default)]
+    pub policies: Vec<String>,
+    /// Fuel available to each invocation, bounding the plugin's CPU time.
+    #[serde(default = "default_fuel")]
+    pub fuel: u64,
+    /// Linear memory available to each invocation, in bytes.
+    #[serde(default = "default_max_memory_bytes")]
+    pub max_memory_bytes: usize,
+    /// Reject requests when the plugin fails instead of ignoring the failure.
+    #[serde(default)]
+    pub fail_closed: bool,
+}
+
+fn default_fuel() -> u64 {
+    10_000_000
+}
+
+fn default_max_memory_bytes() -> usize {
+    16 << 20
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PluginsConfig {
+    pub plugins: Vec<PluginConfig>,
+    /// How often plugin files are checked for changes and reloaded; never if
+    /// unset.
+    pub reload_interval_secs: Option<u64>,
+}
+
+/// Request as seen and modified by request hooks.
+#[derive(Debug, Clone, Serialize)]
+pub struct PluginRequest {
+    pub key: String,
+    pub policy: String,
+    pub method: String,
+    pub url: String,
+    pub headers: HashMap<String, MultiValue>,
+    pub query: HashMap<String, MultiValue>,
+    pub body: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RequestPatch {
+    pub method: Option<String>,
+    pub url: Option<String>,
+    pub headers: Option<HashMap<String, MultiValue>>,
+    pub query: Option<HashMap<String, MultiValue>>,
+    pub body: Option<serde_json::Value>,
+}
+
+/// Response as seen and modified by response hooks.
+#[derive(Debug, Clone, Serialize)]
+pub struct PluginResponse {
+    pub key: String,
+    pub policy: String,
+    pub status: u16,
+    pub headers: HashMap<String, MultiValue>,
+    /// Base64 encoded body.
+    pub body: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ResponsePatch {
+    pub status: Option<u16>,
+    pub headers: Option<HashMap<String, MultiValue>>,
+    pub body: Option<String>,
+}
+
+/// Hook input that plugin patches are applied to.
+trait Patchable: Serialize {
+    type Patch: for<'de> Deserialize<'de>;
+
+    fn policy(&self) -> &str;
+    fn apply(&mut self, patch: Self::Patch);
+}
+
+impl Patchable for PluginRequest {
+    type Patch = RequestPatch;
+
+    fn policy(&self) -> &str {
+        &self.policy
+    }
+
+    fn apply(&mut self, patch: RequestPatch) {
+        if let Some(method) = patch.method {
+            self.method = method;
+        }
+        if let Some(url) = patch.url {
+            self.url = url;
+        }
+        if let Some(headers) = patch.headers {
+            self.headers = headers;
+        }
+        if let Some(query) = patch.query {
+            self.query = query;
+        }
+        if patch.body.is_some() {
+            self.body = patch.body;
+        }
+    }
+}
+
+impl Patchable for PluginResponse {
+    type Patch = ResponsePatch;
+
+    fn policy(&self) -> &str {
+        &self.policy
+    }
+
+    fn apply(&mut self, patch: ResponsePatch) {
+        if let Some(status) = patch.status {
+            self.status = status;
+        }
+        if let Some(headers) = patch.headers {
+            self.headers = headers;
+        }
+        if let Some(body) = patch.body {
+            self.body = body;
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Outcome<P> {
+    Continue,
+    Modify(P),
+    Reject {
+        #[serde(default = "default_reject_status")]
+        status: u16,
+        #[serde(default)]
+        body: serde_json::Value,
+    },
+}
+
+fn default_reject_status() -> u16 {
+    403
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Hook {
+    Request,
+    Response,
+}
+
+impl Hook {
+    fn export(self) -> &'static str {
+        match self {
+            Self::Request => "grenze_on_request",
+            Self::Response => "grenze_on_response",
+        }
+    }
+}
+
+/// Per-plugin counters, exposed through the admin API.
+#[derive(Debug, Default, Serialize)]
+pub struct PluginStats {
+    pub invocations: AtomicU64,
+    pub errors: AtomicU64,
+    pub rejections: AtomicU64,
+    pub modifications: AtomicU64,
+    pub total_micros: AtomicU64,
+}
+
+pub struct Plugin {
+    pub config: PluginConfig,
+    pub enabled: AtomicBool,
+    pub stats: PluginStats,
+    pre: InstancePre<StoreLimits>,
+    modified: Option<SystemTime>,
+    loaded_at: SystemTime,
+}
+
+impl Plugin {
+    pub fn loaded_at(&self) -> SystemTime {
+        self.loaded_at
+    }
+
+    fn applies(&self, policy: &str) -> bool {
+        self.enabled.load(Ordering::Relaxed) && (self.config.policies.is_empty() || self.config.policies.iter().any(|p| p == policy))
+    }
+
+    fn has(&self, hook: Hook) -> bool {
+        self.pre.module().get_export(hook.export()).is_some()
+    }
+
+    /// Runs a hook on a blocking thread. Returns `Ok(None)` if the plugin does
+    /// not implement it.
+    async fn invoke<P: for<'de> Deserialize<'de>>(self: &Arc<Self>, hook: Hook, input: Vec<u8>) -> Result<Option<Outcome<P>>> {
+        if !self.has(hook) {
+            return Ok(None);
+        }
+        let plugin = self.clone();
+        let started = Instant::now();
+        let output = tokio::task::spawn_blocking(move || plugin.call(hook, &input)).await?;
+        self.stats.invocations.fetch_add(1, Ordering::Relaxed);
+        self.stats.total_micros.fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+        let outcome = output.and_then(|out| match out {
+            None => Ok(Outcome::Continue),
+            Some(bytes) => serde_json::from_slice(&bytes).context("invalid plugin output"),
+        });
+        match &outcome {
+            Ok(Outcome::Modify(_)) => self.stats.modifications.fetch_add(1, Ordering::Relaxed),
+            Ok(Outcome::Reject { .. }) => self.stats.rejections.fetch_add(1, Ordering::Relaxed),
+            Ok(Outcome::Continue) => 0,
+            Err(_) => self.stats.errors.fetch_add(1, Ordering::Relaxed),
+        };
+        outcome.map(Some)
+    }
+
+    fn call(&self, hook: Hook, input: &[u8]) -> Result<Option<Vec<u8>>> {
+        let limits = StoreLimitsBuilder::new().memory_size(self.config.max_memory_bytes).instances(1).memories(1).build();
+        let mut store = Store::new(self.pre.module().engine(), limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(self.config.fuel)?;
+        let instance = self.pre.instantiate(&mut store)?;
+        let memory = instance.get_memory(&mut store, "memory").ok_or_else(|| anyhow!("plugin exports no memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "grenze_alloc")?;
+        let func = instance.get_typed_func::<(i32, i32), i64>(&mut store, hook.export())?;
+
+        let len = i32::try_from(input.len())?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, ptr as u32 as usize, input)?;
+        let packed = func.call(&mut store, (ptr, len))? as u64;
+        if packed == 0 {
+            return Ok(None);
+        }
+        let (out_ptr, out_len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        let mut out = vec![0; out_len];
+        memory.read(&store, out_ptr, &mut out)?;
+        Ok(Some(out))
+    }
+}
+
+/// Loaded plugins in configuration order.
+pub struct PluginManager {
+    engine: Engine,
+    plugins: RwLock<Vec<Arc<Plugin>>>,
+}
+
+/// Plugin veto, returned to the client as is.
+pub struct Rejection {
+    pub plugin: String,
+    pub status: u16,
+    pub body: serde_json::Value,
+}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        println!("Plugin {} rejected the request", self.plugin);
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::FORBIDDEN);
+        (status, Json(self.body)).into_response()
+    }
+}
+
+impl PluginManager {
+    pub fn new(config: &PluginsConfig) -> Result<Self> {
+        let mut engine_config = EngineConfig::new();
+        engine_config.consume_fuel(true);
+        let engine = Engine::new(&engine_config)?;
+        let plugins = config.plugins.iter().map(|c| load(&engine, c).map(Arc::new)).collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            engine,
+            plugins: RwLock::new(plugins),
+        })
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.plugins.read().await.is_empty()
+    }
+
+    pub async fn list(&self) -> Vec<Arc<Plugin>> {
+        self.plugins.read().await.clone()
+    }
+
+    pub async fn set_enabled(&self, name: &str, enabled: bool) -> bool {
+        let plugins = self.plugins.read().await;
+        match plugins.iter().find(|p| p.config.name == name) {
+            Some(p) => {
+                p.enabled.store(enabled, Ordering::Relaxed);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Recompiles plugins whose file changed since they were loaded. A plugin
+    /// failing to compile keeps its previous version.
+    pub async fn reload_changed(&self) {
+        let current = self.list().await;
+        let mut next = Vec::with_capacity(current.len());
+        for plugin in current {
+            let modified = std::fs::metadata(&plugin.config.path).and_then(|m| m.modified()).ok();
+            if modified == plugin.modified {
+                next.push(plugin);
+                continue;
+            }
+            match load(&self.engine, &plugin.config) {
+                Ok(reloaded) => {
+                    println!("Reloaded plugin {}", plugin.config.name);
+                    reloaded.enabled.store(plugin.enabled.load(Ordering::Relaxed), Ordering::Relaxed);
+                    next.push(Arc::new(reloaded));
+                },
+                Err(e) => {
+                    println!("Failed to reload plugin {}: {:#}", plugin.config.name, e);
+                    next.push(plugin);
+                },
+            }
+        }
+        *self.plugins.write().await = next;
+    }
+
+    pub fn spawn_reloader(self: &Arc<Self>, interval: Duration) {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                manager.reload_changed().await;
+            }
+        });
+    }
+
+    /// Runs the request hooks in order, each seeing the modifications of the
+    /// previous ones.
+    pub async fn on_request(&self, request: &mut PluginRequest) -> Result<(), Rejection> {
+        self.run(Hook::Request, request).await
+    }
+
+    /// Runs the response hooks in order, each seeing the modifications of the
+    /// previous ones.
+    pub async fn on_response(&self, response: &mut PluginResponse) -> Result<(), Rejection> {
+        self.run(Hook::Response, response).await
+    }
+
+    async fn run<T: Patchable>(&self, hook: Hook, target: &mut T) -> Result<(), Rejection> {
+        let policy = target.policy().to_string();
+        for plugin in self.list().await.iter().filter(|p| p.applies(&policy)) {
+            let input = serde_json::to_vec(target).unwrap_or_default();
+            match plugin.invoke::<T::Patch>(hook, input).await {
+                Ok(None | Some(Outcome::Continue)) => {},
+                Ok(Some(Outcome::Modify(patch))) => target.apply(patch),
+                Ok(Some(Outcome::Reject { status, body })) => {
+                    return Err(Rejection {
+                        plugin: plugin.config.name.clone(),
+                        status,
+                        body,
+                    });
+                },
+                Err(e) => {
+                    logging::warn("plugin", format_args!("Plugin {} failed: {:#}", plugin.config.name, e));
+                    if plugin.config.fail_closed {
+                        return Err(Rejection {
+                            plugin: plugin.config.name.clone(),
+                            status: 500,
+                            body: serde_json::json!({"error": "plugin_error", "message": "Plugin failed"}),
+                        });
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+fn load(engine: &Engine, config: &PluginConfig) -> Result<Plugin> {
+    let modified = std::fs::metadata(&config.path).and_then(|m| m.modified()).ok();
+    let module = Module::from_file(engine, &config.path).with_context(|| format!("failed to load plugin {}", config.name))?;
+    let (Some(_), Some(ExternType::Memory(memory))) = (module.get_export("grenze_alloc"), module.get_export("memory")) else {
+        bail!("plugin {} must export memory and grenze_alloc", config.name);
+    };
+    if memory.minimum().saturating_mul(memory.page_size()) > config.max_memory_bytes as u64 {
+        bail!("plugin {} needs more memory than its max_memory_bytes of {}", config.name, config.max_memory_bytes);
+    }
+    let pre = Linker::new(engine)
+        .instantiate_pre(&module)
+        .with_context(|| format!("plugin {} must not import host functions", config.name))?;
+    Ok(Plugin {
+        config: config.clone(),
+        enabled: AtomicBool::new(true),
+        stats: PluginStats::default(),
+        pre,
+        modified,
+        loaded_at: SystemTime::now(),
+    })
+}
+
+#[async_trait]
+impl ProxyMiddleware for PluginManager {
+    async fn on_request(&self, ctx: &middleware::Context<'_>, request: &mut ProxyRequest) -> Result<(), Response> {
+        if self.is_empty().await {
+            return Ok(());
+        }
+        let mut view = PluginRequest {
+            key: ctx.key.clone(),
+            policy: ctx.policy.name.clone(),
+            method: std::mem::take(&mut request.method),
+            url: std::mem::take(&mut request.url),
+            headers: std::mem::take(&mut request.headers),
+            query: std::mem::take(&mut request.query),
+            body: request.body.take(),
+        };
+        let result = PluginManager::on_request(self, &mut view).await;
+        request.method = view.method;
+        request.url = view.url;
+        request.headers = view.headers;
+        request.query = view.query;
+        request.body = view.body;
+        result.map_err(IntoResponse::into_response)
+    }
+
+    async fn on_response(&self, ctx: &middleware::Context<'_>, response: &mut DownstreamResponse) -> Result<(), Response> {
+        if self.is_empty().await {
+            return Ok(());
+        }
+        let mut view = PluginResponse {
+            key: ctx.key.clone(),
+            policy: ctx.policy.name.clone(),
+            status: response.status.as_u16(),
+            headers: headers::to_map(&response.headers),
+            body: STANDARD.encode(&response.body),
+        };
+        PluginManager::on_response(self, &mut view).await.map_err(IntoResponse::into_response)?;
+        response.status = StatusCode::from_u16(view.status).unwrap_or(response.status);
+        response.headers = headers::from_map(&view.headers);
+        if let Ok(body) = STANDARD.decode(&view.body)
+            && body != response.body
+        {
+            response.headers.remove(axum::http::header::CONTENT_LENGTH);
+            headers::weaken_etag(&mut response.headers);
+            response.body = body.into();
+            response.stream = None;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,479 @@
+use anyhow::{bail, Context, Result};
+use axum::http::StatusCode;
+use serde::Deserialize;
+use std::{collections::HashSet, sync::{Arc, RwLock}};
+
+use crate::{cookies::CookieJarConfig, credentials::DownstreamAuth, encoding::EncodingConfig, fairness::FairShare, headers::HeaderRule, hedge::HedgeConfig, limiter::BucketSize, mock::Mock, rejection::Rejection, schema::BodyValidation, transform::BodyTransform, upstream_tls::UpstreamTlsConfig};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    pub name: String,
+    /// Destination hosts the policy applies to. Entries may start with `*.` to
+    /// match subdomains; an empty list matches every destination.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// What a bucket is shared by.
+    #[serde(default)]
+    pub bucket: BucketScope,
+    /// Size of the policy's buckets; the limiter's if unset.
+    #[serde(default)]
+    pub capacity: Option<u32>,
+    #[serde(default)]
+    pub leak_per_sec: Option<f64>,
+    /// Limits each key exactly by a log of its requests within a sliding
+    /// window instead of a leaky bucket, for low limits like 10 password
+    /// resets a day.
+    #[serde(default)]
+    pub sliding_log: Option<SlidingLog>,
+    /// Windows each key's requests must all fit into, e.g. 10 a second, 300
+    /// a minute and 10k a day. The first sizes the key's own bucket, the
+    /// others add buckets of their own.
+    #[serde(default)]
+    pub windows: Vec<Window>,
+    /// Credentials injected into downstream requests, replacing any
+    /// `Authorization` header sent by the client.
+    #[serde(default)]
+    pub auth: Option<DownstreamAuth>,
+    /// Rewrites applied to the headers sent downstream, in order.
+    #[serde(default)]
+    pub headers: Vec<HeaderRule>,
+    /// Downstream response headers passed back to clients besides those
+    /// always passed back (`SAFE_RESPONSE_HEADERS` in the proxy: the content
+    /// and caching headers, `Trailer` and the gRPC status), with all their
+    /// values, e.g. `Set-Cookie` or `Link`.
+    #[serde(default)]
+    pub response_headers: Vec<String>,
+    /// Transformations applied to successful JSON responses, in order.
+    #[serde(default)]
+    pub transform: Vec<BodyTransform>,
+    /// Canned responses returned instead of calling the downstream for
+    /// matching requests; the first match wins.
+    #[serde(default)]
+    pub mocks: Vec<Mock>,
+    /// Further buckets requests must fit into besides their own, e.g. one
+    /// per organization or per upstream. A request is admitted into all of
+    /// them at once or into none.
+    #[serde(default)]
+    pub limits: Vec<Limit>,
+    /// Additional tokens charged for the size of downstream responses, for
+    /// upstreams billing by bandwidth.
+    #[serde(default)]
+    pub bandwidth: Option<BandwidthCharge>,
+    /// Fill added to the key's bucket when the downstream answers 429, so
+    /// clients already over the upstream's limit back off harder.
+    #[serde(default)]
+    pub penalty: Option<Penalty>,
+    /// Slow start for new keys, whose buckets start smaller and grow to
+    /// full size, protecting upstreams from cold clients bursting at once.
+    #[serde(default)]
+    pub warmup: Option<Warmup>,
+    /// Cookie jar per rate limit key, keeping the cookies the downstream
+    /// sets and sending them with the key's following requests.
+    #[serde(default)]
+    pub cookies: Option<CookieJarConfig>,
+    /// Compression requested from the downstream and whether responses are
+    /// decompressed before they are returned.
+    #[serde(default)]
+    pub encoding: Option<EncodingConfig>,
+    /// Hedging of slow requests to an alternate upstream.
+    #[serde(default)]
+    pub hedge: Option<HedgeConfig>,
+    /// What clients get of the bodies of downstream error responses.
+    #[serde(default)]
+    pub error_bodies: ErrorBodies,
+    /// Response to rate limited requests instead of the default JSON error.
+    #[serde(default)]
+    pub rejection: Option<Rejection>,
+    /// Content types and body schema requests must match before they take
+    /// a token.
+    #[serde(default)]
+    pub validation: Option<BodyValidation>,
+    /// TLS settings of the connections to the policy's upstreams.
+    #[serde(default)]
+    pub upstream_tls: Option<UpstreamTlsConfig>,
+    /// Client profile requests under the policy are sent with, instead of
+    /// the one matching their destination.
+    #[serde(default)]
+    pub client_profile: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BucketScope {
+    /// One bucket per rate limit key.
+    #[default]
+    Key,
+    /// One bucket per rate limit key and destination host, so calls to
+    /// different upstreams draw from independent budgets.
+    KeyAndHost,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limit {
+    pub scope: LimitScope,
+    /// Bucket size; the limiter's if unset.
+    #[serde(default)]
+    pub capacity: Option<u32>,
+    #[serde(default)]
+    pub leak_per_sec: Option<f64>,
+    /// Holds every key to an equal share of the bucket while it is
+    /// contended.
+    #[serde(default)]
+    pub fair: Option<FairShare>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum LimitScope {
+    /// One bucket per rate limit key prefix up to the first `delimiter`, e.g.
+    /// the organization of `acme:alice`, or per key without one.
+    KeyPrefix { delimiter: String },
+    /// One bucket per destination host; requests without one are exempt.
+    Host,
+    /// One bucket shared by all requests under the policy.
+    Global,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BandwidthCharge {
+    /// Response bytes per token, e.g. 102400 for one token per 100KB.
+    pub bytes_per_token: u64,
+}
+
+impl BandwidthCharge {
+    pub fn validate(&self) -> Result<()> {
+        if self.bytes_per_token == 0 {
+            bail!("bandwidth bytes_per_token must be positive");
+        }
+        Ok(())
+    }
+
+    /// Tokens charged for a response of `bytes`, including fractions.
+    pub fn tokens(&self, bytes: usize) -> f64 {
+        bytes as f64 / self.bytes_per_token as f64
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Penalty {
+    /// Fill added per downstream 429 as a multiple of the bucket's capacity;
+    /// 1 fills it, pausing the key until it has leaked out.
+    pub multiplier: f64,
+}
+
+impl Penalty {
+    pub fn validate(&self) -> Result<()> {
+        if self.multiplier <= 0.0 || !self.multiplier.is_finite() {
+            bail!("penalty multiplier must be positive");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SlidingLog {
+    /// Requests admitted per window; a key's own size overrides it.
+    pub limit: u32,
+    pub window_secs: u64,
+}
+
+impl SlidingLog {
+    pub fn validate(&self) -> Result<()> {
+        if self.limit == 0 {
+            bail!("sliding_log limit must be positive");
+        }
+        if self.window_secs == 0 {
+            bail!("sliding_log window_secs must be positive");
+        }
+        Ok(())
+    }
+}
+
+/// Requests admitted per window, approximated by a leaky bucket holding
+/// `limit` and leaking it over the window.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Window {
+    pub limit: u32,
+    pub window_secs: u64,
+}
+
+impl Window {
+    pub fn validate(&self) -> Result<()> {
+        if self.limit == 0 {
+            bail!("window limit must be positive");
+        }
+        if self.window_secs == 0 {
+            bail!("window window_secs must be positive");
+        }
+        Ok(())
+    }
+
+    pub fn leak_per_sec(&self) -> f64 {
+        self.limit as f64 / self.window_secs as f64
+    }
+}
+
+/// Handling of downstream error bodies by status class.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ErrorBodies {
+    /// Bodies of 4xx responses.
+    pub client: ErrorBody,
+    /// Bodies of 5xx responses.
+    pub server: ErrorBody,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorBody {
+    /// Returned verbatim.
+    #[default]
+    Passthrough,
+    /// Returned inside grenze's error envelope.
+    Wrap,
+    /// Replaced by grenze's error envelope, so no upstream details leak.
+    Sanitize,
+}
+
+impl ErrorBodies {
+    /// Handling of the body of a response with `status`.
+    pub fn get(&self, status: StatusCode) -> ErrorBody {
+        if status.is_client_error() {
+            self.client
+        } else if status.is_server_error() {
+            self.server
+        } else {
+            ErrorBody::Passthrough
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Warmup {
+    /// Share of the capacity and leak rate a new key starts with.
+    pub initial_fraction: f64,
+    /// Time over which a key ramps up to its full capacity and rate.
+    pub window_secs: u64,
+    /// Keys idle for this long count as new again.
+    #[serde(default = "default_forget_after_secs")]
+    pub forget_after_secs: u64,
+}
+
+fn default_forget_after_secs() -> u64 {
+    86_400
+}
+
+impl Warmup {
+    pub fn validate(&self) -> Result<()> {
+        if !(self.initial_fraction > 0.0 && self.initial_fraction <= 1.0) {
+            bail!("warmup initial_fraction must be in (0, 1]");
+        }
+        if self.window_secs == 0 {
+            bail!("warmup window_secs must be positive");
+        }
+        if self.forget_after_secs == 0 {
+            bail!("warmup forget_after_secs must be positive");
+        }
+        Ok(())
+    }
+
+    /// Share of its capacity and rate a key first seen `age_ms` ago gets.
+    pub fn factor(&self, age_ms: i64) -> f64 {
+        let progress = (age_ms.max(0) as f64 / (self.window_secs as f64 * 1000.0)).min(1.0);
+        self.initial_fraction + (1.0 - self.initial_fraction) * progress
+    }
+}
+
+impl Limit {
+    pub fn validate(&self) -> Result<()> {
+        BucketSize::validate(self.capacity, self.leak_per_sec).context("limit")?;
+        if let LimitScope::KeyPrefix { delimiter } = &self.scope
+            && delimiter.is_empty()
+        {
+            bail!("limit delimiter must not be empty");
+        }
+        if let Some(fair) = &self.fair {
+            fair.validate()?;
+        }
+        Ok(())
+    }
+}
+
+impl Policy {
+    fn fallback() -> Self {
+        Self {
+            name: "default".to_string(),
+            hosts: Vec::new(),
+            bucket: BucketScope::Key,
+            capacity: None,
+            leak_per_sec: None,
+            sliding_log: None,
+            windows: Vec::new(),
+            auth: None,
+            headers: Vec::new(),
+            response_headers: Vec::new(),
+            transform: Vec::new(),
+            mocks: Vec::new(),
+            limits: Vec::new(),
+            bandwidth: None,
+            penalty: None,
+            warmup: None,
+            cookies: None,
+            encoding: None,
+            hedge: None,
+            error_bodies: ErrorBodies::default(),
+            rejection: None,
+            validation: None,
+            upstream_tls: None,
+            client_profile: None,
+        }
+    }
+
+    pub fn matches(&self, host: Option<&str>) -> bool {
+        if self.hosts.is_empty() {
+            return true;
+        }
+        let Some(host) = host else {
+            return false;
+        };
+        self.hosts.iter().any(|pattern| host_matches(pattern, host))
+    }
+
+    /// Name of the bucket a request with the given key and destination host
+    /// draws from under this policy, `{policy}#key:{key}`. Keys are free to
+    /// contain `#`, but policy names are not, so no key can name the bucket of
+    /// another policy or one of the policy's limits.
+    pub fn bucket_key(&self, key: &str, host: Option<&str>) -> String {
+        match (self.bucket, host) {
+            (BucketScope::KeyAndHost, Some(host)) => format!("{}#key:{}@{}", self.name, key, host),
+            _ => format!("{}#key:{}", self.name, key),
+        }
+    }
+
+    /// Buckets of the policy's limits a request with the given key and
+    /// destination host draws from. They are named after the policy, so
+    /// policies do not share them.
+    pub fn limit_buckets(&self, key: &str, host: Option<&str>) -> Vec<(String, &Limit)> {
+        self.limits
+            .iter()
+            .filter_map(|limit| {
+                let bucket = match (&limit.scope, host) {
+                    (LimitScope::KeyPrefix { delimiter }, _) => {
+                        let prefix = key.split_once(delimiter.as_str()).map_or(key, |(prefix, _)| prefix);
+                        format!("{}#prefix:{}", self.name, prefix)
+                    },
+                    (LimitScope::Host, Some(host)) => format!("{}#host:{}", self.name, host),
+                    (LimitScope::Host, None) => return None,
+                    (LimitScope::Global, _) => format!("{}#global", self.name),
+                };
+                Some((bucket, limit))
+            })
+            .collect()
+    }
+}
+
+impl Policy {
+    /// Whether `bucket` is shared by keys under the policy's limits, or a
+    /// key's fair share of one.
+    pub fn shares(&self, bucket: &str) -> bool {
+        bucket.strip_prefix(self.name.as_str()).is_some_and(|rest| ["#prefix:", "#host:", "#global"].iter().any(|scope| rest.starts_with(scope)))
+    }
+}
+
+/// Host of `url` including an explicit port, as used by
+/// [`BucketScope::KeyAndHost`].
+pub fn authority(url: &reqwest::Url) -> Option<String> {
+    url.host_str().map(|h| match url.port() {
+        Some(port) => format!("{}:{}", h, port),
+        None => h.to_string(),
+    })
+}
+
+/// Configured policies in evaluation order. The first policy matching the
+/// destination wins; requests matching none use a built-in default policy.
+#[derive(Debug, Clone)]
+pub struct PolicySet {
+    policies: Vec<Policy>,
+    fallback: Policy,
+}
+
+impl PolicySet {
+    pub fn new(policies: Vec<Policy>) -> Result<Self> {
+        let mut names = HashSet::new();
+        for policy in &policies {
+            if !names.insert(policy.name.as_str()) {
+                bail!("duplicate policy name '{}'", policy.name);
+            }
+        }
+        Ok(Self {
+            policies,
+            fallback: Policy::fallback(),
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Policy> {
+        self.policies.iter().chain([&self.fallback]).find(|p| p.name == name)
+    }
+
+    pub fn resolve(&self, host: Option<&str>) -> &Policy {
+        self.policies.iter().find(|p| p.matches(host)).unwrap_or(&self.fallback)
+    }
+}
+
+/// Whether `host` matches `pattern`, a host name or `*.` and the domain of
+/// its subdomains.
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => host.len() > suffix.len() + 1 && host.ends_with(suffix) && host[..host.len() - suffix.len()].ends_with('.'),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+/// The active [`PolicySet`], replaced as a whole when policies change at
+/// runtime. Requests keep the set they started with.
+pub struct Policies {
+    current: RwLock<Arc<PolicySet>>,
+}
+
+impl Policies {
+    pub fn new(set: PolicySet) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(set)),
+        }
+    }
+
+    pub fn load(&self) -> Arc<PolicySet> {
+        self.current.read().expect("policy lock poisoned").clone()
+    }
+
+    pub fn replace(&self, set: PolicySet) {
+        *self.current.write().expect("policy lock poisoned") = Arc::new(set);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(json: serde_json::Value) -> Policy {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn keys_cannot_name_other_buckets() {
+        let api = policy(serde_json::json!({"name": "api", "limits": [{"scope": {"type": "global"}}]}));
+        let batch = policy(serde_json::json!({"name": "batch"}));
+        assert_ne!(api.bucket_key("alice", None), batch.bucket_key("alice", None));
+        let (global, _) = &api.limit_buckets("alice", None)[0];
+        assert_ne!(&api.bucket_key("api#global", None), global);
+        assert!(api.shares(global));
+        assert!(!api.shares(&api.bucket_key("global", None)));
+    }
+}
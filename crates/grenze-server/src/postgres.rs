@@ -0,0 +1,309 @@
+//! Bucket store over PostgreSQL, for teams whose only durable store is
+//! Postgres. Admissions are single UPSERT statements, so concurrent instances
+//! serialize on the bucket's row without advisory locks. Daily usage is
+//! recorded per bucket and can be capped with quotas.
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use rustls::{pki_types::{pem::PemObject, CertificateDer}, ClientConfig, RootCertStore};
+use serde::Deserialize;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+use tokio_postgres::Client;
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::{limiter::{bucket_ttl_ms, Admission, BucketState, LimiterStore, BUCKET_TTL_GRACE_MS, MAX_BUCKET_TTL_MS}, logging};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PostgresConfig {
+    /// Connection string, e.g. `postgres://grenze@db/grenze?sslmode=require`;
+    /// read from `DATABASE_URL` if unset.
+    pub url: Option<String>,
+    /// PEM file with the CA certificates trusted for TLS instead of the
+    /// system's.
+    pub ca_file: Option<String>,
+    /// Prefix of grenze's table names.
+    pub table_prefix: String,
+    /// Creates the tables at startup if they do not exist.
+    pub create_tables: bool,
+    /// How often expired buckets and markers are deleted.
+    pub gc_interval_secs: u64,
+    /// Days of daily usage kept.
+    pub usage_retention_days: u32,
+}
+
+impl Default for PostgresConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            ca_file: None,
+            table_prefix: "grenze_".to_string(),
+            create_tables: true,
+            gc_interval_secs: 60,
+            usage_retention_days: 30,
+        }
+    }
+}
+
+impl PostgresConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.table_prefix.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_') {
+            bail!("postgres table_prefix may only contain lowercase letters, digits and underscores");
+        }
+        if let Some(ca_file) = &self.ca_file {
+            std::fs::read(ca_file).with_context(|| format!("failed to read postgres ca_file {}", ca_file))?;
+        }
+        Ok(())
+    }
+
+    fn tls(&self) -> Result<MakeRustlsConnect> {
+        let mut roots = RootCertStore::empty();
+        match &self.ca_file {
+            Some(path) => {
+                for cert in CertificateDer::pem_file_iter(path).with_context(|| format!("failed to read postgres ca_file {}", path))? {
+                    roots.add(cert?)?;
+                }
+            },
+            None => {
+                roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+            },
+        }
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(MakeRustlsConnect::new(config))
+    }
+}
+
+/// Buckets shared by all instances through Postgres tables.
+pub struct PostgresStore {
+    config: PostgresConfig,
+    url: String,
+    tls: MakeRustlsConnect,
+    client: Mutex<Arc<Client>>,
+}
+
+impl PostgresStore {
+    pub async fn connect(config: &PostgresConfig) -> Result<Self> {
+        let url = match &config.url {
+            Some(url) => url.clone(),
+            None => std::env::var("DATABASE_URL").context("DATABASE_URL must be set for the postgres store")?,
+        };
+        let tls = config.tls()?;
+        let client = open(&url, tls.clone()).await?;
+        let store = Self {
+            config: config.clone(),
+            url,
+            tls,
+            client: Mutex::new(Arc::new(client)),
+        };
+        if config.create_tables {
+            store.client().await?.batch_execute(&store.schema()).await.context("failed to create the postgres tables")?;
+        }
+        Ok(store)
+    }
+
+    /// The connection, reopened if it was closed.
+    async fn client(&self) -> Result<Arc<Client>> {
+        let mut client = self.client.lock().await;
+        if client.is_closed() {
+            *client = Arc::new(open(&self.url, self.tls.clone()).await?);
+        }
+        Ok(client.clone())
+    }
+
+    fn table(&self, name: &str) -> String {
+        format!("{}{}", self.config.table_prefix, name)
+    }
+
+    fn schema(&self) -> String {
+        format!(
+            "CREATE TABLE IF NOT EXISTS {buckets} (
+                bucket TEXT PRIMARY KEY,
+                fill DOUBLE PRECISION NOT NULL,
+                last_ms BIGINT NOT NULL,
+                admitted BOOLEAN NOT NULL,
+                expires_at TIMESTAMPTZ NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS {buckets}_expires_at ON {buckets} (expires_at);
+            CREATE TABLE IF NOT EXISTS {markers} (
+                key TEXT PRIMARY KEY,
+                expires_at TIMESTAMPTZ NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS {usage} (
+                bucket TEXT NOT NULL,
+                day DATE NOT NULL,
+                allowed BIGINT NOT NULL DEFAULT 0,
+                limited BIGINT NOT NULL DEFAULT 0,
+                PRIMARY KEY (bucket, day)
+            );
+            CREATE TABLE IF NOT EXISTS {quotas} (
+                bucket TEXT PRIMARY KEY,
+                daily_limit BIGINT NOT NULL
+            );",
+            buckets = self.table("buckets"),
+            markers = self.table("markers"),
+            usage = self.table("usage"),
+            quotas = self.table("quotas"),
+        )
+    }
+
+    /// Deletes expired buckets and markers, and old usage, every
+    /// `gc_interval_secs` in the background.
+    pub fn spawn_gc(self: &Arc<Self>) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(store.config.gc_interval_secs.max(1)));
+            loop {
+                ticker.tick().await;
+                if let Err(e) = store.collect().await {
+                    logging::warn("postgres", format_args!("Failed to delete expired postgres rows: {:#}", e));
+                }
+            }
+        });
+    }
+
+    async fn collect(&self) -> Result<()> {
+        let client = self.client().await?;
+        client.execute(&format!("DELETE FROM {} WHERE expires_at < now()", self.table("buckets")), &[]).await?;
+        client.execute(&format!("DELETE FROM {} WHERE expires_at < now()", self.table("markers")), &[]).await?;
+        let retention = self.config.usage_retention_days as i32;
+        client
+            .execute(&format!("DELETE FROM {} WHERE day < current_date - $1::int", self.table("usage")), &[&retention])
+            .await?;
+        Ok(())
+    }
+}
+
+async fn open(url: &str, tls: MakeRustlsConnect) -> Result<Client> {
+    let (client, connection) = tokio_postgres::connect(url, tls).await.context("failed to connect to postgres")?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            logging::warn("postgres", format_args!("Postgres connection failed: {}", e));
+        }
+    });
+    Ok(client)
+}
+
+#[async_trait]
+impl LimiterStore for PostgresStore {
+    async fn allow(&self, bucket: &str, cost: f64, capacity: u32, leak_per_sec: f64, now_ms: i64) -> Result<Admission> {
+        // Parameters: $1 bucket, $2 capacity, $3 leak per second, $4 now in
+        // ms, $5 cost. Within the update, the bucket's columns still hold the
+        // previous state.
+        let level = "GREATEST(b.fill - GREATEST($4::bigint - b.last_ms, 0)::float8 / 1000 * $3::float8, 0)";
+        let inserted = "CASE WHEN quota.open AND $5::float8 <= $2::float8 THEN $5::float8 ELSE 0 END";
+        let updated = format!("{level} + CASE WHEN (SELECT open FROM quota) AND {level} + $5::float8 <= $2::float8 THEN $5::float8 ELSE 0 END");
+        // Like `bucket_ttl_ms`, from the bucket's new fill
+        let expiry = |fill: &str| {
+            format!("to_timestamp(($4::bigint + LEAST(CEIL({fill} * 1000 / $3::float8) + {BUCKET_TTL_GRACE_MS}, {MAX_BUCKET_TTL_MS})) / 1000.0)")
+        };
+        let sql = format!(
+            "WITH quota AS (
+                SELECT COALESCE((
+                    SELECT q.daily_limit > COALESCE(u.allowed, 0)
+                    FROM {quotas} q
+                    LEFT JOIN {usage} u ON u.bucket = q.bucket AND u.day = (to_timestamp($4::bigint / 1000.0) AT TIME ZONE 'UTC')::date
+                    WHERE q.bucket = $1::text
+                ), true) AS open
+            ),
+            admission AS (
+                INSERT INTO {buckets} AS b (bucket, fill, last_ms, admitted, expires_at)
+                SELECT $1::text, {inserted}, $4::bigint, quota.open AND $5::float8 <= $2::float8, {inserted_expiry}
+                FROM quota
+                ON CONFLICT (bucket) DO UPDATE SET
+                    admitted = (SELECT open FROM quota) AND {level} + $5::float8 <= $2::float8,
+                    fill = {updated},
+                    last_ms = $4::bigint,
+                    expires_at = {updated_expiry}
+                RETURNING fill, admitted
+            ),
+            recorded AS (
+                INSERT INTO {usage} (bucket, day, allowed, limited)
+                SELECT $1::text, (to_timestamp($4::bigint / 1000.0) AT TIME ZONE 'UTC')::date, admitted::int, (NOT admitted)::int
+                FROM admission
+                ON CONFLICT (bucket, day) DO UPDATE SET
+                    allowed = {usage}.allowed + EXCLUDED.allowed,
+                    limited = {usage}.limited + EXCLUDED.limited
+            )
+            SELECT fill, admitted FROM admission",
+            buckets = self.table("buckets"),
+            usage = self.table("usage"),
+            quotas = self.table("quotas"),
+            level = level,
+            inserted_expiry = expiry(inserted),
+            updated_expiry = expiry(&updated),
+        );
+        let row = self
+            .client()
+            .await?
+            .query_one(&sql, &[&bucket, &(capacity as f64), &leak_per_sec, &now_ms, &cost])
+            .await?;
+        Ok(Admission {
+            allowed: row.get(1),
+            fill: row.get(0),
+        })
+    }
+
+    async fn remember(&self, key: &str, ttl_secs: u64) -> Result<bool> {
+        let sql = format!(
+            "INSERT INTO {markers} AS m (key, expires_at) VALUES ($1::text, now() + make_interval(secs => $2::float8))
+            ON CONFLICT (key) DO UPDATE SET expires_at = EXCLUDED.expires_at WHERE m.expires_at < now()
+            RETURNING key",
+            markers = self.table("markers"),
+        );
+        Ok(self.client().await?.query_opt(&sql, &[&key, &(ttl_secs.max(1) as f64)]).await?.is_some())
+    }
+
+    async fn forget(&self, key: &str) {
+        if let Ok(client) = self.client().await {
+            let _ = client.execute(&format!("DELETE FROM {} WHERE key = $1", self.table("markers")), &[&key]).await;
+        }
+    }
+
+    async fn evict(&self, bucket: &str) {
+        if let Ok(client) = self.client().await {
+            let _ = client.execute(&format!("DELETE FROM {} WHERE bucket = $1", self.table("buckets")), &[&bucket]).await;
+        }
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.client().await?.simple_query("SELECT 1").await?;
+        Ok(())
+    }
+
+    async fn export(&self) -> Result<Vec<BucketState>> {
+        let sql = format!("SELECT bucket, fill, last_ms FROM {} WHERE expires_at > now()", self.table("buckets"));
+        let rows = self.client().await?.query(&sql, &[]).await?;
+        Ok(rows
+            .iter()
+            .map(|row| BucketState {
+                bucket: row.get(0),
+                fill: row.get(1),
+                last_ms: row.get(2),
+            })
+            .collect())
+    }
+
+    async fn import(&self, buckets: &[BucketState], _capacity: u32, leak_per_sec: f64) -> Result<()> {
+        let sql = format!(
+            "INSERT INTO {} (bucket, fill, last_ms, admitted, expires_at)
+            SELECT bucket, fill, last_ms, true, now() + make_interval(secs => ttl_secs)
+            FROM unnest($1::text[], $2::float8[], $3::bigint[], $4::float8[]) AS t(bucket, fill, last_ms, ttl_secs)
+            ON CONFLICT (bucket) DO UPDATE SET fill = EXCLUDED.fill, last_ms = EXCLUDED.last_ms, expires_at = EXCLUDED.expires_at",
+            self.table("buckets"),
+        );
+        let client = self.client().await?;
+        for chunk in buckets.chunks(500) {
+            let names: Vec<&str> = chunk.iter().map(|b| b.bucket.as_str()).collect();
+            let fills: Vec<f64> = chunk.iter().map(|b| b.fill).collect();
+            let stamps: Vec<i64> = chunk.iter().map(|b| b.last_ms).collect();
+            let ttls: Vec<f64> = chunk.iter().map(|b| bucket_ttl_ms(b.fill, leak_per_sec) as f64 / 1000.0).collect();
+            client.execute(&sql, &[&names, &fills, &stamps, &ttls]).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,417 @@
+//! Opt-in recording of proxied requests and their responses to a Redis
+//! stream or a file, so they can be replayed through `/admin/replay` for
+//! debugging or to reproduce load, or exported as HAR. Credentials and other
+//! sensitive values are redacted before they are written, and bodies are
+//! only kept if asked for.
+
+use anyhow::{bail, Context, Result};
+use axum::http::header::CONTENT_ENCODING;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::HashMap, sync::atomic::{AtomicU64, Ordering}, time::Duration};
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, sync::mpsc};
+use utoipa::ToSchema;
+
+use crate::{api::proxy::{MultiValue, ProxyRequest}, logging, middleware::DownstreamResponse};
+
+/// Value written instead of redacted ones. Redacted headers are left out of
+/// replayed requests.
+pub const REDACTED: &str = "[redacted]";
+/// Recordings waiting to be written; further ones are dropped while the sink
+/// falls behind.
+const RECORDING_BUFFER: usize = 1024;
+/// Stream entries read per round trip while looking for recordings.
+const PAGE_SIZE: usize = 500;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordingConfig {
+    pub sink: RecordingSink,
+    /// Share of requests recorded, between 0 and 1.
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    /// Policies whose requests are recorded; all if empty.
+    #[serde(default)]
+    pub policies: Vec<String>,
+    /// Headers whose values are redacted, matched case-insensitively.
+    #[serde(default = "default_redact_headers")]
+    pub redact_headers: Vec<String>,
+    /// Query parameters whose values are redacted.
+    #[serde(default = "default_redact_query")]
+    pub redact_query: Vec<String>,
+    /// Keeps JSON request bodies and response bodies up to `max_body_bytes`,
+    /// with the values of `redact_fields` in JSON redacted at any depth.
+    #[serde(default)]
+    pub record_bodies: bool,
+    #[serde(default = "default_redact_fields")]
+    pub redact_fields: Vec<String>,
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum RecordingSink {
+    /// Stream in the Redis holding the buckets, trimmed to about `max_len`
+    /// entries.
+    Redis {
+        #[serde(default = "default_stream")]
+        stream: String,
+        #[serde(default = "default_max_len")]
+        max_len: u64,
+    },
+    /// File the recordings are appended to as JSON lines.
+    File { path: String },
+}
+
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+fn default_redact_headers() -> Vec<String> {
+    ["authorization", "proxy-authorization", "cookie", "set-cookie", "x-api-key"].map(String::from).to_vec()
+}
+
+fn default_redact_query() -> Vec<String> {
+    ["access_token", "api_key", "token"].map(String::from).to_vec()
+}
+
+fn default_redact_fields() -> Vec<String> {
+    ["password", "secret", "token"].map(String::from).to_vec()
+}
+
+fn default_max_body_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_stream() -> String {
+    "grenze:recordings".to_string()
+}
+
+fn default_max_len() -> u64 {
+    100_000
+}
+
+impl RecordingConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !(0.0..=1.0).contains(&self.sample_rate) {
+            bail!("recording sample_rate must be between 0 and 1");
+        }
+        match &self.sink {
+            RecordingSink::Redis { stream, max_len } => {
+                if stream.is_empty() || *max_len == 0 {
+                    bail!("recording stream must be named and max_len positive");
+                }
+            },
+            RecordingSink::File { path } => {
+                if path.is_empty() {
+                    bail!("recording file path must not be empty");
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+/// A proxied request as recorded.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Recording {
+    /// ID of the stream entry, or the time and sequence number of a line in
+    /// the file.
+    #[serde(default)]
+    pub id: String,
+    pub at_ms: i64,
+    pub key: String,
+    pub policy: String,
+    pub method: String,
+    pub url: String,
+    pub headers: HashMap<String, MultiValue>,
+    pub query: HashMap<String, MultiValue>,
+    #[schema(value_type = Option<Object>)]
+    pub body: Option<Value>,
+    /// Status the downstream answered, or grenze's if it could not be
+    /// reached.
+    pub status: u16,
+    pub latency_ms: u64,
+    /// The downstream's response, unless it could not be reached.
+    #[serde(default)]
+    pub response: Option<RecordedResponse>,
+}
+
+/// A downstream response as recorded.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct RecordedResponse {
+    /// Headers returned to the client.
+    pub headers: HashMap<String, MultiValue>,
+    pub body_size: u64,
+    /// Body, base64 encoded if it is not UTF-8; left out unless bodies are
+    /// recorded, and for streamed, encoded or oversized bodies.
+    #[serde(default)]
+    pub body: Option<String>,
+    #[serde(default)]
+    pub base64: bool,
+}
+
+/// Recordings to read, by ID or by time.
+pub struct Selection<'a> {
+    pub id: Option<&'a str>,
+    pub from_ms: i64,
+    pub to_ms: i64,
+    /// Only recordings of this rate limit key.
+    pub key: Option<&'a str>,
+    pub limit: usize,
+}
+
+impl Selection<'_> {
+    fn matches(&self, recording: &Recording) -> bool {
+        match self.id {
+            Some(id) => recording.id == id,
+            None => (self.from_ms..self.to_ms).contains(&recording.at_ms) && self.key.is_none_or(|k| k == recording.key),
+        }
+    }
+}
+
+pub struct Recorder {
+    config: RecordingConfig,
+    client: Option<redis::Client>,
+    sender: mpsc::Sender<Recording>,
+    /// Sequence numbers of the lines written to a file.
+    sequence: AtomicU64,
+}
+
+impl Recorder {
+    /// Starts writing recordings to the configured sink in the background.
+    /// Recording to Redis needs the `client` of the bucket store.
+    pub fn start(config: &RecordingConfig, client: Option<redis::Client>) -> Result<Self> {
+        if matches!(config.sink, RecordingSink::Redis { .. }) && client.is_none() {
+            bail!("recording to redis requires the redis store");
+        }
+        let (sender, receiver) = mpsc::channel(RECORDING_BUFFER);
+        tokio::spawn(write(config.sink.clone(), client.clone(), receiver));
+        Ok(Self {
+            config: config.clone(),
+            client,
+            sender,
+            sequence: AtomicU64::new(0),
+        })
+    }
+
+    /// Redacted copy of `req` under `policy` at `now_ms`, if it is sampled for
+    /// recording.
+    pub fn capture(&self, key: &str, policy: &str, req: &ProxyRequest, now_ms: i64) -> Option<Recording> {
+        if !self.config.policies.is_empty() && !self.config.policies.iter().any(|p| p == policy) {
+            return None;
+        }
+        if self.config.sample_rate < 1.0 && rand::random::<f64>() >= self.config.sample_rate {
+            return None;
+        }
+        let redact = |values: &HashMap<String, MultiValue>, names: &[String]| {
+            values
+                .iter()
+                .map(|(name, value)| match names.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+                    true => (name.clone(), MultiValue::One(REDACTED.to_string())),
+                    false => (name.clone(), value.clone()),
+                })
+                .collect()
+        };
+        let mut url = reqwest::Url::parse(&req.url).ok()?;
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .map(|(name, value)| match self.config.redact_query.iter().any(|n| n.eq_ignore_ascii_case(&name)) {
+                true => (name.into_owned(), REDACTED.to_string()),
+                false => (name.into_owned(), value.into_owned()),
+            })
+            .collect();
+        if !pairs.is_empty() {
+            url.query_pairs_mut().clear().extend_pairs(pairs);
+        }
+        Some(Recording {
+            id: String::new(),
+            at_ms: now_ms,
+            key: key.to_string(),
+            policy: policy.to_string(),
+            method: req.method.clone(),
+            url: url.to_string(),
+            headers: redact(&req.headers, &self.config.redact_headers),
+            query: redact(&req.query, &self.config.redact_query),
+            body: req.body.as_ref().filter(|_| self.config.record_bodies).map(|b| redact_fields(b, &self.config.redact_fields)),
+            status: 0,
+            latency_ms: 0,
+            response: None,
+        })
+    }
+
+    /// Queues a captured request, answered with `status` and the downstream's
+    /// `response` after `latency`, for writing.
+    pub fn record(&self, mut recording: Recording, status: u16, response: Option<&DownstreamResponse>, latency: Duration) {
+        recording.status = status;
+        recording.latency_ms = latency.as_millis() as u64;
+        recording.response = response.map(|r| self.capture_response(r));
+        if matches!(self.config.sink, RecordingSink::File { .. }) {
+            recording.id = format!("{}-{}", recording.at_ms, self.sequence.fetch_add(1, Ordering::Relaxed));
+        }
+        let _ = self.sender.try_send(recording);
+    }
+
+    /// Recordings picked by `selection`, oldest first.
+    pub async fn load(&self, selection: &Selection<'_>) -> Result<Vec<Recording>> {
+        match &self.config.sink {
+            RecordingSink::Redis { stream, .. } => {
+                let client = self.client.as_ref().context("recording to redis requires the redis store")?;
+                load_stream(client, stream, selection).await
+            },
+            RecordingSink::File { path } => load_file(path, selection).await,
+        }
+    }
+
+    fn capture_response(&self, response: &DownstreamResponse) -> RecordedResponse {
+        let mut headers: HashMap<String, MultiValue> = HashMap::new();
+        for (name, value) in &response.headers {
+            let value = match self.config.redact_headers.iter().any(|n| n.eq_ignore_ascii_case(name.as_str())) {
+                true => REDACTED.to_string(),
+                false => String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            };
+            match headers.get_mut(name.as_str()) {
+                Some(values) => values.push(value),
+                None => {
+                    headers.insert(name.to_string(), MultiValue::One(value));
+                },
+            }
+        }
+        let keep = self.config.record_bodies
+            && response.stream.is_none()
+            && !response.headers.contains_key(CONTENT_ENCODING)
+            && response.body.len() <= self.config.max_body_bytes;
+        let (body, base64) = match std::str::from_utf8(&response.body) {
+            _ if !keep => (None, false),
+            Ok(text) => match serde_json::from_str::<Value>(text) {
+                Ok(json) => (Some(redact_fields(&json, &self.config.redact_fields).to_string()), false),
+                Err(_) => (Some(text.to_string()), false),
+            },
+            Err(_) => (Some(STANDARD.encode(&response.body)), true),
+        };
+        RecordedResponse {
+            headers,
+            body_size: response.body.len() as u64,
+            body,
+            base64,
+        }
+    }
+}
+
+fn redact_fields(value: &Value, fields: &[String]) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| match fields.iter().any(|f| f.eq_ignore_ascii_case(k)) {
+                    true => (k.clone(), Value::String(REDACTED.to_string())),
+                    false => (k.clone(), redact_fields(v, fields)),
+                })
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.iter().map(|v| redact_fields(v, fields)).collect()),
+        other => other.clone(),
+    }
+}
+
+/// Writes queued recordings until the recorder is dropped.
+async fn write(sink: RecordingSink, client: Option<redis::Client>, mut receiver: mpsc::Receiver<Recording>) {
+    let mut conn = None;
+    let mut file = None;
+    while let Some(recording) = receiver.recv().await {
+        let Ok(line) = serde_json::to_string(&recording) else {
+            continue;
+        };
+        let result: Result<()> = async {
+            match (&sink, &client) {
+                (RecordingSink::Redis { stream, max_len }, Some(client)) => {
+                    if conn.is_none() {
+                        conn = Some(client.get_multiplexed_tokio_connection().await?);
+                    }
+                    let conn = conn.as_mut().expect("connection opened above");
+                    redis::cmd("XADD")
+                        .arg(stream)
+                        .arg("MAXLEN")
+                        .arg("~")
+                        .arg(*max_len)
+                        .arg("*")
+                        .arg("request")
+                        .arg(&line)
+                        .query_async::<()>(conn)
+                        .await?;
+                },
+                (RecordingSink::File { path }, _) => {
+                    if file.is_none() {
+                        file = Some(tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?);
+                    }
+                    let file = file.as_mut().expect("file opened above");
+                    file.write_all(format!("{}\n", line).as_bytes()).await?;
+                },
+                (RecordingSink::Redis { .. }, None) => {},
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(e) = result {
+            logging::warn("recording", format_args!("Failed to write recording of key {}: {:#}", recording.key, e));
+            conn = None;
+            file = None;
+        }
+    }
+}
+
+async fn load_stream(client: &redis::Client, stream: &str, selection: &Selection<'_>) -> Result<Vec<Recording>> {
+    let mut conn = client.get_multiplexed_tokio_connection().await?;
+    let (mut start, end) = match selection.id {
+        Some(id) => (id.to_string(), id.to_string()),
+        None => (selection.from_ms.to_string(), selection.to_ms.saturating_sub(1).to_string()),
+    };
+    let mut recordings = Vec::new();
+    loop {
+        let entries: Vec<(String, HashMap<String, String>)> = redis::cmd("XRANGE")
+            .arg(stream)
+            .arg(&start)
+            .arg(&end)
+            .arg("COUNT")
+            .arg(PAGE_SIZE)
+            .query_async(&mut conn)
+            .await
+            .context("failed to read recordings")?;
+        for (id, fields) in &entries {
+            let Some(recording) = fields.get("request").and_then(|r| serde_json::from_str::<Recording>(r).ok()) else {
+                continue;
+            };
+            let recording = Recording { id: id.clone(), ..recording };
+            // Entries are stamped when written, shortly after the request
+            if selection.id.is_some() || selection.key.is_none_or(|k| k == recording.key) {
+                recordings.push(recording);
+            }
+            if recordings.len() >= selection.limit {
+                return Ok(recordings);
+            }
+        }
+        match entries.last() {
+            Some((last, _)) if entries.len() == PAGE_SIZE => start = format!("({}", last),
+            _ => return Ok(recordings),
+        }
+    }
+}
+
+async fn load_file(path: &str, selection: &Selection<'_>) -> Result<Vec<Recording>> {
+    let file = tokio::fs::File::open(path).await.with_context(|| format!("failed to open recordings {}", path))?;
+    let mut lines = BufReader::new(file).lines();
+    let mut recordings = Vec::new();
+    while let Some(line) = lines.next_line().await? {
+        let Ok(recording) = serde_json::from_str::<Recording>(&line) else {
+            continue;
+        };
+        if selection.matches(&recording) {
+            recordings.push(recording);
+            if recordings.len() >= selection.limit {
+                break;
+            }
+        }
+    }
+    Ok(recordings)
+}
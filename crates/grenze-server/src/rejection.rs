@@ -0,0 +1,286 @@
+//! Causes of rejections, and custom responses to rate limited requests, so
+//! the errors end users see match the format of the product in front of
+//! grenze instead of grenze's default JSON. A rejection is configured per
+//! policy and per tenant, the tenant's taking precedence.
+
+use anyhow::{anyhow, bail, Result};
+use axum::{http::{header::{CONTENT_TYPE, LOCATION}, HeaderMap, HeaderName, HeaderValue, StatusCode}, response::{IntoResponse, Response}};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Header telling clients the cause of a rejection, as its `error` code.
+pub const X_REJECTION_REASON: HeaderName = HeaderName::from_static("x-rejection-reason");
+
+/// Cause of a rejection, sent as the `error` of the response and the
+/// `X-Rejection-Reason` header, logged and counted, so clients and dashboards
+/// can tell transient throttling from policy violations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Reason {
+    /// The key's own bucket, or one of its windows or its sliding log, is
+    /// full.
+    RateLimited,
+    /// A bucket the key shares with others under the policy's limits, e.g.
+    /// its organization's or the global one, is full.
+    LimitExceeded,
+    /// The allowance of the key's plan is used up until its period renews.
+    QuotaExceeded,
+    /// Too many requests are in flight.
+    Overloaded,
+    Banned,
+    /// The limiter's circuit breaker is open and requests fail closed.
+    CircuitOpen,
+    /// The limiter's store failed and requests fail closed.
+    StoreUnavailable,
+}
+
+impl Reason {
+    pub const ALL: [Reason; 7] = [
+        Reason::RateLimited,
+        Reason::LimitExceeded,
+        Reason::QuotaExceeded,
+        Reason::Overloaded,
+        Reason::Banned,
+        Reason::CircuitOpen,
+        Reason::StoreUnavailable,
+    ];
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Reason::RateLimited => "rate_limited",
+            Reason::LimitExceeded => "limit_exceeded",
+            Reason::QuotaExceeded => "quota_exceeded",
+            Reason::Overloaded => "overloaded",
+            Reason::Banned => "banned",
+            Reason::CircuitOpen => "circuit_open",
+            Reason::StoreUnavailable => "store_unavailable",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|reason| reason.code() == code)
+    }
+
+    /// Status of rejections for the reason unless configured otherwise: 503
+    /// while the limiter cannot decide, so clients can tell a transient
+    /// failure from being throttled, and 429 for every limit.
+    pub fn status(self) -> StatusCode {
+        match self {
+            Reason::CircuitOpen | Reason::StoreUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            Reason::RateLimited => "Too many requests",
+            Reason::LimitExceeded => "A limit shared with other keys is used up",
+            Reason::QuotaExceeded => "The plan's quota is used up",
+            Reason::Overloaded => "Too many requests in flight",
+            Reason::Banned => "The rate limit key is banned",
+            Reason::CircuitOpen => "The rate limiter is unavailable",
+            Reason::StoreUnavailable => "The rate limiter's store failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RejectionsConfig {
+    /// Rejection of every policy without its own.
+    pub default: Option<Rejection>,
+    /// Rejections by tenant, taking precedence over the policy's.
+    pub tenants: HashMap<String, Rejection>,
+}
+
+impl RejectionsConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(rejection) = &self.default {
+            rejection.validate()?;
+        }
+        for (tenant, rejection) in &self.tenants {
+            rejection.validate().map_err(|e| anyhow!("rejection of tenant '{}': {}", tenant, e))?;
+        }
+        Ok(())
+    }
+
+    /// Rejection for `tenant` under a policy with `policy`'s rejection.
+    pub fn resolve<'a>(&'a self, tenant: &str, policy: Option<&'a Rejection>) -> Option<&'a Rejection> {
+        self.tenants.get(tenant).or(policy).or(self.default.as_ref())
+    }
+}
+
+/// Response returned instead of the default 429 or 503. The body, headers and
+/// redirect URL may reference the placeholders `{error}`, `{message}`,
+/// `{key}`, `{policy}`, `{limit}`, `{remaining}`, `{reset}` and
+/// `{retry_after}`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rejection {
+    /// The reason's status, or 302 with a redirect, if unset.
+    #[serde(default)]
+    pub status: Option<u16>,
+    /// `application/json` for JSON bodies, `text/plain` for strings.
+    #[serde(default)]
+    pub content_type: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Strings are returned as text with the placeholders replaced. In JSON,
+    /// placeholders are replaced in every string, and a string that is just
+    /// a numeric placeholder becomes the number.
+    #[serde(default)]
+    pub body: Option<Value>,
+    /// URL clients are redirected to, e.g. an upgrade page. Placeholders are
+    /// percent-encoded.
+    #[serde(default)]
+    pub redirect: Option<String>,
+}
+
+impl Rejection {
+    pub fn validate(&self) -> Result<()> {
+        let status = self.status(StatusCode::TOO_MANY_REQUESTS);
+        let status = StatusCode::from_u16(status).map_err(|_| anyhow!("rejection: invalid status {}", status))?;
+        if self.redirect.is_some() && !status.is_redirection() {
+            bail!("rejection: redirects require a 3xx status");
+        }
+        if let Some(content_type) = &self.content_type {
+            HeaderValue::from_str(content_type).map_err(|_| anyhow!("rejection: invalid content_type '{}'", content_type))?;
+        }
+        for name in self.headers.keys() {
+            HeaderName::try_from(name.as_str()).map_err(|_| anyhow!("rejection: invalid header name '{}'", name))?;
+        }
+        Ok(())
+    }
+
+    fn status(&self, default: StatusCode) -> u16 {
+        match (self.status, &self.redirect) {
+            (Some(status), _) => status,
+            (None, Some(_)) => 302,
+            (None, None) => default.as_u16(),
+        }
+    }
+
+    pub fn respond(&self, fields: &RejectionFields) -> Response {
+        let default = Reason::from_code(fields.error).map_or(StatusCode::TOO_MANY_REQUESTS, Reason::status);
+        let status = StatusCode::from_u16(self.status(default)).unwrap_or(default);
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            if let (Ok(name), Ok(value)) = (HeaderName::try_from(name.as_str()), HeaderValue::from_str(&fields.render(value, Escape::None))) {
+                headers.insert(name, value);
+            }
+        }
+        if let Some(redirect) = &self.redirect
+            && let Ok(location) = HeaderValue::from_str(&fields.render(redirect, Escape::Url))
+        {
+            headers.insert(LOCATION, location);
+        }
+        let (default_type, body) = match &self.body {
+            None => (None, String::new()),
+            Some(Value::String(text)) => {
+                let markup = self.content_type.as_deref().is_some_and(|t| t.contains("html") || t.contains("xml"));
+                (Some("text/plain; charset=utf-8"), fields.render(text, if markup { Escape::Markup } else { Escape::None }))
+            },
+            Some(json) => (Some("application/json"), fields.render_json(json).to_string()),
+        };
+        if let Some(content_type) = self.content_type.as_deref().or(default_type)
+            && let Ok(content_type) = HeaderValue::from_str(content_type)
+        {
+            headers.insert(CONTENT_TYPE, content_type);
+        }
+        (status, headers, body).into_response()
+    }
+}
+
+/// Values of the placeholders of a rejection.
+pub struct RejectionFields<'a> {
+    pub error: &'a str,
+    pub message: &'a str,
+    pub key: &'a str,
+    pub policy: &'a str,
+    /// Capacity of the key's bucket.
+    pub limit: u32,
+    /// Tokens left in the key's bucket.
+    pub remaining: u64,
+    /// Seconds until the bucket has drained, or until the quota period ends.
+    pub reset: u64,
+    /// Seconds until the request would be admitted.
+    pub retry_after: u64,
+}
+
+#[derive(Clone, Copy)]
+enum Escape {
+    None,
+    Url,
+    Markup,
+}
+
+impl RejectionFields<'_> {
+    fn number(&self, placeholder: &str) -> Option<u64> {
+        match placeholder {
+            "{limit}" => Some(self.limit as u64),
+            "{remaining}" => Some(self.remaining),
+            "{reset}" => Some(self.reset),
+            "{retry_after}" => Some(self.retry_after),
+            _ => None,
+        }
+    }
+
+    fn render(&self, template: &str, escape: Escape) -> String {
+        let escape = |value: &str| match escape {
+            Escape::None => value.to_string(),
+            Escape::Url => utf8_percent_encode(value, NON_ALPHANUMERIC).to_string(),
+            Escape::Markup => value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;"),
+        };
+        template
+            .replace("{error}", &escape(self.error))
+            .replace("{message}", &escape(self.message))
+            .replace("{key}", &escape(self.key))
+            .replace("{policy}", &escape(self.policy))
+            .replace("{limit}", &self.limit.to_string())
+            .replace("{remaining}", &self.remaining.to_string())
+            .replace("{reset}", &self.reset.to_string())
+            .replace("{retry_after}", &self.retry_after.to_string())
+    }
+
+    fn render_json(&self, value: &Value) -> Value {
+        match value {
+            Value::String(s) => match self.number(s) {
+                Some(n) => Value::from(n),
+                None => Value::String(self.render(s, Escape::None)),
+            },
+            Value::Array(items) => Value::Array(items.iter().map(|v| self.render_json(v)).collect()),
+            Value::Object(map) => Value::Object(map.iter().map(|(k, v)| (k.clone(), self.render_json(v))).collect()),
+            other => other.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(reason: Reason) -> RejectionFields<'static> {
+        RejectionFields {
+            error: reason.code(),
+            message: reason.message(),
+            key: "alice",
+            policy: "default",
+            limit: 10,
+            remaining: 0,
+            reset: 5,
+            retry_after: 5,
+        }
+    }
+
+    #[test]
+    fn unavailable_limiters_are_not_reported_as_throttling() {
+        let rejection: Rejection = serde_json::from_value(serde_json::json!({"body": "Try again in {retry_after}s"})).unwrap();
+        assert_eq!(rejection.respond(&fields(Reason::RateLimited)).status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(rejection.respond(&fields(Reason::CircuitOpen)).status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(rejection.respond(&fields(Reason::StoreUnavailable)).status(), StatusCode::SERVICE_UNAVAILABLE);
+        // A configured status applies whatever the reason
+        let rejection: Rejection = serde_json::from_value(serde_json::json!({"status": 429})).unwrap();
+        assert_eq!(rejection.respond(&fields(Reason::CircuitOpen)).status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
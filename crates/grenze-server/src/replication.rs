@@ -0,0 +1,289 @@
+//! Eventually consistent limits across regions. Each region admits requests
+//! against its local Redis and periodically reconciles with a global budget in
+//! a shared Redis: it adds the requests it admitted since the last sync to the
+//! global bucket and raises its local bucket to the global fill, which holds
+//! every region's consumption. Between syncs, regions can together overshoot a
+//! limit by what they admit within one `sync_interval_ms`.
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+
+use crate::{erasure::{Erasure, Tenant}, limiter::{bucket_ttl_ms, Admission, BucketLimit, BucketState, Clock, LimiterStore, LogAdmission, LogLimit, QuotaAdmission, QuotaLimit, QuotaState, RedisConfig, RedisStore}, usage::{Granularity, UsageCount}};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReplicationConfig {
+    /// Redis holding the global budget, shared by all regions.
+    pub global_url: String,
+    /// Credentials and connection settings for `global_url`.
+    #[serde(default)]
+    pub global_redis: RedisConfig,
+    /// How often consumption is reconciled with the global budget.
+    #[serde(default = "default_sync_interval_ms")]
+    pub sync_interval_ms: u64,
+}
+
+fn default_sync_interval_ms() -> u64 {
+    1_000
+}
+
+impl ReplicationConfig {
+    pub fn validate(&self) -> Result<()> {
+        self.global_redis.validate().context("limiter replication global_redis")
+    }
+}
+
+/// A bucket used in this region since it was last synced.
+struct Tracked {
+    /// Tokens admitted locally and not yet added to the global bucket.
+    pending: f64,
+    capacity: u32,
+    leak_per_sec: f64,
+    last_seen_ms: i64,
+}
+
+/// Local store reconciled with a global budget in the background.
+pub struct ReplicatedStore {
+    local: Arc<dyn LimiterStore>,
+    global: RedisStore,
+    config: ReplicationConfig,
+    tracked: Mutex<HashMap<String, Tracked>>,
+}
+
+impl ReplicatedStore {
+    pub async fn connect(local: Arc<dyn LimiterStore>, config: &ReplicationConfig) -> Result<Self> {
+        let global = RedisStore::connect(&config.global_url, &config.global_redis)
+            .await
+            .context("failed to connect to the global redis")?;
+        Ok(Self {
+            local,
+            global,
+            config: config.clone(),
+            tracked: Mutex::default(),
+        })
+    }
+
+    /// Reconciles with the global budget every `sync_interval_ms`.
+    pub fn spawn_sync(self: &Arc<Self>, clock: Arc<dyn Clock>) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_millis(store.config.sync_interval_ms.max(10)));
+            let mut failing = false;
+            loop {
+                ticker.tick().await;
+                match store.sync(clock.now_ms()).await {
+                    Ok(()) if failing => {
+                        failing = false;
+                        println!("Global budget sync recovered");
+                    },
+                    Ok(()) => {},
+                    Err(e) => {
+                        if !failing {
+                            failing = true;
+                            println!("Global budget sync failed, enforcing region-local limits meanwhile: {:#}", e);
+                        }
+                        // Reopens the connection if the global Redis dropped it
+                        let _ = store.global.ping().await;
+                    },
+                }
+            }
+        });
+    }
+
+    async fn sync(&self, now_ms: i64) -> Result<()> {
+        let buckets: Vec<(String, f64, u32, f64)> = {
+            let mut tracked = self.tracked.lock().expect("replication lock poisoned");
+            // Buckets idle for longer than they take to drain need no updates
+            tracked.retain(|_, t| t.pending != 0.0 || now_ms - t.last_seen_ms < bucket_ttl_ms(t.capacity as f64, t.leak_per_sec));
+            tracked
+                .iter_mut()
+                .map(|(bucket, t)| (bucket.clone(), std::mem::take(&mut t.pending), t.capacity, t.leak_per_sec))
+                .collect()
+        };
+        for (i, (bucket, pending, capacity, leak_per_sec)) in buckets.iter().enumerate() {
+            let global = match self.global.charge(bucket, *pending, *capacity, *leak_per_sec, now_ms).await {
+                Ok(fill) => fill,
+                Err(e) => {
+                    // Keep what was not pushed for the next sync
+                    let mut tracked = self.tracked.lock().expect("replication lock poisoned");
+                    for (bucket, pending, _, _) in &buckets[i..] {
+                        if let Some(t) = tracked.get_mut(bucket) {
+                            t.pending += pending;
+                        }
+                    }
+                    return Err(e);
+                },
+            };
+            let local = self.local.charge(bucket, 0.0, *capacity, *leak_per_sec, now_ms).await?;
+            // Ignore rounding noise
+            if global - local > 1e-6 {
+                self.local.charge(bucket, global - local, *capacity, *leak_per_sec, now_ms).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn track(&self, bucket: &str, admitted: f64, capacity: u32, leak_per_sec: f64, now_ms: i64) {
+        let mut tracked = self.tracked.lock().expect("replication lock poisoned");
+        let entry = tracked.entry(bucket.to_string()).or_insert(Tracked {
+            pending: 0.0,
+            capacity,
+            leak_per_sec,
+            last_seen_ms: now_ms,
+        });
+        entry.pending += admitted;
+        entry.capacity = capacity;
+        entry.leak_per_sec = leak_per_sec;
+        entry.last_seen_ms = now_ms;
+    }
+}
+
+#[async_trait]
+impl LimiterStore for ReplicatedStore {
+    async fn allow(&self, bucket: &str, cost: f64, capacity: u32, leak_per_sec: f64, now_ms: i64) -> Result<Admission> {
+        let admission = self.local.allow(bucket, cost, capacity, leak_per_sec, now_ms).await?;
+        // Denied buckets are tracked too, so they learn about other regions
+        self.track(bucket, if admission.allowed { cost } else { 0.0 }, capacity, leak_per_sec, now_ms);
+        Ok(admission)
+    }
+
+    async fn allow_all(&self, limits: &[BucketLimit], cost: f64, now_ms: i64) -> Result<Vec<Admission>> {
+        let admissions = self.local.allow_all(limits, cost, now_ms).await?;
+        let admitted = admissions.iter().all(|a| a.allowed);
+        for limit in limits {
+            self.track(&limit.bucket, if admitted { cost } else { 0.0 }, limit.capacity, limit.leak_per_sec, now_ms);
+        }
+        Ok(admissions)
+    }
+
+    async fn allow_batch(&self, requests: &[(&[BucketLimit], f64)], now_ms: i64) -> Result<Vec<Vec<Admission>>> {
+        let outcomes = self.local.allow_batch(requests, now_ms).await?;
+        for ((limits, cost), admissions) in requests.iter().zip(&outcomes) {
+            let admitted = admissions.iter().all(|a| a.allowed);
+            for limit in *limits {
+                self.track(&limit.bucket, if admitted { *cost } else { 0.0 }, limit.capacity, limit.leak_per_sec, now_ms);
+            }
+        }
+        Ok(outcomes)
+    }
+
+    async fn remember(&self, key: &str, ttl_secs: u64) -> Result<bool> {
+        self.local.remember(key, ttl_secs).await
+    }
+
+    async fn forget(&self, key: &str) {
+        self.local.forget(key).await
+    }
+
+    async fn evict(&self, bucket: &str) {
+        self.tracked.lock().expect("replication lock poisoned").remove(bucket);
+        self.local.evict(bucket).await
+    }
+
+    async fn charge(&self, bucket: &str, amount: f64, capacity: u32, leak_per_sec: f64, now_ms: i64) -> Result<f64> {
+        let fill = self.local.charge(bucket, amount, capacity, leak_per_sec, now_ms).await?;
+        self.track(bucket, amount, capacity, leak_per_sec, now_ms);
+        Ok(fill)
+    }
+
+    async fn level(&self, bucket: &str, leak_per_sec: f64, now_ms: i64) -> Result<f64> {
+        self.local.level(bucket, leak_per_sec, now_ms).await
+    }
+
+    async fn first_seen(&self, key: &str, now_ms: i64, ttl_secs: u64) -> Result<i64> {
+        self.local.first_seen(key, now_ms, ttl_secs).await
+    }
+
+    async fn cookie_jar(&self, key: &str) -> Result<Option<String>> {
+        self.local.cookie_jar(key).await
+    }
+
+    async fn store_cookie_jar(&self, key: &str, jar: &str, ttl_secs: u64) -> Result<()> {
+        self.local.store_cookie_jar(key, jar, ttl_secs).await
+    }
+
+    /// Locks are taken in the global store, as they are shared by all
+    /// instances.
+    async fn lock(&self, name: &str, holder: &str, ttl_ms: u64) -> Result<bool> {
+        self.global.lock(name, holder, ttl_ms).await
+    }
+
+    async fn unlock(&self, name: &str, holder: &str) -> Result<()> {
+        self.global.unlock(name, holder).await
+    }
+
+    /// Usage is kept in the global store, so it covers every region.
+    async fn record_usage(&self, counts: &[UsageCount], ttl_secs: u64) -> Result<()> {
+        self.global.record_usage(counts, ttl_secs).await
+    }
+
+    async fn take_usage(&self, before_ms: i64) -> Result<Vec<UsageCount>> {
+        self.global.take_usage(before_ms).await
+    }
+
+    async fn roll_up_usage(&self, granularity: Granularity, counts: &[UsageCount], retention_secs: u64) -> Result<()> {
+        self.global.roll_up_usage(granularity, counts, retention_secs).await
+    }
+
+    async fn usage(&self, key: &str, granularity: Granularity, from_ms: i64, to_ms: i64) -> Result<Vec<UsageCount>> {
+        self.global.usage(key, granularity, from_ms, to_ms).await
+    }
+
+    async fn usage_keys(&self, granularity: Granularity, from_ms: i64, to_ms: i64) -> Result<Vec<String>> {
+        self.global.usage_keys(granularity, from_ms, to_ms).await
+    }
+
+    async fn purge_usage(&self, granularity: Option<Granularity>, before_ms: i64) -> Result<u64> {
+        self.global.purge_usage(granularity, before_ms).await
+    }
+
+    /// Quotas are counted in the global store, as they cover every region.
+    async fn allow_quota(&self, quota: &QuotaLimit, cost: f64, now_ms: i64) -> Result<QuotaAdmission> {
+        self.global.allow_quota(quota, cost, now_ms).await
+    }
+
+    async fn charge_quota(&self, counter: &str, bytes: u64, until_ms: i64) -> Result<()> {
+        self.global.charge_quota(counter, bytes, until_ms).await
+    }
+
+    /// Sliding logs are kept in the global store, as they limit exactly
+    /// across regions.
+    async fn allow_log(&self, log: &LogLimit, cost: u32, now_ms: i64) -> Result<LogAdmission> {
+        self.global.allow_log(log, cost, now_ms).await
+    }
+
+    async fn release_log(&self, log: &LogLimit, cost: u32, now_ms: i64) -> Result<()> {
+        self.global.release_log(log, cost, now_ms).await
+    }
+
+    /// Erases the tenant from the local and the global store, which holds
+    /// its global buckets, quotas and usage.
+    async fn erase(&self, tenant: &Tenant) -> Result<Erasure> {
+        self.tracked.lock().expect("replication lock poisoned").retain(|bucket, _| !tenant.owns_bucket(bucket));
+        let mut erasure = self.local.erase(tenant).await?;
+        erasure.add(&self.global.erase(tenant).await.context("global redis")?);
+        Ok(erasure)
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.local.ping().await
+    }
+
+    async fn export(&self) -> Result<Vec<BucketState>> {
+        self.local.export().await
+    }
+
+    async fn import(&self, buckets: &[BucketState], capacity: u32, leak_per_sec: f64) -> Result<()> {
+        self.local.import(buckets, capacity, leak_per_sec).await
+    }
+
+    async fn export_quotas(&self, now_ms: i64) -> Result<Vec<QuotaState>> {
+        self.global.export_quotas(now_ms).await
+    }
+
+    async fn import_quotas(&self, quotas: &[QuotaState]) -> Result<()> {
+        self.global.import_quotas(quotas).await
+    }
+}
@@ -0,0 +1,327 @@
+//! Rules of single rate limit keys written through the admin API: bans and
+//! bucket sizes. They are stored in Redis and cached by every instance, which
+//! drop their cached copy as soon as a rule changes, told through pub/sub.
+
+use anyhow::{bail, Context, Result};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt::Write, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use utoipa::ToSchema;
+
+use crate::{limiter::{self, BucketLimit}, logging};
+
+/// Most rules, or their absence, cached per instance; the cache is emptied of
+/// expired entries, then entirely, when full.
+const MAX_CACHED_RULES: usize = 100_000;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyRulesConfig {
+    /// How long a rule is cached. Changes made through the admin API reach
+    /// every instance at once; the TTL bounds staleness if an invalidation is
+    /// missed while an instance is disconnected.
+    pub cache_ttl_secs: u64,
+    /// How long the absence of a rule is cached, kept short as most keys
+    /// have none and a missed ban matters more than a missed resize.
+    pub negative_cache_ttl_secs: u64,
+    /// Pub/sub channel rule changes are announced on, prefixed like keys
+    /// with the Redis namespace.
+    pub channel: String,
+}
+
+impl Default for KeyRulesConfig {
+    fn default() -> Self {
+        Self {
+            cache_ttl_secs: 60,
+            negative_cache_ttl_secs: 10,
+            channel: "grenze:key-rules".to_string(),
+        }
+    }
+}
+
+impl KeyRulesConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.cache_ttl_secs == 0 || self.negative_cache_ttl_secs == 0 {
+            bail!("key_rules cache TTLs must be positive");
+        }
+        if self.channel.is_empty() {
+            bail!("key_rules channel must not be empty");
+        }
+        Ok(())
+    }
+}
+
+/// Rule of a rate limit key.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct KeyRule {
+    /// Rejects every request of the key with `403`.
+    #[serde(default)]
+    pub banned: bool,
+    /// Told to banned callers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Size of the key's own bucket, overriding its policy's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capacity: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leak_per_sec: Option<f64>,
+    /// When the rule lapses; never if unset. Set from `ttl_secs` on writes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at_ms: Option<i64>,
+    /// Lifetime of the rule in seconds, when writing it.
+    #[serde(default, skip_serializing)]
+    pub ttl_secs: Option<u64>,
+}
+
+impl KeyRule {
+    pub fn validate(&self) -> Result<()> {
+        if self.capacity == Some(0) {
+            bail!("capacity must be at least 1");
+        }
+        if self.leak_per_sec.is_some_and(|l| !l.is_finite() || l <= 0.0) {
+            bail!("leak_per_sec must be positive");
+        }
+        if self.ttl_secs == Some(0) {
+            bail!("ttl_secs must be positive");
+        }
+        Ok(())
+    }
+
+    /// Resizes the key's own bucket as the rule says.
+    pub fn apply(&self, limit: &mut BucketLimit) {
+        if let Some(capacity) = self.capacity {
+            limit.capacity = capacity;
+        }
+        if let Some(leak) = self.leak_per_sec {
+            limit.leak_per_sec = leak;
+        }
+    }
+}
+
+#[derive(Default)]
+struct Cache {
+    /// Rules by key, `None` for keys without one, with when they were read.
+    rules: HashMap<String, (Instant, Option<Arc<KeyRule>>)>,
+    /// Bumped on every invalidation, so a rule read before one is not cached
+    /// after it.
+    generation: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl Cache {
+    fn invalidate(&mut self, key: Option<&str>) {
+        match key {
+            Some(key) => {
+                self.rules.remove(key);
+            },
+            None => self.rules.clear(),
+        }
+        self.generation += 1;
+    }
+}
+
+/// Payload announcing that every cached rule is to be dropped; rate limit
+/// keys are never empty.
+const FLUSH: &str = "";
+
+pub struct KeyRules {
+    client: redis::Client,
+    conn: tokio::sync::Mutex<Option<redis::aio::MultiplexedConnection>>,
+    config: KeyRulesConfig,
+    cache: Arc<Mutex<Cache>>,
+    /// Prefix of the rules' keys and channel, from the Redis namespace.
+    prefix: String,
+}
+
+impl KeyRules {
+    /// Starts following rule changes announced on the configured channel,
+    /// resubscribing after connection loss. Keys and channel are prefixed
+    /// with `prefix`.
+    pub fn start(client: redis::Client, config: &KeyRulesConfig, prefix: String) -> Self {
+        let cache = Arc::new(Mutex::new(Cache::default()));
+        tokio::spawn(follow(client.clone(), format!("{}{}", prefix, config.channel), cache.clone()));
+        Self {
+            client,
+            conn: tokio::sync::Mutex::new(None),
+            config: config.clone(),
+            cache,
+            prefix,
+        }
+    }
+
+    fn redis_key(&self, key: &str) -> String {
+        format!("{}grenze:rule:{}", self.prefix, key)
+    }
+
+    async fn conn(&self) -> Result<redis::aio::MultiplexedConnection> {
+        let mut conn = self.conn.lock().await;
+        if conn.is_none() {
+            *conn = Some(self.client.get_multiplexed_tokio_connection().await?);
+        }
+        Ok(conn.clone().expect("connection opened above"))
+    }
+
+    /// Runs a command, reopening the connection next time if it fails.
+    async fn query<T: redis::FromRedisValue>(&self, cmd: &redis::Cmd) -> Result<T> {
+        let mut conn = self.conn().await?;
+        let result = cmd.query_async(&mut conn).await;
+        if result.is_err() {
+            *self.conn.lock().await = None;
+        }
+        Ok(result?)
+    }
+
+    /// Whether a cached rule, or absence of one, read at `read` is fresh.
+    fn fresh(&self, read: Instant, rule: &Option<Arc<KeyRule>>) -> bool {
+        let ttl_secs = match rule {
+            Some(_) => self.config.cache_ttl_secs,
+            None => self.config.negative_cache_ttl_secs,
+        };
+        read.elapsed() < Duration::from_secs(ttl_secs)
+    }
+
+    /// Rule of `key` in effect at `now_ms`, from the cache if it is fresh.
+    pub async fn get(&self, key: &str, now_ms: i64) -> Result<Option<Arc<KeyRule>>> {
+        let (cached, generation) = {
+            let mut cache = self.cache.lock().expect("key rules lock poisoned");
+            let cached = cache.rules.get(key).filter(|(read, rule)| self.fresh(*read, rule)).map(|(_, rule)| rule.clone());
+            match cached {
+                Some(_) => cache.hits += 1,
+                None => cache.misses += 1,
+            }
+            (cached, cache.generation)
+        };
+        let rule = match cached {
+            Some(rule) => rule,
+            None => {
+                let value: Option<String> = self.query(redis::cmd("GET").arg(self.redis_key(key))).await?;
+                let rule = value.map(|v| serde_json::from_str::<KeyRule>(&v)).transpose().context("invalid key rule")?.map(Arc::new);
+                let mut cache = self.cache.lock().expect("key rules lock poisoned");
+                if cache.generation == generation {
+                    if cache.rules.len() >= MAX_CACHED_RULES {
+                        cache.rules.retain(|_, (read, rule)| self.fresh(*read, rule));
+                        if cache.rules.len() >= MAX_CACHED_RULES {
+                            cache.rules.clear();
+                        }
+                    }
+                    cache.rules.insert(key.to_string(), (Instant::now(), rule.clone()));
+                }
+                rule
+            },
+        };
+        Ok(rule.filter(|r| r.expires_at_ms.is_none_or(|at| at > now_ms)))
+    }
+
+    /// Stores the rule of `key` and has every instance drop its cached one.
+    pub async fn set(&self, key: &str, mut rule: KeyRule, now_ms: i64) -> Result<KeyRule> {
+        rule.expires_at_ms = rule.ttl_secs.map(|secs| now_ms + (secs * 1000) as i64);
+        let mut cmd = redis::cmd("SET");
+        cmd.arg(self.redis_key(key)).arg(serde_json::to_string(&rule)?);
+        if let Some(secs) = rule.ttl_secs {
+            cmd.arg("EX").arg(secs);
+        }
+        self.query::<()>(&cmd).await?;
+        self.announce(key).await?;
+        Ok(rule)
+    }
+
+    /// Deletes the rule of `key`, returning whether it had one, and has every
+    /// instance drop its cached one.
+    pub async fn delete(&self, key: &str) -> Result<bool> {
+        let deleted: u64 = self.query(redis::cmd("DEL").arg(self.redis_key(key))).await?;
+        self.announce(key).await?;
+        Ok(deleted > 0)
+    }
+
+    /// Every rule in effect at `now_ms`, by key.
+    pub async fn export(&self, now_ms: i64) -> Result<Vec<(String, KeyRule)>> {
+        let mut conn = self.conn().await?;
+        let prefix = self.redis_key("");
+        let names = limiter::scan(&mut conn, &format!("{}*", prefix)).await?;
+        let mut rules = Vec::new();
+        for chunk in names.chunks(500) {
+            let values: Vec<Option<String>> = redis::cmd("MGET").arg(chunk).query_async(&mut conn).await?;
+            for (name, value) in chunk.iter().zip(values) {
+                let Some(value) = value else {
+                    continue;
+                };
+                let rule: KeyRule = serde_json::from_str(&value).with_context(|| format!("invalid key rule {}", name))?;
+                if rule.expires_at_ms.is_none_or(|at| at > now_ms) {
+                    rules.push((name.strip_prefix(prefix.as_str()).unwrap_or(name).to_string(), rule));
+                }
+            }
+        }
+        Ok(rules)
+    }
+
+    /// Stores `rules`, each lapsing when it did before, and has every
+    /// instance drop its cached rules.
+    pub async fn import(&self, rules: &[(String, KeyRule)], now_ms: i64) -> Result<()> {
+        let mut conn = self.conn().await?;
+        for chunk in rules.chunks(500) {
+            let mut pipe = redis::pipe();
+            for (key, rule) in chunk {
+                let cmd = pipe.cmd("SET").arg(self.redis_key(key)).arg(serde_json::to_string(rule)?);
+                if let Some(at) = rule.expires_at_ms {
+                    cmd.arg("PX").arg((at - now_ms).max(1));
+                }
+                cmd.ignore();
+            }
+            pipe.query_async::<()>(&mut conn).await?;
+        }
+        self.flush().await
+    }
+
+    /// Has every instance drop all its cached rules, e.g. after rules were
+    /// restored or edited in Redis directly.
+    pub async fn flush(&self) -> Result<()> {
+        self.announce(FLUSH).await
+    }
+
+    async fn announce(&self, key: &str) -> Result<()> {
+        self.cache.lock().expect("key rules lock poisoned").invalidate(Some(key).filter(|k| *k != FLUSH));
+        self.query::<()>(redis::cmd("PUBLISH").arg(format!("{}{}", self.prefix, self.config.channel)).arg(key)).await
+    }
+
+    /// Renders the cache's effectiveness in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let cache = self.cache.lock().expect("key rules lock poisoned");
+        let mut out = String::new();
+        out.push_str("# HELP grenze_key_rule_lookups_total Lookups of key rules, by whether the cache answered them.\n");
+        out.push_str("# TYPE grenze_key_rule_lookups_total counter\n");
+        let _ = writeln!(out, "grenze_key_rule_lookups_total{{cache=\"hit\"}} {}", cache.hits);
+        let _ = writeln!(out, "grenze_key_rule_lookups_total{{cache=\"miss\"}} {}", cache.misses);
+        out.push_str("# HELP grenze_key_rules_cached Keys whose rule, or absence of one, is cached.\n");
+        out.push_str("# TYPE grenze_key_rules_cached gauge\n");
+        let present = cache.rules.values().filter(|(_, rule)| rule.is_some()).count();
+        let _ = writeln!(out, "grenze_key_rules_cached{{rule=\"present\"}} {}", present);
+        let _ = writeln!(out, "grenze_key_rules_cached{{rule=\"absent\"}} {}", cache.rules.len() - present);
+        out
+    }
+}
+
+/// Drops cached rules as their changes are announced. Announcements may have
+/// been missed while disconnected, so the cache is emptied on resubscribing.
+async fn follow(client: redis::Client, channel: String, cache: Arc<Mutex<Cache>>) {
+    loop {
+        let result: Result<()> = async {
+            let mut pubsub = client.get_async_pubsub().await?;
+            pubsub.subscribe(&channel).await?;
+            cache.lock().expect("key rules lock poisoned").invalidate(None);
+            let mut messages = pubsub.on_message();
+            while let Some(msg) = messages.next().await {
+                if let Ok(key) = msg.get_payload::<String>() {
+                    cache.lock().expect("key rules lock poisoned").invalidate(Some(key.as_str()).filter(|k| *k != FLUSH));
+                }
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(e) = result {
+            logging::warn("key_rules", format_args!("Key rule subscription failed: {:#}", e));
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
@@ -0,0 +1,331 @@
+//! Validation of proxied requests before they take a token: the content types
+//! a policy accepts, and a JSON Schema the embedded body must match. Schemas
+//! are compiled when the config is loaded and support the keywords needed to
+//! describe request payloads; references and the remaining keywords are
+//! refused rather than ignored.
+
+use anyhow::{anyhow, bail, Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BodyValidation {
+    /// Media types requests may declare in `Content-Type`, e.g.
+    /// `application/json`; any if empty. Bodies sent without one are JSON.
+    #[serde(default)]
+    pub content_types: Vec<String>,
+    /// JSON Schema the body must match; requests without a body are checked
+    /// as `null`.
+    #[serde(default)]
+    pub schema: Option<Schema>,
+}
+
+/// Why a request was refused.
+#[derive(Debug)]
+pub enum Refusal {
+    /// The content type is not among the allowed ones.
+    ContentType(String),
+    /// The body does not match the schema, at the JSON pointer given.
+    Body { pointer: String, message: String },
+}
+
+impl BodyValidation {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(t) = self.content_types.iter().find(|t| !t.contains('/')) {
+            bail!("validation content type '{}' is not a media type", t);
+        }
+        Ok(())
+    }
+
+    /// Checks the `Content-Type` a request declares, if any.
+    pub fn check_content_type(&self, content_type: Option<&str>) -> Result<(), Refusal> {
+        let declared = content_type.map(media_type).unwrap_or_else(|| "application/json".to_string());
+        if !self.content_types.is_empty() && !self.content_types.iter().any(|t| t.eq_ignore_ascii_case(&declared)) {
+            return Err(Refusal::ContentType(declared));
+        }
+        Ok(())
+    }
+
+    /// Checks the JSON body of a request, if any, against the schema.
+    pub fn check_body(&self, body: Option<&Value>) -> Result<(), Refusal> {
+        match &self.schema {
+            Some(schema) => schema.check(body.unwrap_or(&Value::Null), &mut String::new()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// `Content-Type` without its parameters, lower-cased.
+fn media_type(content_type: &str) -> String {
+    content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase()
+}
+
+/// Compiled JSON Schema.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "Value")]
+pub enum Schema {
+    /// `true` or `false`, accepting everything or nothing.
+    Always(bool),
+    Keywords(Box<Keywords>),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Keywords {
+    types: Vec<String>,
+    enumeration: Option<Vec<Value>>,
+    constant: Option<Value>,
+    properties: Vec<(String, Schema)>,
+    required: Vec<String>,
+    additional_properties: Option<Schema>,
+    items: Option<Schema>,
+    min_items: Option<usize>,
+    max_items: Option<usize>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    pattern: Option<Regex>,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    exclusive_minimum: Option<f64>,
+    exclusive_maximum: Option<f64>,
+    all_of: Vec<Schema>,
+    any_of: Vec<Schema>,
+    one_of: Vec<Schema>,
+    not: Option<Schema>,
+}
+
+/// Keywords carrying no constraint.
+const ANNOTATIONS: [&str; 8] = ["$schema", "$id", "$comment", "title", "description", "default", "examples", "format"];
+
+const TYPES: [&str; 7] = ["null", "boolean", "object", "array", "number", "integer", "string"];
+
+impl TryFrom<Value> for Schema {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Value) -> Result<Self> {
+        compile(&value)
+    }
+}
+
+fn compile(value: &Value) -> Result<Schema> {
+    let object = match value {
+        Value::Bool(b) => return Ok(Schema::Always(*b)),
+        Value::Object(object) => object,
+        _ => bail!("schema must be an object or a boolean"),
+    };
+    let mut k = Keywords::default();
+    for (name, v) in object {
+        let at = || format!("schema keyword '{}'", name);
+        match name.as_str() {
+            "type" => {
+                k.types = match v {
+                    Value::String(t) => vec![t.clone()],
+                    Value::Array(ts) => ts.iter().map(|t| t.as_str().map(str::to_string).ok_or_else(|| anyhow!("types must be strings"))).collect::<Result<_>>().with_context(at)?,
+                    _ => bail!("{} must be a string or an array", at()),
+                };
+                if let Some(t) = k.types.iter().find(|t| !TYPES.contains(&t.as_str())) {
+                    bail!("unknown schema type '{}'", t);
+                }
+            },
+            "enum" => k.enumeration = Some(v.as_array().cloned().ok_or_else(|| anyhow!("{} must be an array", at()))?),
+            "const" => k.constant = Some(v.clone()),
+            "properties" => {
+                let properties = v.as_object().ok_or_else(|| anyhow!("{} must be an object", at()))?;
+                k.properties = properties
+                    .iter()
+                    .map(|(p, s)| Ok((p.clone(), compile(s).with_context(|| format!("property '{}'", p))?)))
+                    .collect::<Result<_>>()?;
+            },
+            "required" => {
+                k.required = v
+                    .as_array()
+                    .and_then(|r| r.iter().map(|p| p.as_str().map(str::to_string)).collect())
+                    .ok_or_else(|| anyhow!("{} must be an array of strings", at()))?
+            },
+            "additionalProperties" => k.additional_properties = Some(compile(v).with_context(at)?),
+            "items" => k.items = Some(compile(v).with_context(at)?),
+            "minItems" => k.min_items = Some(count(v).with_context(at)?),
+            "maxItems" => k.max_items = Some(count(v).with_context(at)?),
+            "minLength" => k.min_length = Some(count(v).with_context(at)?),
+            "maxLength" => k.max_length = Some(count(v).with_context(at)?),
+            "pattern" => {
+                let pattern = v.as_str().ok_or_else(|| anyhow!("{} must be a string", at()))?;
+                k.pattern = Some(Regex::new(pattern).with_context(at)?);
+            },
+            "minimum" => k.minimum = Some(number(v).with_context(at)?),
+            "maximum" => k.maximum = Some(number(v).with_context(at)?),
+            "exclusiveMinimum" => k.exclusive_minimum = Some(number(v).with_context(at)?),
+            "exclusiveMaximum" => k.exclusive_maximum = Some(number(v).with_context(at)?),
+            "allOf" => k.all_of = subschemas(v).with_context(at)?,
+            "anyOf" => k.any_of = subschemas(v).with_context(at)?,
+            "oneOf" => k.one_of = subschemas(v).with_context(at)?,
+            "not" => k.not = Some(compile(v).with_context(at)?),
+            _ if ANNOTATIONS.contains(&name.as_str()) => {},
+            _ => bail!("unsupported schema keyword '{}'", name),
+        }
+    }
+    Ok(Schema::Keywords(Box::new(k)))
+}
+
+fn count(v: &Value) -> Result<usize> {
+    v.as_u64().map(|n| n as usize).ok_or_else(|| anyhow!("must be a non-negative integer"))
+}
+
+fn number(v: &Value) -> Result<f64> {
+    v.as_f64().ok_or_else(|| anyhow!("must be a number"))
+}
+
+fn subschemas(v: &Value) -> Result<Vec<Schema>> {
+    match v {
+        Value::Array(schemas) if !schemas.is_empty() => schemas.iter().map(compile).collect(),
+        _ => bail!("must be a non-empty array of schemas"),
+    }
+}
+
+fn type_of(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Object(_) => "object",
+        Value::Array(_) => "array",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+    }
+}
+
+impl Schema {
+    /// Checks `value`, found at the JSON pointer `pointer`, reporting the
+    /// first violation.
+    fn check(&self, value: &Value, pointer: &mut String) -> Result<(), Refusal> {
+        let k = match self {
+            Schema::Always(true) => return Ok(()),
+            Schema::Always(false) => return Err(refuse(pointer, "no value is allowed here".to_string())),
+            Schema::Keywords(k) => k,
+        };
+        if !k.types.is_empty() && !k.types.iter().any(|t| has_type(value, t)) {
+            return Err(refuse(pointer, format!("expected {}, got {}", k.types.join(" or "), type_of(value))));
+        }
+        if let Some(allowed) = &k.enumeration
+            && !allowed.contains(value)
+        {
+            return Err(refuse(pointer, format!("must be one of {}", Value::Array(allowed.clone()))));
+        }
+        if let Some(constant) = &k.constant
+            && constant != value
+        {
+            return Err(refuse(pointer, format!("must be {}", constant)));
+        }
+        match value {
+            Value::Object(fields) => k.check_object(fields, pointer)?,
+            Value::Array(items) => {
+                if let Some(min) = k.min_items
+                    && items.len() < min
+                {
+                    return Err(refuse(pointer, format!("must have at least {} items", min)));
+                }
+                if let Some(max) = k.max_items
+                    && items.len() > max
+                {
+                    return Err(refuse(pointer, format!("must have at most {} items", max)));
+                }
+                if let Some(schema) = &k.items {
+                    for (i, item) in items.iter().enumerate() {
+                        nested(pointer, &i.to_string(), |pointer| schema.check(item, pointer))?;
+                    }
+                }
+            },
+            Value::String(s) => {
+                let length = s.chars().count();
+                if let Some(min) = k.min_length
+                    && length < min
+                {
+                    return Err(refuse(pointer, format!("must be at least {} characters long", min)));
+                }
+                if let Some(max) = k.max_length
+                    && length > max
+                {
+                    return Err(refuse(pointer, format!("must be at most {} characters long", max)));
+                }
+                if let Some(pattern) = &k.pattern
+                    && !pattern.is_match(s)
+                {
+                    return Err(refuse(pointer, format!("must match {}", pattern)));
+                }
+            },
+            Value::Number(n) => {
+                let n = n.as_f64().unwrap_or_default();
+                let bounds = [
+                    (k.minimum, n >= k.minimum.unwrap_or_default(), "at least"),
+                    (k.maximum, n <= k.maximum.unwrap_or_default(), "at most"),
+                    (k.exclusive_minimum, n > k.exclusive_minimum.unwrap_or_default(), "above"),
+                    (k.exclusive_maximum, n < k.exclusive_maximum.unwrap_or_default(), "below"),
+                ];
+                if let Some((Some(bound), _, relation)) = bounds.into_iter().find(|(bound, holds, _)| bound.is_some() && !holds) {
+                    return Err(refuse(pointer, format!("must be {} {}", relation, bound)));
+                }
+            },
+            _ => {},
+        }
+        for schema in &k.all_of {
+            schema.check(value, pointer)?;
+        }
+        if !k.any_of.is_empty() && !k.any_of.iter().any(|s| s.check(value, &mut pointer.clone()).is_ok()) {
+            return Err(refuse(pointer, "must match at least one of the anyOf schemas".to_string()));
+        }
+        if !k.one_of.is_empty() && k.one_of.iter().filter(|s| s.check(value, &mut pointer.clone()).is_ok()).count() != 1 {
+            return Err(refuse(pointer, "must match exactly one of the oneOf schemas".to_string()));
+        }
+        if let Some(not) = &k.not
+            && not.check(value, &mut pointer.clone()).is_ok()
+        {
+            return Err(refuse(pointer, "must not match the not schema".to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl Keywords {
+    fn check_object(&self, fields: &Map<String, Value>, pointer: &mut String) -> Result<(), Refusal> {
+        if let Some(missing) = self.required.iter().find(|p| !fields.contains_key(*p)) {
+            return Err(refuse(pointer, format!("missing required property '{}'", missing)));
+        }
+        for (name, value) in fields {
+            let schema = match self.properties.iter().find(|(p, _)| p == name) {
+                Some((_, schema)) => schema,
+                None => match &self.additional_properties {
+                    Some(Schema::Always(false)) => return Err(refuse(pointer, format!("unexpected property '{}'", name))),
+                    Some(schema) => schema,
+                    None => continue,
+                },
+            };
+            nested(pointer, name, |pointer| schema.check(value, pointer))?;
+        }
+        Ok(())
+    }
+}
+
+fn has_type(value: &Value, t: &str) -> bool {
+    match t {
+        "integer" => value.as_f64().is_some_and(|n| n.fract() == 0.0),
+        _ => type_of(value) == t,
+    }
+}
+
+/// Runs `check` with `segment` appended to `pointer`, escaped as JSON
+/// pointers require.
+fn nested(pointer: &mut String, segment: &str, check: impl FnOnce(&mut String) -> Result<(), Refusal>) -> Result<(), Refusal> {
+    let length = pointer.len();
+    pointer.push('/');
+    pointer.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+    let result = check(pointer);
+    pointer.truncate(length);
+    result
+}
+
+fn refuse(pointer: &str, message: String) -> Refusal {
+    Refusal::Body {
+        pointer: if pointer.is_empty() { "/".to_string() } else { pointer.to_string() },
+        message,
+    }
+}
@@ -0,0 +1,149 @@
+//! Rhai scripts computing the rate limit key, choosing the policy or rewriting
+//! the destination URL from the request contents.
+//!
+//! A script may define any of the functions `key(request)`,
+//! `destination(request)` and `policy(request)`. Each returns a string, or
+//! `()` to keep grenze's default. `request` is a map with `key` (the derived
+//! rate limit key), `client_key`, `ip`, `method`, `url`, `host`, `headers`,
+//! `query` and `body`.
+
+use anyhow::{anyhow, Context, Result};
+use rhai::{CallFnOptions, Dynamic, Engine, Scope, AST};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::{Arc, RwLock}, time::{Duration, SystemTime}};
+
+use crate::api::proxy::MultiValue;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScriptConfig {
+    pub path: String,
+    /// How often the script file is checked for changes and reloaded; never
+    /// if unset.
+    #[serde(default)]
+    pub reload_interval_secs: Option<u64>,
+    /// Upper bound of operations per function call, so a runaway script
+    /// cannot stall the proxy.
+    #[serde(default = "default_max_operations")]
+    pub max_operations: u64,
+}
+
+fn default_max_operations() -> u64 {
+    100_000
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScriptRequest<'a> {
+    pub key: &'a str,
+    pub client_key: &'a str,
+    pub ip: String,
+    pub method: &'a str,
+    pub url: &'a str,
+    pub host: &'a str,
+    pub headers: HashMap<String, String>,
+    pub query: &'a HashMap<String, MultiValue>,
+    pub body: &'a Option<serde_json::Value>,
+}
+
+/// Overrides returned by the script.
+#[derive(Debug, Default)]
+pub struct Routing {
+    pub key: Option<String>,
+    pub destination: Option<String>,
+    pub policy: Option<String>,
+}
+
+struct Loaded {
+    ast: Arc<AST>,
+    modified: Option<SystemTime>,
+}
+
+pub struct Scripts {
+    engine: Engine,
+    config: ScriptConfig,
+    loaded: RwLock<Loaded>,
+}
+
+impl Scripts {
+    pub fn new(config: &ScriptConfig) -> Result<Self> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(config.max_operations);
+        let loaded = compile(&engine, &config.path)?;
+        Ok(Self {
+            engine,
+            config: config.clone(),
+            loaded: RwLock::new(loaded),
+        })
+    }
+
+    /// Recompiles the script. On failure the previous version stays active.
+    pub fn reload(&self) -> Result<()> {
+        let loaded = compile(&self.engine, &self.config.path)?;
+        *self.loaded.write().expect("script lock poisoned") = loaded;
+        Ok(())
+    }
+
+    fn reload_changed(&self) {
+        let modified = std::fs::metadata(&self.config.path).and_then(|m| m.modified()).ok();
+        if modified == self.loaded.read().expect("script lock poisoned").modified {
+            return;
+        }
+        match self.reload() {
+            Ok(()) => println!("Reloaded script {}", self.config.path),
+            Err(e) => println!("Failed to reload script {}: {:#}", self.config.path, e),
+        }
+    }
+
+    pub fn spawn_reloader(self: &Arc<Self>) {
+        let Some(secs) = self.config.reload_interval_secs else {
+            return;
+        };
+        let scripts = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(secs));
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                scripts.reload_changed();
+            }
+        });
+    }
+
+    pub fn route(&self, request: &ScriptRequest) -> Result<Routing> {
+        let ast = self.loaded.read().expect("script lock poisoned").ast.clone();
+        let request = rhai::serde::to_dynamic(request)?;
+        Ok(Routing {
+            key: self.call(&ast, "key", &request)?,
+            destination: self.call(&ast, "destination", &request)?,
+            policy: self.call(&ast, "policy", &request)?,
+        })
+    }
+
+    fn call(&self, ast: &AST, name: &str, request: &Dynamic) -> Result<Option<String>> {
+        if !ast.iter_functions().any(|f| f.name == name && f.params.len() == 1) {
+            return Ok(None);
+        }
+        let options = CallFnOptions::new().eval_ast(false);
+        let result: Dynamic = self
+            .engine
+            .call_fn_with_options(options, &mut Scope::new(), ast, name, (request.clone(),))
+            .map_err(|e| anyhow!("script function {} failed: {}", name, e))?;
+        if result.is_unit() {
+            return Ok(None);
+        }
+        result
+            .into_string()
+            .map(Some)
+            .map_err(|t| anyhow!("script function {} returned {} instead of a string", name, t))
+    }
+}
+
+fn compile(engine: &Engine, path: &str) -> Result<Loaded> {
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let source = std::fs::read_to_string(path).with_context(|| format!("failed to read script {}", path))?;
+    let ast = engine.compile(source).map_err(|e| anyhow!("failed to compile script {}: {}", path, e))?;
+    Ok(Loaded {
+        ast: Arc::new(ast),
+        modified,
+    })
+}
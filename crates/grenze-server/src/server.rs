@@ -0,0 +1,298 @@
+//! The listening socket and the runtime serving it, with the timeouts that
+//! keep slow clients from pinning connections and memory indefinitely.
+
+use anyhow::{bail, Context, Result};
+use axum::{body::{Body, HttpBody}, extract::{ConnectInfo, Request, State}, middleware::Next, response::Response, serve::Listener, Router};
+use hyper::body::{Frame, Incoming, SizeHint};
+use hyper_util::{rt::{TokioExecutor, TokioIo, TokioTimer}, server::{conn::auto, graceful::GracefulShutdown}, service::TowerToHyperService};
+use serde::Deserialize;
+use std::{future::Future, io, net::SocketAddr, os::fd::{AsRawFd, RawFd}, pin::Pin, sync::Arc, task::{Context as TaskContext, Poll}, time::Duration};
+use tokio::{io::{AsyncRead, AsyncWrite, ReadBuf}, net::{TcpListener, TcpSocket, TcpStream}, runtime::Runtime, sync::{OwnedSemaphorePermit, Semaphore}, time::{Instant, Sleep}};
+use tower::ServiceExt;
+
+use crate::{logging, systemd, upgrade};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Disables Nagle's algorithm on accepted connections, so small responses
+    /// are sent without delay.
+    pub tcp_nodelay: bool,
+    /// Lets several processes listen on the port at once, e.g. the old and
+    /// the new instance during a deploy. The kernel spreads connections
+    /// across them.
+    pub reuse_port: bool,
+    /// Connections the kernel queues until they are accepted.
+    pub backlog: u32,
+    /// Most connections served at once; further ones wait in the backlog.
+    pub max_connections: Option<usize>,
+    /// Threads of the tokio runtime; one per CPU core if unset.
+    pub worker_threads: Option<usize>,
+    /// Time clients have to send the headers of a request over HTTP/1,
+    /// counted from the connection or the previous response.
+    pub header_read_timeout_ms: Option<u64>,
+    /// Time clients have to send the body of a request, counted from its
+    /// headers.
+    pub body_read_timeout_ms: Option<u64>,
+    /// Time writing a response may stall on a client not reading it before
+    /// the connection is closed.
+    pub write_timeout_ms: Option<u64>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            tcp_nodelay: false,
+            reuse_port: false,
+            backlog: 1024,
+            max_connections: None,
+            worker_threads: None,
+            header_read_timeout_ms: Some(30_000),
+            body_read_timeout_ms: None,
+            write_timeout_ms: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.backlog == 0 {
+            bail!("server backlog must be positive");
+        }
+        if self.max_connections == Some(0) || self.worker_threads == Some(0) {
+            bail!("server max_connections and worker_threads must be positive");
+        }
+        if [self.header_read_timeout_ms, self.body_read_timeout_ms, self.write_timeout_ms].contains(&Some(0)) {
+            bail!("server timeouts must be positive");
+        }
+        Ok(())
+    }
+
+    pub fn runtime(&self) -> io::Result<Runtime> {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        if let Some(threads) = self.worker_threads {
+            builder.worker_threads(threads);
+        }
+        builder.enable_all().build()
+    }
+
+    /// Listens on `addr` with the configured socket options, or on the
+    /// listener handed over by the previous process during an upgrade or
+    /// passed by systemd, which keeps its socket options apart from
+    /// `tcp_nodelay`.
+    pub fn bind(&self, addr: SocketAddr) -> Result<ServerListener> {
+        let inherited = match upgrade::inherited()? {
+            Some(listener) => Some(listener),
+            None => systemd::listener()?,
+        };
+        if let Some(listener) = inherited {
+            return Ok(self.listener(TcpListener::from_std(listener)?));
+        }
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        socket.set_reuseaddr(true)?;
+        if self.reuse_port {
+            socket.set_reuseport(true)?;
+        }
+        socket.bind(addr).with_context(|| format!("failed to bind {}", addr))?;
+        Ok(self.listener(socket.listen(self.backlog)?))
+    }
+
+    fn listener(&self, listener: TcpListener) -> ServerListener {
+        ServerListener {
+            listener,
+            tcp_nodelay: self.tcp_nodelay,
+            permits: self.max_connections.map(|n| Arc::new(Semaphore::new(n))),
+            header_read_timeout: self.header_read_timeout_ms.map(Duration::from_millis),
+            write_timeout: self.write_timeout_ms.map(Duration::from_millis),
+        }
+    }
+}
+
+/// Listener applying the configured options to accepted connections.
+pub struct ServerListener {
+    listener: TcpListener,
+    tcp_nodelay: bool,
+    permits: Option<Arc<Semaphore>>,
+    header_read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl ServerListener {
+    /// Builder serving HTTP/1 and HTTP/2 on accepted connections.
+    pub fn http(&self) -> auto::Builder<TokioExecutor> {
+        let mut builder = auto::Builder::new(TokioExecutor::new());
+        builder.http1().timer(TokioTimer::new()).header_read_timeout(self.header_read_timeout);
+        builder
+    }
+
+    pub fn header_read_timeout(&self) -> Option<Duration> {
+        self.header_read_timeout
+    }
+}
+
+impl Listener for ServerListener {
+    type Io = Connection;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        // Connections over the limit are left in the backlog
+        let permit = match &self.permits {
+            Some(permits) => Some(permits.clone().acquire_owned().await.expect("connection semaphore closed")),
+            None => None,
+        };
+        loop {
+            match self.listener.accept().await {
+                Ok((stream, peer)) => {
+                    if self.tcp_nodelay && let Err(e) = stream.set_nodelay(true) {
+                        logging::warn("tcp_nodelay", format_args!("Failed to set TCP_NODELAY for {}: {}", peer, e));
+                    }
+                    let connection = Connection {
+                        stream,
+                        _permit: permit,
+                        write_timeout: self.write_timeout,
+                        stalled: None,
+                    };
+                    return (connection, peer);
+                },
+                Err(e) if matches!(e.kind(), io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset) => {},
+                Err(e) => {
+                    // Likely out of file descriptors, which closing connections frees
+                    logging::warn("accept", format_args!("Failed to accept connection: {}", e));
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                },
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.listener.local_addr()
+    }
+}
+
+impl AsRawFd for ServerListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+/// An accepted connection, counting towards `max_connections` until dropped.
+pub struct Connection {
+    stream: TcpStream,
+    _permit: Option<OwnedSemaphorePermit>,
+    write_timeout: Option<Duration>,
+    /// Deadline of a write waiting for the client to read.
+    stalled: Option<Pin<Box<Sleep>>>,
+}
+
+impl Connection {
+    /// Fails a write that has been pending for longer than `write_timeout`.
+    fn within_write_timeout<T>(&mut self, cx: &mut TaskContext<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        let (Poll::Pending, Some(timeout)) = (&poll, self.write_timeout) else {
+            self.stalled = None;
+            return poll;
+        };
+        let stalled = self.stalled.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        match stalled.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "client stopped reading the response"))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.stream).poll_write(cx, buf);
+        self.within_write_timeout(cx, poll)
+    }
+
+    fn poll_write_vectored(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, bufs: &[io::IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.stream).poll_write_vectored(cx, bufs);
+        self.within_write_timeout(cx, poll)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.stream).poll_flush(cx);
+        self.within_write_timeout(cx, poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Serves the router over plain HTTP, attaching the peer address to every
+/// request.
+pub async fn serve(mut listener: ServerListener, app: Router, shutdown: impl Future<Output = ()>) -> Result<()> {
+    let builder = listener.http();
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => break,
+        };
+        let service = app.clone().map_request(move |mut req: Request<Incoming>| {
+            req.extensions_mut().insert(ConnectInfo(peer));
+            req
+        });
+        let conn = builder.serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(service)).into_owned();
+        let conn = graceful.watch(conn);
+        tokio::spawn(async move {
+            if let Err(e) = conn.await {
+                logging::warn("connection", format_args!("Connection from {} failed: {}", peer, e));
+            }
+        });
+    }
+
+    graceful.shutdown().await;
+    Ok(())
+}
+
+/// Fails reading a request body once `body_read_timeout_ms` has passed since
+/// its headers arrived.
+pub async fn body_read_timeout(State(timeout): State<Duration>, req: Request, next: Next) -> Response {
+    let deadline = Instant::now() + timeout;
+    next.run(req.map(|body| Body::new(DeadlineBody { body, deadline: Box::pin(tokio::time::sleep_until(deadline)) }))).await
+}
+
+struct DeadlineBody {
+    body: Body,
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl HttpBody for DeadlineBody {
+    type Data = axum::body::Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if let Poll::Ready(frame) = Pin::new(&mut self.body).poll_frame(cx) {
+            return Poll::Ready(frame);
+        }
+        match self.deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Some(Err(axum::Error::new(io::Error::new(io::ErrorKind::TimedOut, "request body not received in time"))))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
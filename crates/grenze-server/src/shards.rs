@@ -0,0 +1,359 @@
+//! Buckets spread over several independent Redis servers, for key
+//! cardinalities one server cannot hold.
+//!
+//! Buckets are assigned to shards by consistent hashing of their names onto a
+//! ring of virtual nodes. The ring is built from shard names rather than
+//! URLs, so moving a shard to another server keeps its buckets, and adding or
+//! removing a shard only moves the buckets of the ring segments it gains or
+//! loses. While a shard is unhealthy its buckets move to the next healthy
+//! shard on the ring, starting over empty, and return once it recovers.
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use redis::IntoConnectionInfo;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{collections::HashSet, sync::{atomic::{AtomicBool, AtomicU32, Ordering}, Arc}, time::Duration};
+
+use crate::{erasure::{Erasure, Tenant}, limiter::{Admission, BucketState, LimiterStore, QuotaAdmission, QuotaLimit, QuotaState, RedisConfig, RedisStore, RAW_USAGE}, usage::{Granularity, UsageCount}};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedisShardsConfig {
+    pub shards: Vec<RedisShardConfig>,
+    /// Points per shard on the hash ring; more spread buckets more evenly.
+    #[serde(default = "default_vnodes")]
+    pub vnodes: u32,
+    /// Consecutive failed calls that mark a shard unhealthy.
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    /// Time a shard call may take before it counts as failed. Keep it below
+    /// `limiter.breaker.timeout_ms`, which cancels slower calls unrecorded.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// How often unhealthy shards are checked for recovery.
+    #[serde(default = "default_probe_interval_ms")]
+    pub probe_interval_ms: u64,
+}
+
+fn default_vnodes() -> u32 {
+    160
+}
+
+fn default_failure_threshold() -> u32 {
+    3
+}
+
+fn default_timeout_ms() -> u64 {
+    250
+}
+
+fn default_probe_interval_ms() -> u64 {
+    5_000
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedisShardConfig {
+    /// Stable identity of the shard on the hash ring.
+    pub name: String,
+    pub url: String,
+}
+
+impl RedisShardsConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.shards.is_empty() {
+            bail!("redis_shards requires at least one shard");
+        }
+        if self.vnodes == 0 {
+            bail!("redis_shards vnodes must be positive");
+        }
+        let mut names = HashSet::new();
+        for shard in &self.shards {
+            if !names.insert(shard.name.as_str()) {
+                bail!("duplicate redis shard name '{}'", shard.name);
+            }
+            shard
+                .url
+                .as_str()
+                .into_connection_info()
+                .with_context(|| format!("invalid url of redis shard '{}'", shard.name))?;
+        }
+        Ok(())
+    }
+}
+
+struct Shard {
+    name: String,
+    store: RedisStore,
+    healthy: AtomicBool,
+    failures: AtomicU32,
+}
+
+/// Store routing each bucket to the Redis shard owning it.
+pub struct ShardedStore {
+    config: RedisShardsConfig,
+    shards: Vec<Shard>,
+    /// Virtual nodes as (position, shard index), sorted by position.
+    ring: Vec<(u64, usize)>,
+}
+
+impl ShardedStore {
+    /// Connects to every shard; all of them must be reachable at startup.
+    pub async fn connect(config: &RedisShardsConfig, redis: &RedisConfig) -> Result<Self> {
+        let mut shards = Vec::with_capacity(config.shards.len());
+        for shard in &config.shards {
+            let store = RedisStore::connect(&shard.url, redis)
+                .await
+                .with_context(|| format!("failed to connect to redis shard '{}'", shard.name))?;
+            shards.push(Shard {
+                name: shard.name.clone(),
+                store,
+                healthy: AtomicBool::new(true),
+                failures: AtomicU32::new(0),
+            });
+        }
+        let mut ring: Vec<(u64, usize)> = config
+            .shards
+            .iter()
+            .enumerate()
+            .flat_map(|(i, shard)| (0..config.vnodes).map(move |v| (position(&format!("{}#{}", shard.name, v)), i)))
+            .collect();
+        ring.sort_unstable();
+        Ok(Self {
+            config: config.clone(),
+            shards,
+            ring,
+        })
+    }
+
+    /// Index of the shard owning `name`: the first healthy shard at or after
+    /// its position on the ring, or its primary shard if none is healthy.
+    fn owner(&self, name: &str) -> usize {
+        let start = self.ring.partition_point(|(p, _)| *p < position(name));
+        let mut nodes = self.ring[start..].iter().chain(&self.ring[..start]).map(|(_, i)| *i);
+        let primary = nodes.clone().next().expect("ring has at least one node");
+        nodes.find(|i| self.shards[*i].healthy.load(Ordering::Relaxed)).unwrap_or(primary)
+    }
+
+    fn route(&self, name: &str) -> &Shard {
+        &self.shards[self.owner(name)]
+    }
+
+    fn record<T>(&self, shard: &Shard, result: &Result<T>) {
+        match result {
+            Ok(_) => shard.failures.store(0, Ordering::Relaxed),
+            Err(e) => {
+                let failures = shard.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if failures >= self.config.failure_threshold.max(1) && shard.healthy.swap(false, Ordering::Relaxed) {
+                    println!("Redis shard '{}' marked unhealthy after {} failed calls: {:#}", shard.name, failures, e);
+                }
+            },
+        }
+    }
+
+    /// Pings unhealthy shards every `probe_interval_ms`, returning them to the
+    /// ring once they answer.
+    pub fn spawn_probe(self: &Arc<Self>) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_millis(store.config.probe_interval_ms.max(100)));
+            loop {
+                ticker.tick().await;
+                let timeout = Duration::from_millis(store.config.timeout_ms);
+                for shard in store.shards.iter().filter(|s| !s.healthy.load(Ordering::Relaxed)) {
+                    if let Ok(Ok(())) = tokio::time::timeout(timeout, shard.store.ping()).await {
+                        shard.failures.store(0, Ordering::Relaxed);
+                        shard.healthy.store(true, Ordering::Relaxed);
+                        println!("Redis shard '{}' is healthy again", shard.name);
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Position of `name` on the ring, stable across instances and releases.
+fn position(name: &str) -> u64 {
+    let digest = Sha256::digest(name.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().expect("digest has 8 bytes"))
+}
+
+#[async_trait]
+impl LimiterStore for ShardedStore {
+    async fn allow(&self, bucket: &str, cost: f64, capacity: u32, leak_per_sec: f64, now_ms: i64) -> Result<Admission> {
+        let shard = self.route(bucket);
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        let result = match tokio::time::timeout(timeout, shard.store.allow(bucket, cost, capacity, leak_per_sec, now_ms)).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("redis shard '{}' timed out after {}ms", shard.name, self.config.timeout_ms)),
+        };
+        self.record(shard, &result);
+        result
+    }
+
+    async fn remember(&self, key: &str, ttl_secs: u64) -> Result<bool> {
+        self.route(key).store.remember(key, ttl_secs).await
+    }
+
+    async fn forget(&self, key: &str) {
+        self.route(key).store.forget(key).await
+    }
+
+    async fn evict(&self, bucket: &str) {
+        self.route(bucket).store.evict(bucket).await
+    }
+
+    async fn charge(&self, bucket: &str, amount: f64, capacity: u32, leak_per_sec: f64, now_ms: i64) -> Result<f64> {
+        self.route(bucket).store.charge(bucket, amount, capacity, leak_per_sec, now_ms).await
+    }
+
+    async fn level(&self, bucket: &str, leak_per_sec: f64, now_ms: i64) -> Result<f64> {
+        self.route(bucket).store.level(bucket, leak_per_sec, now_ms).await
+    }
+
+    async fn first_seen(&self, key: &str, now_ms: i64, ttl_secs: u64) -> Result<i64> {
+        self.route(key).store.first_seen(key, now_ms, ttl_secs).await
+    }
+
+    async fn cookie_jar(&self, key: &str) -> Result<Option<String>> {
+        self.route(key).store.cookie_jar(key).await
+    }
+
+    async fn store_cookie_jar(&self, key: &str, jar: &str, ttl_secs: u64) -> Result<()> {
+        self.route(key).store.store_cookie_jar(key, jar, ttl_secs).await
+    }
+
+    async fn lock(&self, name: &str, holder: &str, ttl_ms: u64) -> Result<bool> {
+        self.route(name).store.lock(name, holder, ttl_ms).await
+    }
+
+    async fn unlock(&self, name: &str, holder: &str) -> Result<()> {
+        self.route(name).store.unlock(name, holder).await
+    }
+
+    async fn record_usage(&self, counts: &[UsageCount], ttl_secs: u64) -> Result<()> {
+        self.route(RAW_USAGE).store.record_usage(counts, ttl_secs).await
+    }
+
+    /// Takes the raw usage from every shard, as it moves along with the
+    /// shard owning it while that is down.
+    async fn take_usage(&self, before_ms: i64) -> Result<Vec<UsageCount>> {
+        let mut counts = Vec::new();
+        for shard in &self.shards {
+            counts.extend(shard.store.take_usage(before_ms).await.with_context(|| format!("redis shard '{}'", shard.name))?);
+        }
+        Ok(counts)
+    }
+
+    async fn roll_up_usage(&self, granularity: Granularity, counts: &[UsageCount], retention_secs: u64) -> Result<()> {
+        let mut assigned = vec![Vec::new(); self.shards.len()];
+        for count in counts {
+            assigned[self.owner(&count.key)].push(count.clone());
+        }
+        for (shard, counts) in self.shards.iter().zip(assigned) {
+            if !counts.is_empty() {
+                shard.store.roll_up_usage(granularity, &counts, retention_secs).await.with_context(|| format!("redis shard '{}'", shard.name))?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn usage(&self, key: &str, granularity: Granularity, from_ms: i64, to_ms: i64) -> Result<Vec<UsageCount>> {
+        self.route(key).store.usage(key, granularity, from_ms, to_ms).await
+    }
+
+    async fn usage_keys(&self, granularity: Granularity, from_ms: i64, to_ms: i64) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for shard in &self.shards {
+            keys.extend(shard.store.usage_keys(granularity, from_ms, to_ms).await.with_context(|| format!("redis shard '{}'", shard.name))?);
+        }
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    async fn purge_usage(&self, granularity: Option<Granularity>, before_ms: i64) -> Result<u64> {
+        let mut purged = 0;
+        for shard in &self.shards {
+            purged += shard.store.purge_usage(granularity, before_ms).await.with_context(|| format!("redis shard '{}'", shard.name))?;
+        }
+        Ok(purged)
+    }
+
+    /// Routed by the counter, whose shard also holds the throttling bucket.
+    async fn allow_quota(&self, quota: &QuotaLimit, cost: f64, now_ms: i64) -> Result<QuotaAdmission> {
+        let shard = self.route(&quota.counter);
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        let result = match tokio::time::timeout(timeout, shard.store.allow_quota(quota, cost, now_ms)).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("redis shard '{}' timed out after {}ms", shard.name, self.config.timeout_ms)),
+        };
+        self.record(shard, &result);
+        result
+    }
+
+    async fn charge_quota(&self, counter: &str, bytes: u64, until_ms: i64) -> Result<()> {
+        self.route(counter).store.charge_quota(counter, bytes, until_ms).await
+    }
+
+    /// Erases the tenant from every shard, as its data moves along with the
+    /// shard owning it while that is down.
+    async fn erase(&self, tenant: &Tenant) -> Result<Erasure> {
+        let mut erasure = Erasure::default();
+        for shard in &self.shards {
+            erasure.add(&shard.store.erase(tenant).await.with_context(|| format!("redis shard '{}'", shard.name))?);
+        }
+        Ok(erasure)
+    }
+
+    /// Succeeds while any shard is healthy, as the others' buckets fail over.
+    async fn ping(&self) -> Result<()> {
+        if self.shards.iter().any(|s| s.healthy.load(Ordering::Relaxed)) {
+            return Ok(());
+        }
+        bail!("no redis shard is healthy")
+    }
+
+    async fn export(&self) -> Result<Vec<BucketState>> {
+        let mut buckets = Vec::new();
+        for shard in &self.shards {
+            buckets.extend(shard.store.export().await.with_context(|| format!("redis shard '{}'", shard.name))?);
+        }
+        Ok(buckets)
+    }
+
+    async fn import(&self, buckets: &[BucketState], capacity: u32, leak_per_sec: f64) -> Result<()> {
+        let mut assigned = vec![Vec::new(); self.shards.len()];
+        for bucket in buckets {
+            assigned[self.owner(&bucket.bucket)].push(bucket.clone());
+        }
+        for (shard, buckets) in self.shards.iter().zip(assigned) {
+            if !buckets.is_empty() {
+                shard.store.import(&buckets, capacity, leak_per_sec).await.with_context(|| format!("redis shard '{}'", shard.name))?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn export_quotas(&self, now_ms: i64) -> Result<Vec<QuotaState>> {
+        let mut quotas = Vec::new();
+        for shard in &self.shards {
+            quotas.extend(shard.store.export_quotas(now_ms).await.with_context(|| format!("redis shard '{}'", shard.name))?);
+        }
+        Ok(quotas)
+    }
+
+    async fn import_quotas(&self, quotas: &[QuotaState]) -> Result<()> {
+        let mut assigned = vec![Vec::new(); self.shards.len()];
+        for quota in quotas {
+            assigned[self.owner(&quota.counter)].push(quota.clone());
+        }
+        for (shard, quotas) in self.shards.iter().zip(assigned) {
+            if !quotas.is_empty() {
+                shard.store.import_quotas(&quotas).await.with_context(|| format!("redis shard '{}'", shard.name))?;
+            }
+        }
+        Ok(())
+    }
+}
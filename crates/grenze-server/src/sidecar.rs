@@ -0,0 +1,102 @@
+//! Sidecar mode: grenze runs next to an application in its pod, which sends
+//! its requests to grenze instead of the upstream. All requests of the pod
+//! share one rate limit key, coordinated with the other pods through Redis.
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+/// Annotations file of the Kubernetes downward API.
+const DEFAULT_ANNOTATIONS_PATH: &str = "/etc/podinfo/annotations";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SidecarConfig {
+    /// Where requests are forwarded to.
+    pub upstream: SidecarUpstream,
+    /// Source of the rate limit key of the pod's requests.
+    pub key: SidecarKey,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum SidecarUpstream {
+    /// Port on localhost, e.g. of another container in the pod.
+    Port(u16),
+    /// Base URL the request path is appended to.
+    Url(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum SidecarKey {
+    /// Environment variable holding the key, e.g. set from a pod label or
+    /// annotation through the downward API.
+    Env(String),
+    /// Pod annotation, read from the downward API annotations file.
+    Annotation {
+        name: String,
+        #[serde(default = "default_annotations_path")]
+        path: String,
+    },
+}
+
+fn default_annotations_path() -> String {
+    DEFAULT_ANNOTATIONS_PATH.to_string()
+}
+
+/// Upstream and key of the pod, resolved at startup.
+#[derive(Debug, Clone)]
+pub struct Sidecar {
+    pub upstream: String,
+    pub key: String,
+}
+
+impl SidecarConfig {
+    pub fn validate(&self) -> Result<()> {
+        self.resolve().map(|_| ())
+    }
+
+    pub fn resolve(&self) -> Result<Sidecar> {
+        let upstream = match &self.upstream {
+            SidecarUpstream::Port(port) => format!("http://127.0.0.1:{}", port),
+            SidecarUpstream::Url(base) => {
+                let url = reqwest::Url::parse(base).context("sidecar upstream")?;
+                if !matches!(url.scheme(), "http" | "https") || url.query().is_some() {
+                    bail!("sidecar upstream must be an http(s) URL without query");
+                }
+                base.trim_end_matches('/').to_string()
+            },
+        };
+        let key = match &self.key {
+            SidecarKey::Env(var) => std::env::var(var).with_context(|| format!("sidecar key variable {} is not set", var))?,
+            SidecarKey::Annotation { name, path } => {
+                let annotations = std::fs::read_to_string(path).with_context(|| format!("failed to read annotations file {}", path))?;
+                annotation(&annotations, name).with_context(|| format!("pod has no annotation {}", name))?
+            },
+        };
+        if key.is_empty() {
+            bail!("sidecar key must not be empty");
+        }
+        Ok(Sidecar { upstream, key })
+    }
+}
+
+/// Value of annotation `name` in a downward API annotations file, which holds
+/// one `name="value"` line per annotation with the value quoted and escaped.
+fn annotation(annotations: &str, name: &str) -> Option<String> {
+    let quoted = annotations.lines().find_map(|line| line.split_once('=').filter(|(n, _)| *n == name).map(|(_, v)| v))?;
+    let quoted = quoted.strip_prefix('"')?.strip_suffix('"')?;
+    let mut value = String::with_capacity(quoted.len());
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                escaped => value.push(escaped),
+            },
+            c => value.push(c),
+        }
+    }
+    Some(value)
+}
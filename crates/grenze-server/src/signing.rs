@@ -0,0 +1,84 @@
+use axum::http::HeaderMap;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// Verification of HMAC signed proxy requests.
+///
+/// Clients sign `"{timestamp}\n{hex(sha256(body))}"` with the shared secret of
+/// their rate limit key using HMAC-SHA256 and send the hex encoded result in
+/// the signature header, alongside the unix timestamp they signed.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SigningConfig {
+    pub signature_header: String,
+    pub timestamp_header: String,
+    /// Maximum clock skew, and how long a used signature is remembered to
+    /// reject replays.
+    pub window_secs: u64,
+    /// Shared secrets by rate limit key.
+    pub secrets: HashMap<String, String>,
+    /// Whether keys without a secret are rejected. If unset, only keys with a
+    /// configured secret must sign their requests.
+    pub required: bool,
+}
+
+impl Default for SigningConfig {
+    fn default() -> Self {
+        Self {
+            signature_header: "x-grenze-signature".to_string(),
+            timestamp_header: "x-grenze-timestamp".to_string(),
+            window_secs: 300,
+            secrets: HashMap::new(),
+            required: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Verification {
+    /// The key has no secret and signing is optional.
+    Skipped,
+    /// The signature is valid; it must be recorded to prevent replays for
+    /// `ttl_secs`, until its timestamp leaves the window.
+    Valid { signature: String, ttl_secs: u64 },
+    Invalid(&'static str),
+}
+
+impl SigningConfig {
+    /// Verifies the signature of a request received at `now_ms`.
+    pub fn verify(&self, key: &str, headers: &HeaderMap, body: &[u8], now_ms: i64) -> Verification {
+        let Some(secret) = self.secrets.get(key) else {
+            return match self.required {
+                true => Verification::Invalid("No signing secret configured for key"),
+                false => Verification::Skipped,
+            };
+        };
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok()).map(str::trim);
+        let (Some(signature), Some(timestamp)) = (header(&self.signature_header), header(&self.timestamp_header)) else {
+            return Verification::Invalid("Request must be signed");
+        };
+        let Ok(ts) = timestamp.parse::<u64>() else {
+            return Verification::Invalid("Invalid signature timestamp");
+        };
+        let now = now_ms.max(0) as u64 / 1000;
+        if now.abs_diff(ts) > self.window_secs {
+            return Verification::Invalid("Signature timestamp outside of the allowed window");
+        }
+        let Ok(provided) = hex::decode(signature) else {
+            return Verification::Invalid("Malformed signature");
+        };
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any length");
+        mac.update(format!("{}\n{}", timestamp, hex::encode(Sha256::digest(body))).as_bytes());
+        match mac.verify_slice(&provided) {
+            Ok(()) => Verification::Valid {
+                signature: signature.to_ascii_lowercase(),
+                // Timestamps ahead of the clock stay valid for longer
+                ttl_secs: (ts + self.window_secs).saturating_sub(now).max(1),
+            },
+            Err(_) => Verification::Invalid("Signature mismatch"),
+        }
+    }
+}
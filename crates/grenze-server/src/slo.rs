@@ -0,0 +1,286 @@
+//! Service level objectives of upstreams: success rate and latency of the
+//! responses of every destination host over a rolling window, reported on
+//! `/admin/slo` and `/metrics`, with alerts when a destination violates its
+//! objectives.
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{collections::{HashMap, HashSet, VecDeque}, fmt::Write, sync::{Arc, Mutex}, time::Duration};
+use utoipa::ToSchema;
+
+use crate::{limiter::Clock, logging};
+
+/// Responses kept per destination, so busy upstreams cannot grow the window
+/// without limit.
+const MAX_SAMPLES: usize = 10_000;
+/// Upper bound of tracked destinations; responses of further ones are not
+/// tracked.
+const MAX_DESTINATIONS: usize = 1000;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SloConfig {
+    /// Rolling window the objectives are evaluated over.
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    /// Objectives of every destination without its own.
+    #[serde(default)]
+    pub objectives: Objectives,
+    /// Objectives by destination host.
+    #[serde(default)]
+    pub destinations: HashMap<String, Objectives>,
+    /// Responses needed in the window before a destination can violate its
+    /// objectives, so single failures of idle upstreams do not alert.
+    #[serde(default = "default_min_requests")]
+    pub min_requests: usize,
+    /// URL alerts are POSTed to when a destination starts or stops violating
+    /// its objectives; alerts are only logged without one.
+    #[serde(default)]
+    pub webhook: Option<String>,
+    /// How often destinations are checked for alerts.
+    #[serde(default = "default_check_interval_secs")]
+    pub check_interval_secs: u64,
+}
+
+fn default_window_secs() -> u64 {
+    300
+}
+
+fn default_min_requests() -> usize {
+    20
+}
+
+fn default_check_interval_secs() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Objectives {
+    /// Smallest share of successful responses, e.g. `0.999`. Failed requests
+    /// and 5xx responses count as errors.
+    pub success_rate: Option<f64>,
+    /// Latency the `latency_percentile` of responses must stay within.
+    pub latency_ms: Option<u64>,
+    #[serde(default = "default_latency_percentile")]
+    pub latency_percentile: f64,
+}
+
+fn default_latency_percentile() -> f64 {
+    99.0
+}
+
+impl SloConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.window_secs == 0 || self.check_interval_secs == 0 {
+            bail!("slo window_secs and check_interval_secs must be positive");
+        }
+        if let Some(webhook) = &self.webhook
+            && !matches!(reqwest::Url::parse(webhook).map(|u| u.scheme().to_string()).as_deref(), Ok("http" | "https"))
+        {
+            bail!("slo webhook must be an http(s) URL");
+        }
+        for objectives in std::iter::once(&self.objectives).chain(self.destinations.values()) {
+            if let Some(rate) = objectives.success_rate
+                && !(0.0..=1.0).contains(&rate)
+            {
+                bail!("slo success_rate must be between 0 and 1");
+            }
+            if !(objectives.latency_percentile > 0.0 && objectives.latency_percentile <= 100.0) {
+                bail!("slo latency_percentile must be in (0, 100]");
+            }
+        }
+        Ok(())
+    }
+
+    fn objectives(&self, destination: &str) -> &Objectives {
+        self.destinations.get(destination).unwrap_or(&self.objectives)
+    }
+}
+
+/// A response of a destination.
+struct Sample {
+    at_ms: i64,
+    latency_ms: u64,
+    ok: bool,
+}
+
+/// Success rate and latency of a destination over the window.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SloStatus {
+    pub destination: String,
+    pub requests: usize,
+    pub errors: usize,
+    pub success_rate: f64,
+    pub latency_p50_ms: u64,
+    pub latency_p90_ms: u64,
+    pub latency_p99_ms: u64,
+    /// Objectives the destination misses; empty while it meets them or saw
+    /// fewer than `min_requests` responses.
+    pub violations: Vec<String>,
+}
+
+/// Recent responses by destination host.
+pub struct SloTracker {
+    config: SloConfig,
+    destinations: Mutex<HashMap<String, VecDeque<Sample>>>,
+}
+
+impl SloTracker {
+    pub fn new(config: &SloConfig) -> Self {
+        Self {
+            config: config.clone(),
+            destinations: Mutex::default(),
+        }
+    }
+
+    /// Records a response of `destination`; `ok` is false for failed requests
+    /// and 5xx responses.
+    pub fn record(&self, destination: &str, latency: Duration, ok: bool, now_ms: i64) {
+        let mut destinations = self.destinations.lock().expect("slo lock poisoned");
+        if destinations.len() >= MAX_DESTINATIONS && !destinations.contains_key(destination) {
+            return;
+        }
+        let samples = destinations.entry(destination.to_string()).or_default();
+        if samples.len() >= MAX_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(Sample {
+            at_ms: now_ms,
+            latency_ms: latency.as_millis() as u64,
+            ok,
+        });
+    }
+
+    /// Status of every destination with responses in the window, sorted by
+    /// destination. Destinations without any are forgotten.
+    pub fn statuses(&self, now_ms: i64) -> Vec<SloStatus> {
+        let window_ms = (self.config.window_secs * 1000) as i64;
+        let mut destinations = self.destinations.lock().expect("slo lock poisoned");
+        destinations.retain(|_, samples| {
+            while samples.front().is_some_and(|s| now_ms - s.at_ms > window_ms) {
+                samples.pop_front();
+            }
+            !samples.is_empty()
+        });
+        let mut statuses: Vec<SloStatus> = destinations.iter().map(|(destination, samples)| self.status(destination, samples)).collect();
+        statuses.sort_by(|a, b| a.destination.cmp(&b.destination));
+        statuses
+    }
+
+    fn status(&self, destination: &str, samples: &VecDeque<Sample>) -> SloStatus {
+        let mut latencies: Vec<u64> = samples.iter().map(|s| s.latency_ms).collect();
+        latencies.sort_unstable();
+        let errors = samples.iter().filter(|s| !s.ok).count();
+        let success_rate = if samples.is_empty() { 1.0 } else { 1.0 - errors as f64 / samples.len() as f64 };
+
+        let objectives = self.config.objectives(destination);
+        let mut violations = Vec::new();
+        if samples.len() >= self.config.min_requests {
+            if let Some(target) = objectives.success_rate
+                && success_rate < target
+            {
+                violations.push(format!("success rate {:.4} below {}", success_rate, target));
+            }
+            if let Some(target) = objectives.latency_ms {
+                let latency = percentile(&latencies, objectives.latency_percentile);
+                if latency > target {
+                    violations.push(format!("p{} latency {}ms above {}ms", objectives.latency_percentile, latency, target));
+                }
+            }
+        }
+        SloStatus {
+            destination: destination.to_string(),
+            requests: samples.len(),
+            errors,
+            success_rate,
+            latency_p50_ms: percentile(&latencies, 50.0),
+            latency_p90_ms: percentile(&latencies, 90.0),
+            latency_p99_ms: percentile(&latencies, 99.0),
+            violations,
+        }
+    }
+
+    /// Renders the statuses in the Prometheus text exposition format.
+    pub fn render(&self, now_ms: i64) -> String {
+        let statuses = self.statuses(now_ms);
+        let mut out = String::new();
+        out.push_str("# HELP grenze_slo_requests Responses of a destination in the SLO window.\n");
+        out.push_str("# TYPE grenze_slo_requests gauge\n");
+        for s in &statuses {
+            let _ = writeln!(out, "grenze_slo_requests{{destination=\"{}\"}} {}", crate::metrics::escape(&s.destination), s.requests);
+        }
+        out.push_str("# HELP grenze_slo_success_ratio Share of successful responses of a destination in the SLO window.\n");
+        out.push_str("# TYPE grenze_slo_success_ratio gauge\n");
+        for s in &statuses {
+            let _ = writeln!(out, "grenze_slo_success_ratio{{destination=\"{}\"}} {}", crate::metrics::escape(&s.destination), s.success_rate);
+        }
+        out.push_str("# HELP grenze_slo_latency_ms Response latency of a destination in the SLO window.\n");
+        out.push_str("# TYPE grenze_slo_latency_ms gauge\n");
+        for s in &statuses {
+            let destination = crate::metrics::escape(&s.destination);
+            for (q, latency) in [("0.5", s.latency_p50_ms), ("0.9", s.latency_p90_ms), ("0.99", s.latency_p99_ms)] {
+                let _ = writeln!(out, "grenze_slo_latency_ms{{destination=\"{}\",quantile=\"{}\"}} {}", destination, q, latency);
+            }
+        }
+        out.push_str("# HELP grenze_slo_violating Whether a destination violates its objectives.\n");
+        out.push_str("# TYPE grenze_slo_violating gauge\n");
+        for s in &statuses {
+            let _ = writeln!(out, "grenze_slo_violating{{destination=\"{}\"}} {}", crate::metrics::escape(&s.destination), !s.violations.is_empty() as u8);
+        }
+        out
+    }
+
+    /// Checks the destinations every `check_interval_secs` in the background,
+    /// alerting when one starts or stops violating its objectives.
+    pub fn spawn_alerts(self: &Arc<Self>, http_client: reqwest::Client, clock: Arc<dyn Clock>) {
+        let tracker = self.clone();
+        tokio::spawn(async move {
+            let mut violating: HashSet<String> = HashSet::new();
+            let mut ticker = tokio::time::interval(Duration::from_secs(tracker.config.check_interval_secs));
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let statuses = tracker.statuses(clock.now_ms());
+                let mut alerts = Vec::new();
+                for status in &statuses {
+                    let now_violating = !status.violations.is_empty();
+                    if now_violating != violating.contains(&status.destination) {
+                        alerts.push((status.clone(), now_violating));
+                    }
+                }
+                // Destinations gone from the window have recovered
+                for destination in &violating {
+                    if !statuses.iter().any(|s| &s.destination == destination) {
+                        alerts.push((tracker.status(destination, &VecDeque::new()), false));
+                    }
+                }
+                for (status, now_violating) in alerts {
+                    if now_violating {
+                        println!("SLO violated by {}: {}", status.destination, status.violations.join(", "));
+                        violating.insert(status.destination.clone());
+                    } else {
+                        println!("SLO met again by {}", status.destination);
+                        violating.remove(&status.destination);
+                    }
+                    if let Some(webhook) = &tracker.config.webhook {
+                        let alert = json!({ "violating": now_violating, "status": status });
+                        let result = http_client.post(webhook).json(&alert).send().await.and_then(|r| r.error_for_status());
+                        if let Err(e) = result {
+                            logging::warn("slo_alert", format_args!("Failed to send SLO alert for {}: {}", status.destination, e));
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+fn percentile(sorted: &[u64], percentile: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = ((percentile / 100.0 * sorted.len() as f64).ceil() as usize).clamp(1, sorted.len());
+    sorted[rank - 1]
+}
@@ -0,0 +1,195 @@
+//! Push exporter sending metrics to StatsD or DogStatsD over UDP, for hosts
+//! without Prometheus scrape infrastructure.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::{collections::HashMap, net::{ToSocketAddrs, UdpSocket}, sync::{Arc, Mutex}, time::Duration};
+
+use crate::{limiter::Clock, metrics::Metrics};
+
+/// Largest datagram sent, staying below common MTUs.
+const MAX_PACKET_BYTES: usize = 1400;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatsdConfig {
+    /// Agent address, e.g. `127.0.0.1:8125`.
+    pub address: String,
+    #[serde(default)]
+    pub flavor: StatsdFlavor,
+    /// Prefix of every metric name.
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// Tags added to every metric (DogStatsD only).
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+    /// The `key_prefix` tag is the rate limit key up to the first occurrence
+    /// of this delimiter.
+    #[serde(default = "default_key_delimiter")]
+    pub key_delimiter: String,
+    #[serde(default = "default_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+}
+
+fn default_prefix() -> String {
+    "grenze".to_string()
+}
+
+fn default_key_delimiter() -> String {
+    ":".to_string()
+}
+
+fn default_flush_interval_ms() -> u64 {
+    10_000
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatsdFlavor {
+    /// Plain StatsD; tag values become dotted name segments.
+    Statsd,
+    /// DogStatsD with `|#name:value` tags.
+    #[default]
+    Dogstatsd,
+}
+
+impl StatsdConfig {
+    pub fn validate(&self) -> Result<()> {
+        self.address
+            .to_socket_addrs()
+            .with_context(|| format!("invalid statsd address {}", self.address))?
+            .next()
+            .with_context(|| format!("statsd address {} does not resolve", self.address))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct RequestLabels {
+    outcome: &'static str,
+    policy: String,
+    key_prefix: String,
+    host: String,
+}
+
+/// Aggregates counters between flushes and sends them together with the
+/// bucket fill gauges.
+pub struct StatsdExporter {
+    config: StatsdConfig,
+    socket: UdpSocket,
+    requests: Mutex<HashMap<RequestLabels, u64>>,
+}
+
+impl StatsdExporter {
+    pub fn new(config: &StatsdConfig) -> Result<Self> {
+        let address = config
+            .address
+            .to_socket_addrs()?
+            .next()
+            .with_context(|| format!("statsd address {} does not resolve", config.address))?;
+        let socket = UdpSocket::bind(if address.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" })?;
+        socket.connect(address).with_context(|| format!("failed to reach statsd at {}", config.address))?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            config: config.clone(),
+            socket,
+            requests: Mutex::default(),
+        })
+    }
+
+    pub fn record_request(&self, policy: &str, key: &str, host: Option<&str>, allowed: bool) {
+        let key_prefix = match key.split_once(self.config.key_delimiter.as_str()) {
+            Some((prefix, _)) => prefix,
+            None => key,
+        };
+        let labels = RequestLabels {
+            outcome: if allowed { "allowed" } else { "limited" },
+            policy: policy.to_string(),
+            key_prefix: key_prefix.to_string(),
+            host: host.unwrap_or_default().to_string(),
+        };
+        *self.requests.lock().expect("statsd lock poisoned").entry(labels).or_default() += 1;
+    }
+
+    /// Flushes every `flush_interval_ms` in the background.
+    pub fn spawn(self: &Arc<Self>, metrics: Arc<Metrics>, clock: Arc<dyn Clock>) {
+        let exporter = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_millis(exporter.config.flush_interval_ms.max(100)));
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                exporter.flush(&metrics, clock.now_ms());
+            }
+        });
+    }
+
+    fn flush(&self, metrics: &Metrics, now_ms: i64) {
+        let mut lines = Vec::new();
+        let requests = std::mem::take(&mut *self.requests.lock().expect("statsd lock poisoned"));
+        for (labels, count) in requests {
+            let tags = [("outcome", labels.outcome), ("policy", &labels.policy), ("key_prefix", &labels.key_prefix), ("host", &labels.host)];
+            lines.push(self.line("requests", &count.to_string(), "c", &tags));
+        }
+
+        let fill = metrics.fill_summary(now_ms);
+        lines.push(self.line("active_buckets", &fill.active.to_string(), "g", &[]));
+        for (name, (_, ratio)) in ["p50", "p90", "p99", "max"].into_iter().zip(fill.quantiles) {
+            lines.push(self.line("bucket_fill_ratio", &ratio.to_string(), "g", &[("quantile", name)]));
+        }
+        for p in &fill.policies {
+            lines.push(self.line("policy.active_buckets", &p.active.to_string(), "g", &[("policy", &p.policy)]));
+            lines.push(self.line("policy.bucket_fill_ratio", &p.mean.to_string(), "g", &[("policy", &p.policy), ("stat", "mean")]));
+            lines.push(self.line("policy.bucket_fill_ratio", &p.max.to_string(), "g", &[("policy", &p.policy), ("stat", "max")]));
+        }
+        self.send(&lines);
+    }
+
+    fn line(&self, name: &str, value: &str, kind: &str, tags: &[(&str, &str)]) -> String {
+        match self.config.flavor {
+            StatsdFlavor::Statsd => {
+                let mut metric = format!("{}.{}", self.config.prefix, name);
+                for (_, v) in tags {
+                    metric.push('.');
+                    metric.push_str(&sanitize(v).replace('.', "_"));
+                }
+                format!("{}:{}|{}", metric, value, kind)
+            },
+            StatsdFlavor::Dogstatsd => {
+                let mut all: Vec<String> = tags.iter().map(|(k, v)| format!("{}:{}", k, sanitize(v))).collect();
+                all.extend(self.config.tags.iter().map(|(k, v)| format!("{}:{}", k, sanitize(v))));
+                all.sort();
+                if all.is_empty() {
+                    format!("{}.{}:{}|{}", self.config.prefix, name, value, kind)
+                } else {
+                    format!("{}.{}:{}|{}|#{}", self.config.prefix, name, value, kind, all.join(","))
+                }
+            },
+        }
+    }
+
+    /// Sends the lines in as few datagrams as possible. Send failures are
+    /// dropped; metrics are best effort.
+    fn send(&self, lines: &[String]) {
+        let mut packet = String::new();
+        for line in lines {
+            if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET_BYTES {
+                let _ = self.socket.send(packet.as_bytes());
+                packet.clear();
+            }
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            packet.push_str(line);
+        }
+        if !packet.is_empty() {
+            let _ = self.socket.send(packet.as_bytes());
+        }
+    }
+}
+
+/// Replaces characters with meaning in the StatsD line protocol.
+fn sanitize(value: &str) -> String {
+    let value = if value.is_empty() { "none" } else { value };
+    value.chars().map(|c| if matches!(c, ':' | '|' | ',' | '#' | '@' | '\n' | ' ') { '_' } else { c }).collect()
+}
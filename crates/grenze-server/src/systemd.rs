@@ -0,0 +1,65 @@
+//! Integration with systemd: listening on a socket passed by socket
+//! activation and reporting the service state through `sd_notify`.
+
+use anyhow::{bail, Context, Result};
+use std::{env, os::{fd::{FromRawFd, RawFd}, linux::net::SocketAddrExt, unix::net::{SocketAddr, UnixDatagram}}, time::Duration};
+
+/// First descriptor passed by socket activation.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The listener passed by systemd socket activation, if the process was
+/// started that way. Only the first passed socket is used.
+pub fn listener() -> Result<Option<std::net::TcpListener>> {
+    let for_us = env::var("LISTEN_PID").ok().and_then(|p| p.parse::<u32>().ok()) == Some(std::process::id());
+    let fds = env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<u32>().ok()).unwrap_or(0);
+    if !for_us || fds == 0 {
+        return Ok(None);
+    }
+    // SAFETY: systemd passed the descriptor to this process, and nothing else
+    // in it owns the descriptor
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(LISTEN_FDS_START) };
+    if listener.local_addr().is_err() {
+        bail!("socket passed by systemd is not a TCP socket");
+    }
+    listener.set_nonblocking(true)?;
+    // Not to be inherited by processes started later
+    // SAFETY: fcntl on a descriptor this process owns
+    unsafe { libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC) };
+    Ok(Some(listener))
+}
+
+/// Sends `state` to systemd, e.g. `READY=1`, if it runs the process as a
+/// notify service.
+pub fn notify(state: &str) {
+    if let Err(e) = try_notify(state) {
+        println!("Failed to notify systemd: {:#}", e);
+    }
+}
+
+fn try_notify(state: &str) -> Result<()> {
+    let Ok(path) = env::var("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(&path)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr).with_context(|| format!("failed to send to {}", path))?;
+    Ok(())
+}
+
+/// Pings the systemd watchdog at half its interval in the background, if
+/// enabled for this process.
+pub fn spawn_watchdog() {
+    let for_us = env::var("WATCHDOG_PID").ok().and_then(|p| p.parse::<u32>().ok()).is_none_or(|pid| pid == std::process::id());
+    let Some(usec) = env::var("WATCHDOG_USEC").ok().and_then(|u| u.parse::<u64>().ok()).filter(|u| *u > 0 && for_us) else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_micros(usec / 2));
+        loop {
+            ticker.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
@@ -0,0 +1,165 @@
+use anyhow::{Context, Result};
+use axum::{extract::{ConnectInfo, Request}, serve::Listener, Router};
+use hyper::body::Incoming;
+use hyper_util::{rt::TokioIo, server::graceful::GracefulShutdown, service::TowerToHyperService};
+use rustls::{pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer}, server::WebPkiClientVerifier, RootCertStore, ServerConfig};
+use serde::Deserialize;
+use std::{future::Future, sync::Arc};
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+use x509_parser::{extensions::GeneralName, prelude::FromDer};
+
+use crate::server::ServerListener;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    /// CA bundle client certificates are verified against. Setting it enables
+    /// mutual TLS.
+    #[serde(default)]
+    pub client_ca_path: Option<String>,
+    /// Whether connections without a client certificate are refused. Only
+    /// relevant when `client_ca_path` is set.
+    #[serde(default = "default_require_client_cert")]
+    pub require_client_cert: bool,
+    /// Certificate field the client identity is taken from.
+    #[serde(default)]
+    pub identity: IdentityField,
+    /// Identities allowed to use the proxy; empty allows every certificate
+    /// signed by the client CA.
+    #[serde(default)]
+    pub allowed_identities: Vec<String>,
+}
+
+fn default_require_client_cert() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentityField {
+    /// First DNS, URI or email subject alternative name, falling back to the
+    /// common name.
+    #[default]
+    San,
+    /// Subject common name.
+    Cn,
+}
+
+/// Identity of a verified client certificate, attached to every request on
+/// the connection.
+#[derive(Debug, Clone)]
+pub struct ClientIdentity(pub String);
+
+impl TlsConfig {
+    pub fn server_config(&self) -> Result<ServerConfig> {
+        let certs = CertificateDer::pem_file_iter(&self.cert_path)
+            .and_then(|c| c.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("failed to read certificate chain {}", self.cert_path))?;
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)
+            .with_context(|| format!("failed to read private key {}", self.key_path))?;
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone()).with_safe_default_protocol_versions()?;
+
+        let builder = match &self.client_ca_path {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(path).with_context(|| format!("failed to read client CA {}", path))? {
+                    roots.add(cert.with_context(|| format!("invalid client CA {}", path))?)?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                let verifier = if self.require_client_cert {
+                    verifier.build()?
+                } else {
+                    verifier.allow_unauthenticated().build()?
+                };
+                builder.with_client_cert_verifier(verifier)
+            },
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_single_cert(certs, key)?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(config)
+    }
+
+    /// Whether a request carrying the given identity may use the proxy.
+    pub fn authorize(&self, identity: Option<&ClientIdentity>) -> bool {
+        if self.allowed_identities.is_empty() {
+            return true;
+        }
+        identity.is_some_and(|id| self.allowed_identities.contains(&id.0))
+    }
+}
+
+fn identity_of(cert: &CertificateDer, field: IdentityField) -> Option<ClientIdentity> {
+    let (_, cert) = x509_parser::certificate::X509Certificate::from_der(cert.as_ref()).ok()?;
+    let cn = || cert.subject().iter_common_name().next().and_then(|cn| cn.as_str().ok()).map(str::to_string);
+    let name = match field {
+        IdentityField::San => cert
+            .subject_alternative_name()
+            .ok()
+            .flatten()
+            .and_then(|san| {
+                san.value.general_names.iter().find_map(|name| match name {
+                    GeneralName::DNSName(s) | GeneralName::URI(s) | GeneralName::RFC822Name(s) => Some(s.to_string()),
+                    _ => None,
+                })
+            })
+            .or_else(cn),
+        IdentityField::Cn => cn(),
+    };
+    name.map(ClientIdentity)
+}
+
+/// Serves the router over TLS, attaching the peer address and the client
+/// certificate identity to every request.
+pub async fn serve(mut listener: ServerListener, app: Router, tls: &TlsConfig, shutdown: impl Future<Output = ()>) -> Result<()> {
+    let acceptor = TlsAcceptor::from(Arc::new(tls.server_config()?));
+    let builder = listener.http();
+    let handshake_timeout = listener.header_read_timeout();
+    let graceful = GracefulShutdown::new();
+    let field = tls.identity;
+    tokio::pin!(shutdown);
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => break,
+        };
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        let builder = builder.clone();
+        let watcher = graceful.watcher();
+        tokio::spawn(async move {
+            // Slow handshakes count against the time for the first request's headers
+            let accepted = match handshake_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, acceptor.accept(stream)).await.unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into())),
+                None => acceptor.accept(stream).await,
+            };
+            let stream = match accepted {
+                Ok(s) => s,
+                Err(e) => {
+                    println!("TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+            };
+            let identity = stream.get_ref().1.peer_certificates().and_then(|c| c.first()).and_then(|c| identity_of(c, field));
+            let service = app.map_request(move |mut req: Request<Incoming>| {
+                req.extensions_mut().insert(ConnectInfo(peer));
+                if let Some(identity) = &identity {
+                    req.extensions_mut().insert(identity.clone());
+                }
+                req
+            });
+            let conn = builder.serve_connection(TokioIo::new(stream), TowerToHyperService::new(service));
+            if let Err(e) = watcher.watch(conn).await {
+                println!("Connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+
+    graceful.shutdown().await;
+    Ok(())
+}
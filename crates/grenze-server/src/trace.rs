@@ -0,0 +1,323 @@
+//! Trace context of requests, read from the caller's W3C `traceparent` or B3
+//! headers and passed on downstream, and spans of proxied requests exported
+//! to Zipkin or Jaeger, so metrics and traces point at each other.
+
+use anyhow::{bail, Result};
+use axum::http::HeaderMap;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::{collections::HashMap, time::{Duration, SystemTime, UNIX_EPOCH}};
+use tokio::sync::mpsc;
+
+use crate::{api::proxy::MultiValue, logging};
+
+/// Spans queued for export; further ones are dropped while the collector
+/// lags behind.
+const SPAN_BUFFER: usize = 10_000;
+/// Most spans sent to the collector at once.
+const SPAN_BATCH: usize = 500;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TracingConfig {
+    /// Formats trace context is read in, the first one found winning, and
+    /// passed on downstream in.
+    #[serde(default = "default_propagation")]
+    pub propagation: Vec<Propagation>,
+    /// Collector spans of proxied requests are sent to; without one, the
+    /// caller's trace context is only passed on.
+    #[serde(default)]
+    pub exporter: Option<Exporter>,
+    /// Share of requests without a sampling decision that are traced.
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+}
+
+fn default_propagation() -> Vec<Propagation> {
+    vec![Propagation::W3c]
+}
+
+fn default_sample_rate() -> f64 {
+    0.01
+}
+
+fn default_service_name() -> String {
+    "grenze".to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Propagation {
+    /// The `traceparent` header of W3C Trace Context.
+    W3c,
+    /// Zipkin's `b3` header, or its `X-B3-*` headers.
+    B3,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Exporter {
+    /// Zipkin's JSON v2 API, e.g. `http://zipkin:9411/api/v2/spans`. Jaeger
+    /// accepts it on its Zipkin-compatible collector port.
+    Zipkin {
+        endpoint: String,
+        #[serde(default = "default_flush_interval_ms")]
+        flush_interval_ms: u64,
+    },
+}
+
+fn default_flush_interval_ms() -> u64 {
+    1000
+}
+
+impl TracingConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.propagation.is_empty() {
+            bail!("tracing propagation must name at least one format");
+        }
+        if !(0.0..=1.0).contains(&self.sample_rate) {
+            bail!("tracing sample_rate must be between 0 and 1");
+        }
+        if let Some(Exporter::Zipkin { endpoint, flush_interval_ms }) = &self.exporter {
+            if !matches!(reqwest::Url::parse(endpoint).map(|u| u.scheme().to_string()).as_deref(), Ok("http" | "https")) {
+                bail!("tracing exporter endpoint must be an http(s) URL");
+            }
+            if *flush_interval_ms == 0 {
+                bail!("tracing exporter flush_interval_ms must be positive");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Trace a request belongs to, with grenze's span of it.
+#[derive(Debug, Clone)]
+pub struct TraceContext {
+    /// 32 hex digits, or 16 for a 64-bit B3 trace.
+    pub trace_id: String,
+    /// Span of the caller, if it sent trace context.
+    pub parent_id: Option<String>,
+    /// grenze's span of the request.
+    pub span_id: String,
+    pub sampled: bool,
+}
+
+/// Trace context as sent by a caller; `sampled` is `None` if it left the
+/// decision to grenze.
+struct Incoming {
+    trace_id: String,
+    span_id: String,
+    sampled: Option<bool>,
+}
+
+/// A new random span ID.
+pub fn span_id() -> String {
+    format!("{:016x}", rand::random::<u64>().max(1))
+}
+
+fn hex_id(s: &str, lens: &[usize]) -> bool {
+    lens.contains(&s.len()) && s.bytes().all(|b| b.is_ascii_hexdigit()) && !s.bytes().all(|b| b == b'0')
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok()).map(str::trim)
+}
+
+impl Propagation {
+    fn read(self, headers: &HeaderMap) -> Option<Incoming> {
+        match self {
+            Propagation::W3c => {
+                let mut fields = header(headers, "traceparent")?.split('-');
+                let (version, trace_id, span_id, flags) = (fields.next()?, fields.next()?, fields.next()?, fields.next()?);
+                if version.len() != 2 || !version.bytes().all(|b| b.is_ascii_hexdigit()) || version.eq_ignore_ascii_case("ff") {
+                    return None;
+                }
+                if !hex_id(trace_id, &[32]) || !hex_id(span_id, &[16]) {
+                    return None;
+                }
+                let flags = u8::from_str_radix(flags, 16).ok()?;
+                Some(Incoming {
+                    trace_id: trace_id.to_ascii_lowercase(),
+                    span_id: span_id.to_ascii_lowercase(),
+                    sampled: Some(flags & 1 == 1),
+                })
+            },
+            Propagation::B3 => {
+                // The single header is `{trace}-{span}[-{sampled}[-{parent}]]`
+                let (trace_id, span_id, sampled) = match header(headers, "b3") {
+                    Some(b3) => {
+                        let mut fields = b3.split('-');
+                        (fields.next()?, fields.next()?, fields.next())
+                    },
+                    None => {
+                        let debug = header(headers, "x-b3-flags") == Some("1");
+                        let sampled = header(headers, "x-b3-sampled").or(debug.then_some("d"));
+                        (header(headers, "x-b3-traceid")?, header(headers, "x-b3-spanid")?, sampled)
+                    },
+                };
+                if !hex_id(trace_id, &[16, 32]) || !hex_id(span_id, &[16]) {
+                    return None;
+                }
+                Some(Incoming {
+                    trace_id: trace_id.to_ascii_lowercase(),
+                    span_id: span_id.to_ascii_lowercase(),
+                    sampled: sampled.map(|s| matches!(s, "1" | "d" | "true")),
+                })
+            },
+        }
+    }
+
+    /// Sets the headers passing `trace` on to a call made in span `span_id`,
+    /// replacing any the caller set.
+    fn write(self, trace: &TraceContext, span_id: &str, headers: &mut HashMap<String, MultiValue>) {
+        let names: &[&str] = match self {
+            Propagation::W3c => &["traceparent"],
+            Propagation::B3 => &["b3", "x-b3-traceid", "x-b3-spanid", "x-b3-parentspanid", "x-b3-sampled", "x-b3-flags"],
+        };
+        headers.retain(|name, _| !names.iter().any(|n| n.eq_ignore_ascii_case(name)));
+        match self {
+            Propagation::W3c => {
+                // 64-bit B3 trace IDs are left-padded to W3C's 128 bits
+                let value = format!("00-{:0>32}-{}-{}", trace.trace_id, span_id, if trace.sampled { "01" } else { "00" });
+                headers.insert("traceparent".to_string(), MultiValue::One(value));
+            },
+            Propagation::B3 => {
+                headers.insert("x-b3-traceid".to_string(), MultiValue::One(trace.trace_id.clone()));
+                headers.insert("x-b3-spanid".to_string(), MultiValue::One(span_id.to_string()));
+                headers.insert("x-b3-sampled".to_string(), MultiValue::One((trace.sampled as u8).to_string()));
+            },
+        }
+    }
+}
+
+/// The caller's W3C trace context, read when tracing is not configured.
+pub fn caller_context(headers: &HeaderMap) -> Option<TraceContext> {
+    let incoming = Propagation::W3c.read(headers)?;
+    Some(TraceContext {
+        trace_id: incoming.trace_id,
+        parent_id: Some(incoming.span_id),
+        span_id: span_id(),
+        sampled: incoming.sampled.unwrap_or_default(),
+    })
+}
+
+/// A finished span of a proxied request.
+pub struct Span<'a> {
+    pub trace: &'a TraceContext,
+    pub id: &'a str,
+    pub parent_id: Option<&'a str>,
+    /// `SERVER` for the request to grenze, `CLIENT` for the downstream call.
+    pub kind: &'static str,
+    pub name: &'a str,
+    pub started: SystemTime,
+    pub duration: Duration,
+    pub tags: Vec<(&'static str, String)>,
+}
+
+pub struct Tracer {
+    config: TracingConfig,
+    sender: Option<mpsc::Sender<Value>>,
+}
+
+impl Tracer {
+    /// Starts exporting spans to the configured collector in the background.
+    pub fn start(config: &TracingConfig, http_client: reqwest::Client) -> Self {
+        let sender = config.exporter.as_ref().map(|exporter| {
+            let (sender, receiver) = mpsc::channel(SPAN_BUFFER);
+            tokio::spawn(export(exporter.clone(), http_client, receiver));
+            sender
+        });
+        Self { config: config.clone(), sender }
+    }
+
+    /// Trace context of a request: the caller's, in the first configured
+    /// format it sent, else a new trace if the request is sampled and spans
+    /// are exported.
+    pub fn extract(&self, headers: &HeaderMap) -> Option<TraceContext> {
+        let sample = || rand::random::<f64>() < self.config.sample_rate;
+        match self.config.propagation.iter().find_map(|p| p.read(headers)) {
+            Some(incoming) => Some(TraceContext {
+                trace_id: incoming.trace_id,
+                parent_id: Some(incoming.span_id),
+                span_id: span_id(),
+                sampled: incoming.sampled.unwrap_or_else(sample),
+            }),
+            None if self.sender.is_some() && sample() => Some(TraceContext {
+                trace_id: format!("{:032x}", rand::random::<u128>().max(1)),
+                parent_id: None,
+                span_id: span_id(),
+                sampled: true,
+            }),
+            None => None,
+        }
+    }
+
+    /// Whether spans of `trace` are exported.
+    pub fn exports(&self, trace: &TraceContext) -> bool {
+        self.sender.is_some() && trace.sampled
+    }
+
+    /// Sets the headers passing `trace` on to a downstream call made in span
+    /// `span_id`, in every configured format.
+    pub fn inject(&self, trace: &TraceContext, span_id: &str, headers: &mut HashMap<String, MultiValue>) {
+        for propagation in &self.config.propagation {
+            propagation.write(trace, span_id, headers);
+        }
+    }
+
+    /// Queues `span` for export if its trace is exported.
+    pub fn record(&self, span: Span) {
+        let Some(sender) = self.sender.as_ref().filter(|_| span.trace.sampled) else {
+            return;
+        };
+        let micros = |d: Duration| d.as_micros() as u64;
+        let mut value = json!({
+            "traceId": span.trace.trace_id,
+            "id": span.id,
+            "name": span.name.to_ascii_lowercase(),
+            "kind": span.kind,
+            "timestamp": micros(span.started.duration_since(UNIX_EPOCH).unwrap_or_default()),
+            "duration": micros(span.duration).max(1),
+            "localEndpoint": { "serviceName": self.config.service_name },
+            "tags": span.tags.into_iter().map(|(k, v)| (k.to_string(), Value::String(v))).collect::<Map<_, _>>(),
+        });
+        if let Some(parent_id) = span.parent_id {
+            value["parentId"] = parent_id.into();
+        }
+        // Dropped rather than slowing down requests while the collector lags
+        let _ = sender.try_send(value);
+    }
+}
+
+/// Sends queued spans to the collector in batches until the tracer is
+/// dropped.
+async fn export(exporter: Exporter, http_client: reqwest::Client, mut receiver: mpsc::Receiver<Value>) {
+    let Exporter::Zipkin { endpoint, flush_interval_ms } = exporter;
+    let mut ticker = tokio::time::interval(Duration::from_millis(flush_interval_ms));
+    let mut batch = Vec::new();
+    loop {
+        tokio::select! {
+            span = receiver.recv() => match span {
+                Some(span) => {
+                    batch.push(span);
+                    if batch.len() < SPAN_BATCH {
+                        continue;
+                    }
+                },
+                None => break,
+            },
+            _ = ticker.tick() => {
+                if batch.is_empty() {
+                    continue;
+                }
+            },
+        }
+        let spans = std::mem::take(&mut batch);
+        let result = http_client.post(&endpoint).json(&spans).send().await.and_then(|r| r.error_for_status());
+        if let Err(e) = result {
+            logging::warn("span_export", format_args!("Failed to export {} spans: {}", spans.len(), e));
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use axum::{http::header::{CONTENT_LENGTH, CONTENT_TYPE}, response::Response};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::{collections::HashMap, sync::Arc};
+
+use crate::middleware::{Context, DownstreamResponse, ProxyMiddleware};
+
+/// Transformation of a JSON response body before it is returned to the
+/// client. Implement this to plug custom transformations into
+/// [`TransformRegistry`] and reference them from policies by name.
+pub trait ResponseTransform: Send + Sync {
+    fn transform(&self, body: Value) -> Value;
+}
+
+/// Built-in transformations, configured per policy. Field names may be dotted
+/// paths into nested objects. When the body is an array, field operations
+/// apply to each element.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum BodyTransform {
+    /// Keeps only the listed fields.
+    Select { fields: Vec<String> },
+    Remove { fields: Vec<String> },
+    Rename { from: String, to: String },
+    /// Wraps the whole body into an object under `field`.
+    Wrap { field: String },
+    /// A transformation registered in code under this name.
+    Custom { name: String },
+}
+
+impl ResponseTransform for BodyTransform {
+    fn transform(&self, body: Value) -> Value {
+        match self {
+            Self::Select { fields } => each(body, &|obj| {
+                let mut out = Map::new();
+                for field in fields {
+                    if let Some(v) = take(obj, field) {
+                        insert(&mut out, field, v);
+                    }
+                }
+                *obj = out;
+            }),
+            Self::Remove { fields } => each(body, &|obj| {
+                for field in fields {
+                    take(obj, field);
+                }
+            }),
+            Self::Rename { from, to } => each(body, &|obj| {
+                if let Some(v) = take(obj, from) {
+                    insert(obj, to, v);
+                }
+            }),
+            Self::Wrap { field } => Value::Object(Map::from_iter([(field.clone(), body)])),
+            // Resolved by the registry
+            Self::Custom { .. } => body,
+        }
+    }
+}
+
+fn each(body: Value, f: &dyn Fn(&mut Map<String, Value>)) -> Value {
+    match body {
+        Value::Object(mut obj) => {
+            f(&mut obj);
+            Value::Object(obj)
+        },
+        Value::Array(items) => Value::Array(items.into_iter().map(|item| each(item, f)).collect()),
+        other => other,
+    }
+}
+
+fn take(obj: &mut Map<String, Value>, path: &str) -> Option<Value> {
+    match path.split_once('.') {
+        Some((head, rest)) => take(obj.get_mut(head)?.as_object_mut()?, rest),
+        None => obj.remove(path),
+    }
+}
+
+fn insert(obj: &mut Map<String, Value>, path: &str, value: Value) {
+    match path.split_once('.') {
+        Some((head, rest)) => {
+            let child = obj.entry(head).or_insert_with(|| Value::Object(Map::new()));
+            if !child.is_object() {
+                *child = Value::Object(Map::new());
+            }
+            if let Value::Object(child) = child {
+                insert(child, rest, value);
+            }
+        },
+        None => {
+            obj.insert(path.to_string(), value);
+        },
+    }
+}
+
+/// Custom transformations available to policies by name.
+#[derive(Clone, Default)]
+pub struct TransformRegistry {
+    custom: HashMap<String, Arc<dyn ResponseTransform>>,
+}
+
+impl std::fmt::Debug for TransformRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.custom.keys()).finish()
+    }
+}
+
+impl TransformRegistry {
+    pub fn register(&mut self, name: impl Into<String>, transform: impl ResponseTransform + 'static) {
+        self.custom.insert(name.into(), Arc::new(transform));
+    }
+
+    pub fn validate(&self, transforms: &[BodyTransform]) -> Result<()> {
+        for t in transforms {
+            if let BodyTransform::Custom { name } = t
+                && !self.custom.contains_key(name)
+            {
+                bail!("unknown custom transform '{}'", name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the transformations in order.
+    pub fn apply(&self, transforms: &[BodyTransform], mut body: Value) -> Value {
+        for t in transforms {
+            body = match t {
+                BodyTransform::Custom { name } => match self.custom.get(name) {
+                    Some(custom) => custom.transform(body),
+                    None => body,
+                },
+                builtin => builtin.transform(body),
+            };
+        }
+        body
+    }
+}
+
+/// Transforms successful JSON bodies as configured by the policy.
+#[async_trait]
+impl ProxyMiddleware for TransformRegistry {
+    async fn on_response(&self, ctx: &Context<'_>, response: &mut DownstreamResponse) -> Result<(), Response> {
+        let is_json = response
+            .headers
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|ct| ct.starts_with("application/json") || ct.contains("+json"));
+        if !ctx.policy.transform.is_empty()
+            && response.status.is_success()
+            && is_json
+            && let Ok(body) = serde_json::from_slice(&response.body)
+        {
+            let body = self.apply(&ctx.policy.transform, body);
+            response.headers.remove(CONTENT_LENGTH);
+            crate::headers::weaken_etag(&mut response.headers);
+            response.body = serde_json::to_vec(&body).unwrap_or_default().into();
+        }
+        Ok(())
+    }
+}
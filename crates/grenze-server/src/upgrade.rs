@@ -0,0 +1,101 @@
+//! Zero-downtime binary upgrades: on `SIGUSR2` the running process starts
+//! the binary again, which may have been replaced in the meantime, handing it
+//! the listening socket. Once the new process is ready it tells the old one
+//! to shut down, which stops accepting connections and finishes the requests
+//! in flight. Connections arriving meanwhile wait in the shared backlog.
+
+use anyhow::{Context, Result};
+use std::{env, os::{fd::{FromRawFd, RawFd}, unix::process::CommandExt}, process::Command, sync::atomic::{AtomicBool, Ordering}};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::systemd;
+
+/// Descriptor of the listening socket handed over by the previous process.
+const LISTEN_FD_ENV: &str = "GRENZE_LISTEN_FD";
+/// Pid of the previous process, shut down once the new one is ready.
+const PREVIOUS_PID_ENV: &str = "GRENZE_UPGRADE_FROM";
+
+/// Whether an upgraded process was started and has not exited.
+static UPGRADING: AtomicBool = AtomicBool::new(false);
+
+/// Whether this process is being replaced by an upgraded one, rather than
+/// stopped.
+pub fn upgrading() -> bool {
+    UPGRADING.load(Ordering::SeqCst)
+}
+
+/// The listener handed over by the previous process, if started by one.
+pub fn inherited() -> Result<Option<std::net::TcpListener>> {
+    let Ok(fd) = env::var(LISTEN_FD_ENV) else {
+        return Ok(None);
+    };
+    let fd: RawFd = fd.parse().with_context(|| format!("invalid {}", LISTEN_FD_ENV))?;
+    // SAFETY: the previous process passed the descriptor of its listener,
+    // which nothing else in this process owns
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    // Not to be inherited by processes started later
+    // SAFETY: fcntl on a descriptor this process owns
+    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    Ok(Some(listener))
+}
+
+/// Shuts the previous process down, if this one took over from it.
+pub fn take_over() {
+    let Some(pid) = env::var(PREVIOUS_PID_ENV).ok().and_then(|p| p.parse::<u32>().ok()) else {
+        return;
+    };
+    // Only the process that started this one, not an unrelated one reusing the pid
+    if pid != std::os::unix::process::parent_id() {
+        return;
+    }
+    println!("Taking over from process {}", pid);
+    // Requires NotifyAccess=all, as systemd did not start this process
+    systemd::notify(&format!("MAINPID={}", std::process::id()));
+    // SAFETY: kill has no memory safety requirements
+    unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+}
+
+/// Starts the binary again on every `SIGUSR2`, handing it `listener`.
+pub fn spawn_on_signal(listener: RawFd) {
+    tokio::spawn(async move {
+        let mut sigusr2 = signal(SignalKind::user_defined2()).expect("failed to install SIGUSR2 handler");
+        while sigusr2.recv().await.is_some() {
+            println!("Received SIGUSR2. Starting upgraded process...");
+            if let Err(e) = spawn(listener) {
+                println!("Failed to start upgraded process: {:#}", e);
+            }
+        }
+    });
+}
+
+fn spawn(listener: RawFd) -> Result<()> {
+    // argv[0] rather than current_exe, which points to the replaced binary
+    let mut args = env::args_os();
+    let program = args.next().context("missing program name")?;
+    let mut command = Command::new(&program);
+    command
+        .args(args)
+        .env(LISTEN_FD_ENV, listener.to_string())
+        .env(PREVIOUS_PID_ENV, std::process::id().to_string())
+        // The new process pings the systemd watchdog as the main process
+        .env_remove("WATCHDOG_PID");
+    // SAFETY: only calls fcntl, which is async-signal-safe, between fork and exec
+    unsafe {
+        command.pre_exec(move || match libc::fcntl(listener, libc::F_SETFD, 0) {
+            -1 => Err(std::io::Error::last_os_error()),
+            _ => Ok(()),
+        });
+    }
+    let mut child = command.spawn().with_context(|| format!("failed to run {}", program.to_string_lossy()))?;
+    UPGRADING.store(true, Ordering::SeqCst);
+    // Reaps the process and reports upgrades that failed before taking over
+    std::thread::spawn(move || {
+        match child.wait() {
+            Ok(status) => println!("Upgraded process {} exited: {}", child.id(), status),
+            Err(e) => println!("Failed to wait for upgraded process {}: {}", child.id(), e),
+        }
+        UPGRADING.store(false, Ordering::SeqCst);
+    });
+    Ok(())
+}
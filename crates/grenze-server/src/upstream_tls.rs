@@ -0,0 +1,134 @@
+//! TLS settings of the connections to a policy's upstreams: private CA
+//! bundles, client certificates for mutual TLS, a minimum protocol version,
+//! and skipping verification during development. Policies with settings of
+//! their own get a downstream client of their own, shared by their requests.
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::{client_profiles::ClientProfiles, policy::Policy};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamTlsConfig {
+    /// CA bundle upstream certificates are verified against instead of the
+    /// built-in roots.
+    #[serde(default)]
+    pub ca_path: Option<String>,
+    /// Certificate chain presented to upstreams requiring mutual TLS, with
+    /// its key in `client_key_path`.
+    #[serde(default)]
+    pub client_cert_path: Option<String>,
+    #[serde(default)]
+    pub client_key_path: Option<String>,
+    /// Oldest protocol version accepted.
+    #[serde(default)]
+    pub min_version: Option<TlsVersion>,
+    /// Accepts any upstream certificate, for development against
+    /// self-signed upstreams only.
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// User agent of the clients sending requests downstream.
+pub const USER_AGENT: &str = "grenze-server-proxy/0.0.0";
+
+/// Builder of the clients sending requests downstream over HTTP/1.1, without
+/// any TLS settings of policies.
+pub fn builder() -> reqwest::ClientBuilder {
+    reqwest::Client::builder().user_agent(USER_AGENT).http1_only()
+}
+
+impl UpstreamTlsConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.client_cert_path.is_some() != self.client_key_path.is_some() {
+            bail!("upstream_tls client_cert_path and client_key_path must be set together");
+        }
+        if self.insecure_skip_verify && self.ca_path.is_some() {
+            bail!("upstream_tls ca_path is pointless with insecure_skip_verify");
+        }
+        self.client().map(|_| ())
+    }
+
+    /// Client applying these settings, reading the certificate files.
+    pub fn client(&self) -> Result<reqwest::Client> {
+        self.apply(builder())?.build().context("failed to build the upstream client")
+    }
+
+    /// Applies these settings to `builder`, reading the certificate files.
+    pub fn apply(&self, mut builder: reqwest::ClientBuilder) -> Result<reqwest::ClientBuilder> {
+        if let Some(path) = &self.ca_path {
+            let pem = std::fs::read(path).with_context(|| format!("failed to read upstream CA bundle {}", path))?;
+            let certs = reqwest::Certificate::from_pem_bundle(&pem).with_context(|| format!("invalid upstream CA bundle {}", path))?;
+            if certs.is_empty() {
+                bail!("upstream CA bundle {} holds no certificates", path);
+            }
+            builder = certs.into_iter().fold(builder.tls_built_in_root_certs(false), |b, cert| b.add_root_certificate(cert));
+        }
+        if let (Some(cert_path), Some(key_path)) = (&self.client_cert_path, &self.client_key_path) {
+            let mut pem = std::fs::read(cert_path).with_context(|| format!("failed to read upstream client certificate {}", cert_path))?;
+            pem.push(b'\n');
+            pem.extend(std::fs::read(key_path).with_context(|| format!("failed to read upstream client key {}", key_path))?);
+            let identity = reqwest::Identity::from_pem(&pem).with_context(|| format!("invalid upstream client certificate {} or key {}", cert_path, key_path))?;
+            builder = builder.identity(identity);
+        }
+        if let Some(version) = self.min_version {
+            builder = builder.min_tls_version(match version {
+                TlsVersion::Tls12 => reqwest::tls::Version::TLS_1_2,
+                TlsVersion::Tls13 => reqwest::tls::Version::TLS_1_3,
+            });
+        }
+        if self.insecure_skip_verify {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        Ok(builder)
+    }
+}
+
+/// Clients of the policies with TLS settings, applied on top of the client
+/// profile a request resolves to, built when first used and rebuilt once a
+/// policy's settings change.
+#[derive(Default)]
+pub struct UpstreamClients {
+    clients: Mutex<HashMap<ClientKey, (UpstreamTlsConfig, reqwest::Client)>>,
+}
+
+/// Names of a policy and of the client profile its settings apply on top of.
+type ClientKey = (String, Option<String>);
+
+impl UpstreamClients {
+    /// Client for requests of `policy` to `host`: that of their client
+    /// profile, with the policy's TLS settings if it has any.
+    pub fn get(&self, profiles: &ClientProfiles, policy: &Policy, host: Option<&str>) -> Result<reqwest::Client> {
+        let (profile, client) = profiles.resolve(policy, host);
+        let Some(tls) = &policy.upstream_tls else {
+            return Ok(client.clone());
+        };
+        let key = (policy.name.clone(), profile.map(|p| p.name.clone()));
+        let mut clients = self.clients.lock().expect("upstream clients lock poisoned");
+        if let Some((built_for, client)) = clients.get(&key)
+            && built_for == tls
+        {
+            return Ok(client.clone());
+        }
+        let builder = match profile {
+            Some(profile) => profile.builder().with_context(|| format!("client profile '{}'", profile.name))?,
+            None => builder(),
+        };
+        let client = tls
+            .apply(builder)
+            .and_then(|b| b.build().context("failed to build the upstream client"))
+            .with_context(|| format!("policy '{}'", policy.name))?;
+        clients.insert(key, (tls.clone(), client.clone()));
+        Ok(client)
+    }
+}
@@ -0,0 +1,329 @@
+//! Usage history per rate limit key. Every instance counts its decisions per
+//! key, policy and minute, and flushes the counts to the store as raw usage
+//! every few seconds. The leader rolls the raw usage of past minutes up into
+//! minute, hour and day totals, which expire after their retention, so the
+//! raw usage stays small. The leader also purges rollups and raw minutes past
+//! the current retention, which covers history kept under a longer one. Rolled
+//! up minutes can also be appended to daily CSV files for offline analysis.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs::OpenOptions, io::Write, path::Path, sync::{Arc, Mutex}, time::{Duration, UNIX_EPOCH}};
+use utoipa::ToSchema;
+
+use crate::{aws, erasure::Tenant, leader::Scheduler, limiter::{Clock, LimiterStore}, logging};
+
+/// Most periods returned by one usage query.
+pub const MAX_PERIODS: i64 = 1440;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsageConfig {
+    /// How often instances flush their counts to the store.
+    pub flush_interval_secs: u64,
+    /// How often the leader rolls up the raw usage of past minutes.
+    pub rollup_interval_secs: u64,
+    /// How often the leader purges history past its retention.
+    pub purge_interval_secs: u64,
+    pub retention: UsageRetention,
+    /// Directory rolled up minutes are appended to, one CSV file per UTC day.
+    pub export_dir: Option<String>,
+}
+
+impl Default for UsageConfig {
+    fn default() -> Self {
+        Self {
+            flush_interval_secs: 10,
+            rollup_interval_secs: 60,
+            purge_interval_secs: 3600,
+            retention: UsageRetention::default(),
+            export_dir: None,
+        }
+    }
+}
+
+/// Days the rollups of every granularity are kept, counted from the start of
+/// their period.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsageRetention {
+    pub minute_days: u64,
+    pub hour_days: u64,
+    pub day_days: u64,
+    /// Days raw usage is kept if it is never rolled up, e.g. while no
+    /// instance leads.
+    pub raw_days: u64,
+}
+
+impl Default for UsageRetention {
+    fn default() -> Self {
+        Self {
+            minute_days: 1,
+            hour_days: 31,
+            day_days: 400,
+            raw_days: 7,
+        }
+    }
+}
+
+impl UsageConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.flush_interval_secs == 0 || self.rollup_interval_secs == 0 || self.purge_interval_secs == 0 {
+            bail!("usage flush_interval_secs, rollup_interval_secs and purge_interval_secs must be positive");
+        }
+        let retention = &self.retention;
+        if retention.minute_days == 0 || retention.hour_days == 0 || retention.day_days == 0 || retention.raw_days == 0 {
+            bail!("usage retention must be at least one day");
+        }
+        if let Some(dir) = &self.export_dir
+            && !Path::new(dir).is_dir()
+        {
+            bail!("usage export_dir {} is not a directory", dir);
+        }
+        Ok(())
+    }
+
+    /// Time after the end of a minute by which its usage is rolled up, with a
+    /// minute to spare.
+    pub fn settle(&self) -> Duration {
+        Duration::from_secs(60 + self.flush_interval_secs + self.rollup_interval_secs)
+    }
+
+    fn retention_secs(&self, granularity: Granularity) -> u64 {
+        let days = match granularity {
+            Granularity::Minute => self.retention.minute_days,
+            Granularity::Hour => self.retention.hour_days,
+            Granularity::Day => self.retention.day_days,
+        };
+        days * 86_400
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Granularity {
+    Minute,
+    Hour,
+    /// UTC day.
+    Day,
+}
+
+impl Granularity {
+    pub const ALL: [Granularity; 3] = [Granularity::Minute, Granularity::Hour, Granularity::Day];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Granularity::Minute => "minute",
+            Granularity::Hour => "hour",
+            Granularity::Day => "day",
+        }
+    }
+
+    pub fn period_ms(self) -> i64 {
+        match self {
+            Granularity::Minute => 60_000,
+            Granularity::Hour => 3_600_000,
+            Granularity::Day => 86_400_000,
+        }
+    }
+
+    /// Start of the period containing `at_ms`.
+    pub fn start(self, at_ms: i64) -> i64 {
+        at_ms - at_ms.rem_euclid(self.period_ms())
+    }
+}
+
+/// Requests of a key under a policy in the period starting at `period_ms`.
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct UsageCount {
+    pub period_ms: i64,
+    pub key: String,
+    pub policy: String,
+    pub allowed: u64,
+    pub limited: u64,
+    /// Size of the downstream responses.
+    pub bytes: u64,
+}
+
+/// Start of a period, key and policy usage is counted by.
+pub type UsageId = (i64, String, String);
+
+impl UsageCount {
+    pub fn new((period_ms, key, policy): UsageId) -> Self {
+        Self {
+            period_ms,
+            key,
+            policy,
+            allowed: 0,
+            limited: 0,
+            bytes: 0,
+        }
+    }
+
+    pub fn id(&self) -> UsageId {
+        (self.period_ms, self.key.clone(), self.policy.clone())
+    }
+
+    /// Adds the requests and bytes of `other`.
+    pub fn add(&mut self, other: &UsageCount) {
+        self.allowed += other.allowed;
+        self.limited += other.limited;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Counts of this instance not flushed to the store yet.
+pub struct UsageTracker {
+    config: UsageConfig,
+    /// Usage by minute, key and policy.
+    pending: Mutex<HashMap<UsageId, UsageCount>>,
+}
+
+impl UsageTracker {
+    pub fn new(config: &UsageConfig) -> Self {
+        Self {
+            config: config.clone(),
+            pending: Mutex::default(),
+        }
+    }
+
+    pub fn record(&self, key: &str, policy: &str, allowed: bool, now_ms: i64) {
+        let mut pending = self.pending.lock().expect("usage lock poisoned");
+        let count = pending.entry((Granularity::Minute.start(now_ms), key.to_string(), policy.to_string())).or_insert_with_key(|id| UsageCount::new(id.clone()));
+        if allowed {
+            count.allowed += 1;
+        } else {
+            count.limited += 1;
+        }
+    }
+
+    /// Records a downstream response of `bytes` to an allowed request.
+    pub fn record_bytes(&self, key: &str, policy: &str, bytes: u64, now_ms: i64) {
+        let mut pending = self.pending.lock().expect("usage lock poisoned");
+        let count = pending.entry((Granularity::Minute.start(now_ms), key.to_string(), policy.to_string())).or_insert_with_key(|id| UsageCount::new(id.clone()));
+        count.bytes += bytes;
+    }
+
+    /// Drops the pending counts of `tenant`'s keys.
+    pub fn forget(&self, tenant: &Tenant) {
+        self.pending.lock().expect("usage lock poisoned").retain(|(_, key, _), _| !tenant.owns_key(key));
+    }
+
+    /// Flushes the counts every `flush_interval_secs` and schedules the
+    /// rollups and purges on the leader.
+    pub fn spawn(self: &Arc<Self>, limiter: Arc<dyn LimiterStore>, scheduler: &Scheduler, clock: Arc<dyn Clock>) {
+        let tracker = self.clone();
+        let flushed = limiter.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(tracker.config.flush_interval_secs));
+            loop {
+                ticker.tick().await;
+                tracker.flush(flushed.as_ref()).await;
+            }
+        });
+        let (config, rolled, rollup_clock) = (self.config.clone(), limiter.clone(), clock.clone());
+        scheduler.every("usage rollup", Duration::from_secs(self.config.rollup_interval_secs), move || {
+            let (limiter, config, now_ms) = (rolled.clone(), config.clone(), rollup_clock.now_ms());
+            async move { roll_up(limiter.as_ref(), &config, now_ms).await }
+        });
+        let config = self.config.clone();
+        scheduler.every("usage purge", Duration::from_secs(self.config.purge_interval_secs), move || {
+            let (limiter, config, now_ms) = (limiter.clone(), config.clone(), clock.now_ms());
+            async move { purge(limiter.as_ref(), &config, now_ms).await }
+        });
+    }
+
+    /// Adds the pending counts to the store's raw usage, keeping them for the
+    /// next flush if the store fails.
+    pub async fn flush(&self, limiter: &dyn LimiterStore) {
+        let pending = std::mem::take(&mut *self.pending.lock().expect("usage lock poisoned"));
+        if pending.is_empty() {
+            return;
+        }
+        let counts: Vec<UsageCount> = pending.into_values().collect();
+        if let Err(e) = limiter.record_usage(&counts, self.config.retention.raw_days * 86_400).await {
+            logging::warn("usage_flush", format_args!("Failed to flush usage of {} keys: {:#}", counts.len(), e));
+            let mut current = self.pending.lock().expect("usage lock poisoned");
+            for count in counts {
+                current.entry(count.id()).or_insert_with_key(|id| UsageCount::new(id.clone())).add(&count);
+            }
+        }
+    }
+}
+
+/// Rolls the raw usage of minutes that instances have flushed by now up into
+/// every granularity. Minutes flushed late are rolled up by a later run,
+/// adding to the same rollups.
+async fn roll_up(limiter: &dyn LimiterStore, config: &UsageConfig, now_ms: i64) -> Result<()> {
+    let before_ms = Granularity::Minute.start(now_ms - config.flush_interval_secs as i64 * 1000);
+    let raw = limiter.take_usage(before_ms).await?;
+    if raw.is_empty() {
+        return Ok(());
+    }
+    for granularity in Granularity::ALL {
+        let mut totals: HashMap<UsageId, UsageCount> = HashMap::new();
+        for count in &raw {
+            let id = (granularity.start(count.period_ms), count.key.clone(), count.policy.clone());
+            totals.entry(id).or_insert_with_key(|id| UsageCount::new(id.clone())).add(count);
+        }
+        let rollups: Vec<UsageCount> = totals.into_values().collect();
+        limiter
+            .roll_up_usage(granularity, &rollups, config.retention_secs(granularity))
+            .await
+            .with_context(|| format!("failed to roll up usage by {}", granularity.as_str()))?;
+    }
+    if let Some(dir) = &config.export_dir {
+        export(Path::new(dir), &raw).context("failed to export usage")?;
+    }
+    Ok(())
+}
+
+/// Deletes the rollups and raw minutes older than their retention.
+async fn purge(limiter: &dyn LimiterStore, config: &UsageConfig, now_ms: i64) -> Result<()> {
+    let mut purged = 0;
+    for granularity in Granularity::ALL {
+        let before_ms = now_ms - config.retention_secs(granularity) as i64 * 1000;
+        purged += limiter
+            .purge_usage(Some(granularity), before_ms)
+            .await
+            .with_context(|| format!("failed to purge usage by {}", granularity.as_str()))?;
+    }
+    let before_ms = now_ms - config.retention.raw_days as i64 * 86_400_000;
+    purged += limiter.purge_usage(None, before_ms).await.context("failed to purge raw usage")?;
+    if purged > 0 {
+        println!("Purged {} usage records past their retention", purged);
+    }
+    Ok(())
+}
+
+/// Appends `counts` to the CSV file of their UTC day in `dir`.
+fn export(dir: &Path, counts: &[UsageCount]) -> Result<()> {
+    let mut days: HashMap<i64, Vec<&UsageCount>> = HashMap::new();
+    for count in counts {
+        days.entry(Granularity::Day.start(count.period_ms)).or_default().push(count);
+    }
+    for (day_ms, mut counts) in days {
+        counts.sort_by(|a, b| (a.period_ms, &a.key, &a.policy).cmp(&(b.period_ms, &b.key, &b.policy)));
+        let (_, date) = aws::timestamps(UNIX_EPOCH + Duration::from_millis(day_ms as u64));
+        let path = dir.join(format!("usage-{}.csv", date));
+        let mut file = OpenOptions::new().create(true).append(true).open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+        let mut csv = String::new();
+        if file.metadata()?.len() == 0 {
+            csv.push_str("minute_ms,key,policy,allowed,limited,bytes\n");
+        }
+        for c in counts {
+            csv.push_str(&format!("{},{},{},{},{},{}\n", c.period_ms, csv_field(&c.key), csv_field(&c.policy), c.allowed, c.limited, c.bytes));
+        }
+        file.write_all(csv.as_bytes()).with_context(|| format!("failed to write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Quotes `value` if it contains a separator, quote or line break.
+pub(crate) fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
@@ -0,0 +1,166 @@
+//! Utilities for testing against grenze without Redis or real upstreams.
+//!
+//! [`TestServer`] runs grenze in-process on a random local port with an
+//! in-memory limiter driven by a [`FakeClock`], so tests can exhaust buckets
+//! and advance time deterministically instead of sleeping:
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use grenze_testing::{Config, TestServer};
+//! use std::time::Duration;
+//!
+//! let server = TestServer::start(Config::default()).await?;
+//! // ... send a request for key "tenant-a" to server.proxy_url() ...
+//! server.assert_fill("default#key:tenant-a", 1.0).await;
+//! server.clock().advance(Duration::from_secs(1));
+//! server.assert_empty("default#key:tenant-a").await;
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::Result;
+use grenze_server::{api::{self, proxy::AppState}, limiter::{Clock, MemoryStore, SystemClock}};
+use std::{net::SocketAddr, sync::{atomic::{AtomicI64, Ordering}, Arc}, time::Duration};
+use tokio::{sync::oneshot, task::JoinHandle};
+
+pub use grenze_server::config::Config;
+
+/// Clock that only moves when told to. Starts at the current time.
+#[derive(Clone)]
+pub struct FakeClock {
+    now_ms: Arc<AtomicI64>,
+}
+
+impl Default for FakeClock {
+    fn default() -> Self {
+        Self::at(SystemClock.now_ms())
+    }
+}
+
+impl FakeClock {
+    pub fn at(now_ms: i64) -> Self {
+        Self {
+            now_ms: Arc::new(AtomicI64::new(now_ms)),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.now_ms.fetch_add(by.as_millis() as i64, Ordering::SeqCst);
+    }
+
+    pub fn set(&self, now_ms: i64) {
+        self.now_ms.store(now_ms, Ordering::SeqCst);
+    }
+}
+
+impl Clock for FakeClock {
+    fn now_ms(&self) -> i64 {
+        self.now_ms.load(Ordering::SeqCst)
+    }
+}
+
+/// grenze served in-process on `127.0.0.1` until dropped.
+pub struct TestServer {
+    addr: SocketAddr,
+    clock: FakeClock,
+    limiter: Arc<MemoryStore>,
+    state: AppState,
+    shutdown: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+
+impl TestServer {
+    /// Starts a server admitting one request per second per bucket, like the
+    /// production default.
+    pub async fn start(config: Config) -> Result<Self> {
+        Self::start_with_rate(config, 1).await
+    }
+
+    /// Starts a server whose buckets hold `rps` requests and leak `rps` per
+    /// second, unless the limiter config sizes them.
+    pub async fn start_with_rate(config: Config, rps: u32) -> Result<Self> {
+        config.validate()?;
+        let clock = FakeClock::default();
+        let limiter = Arc::new(MemoryStore::new(Arc::new(clock.clone())));
+        let state = AppState::with_limiter(rps, limiter.clone(), Arc::new(clock.clone()), &config)?;
+        let app = api::router(&config, state.clone())?;
+
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        let (shutdown, stopped) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            let _ = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(async {
+                    let _ = stopped.await;
+                })
+                .await;
+        });
+        Ok(Self {
+            addr,
+            clock,
+            limiter,
+            state,
+            shutdown: Some(shutdown),
+            task,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Base URL of the server, e.g. `http://127.0.0.1:41234`.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn proxy_url(&self) -> String {
+        format!("{}/proxy", self.url())
+    }
+
+    pub fn clock(&self) -> &FakeClock {
+        &self.clock
+    }
+
+    pub fn limiter(&self) -> &MemoryStore {
+        &self.limiter
+    }
+
+    pub fn state(&self) -> &AppState {
+        &self.state
+    }
+
+    /// Fill level of `bucket`, named as by `/admin/explain`, e.g.
+    /// `default#key:tenant-a`; 0 if it holds no requests.
+    pub async fn fill(&self, bucket: &str) -> f64 {
+        self.limiter.fill(bucket).await.unwrap_or(0.0)
+    }
+
+    /// Panics unless `bucket` holds `expected` requests (within 0.001).
+    pub async fn assert_fill(&self, bucket: &str, expected: f64) {
+        let fill = self.fill(bucket).await;
+        assert!((fill - expected).abs() < 0.001, "bucket '{}' holds {} requests, expected {}", bucket, fill, expected);
+    }
+
+    /// Panics unless `bucket` has fully drained.
+    pub async fn assert_empty(&self, bucket: &str) {
+        self.assert_fill(bucket, 0.0).await;
+    }
+
+    /// Stops the server and waits for in-flight requests to finish.
+    pub async fn shutdown(mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        let _ = (&mut self.task).await;
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if self.shutdown.is_none() {
+            return;
+        }
+        self.task.abort();
+    }
+}
@@ -0,0 +1,84 @@
+//! Rate limiting of a [`TestServer`] as its clients see it, with the
+//! downstream mocked and time moved by its fake clock.
+
+use grenze_testing::{Config, TestServer};
+use reqwest::{header::RETRY_AFTER, StatusCode};
+use std::time::Duration;
+
+/// Own bucket of `alice` under the policy of [`server`].
+const BUCKET: &str = "mocked#key:alice";
+
+/// Server whose policy holds 2 requests and leaks `leak_per_sec`, answering
+/// every request to `api.example.com` itself.
+async fn server(leak_per_sec: f64) -> TestServer {
+    let config: Config = serde_json::from_value(serde_json::json!({
+        "policies": [{
+            "name": "mocked",
+            "hosts": ["api.example.com"],
+            "capacity": 2,
+            "leak_per_sec": leak_per_sec,
+            "mocks": [{"url": "https://api.example.com/*", "body": {"ok": true}}],
+        }],
+    }))
+    .unwrap();
+    TestServer::start(config).await.unwrap()
+}
+
+async fn send(server: &TestServer, key: &str) -> reqwest::Response {
+    let request = serde_json::json!({"key": key, "method": "GET", "url": "https://api.example.com/items", "headers": {}, "query": {}});
+    reqwest::Client::new().post(server.proxy_url()).json(&request).send().await.unwrap()
+}
+
+fn retry_after(response: &reqwest::Response) -> Option<u64> {
+    response.headers().get(RETRY_AFTER)?.to_str().ok()?.parse().ok()
+}
+
+#[tokio::test]
+async fn admits_up_to_the_capacity_and_rejects_the_rest() {
+    let server = server(1.0).await;
+    for _ in 0..2 {
+        let response = send(&server, "alice").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.json::<serde_json::Value>().await.unwrap(), serde_json::json!({"ok": true}));
+    }
+    server.assert_fill(BUCKET, 2.0).await;
+
+    let response = send(&server, "alice").await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()["x-rejection-reason"], "rate_limited");
+    // Rejected requests take no tokens, and other keys have buckets of their own
+    server.assert_fill(BUCKET, 2.0).await;
+    assert_eq!(send(&server, "bob").await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn buckets_leak_over_time() {
+    let server = server(1.0).await;
+    send(&server, "alice").await;
+    send(&server, "alice").await;
+
+    server.clock().advance(Duration::from_millis(500));
+    server.assert_fill(BUCKET, 1.5).await;
+    assert_eq!(send(&server, "alice").await.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    server.clock().advance(Duration::from_millis(500));
+    assert_eq!(send(&server, "alice").await.status(), StatusCode::OK);
+    server.clock().advance(Duration::from_secs(2));
+    server.assert_empty(BUCKET).await;
+}
+
+#[tokio::test]
+async fn rejections_tell_when_to_retry() {
+    let server = server(0.25).await;
+    send(&server, "alice").await;
+    send(&server, "alice").await;
+
+    let response = send(&server, "alice").await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(retry_after(&response), Some(4));
+
+    server.clock().advance(Duration::from_secs(3));
+    assert_eq!(retry_after(&send(&server, "alice").await), Some(1));
+    server.clock().advance(Duration::from_secs(1));
+    assert_eq!(send(&server, "alice").await.status(), StatusCode::OK);
+}